@@ -9,6 +9,25 @@
 
 use zerocopy::AsBytes;
 
+/// The maximum number of snapshot measurements that can be approved in the
+/// IGVM parameter block.
+pub const IGVM_SNAPSHOT_DIGEST_MAX: usize = 8;
+
+/// On restore, the vTPM is reset and the restore is recorded as an event.
+pub const IGVM_VTPM_RESTORE_RESET: u32 = 0;
+/// On restore, the vTPM state is rewound together with guest memory.
+pub const IGVM_VTPM_RESTORE_SNAPSHOT: u32 = 1;
+
+/// Paranoid backup check: verify a digest of every page before restoring it.
+pub const IGVM_PARANOID_PAGE_HASHES: u32 = 1 << 0;
+/// Paranoid backup check: check the RMP entry of every restored page.
+pub const IGVM_PARANOID_RMP_CHECKS: u32 = 1 << 1;
+/// Paranoid backup check: guard unused snapshot memory with canaries.
+pub const IGVM_PARANOID_CANARIES: u32 = 1 << 2;
+/// Paranoid backup check: check that the guest page tables stay within the
+/// registered backup ranges.
+pub const IGVM_PARANOID_PAGE_TABLES: u32 = 1 << 3;
+
 /// The IGVM parameter page is an unmeasured page containing individual
 /// parameters that are provided by the host loader.
 #[repr(C, packed)]
@@ -106,6 +125,13 @@ pub struct IgvmParamBlock {
     /// context is present.
     pub guest_context_offset: u32,
 
+    /// The offset, in bytes, from the base of the parameter block to the base
+    /// of the MADT supplied by the host loader, or zero if no MADT is present.
+    pub madt_offset: u32,
+
+    /// The number of bytes reserved for the MADT.
+    pub madt_size: u32,
+
     /// The guest physical address of the CPUID page.
     pub cpuid_page: u32,
 
@@ -146,6 +172,63 @@ pub struct IgvmParamBlock {
 
     /// The value of vTOM used by the guest, or zero if not used.
     pub vtom: u64,
+
+    /// The number of valid entries in `snapshot_digests`.
+    pub snapshot_digest_count: u32,
+
+    #[doc(hidden)]
+    pub _reserved3: u32,
+
+    /// The SHA-256 measurements of the external snapshots which may be
+    /// imported into the SVSM. Snapshots with any other measurement are
+    /// refused.
+    pub snapshot_digests: [[u8; 32]; IGVM_SNAPSHOT_DIGEST_MAX],
+
+    /// The maximum number of bytes of SVSM memory a single snapshot may use,
+    /// or zero for no limit.
+    pub snapshot_budget: u64,
+
+    /// The maximum number of bytes of SVSM memory all snapshot state may use
+    /// together, or zero for no limit.
+    pub snapshot_global_budget: u64,
+
+    /// The number of pages in the per-CPU window for temporary 4K mappings,
+    /// or zero for the default.
+    pub percpu_map_window_pages: u32,
+
+    #[doc(hidden)]
+    pub _reserved4: u32,
+
+    /// The guest physical address of a shared page into which the SVSM
+    /// publishes its health state for the host, or zero if not used.
+    pub health_page: u64,
+
+    /// The bandwidth of backup copies as a percentage of the copy
+    /// throughput measured at boot, or zero for no limit.
+    pub backup_bandwidth_percent: u32,
+
+    /// How the vTPM state is handled when a restore rewinds guest memory,
+    /// either [`IGVM_VTPM_RESTORE_RESET`] or [`IGVM_VTPM_RESTORE_SNAPSHOT`].
+    pub vtpm_restore_policy: u32,
+
+    /// The guest physical address of a region which the SVSM converts to
+    /// shared memory at boot and uses to hand data to the host, or zero if
+    /// not used.
+    pub scratch_region: u64,
+
+    /// The number of pages of the scratch region, at least three.
+    pub scratch_region_pages: u32,
+
+    /// The expensive backup safety checks enabled at boot, a combination of
+    /// the `IGVM_PARANOID_*` flags.
+    pub paranoid_checks: u32,
+
+    /// Whether backed-up pages are stored compressed where that saves
+    /// memory, zero to store all pages uncompressed.
+    pub backup_compression: u32,
+
+    #[doc(hidden)]
+    pub _reserved5: u32,
 }
 
 /// The IGVM context page is a measured page that is used to specify the start
@@ -4,7 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-const SNP_CPUID_MAX_COUNT: usize = 64;
+pub const SNP_CPUID_MAX_COUNT: usize = 64;
 
 #[derive(Copy, Clone, Default, Debug)]
 #[repr(C, packed)]
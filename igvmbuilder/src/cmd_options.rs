@@ -75,6 +75,59 @@ pub struct CmdOptions {
     /// Use Alternate Injection if available
     #[arg(long, default_value_t = false)]
     pub alt_injection: bool,
+
+    /// SHA-256 measurement (64 hex digits) of a snapshot which may be imported
+    /// into the SVSM. Can be given multiple times.
+    #[arg(long)]
+    pub snapshot_digest: Vec<String>,
+
+    /// Maximum number of bytes of SVSM memory a single snapshot may use
+    /// (0 for no limit)
+    #[arg(long, default_value_t = 0)]
+    pub snapshot_budget: u64,
+
+    /// Maximum number of bytes of SVSM memory all snapshot state may use
+    /// together (0 for no limit)
+    #[arg(long, default_value_t = 0)]
+    pub snapshot_global_budget: u64,
+
+    /// Number of pages in the per-CPU window for temporary 4K mappings
+    /// (0 for the default)
+    #[arg(long, default_value_t = 0)]
+    pub percpu_map_window: u32,
+
+    /// Guest physical address of a shared page the SVSM publishes its health
+    /// state to (0 to disable)
+    #[arg(long, default_value_t = 0)]
+    pub health_page: u64,
+
+    /// Bandwidth of backup copies in percent of the measured copy
+    /// throughput (0 for no limit)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
+    pub backup_bandwidth: u32,
+
+    /// How the vTPM state is handled when a restore rewinds guest memory
+    #[arg(long, value_enum, default_value_t = VtpmRestorePolicy::Reset)]
+    pub vtpm_restore_policy: VtpmRestorePolicy,
+
+    /// Guest physical address of a region the SVSM shares with the host at
+    /// boot for health state, crash records and snapshot export (0 to
+    /// disable)
+    #[arg(long, default_value_t = 0)]
+    pub scratch_region: u64,
+
+    /// Number of pages of the shared scratch region
+    #[arg(long, default_value_t = 0)]
+    pub scratch_region_pages: u32,
+
+    /// Enable all expensive backup safety checks (paranoid mode), e.g. for
+    /// staging environments
+    #[arg(long, default_value_t = false)]
+    pub paranoid: bool,
+
+    /// Store backed-up pages compressed where that saves SVSM memory
+    #[arg(long, default_value_t = false)]
+    pub backup_compression: bool,
 }
 
 impl CmdOptions {
@@ -98,6 +151,15 @@ pub enum Hypervisor {
     HyperV,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum VtpmRestorePolicy {
+    /// Reset the vTPM and record the restore as an event
+    Reset,
+
+    /// Rewind the vTPM state together with guest memory
+    Snapshot,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub enum SevExtraFeatures {
     ReflectVc,
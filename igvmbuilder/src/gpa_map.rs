@@ -63,6 +63,7 @@ pub struct GpaMap {
     pub igvm_param_block: GpaRange,
     pub general_params: GpaRange,
     pub memory_map: GpaRange,
+    pub madt: GpaRange,
     pub guest_context: GpaRange,
     pub kernel: GpaRange,
     pub vmsa: GpaRange,
@@ -154,10 +155,11 @@ impl GpaMap {
         let igvm_param_block = GpaRange::new_page(kernel_fs.get_end())?;
         let general_params = GpaRange::new_page(igvm_param_block.get_end())?;
         let memory_map = GpaRange::new_page(general_params.get_end())?;
+        let madt = GpaRange::new_page(memory_map.get_end())?;
         let guest_context = if let Some(firmware) = firmware {
             if firmware.get_guest_context().is_some() {
-                // Locate the guest context after the memory map parameter page
-                GpaRange::new_page(memory_map.get_end())?
+                // Locate the guest context after the MADT parameter page
+                GpaRange::new_page(madt.get_end())?
             } else {
                 GpaRange::new(0, 0)?
             }
@@ -186,6 +188,7 @@ impl GpaMap {
             igvm_param_block,
             general_params,
             memory_map,
+            madt,
             guest_context,
             kernel,
             vmsa,
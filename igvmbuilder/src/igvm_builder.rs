@@ -10,7 +10,11 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::mem::size_of;
 
-use bootlib::igvm_params::{IgvmGuestContext, IgvmParamBlock, IgvmParamBlockFwInfo};
+use bootlib::igvm_params::{
+    IgvmGuestContext, IgvmParamBlock, IgvmParamBlockFwInfo, IGVM_PARANOID_CANARIES,
+    IGVM_PARANOID_PAGE_HASHES, IGVM_PARANOID_PAGE_TABLES, IGVM_PARANOID_RMP_CHECKS,
+    IGVM_SNAPSHOT_DIGEST_MAX, IGVM_VTPM_RESTORE_RESET, IGVM_VTPM_RESTORE_SNAPSHOT,
+};
 use bootlib::platform::SvsmPlatformType;
 use clap::Parser;
 use igvm::{
@@ -22,7 +26,7 @@ use igvm_defs::{
 };
 use zerocopy::AsBytes;
 
-use crate::cmd_options::{CmdOptions, Hypervisor};
+use crate::cmd_options::{CmdOptions, Hypervisor, VtpmRestorePolicy};
 use crate::cpuid::SnpCpuidPage;
 use crate::firmware::{parse_firmware, Firmware};
 use crate::platform::PlatformMask;
@@ -38,7 +42,8 @@ pub static COMPATIBILITY_MASK: PlatformMask = PlatformMask::new();
 // Parameter area indices
 const IGVM_GENERAL_PARAMS_PA: u32 = 0;
 const IGVM_MEMORY_MAP_PA: u32 = 1;
-const IGVM_PARAMETER_COUNT: u32 = 2;
+const IGVM_MADT_PA: u32 = 2;
+const IGVM_PARAMETER_COUNT: u32 = IGVM_MADT_PA + 1;
 
 const _: () = assert!(size_of::<IgvmParamBlock>() as u64 <= PAGE_SIZE_4K);
 const _: () = assert!(size_of::<IgvmGuestContext>() as u64 <= PAGE_SIZE_4K);
@@ -176,15 +181,15 @@ impl IgvmBuilder {
     fn create_param_block(&self) -> Result<IgvmParamBlock, Box<dyn Error>> {
         let param_page_offset = PAGE_SIZE_4K as u32;
         let memory_map_offset = param_page_offset + PAGE_SIZE_4K as u32;
+        let madt_offset = memory_map_offset + PAGE_SIZE_4K as u32;
+        let madt_size = self.gpa_map.madt.get_size() as u32;
         let (guest_context_offset, param_area_size) = if self.gpa_map.guest_context.get_size() == 0
         {
-            (0, memory_map_offset + PAGE_SIZE_4K as u32)
+            (0, madt_offset + madt_size)
         } else {
             (
-                memory_map_offset + PAGE_SIZE_4K as u32,
-                memory_map_offset
-                    + PAGE_SIZE_4K as u32
-                    + self.gpa_map.guest_context.get_size() as u32,
+                madt_offset + madt_size,
+                madt_offset + madt_size + self.gpa_map.guest_context.get_size() as u32,
             )
         };
 
@@ -204,12 +209,16 @@ impl IgvmBuilder {
             (fw_info, vtom)
         };
 
+        let (snapshot_digest_count, snapshot_digests) = self.snapshot_digests()?;
+
         // Most of the parameter block can be initialised with constants.
         Ok(IgvmParamBlock {
             param_area_size,
             param_page_offset,
             memory_map_offset,
             guest_context_offset,
+            madt_offset,
+            madt_size,
             cpuid_page: self.gpa_map.cpuid_page.get_start() as u32,
             secrets_page: self.gpa_map.secrets_page.get_start() as u32,
             debug_serial_port: self.options.get_port_address(),
@@ -221,10 +230,54 @@ impl IgvmBuilder {
             kernel_base: self.gpa_map.kernel.get_start(),
             vtom,
             use_alternate_injection: u8::from(self.options.alt_injection),
+            snapshot_digest_count,
+            snapshot_digests,
+            snapshot_budget: self.options.snapshot_budget,
+            snapshot_global_budget: self.options.snapshot_global_budget,
+            percpu_map_window_pages: self.options.percpu_map_window,
+            health_page: self.options.health_page,
+            backup_bandwidth_percent: self.options.backup_bandwidth,
+            vtpm_restore_policy: match self.options.vtpm_restore_policy {
+                VtpmRestorePolicy::Reset => IGVM_VTPM_RESTORE_RESET,
+                VtpmRestorePolicy::Snapshot => IGVM_VTPM_RESTORE_SNAPSHOT,
+            },
+            scratch_region: self.options.scratch_region,
+            scratch_region_pages: self.options.scratch_region_pages,
+            paranoid_checks: if self.options.paranoid {
+                IGVM_PARANOID_PAGE_HASHES
+                    | IGVM_PARANOID_RMP_CHECKS
+                    | IGVM_PARANOID_CANARIES
+                    | IGVM_PARANOID_PAGE_TABLES
+            } else {
+                0
+            },
+            backup_compression: u32::from(self.options.backup_compression),
             ..Default::default()
         })
     }
 
+    fn snapshot_digests(
+        &self,
+    ) -> Result<(u32, [[u8; 32]; IGVM_SNAPSHOT_DIGEST_MAX]), Box<dyn Error>> {
+        let mut digests = [[0u8; 32]; IGVM_SNAPSHOT_DIGEST_MAX];
+        if self.options.snapshot_digest.len() > IGVM_SNAPSHOT_DIGEST_MAX {
+            return Err(format!(
+                "At most {} snapshot digests can be specified",
+                IGVM_SNAPSHOT_DIGEST_MAX
+            )
+            .into());
+        }
+        for (digest, hex) in digests.iter_mut().zip(&self.options.snapshot_digest) {
+            if hex.len() != 2 * digest.len() || !hex.is_ascii() {
+                return Err(format!("Invalid snapshot digest {}", hex).into());
+            }
+            for (i, byte) in digest.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+            }
+        }
+        Ok((self.options.snapshot_digest.len() as u32, digests))
+    }
+
     fn build_platforms(&mut self, param_block: &IgvmParamBlock) {
         if COMPATIBILITY_MASK.contains(SNP_COMPATIBILITY_MASK) {
             self.platforms.push(IgvmPlatformHeader::SupportedPlatform(
@@ -295,7 +348,8 @@ impl IgvmBuilder {
             vtl2_protectable: false,
         });
 
-        // Create the two parameter areas for memory map and general parameters.
+        // Create the parameter areas for memory map, general parameters and
+        // MADT.
         self.directives.push(IgvmDirectiveHeader::ParameterArea {
             number_of_bytes: PAGE_SIZE_4K,
             parameter_area_index: IGVM_MEMORY_MAP_PA,
@@ -306,6 +360,11 @@ impl IgvmBuilder {
             parameter_area_index: IGVM_GENERAL_PARAMS_PA,
             initial_data: vec![],
         });
+        self.directives.push(IgvmDirectiveHeader::ParameterArea {
+            number_of_bytes: PAGE_SIZE_4K,
+            parameter_area_index: IGVM_MADT_PA,
+            initial_data: vec![],
+        });
         self.directives
             .push(IgvmDirectiveHeader::VpCount(IGVM_VHS_PARAMETER {
                 parameter_area_index: IGVM_GENERAL_PARAMS_PA,
@@ -321,6 +380,11 @@ impl IgvmBuilder {
                 parameter_area_index: IGVM_MEMORY_MAP_PA,
                 byte_offset: 0,
             }));
+        self.directives
+            .push(IgvmDirectiveHeader::Madt(IGVM_VHS_PARAMETER {
+                parameter_area_index: IGVM_MADT_PA,
+                byte_offset: 0,
+            }));
         self.directives.push(IgvmDirectiveHeader::ParameterInsert(
             IGVM_VHS_PARAMETER_INSERT {
                 gpa: self.gpa_map.memory_map.get_start(),
@@ -335,6 +399,13 @@ impl IgvmBuilder {
                 parameter_area_index: IGVM_GENERAL_PARAMS_PA,
             },
         ));
+        self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+            IGVM_VHS_PARAMETER_INSERT {
+                gpa: self.gpa_map.madt.get_start(),
+                compatibility_mask: COMPATIBILITY_MASK.get(),
+                parameter_area_index: IGVM_MADT_PA,
+            },
+        ));
 
         // Construct a native context object to capture the start context.
         let start_context = construct_start_context();
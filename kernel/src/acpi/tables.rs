@@ -27,8 +27,19 @@ struct RSDPDesc {
     rev: u8,
     /// Physical address of the RSDT
     rsdt_addr: u32,
+    /// Length of the table, only valid for revision 2 and later
+    len: u32,
+    /// Physical address of the XSDT, only valid for revision 2 and later
+    xsdt_addr: u64,
+    /// Checksum of the entire table, only valid for revision 2 and later
+    ext_chksum: u8,
+    /// Reserved
+    reserved: [u8; 3],
 }
 
+/// Size of the ACPI 1.0 portion of the RSDP
+const RSDP_V1_SIZE: usize = 20;
+
 impl RSDPDesc {
     /// Create an RSPDesc instance from FwCfg
     ///
@@ -40,22 +51,37 @@ impl RSDPDesc {
     ///
     /// A [`Result`] containing the [`RSDPDesc`] if successful, or an [`SvsmError`] on failure.
     fn from_fwcfg(fw_cfg: &FwCfg<'_>) -> Result<Self, SvsmError> {
-        let mut buf = mem::MaybeUninit::<Self>::uninit();
+        let mut buf = mem::MaybeUninit::<Self>::zeroed();
         let path = option_env!("ACPI_RSDP_PATH").unwrap_or("etc/acpi/rsdp");
         let file = fw_cfg.file_selector(path)?;
 
-        if (file.size() as usize) < mem::size_of::<Self>() {
+        // ACPI 1.0 descriptors only contain the RSDT address, later revisions
+        // append the XSDT address.
+        let size = file.size() as usize;
+        if size < RSDP_V1_SIZE {
             return Err(SvsmError::Acpi);
         }
+        let size = size.min(mem::size_of::<Self>());
 
-        fw_cfg.select(file.selector());
-        let ptr = buf.as_mut_ptr().cast::<u8>();
-        for i in 0..mem::size_of::<Self>() {
-            let byte: u8 = fw_cfg.read_le();
-            unsafe { ptr.add(i).write(byte) };
+        // SAFETY: the slice covers at most the storage of `buf`, and any
+        // byte pattern is a valid `RSDPDesc`.
+        let bytes = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), size) };
+        fw_cfg.read_file(&file, bytes)?;
+
+        let desc = unsafe { buf.assume_init() };
+        if &desc.sig != b"RSD PTR " {
+            return Err(SvsmError::Acpi);
         }
+        Ok(desc)
+    }
 
-        unsafe { Ok(buf.assume_init()) }
+    /// Returns the offset of the XSDT if the descriptor provides one.
+    fn xsdt_addr(&self) -> Option<usize> {
+        if self.rev < 2 {
+            return None;
+        }
+        let addr = self.xsdt_addr;
+        usize::try_from(addr).ok().filter(|&addr| addr != 0)
     }
 }
 
@@ -189,7 +215,6 @@ impl ACPITable {
     /// Get the signature of the ACPI table.
     ///
     /// This method returns the 4-character signature of the ACPI table, such as "APIC."
-    #[allow(dead_code)]
     fn signature(&self) -> FixedString<4> {
         FixedString::from(self.header.sig)
     }
@@ -290,14 +315,8 @@ impl ACPITableBuffer {
             return Err(SvsmError::Mem);
         }
         buf.try_reserve(size).map_err(|_| SvsmError::Mem)?;
-        let ptr = buf.as_mut_ptr();
-
-        fw_cfg.select(file.selector());
-        for i in 0..size {
-            let byte: u8 = fw_cfg.read_le();
-            unsafe { ptr.add(i).write(byte) };
-        }
-        unsafe { buf.set_len(size) }
+        buf.resize(size, 0);
+        fw_cfg.read_file(&file, &mut buf)?;
 
         let mut acpibuf = Self {
             buf,
@@ -322,11 +341,25 @@ impl ACPITableBuffer {
     fn load_tables(&mut self, fw_cfg: &FwCfg<'_>) -> Result<(), SvsmError> {
         let desc = RSDPDesc::from_fwcfg(fw_cfg)?;
 
-        let rsdt = self.acpi_table_from_offset(desc.rsdt_addr as usize)?;
-        let content = rsdt.content().ok_or(SvsmError::Acpi)?;
-        let offsets = content
-            .chunks_exact(mem::size_of::<u32>())
-            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize);
+        // Prefer the XSDT, which uses 64-bit table pointers, over the RSDT.
+        let offsets: Vec<usize> = match desc.xsdt_addr() {
+            Some(xsdt_addr) => {
+                let xsdt = self.acpi_table_from_offset(xsdt_addr)?;
+                let content = xsdt.content().ok_or(SvsmError::Acpi)?;
+                content
+                    .chunks_exact(mem::size_of::<u64>())
+                    .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as usize)
+                    .collect()
+            }
+            None => {
+                let rsdt = self.acpi_table_from_offset(desc.rsdt_addr as usize)?;
+                let content = rsdt.content().ok_or(SvsmError::Acpi)?;
+                content
+                    .chunks_exact(mem::size_of::<u32>())
+                    .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+                    .collect()
+            }
+        };
 
         for offset in offsets {
             let raw_header = offset
@@ -483,6 +516,26 @@ pub fn load_acpi_cpu_info(fw_cfg: &FwCfg<'_>) -> Result<Vec<ACPICPUInfo>, SvsmEr
     let buffer = ACPITableBuffer::from_fwcfg(fw_cfg)?;
 
     let apic_table = buffer.acp_table_by_sig("APIC").ok_or(SvsmError::Acpi)?;
+    parse_madt(&apic_table)
+}
+
+/// Loads ACPI CPU information from a raw MADT, as provided by the host loader
+/// in the IGVM parameter area.
+///
+/// # Arguments
+///
+/// * `madt`: The raw MADT, starting with the ACPI table header. Trailing
+///   bytes beyond the length recorded in the header are ignored.
+pub fn load_madt_cpu_info(madt: &[u8]) -> Result<Vec<ACPICPUInfo>, SvsmError> {
+    let apic_table = ACPITable::new(madt)?;
+    if apic_table.signature() != "APIC" {
+        return Err(SvsmError::Acpi);
+    }
+    parse_madt(&apic_table)
+}
+
+/// Extracts the local APIC and x2APIC entries from an MADT.
+fn parse_madt(apic_table: &ACPITable) -> Result<Vec<ACPICPUInfo>, SvsmError> {
     let content = apic_table.content().ok_or(SvsmError::Acpi)?;
 
     let mut cpus: Vec<ACPICPUInfo> = Vec::new();
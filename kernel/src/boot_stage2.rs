@@ -81,6 +81,29 @@ global_asm!(
         decl %ecx
         jnz 1b
 
+        /*
+         * Switch to 5-level paging if the CPU supports LA57. The tree built
+         * above moves down by one level: the spare last page becomes the
+         * PML4 and the root page becomes a PML5 pointing to it.
+         */
+        pushl %edx
+        call check_la57
+        popl %edx
+        test %eax, %eax
+        jz 3f
+
+        movl $pgtable, %edi
+        movl 0(%edi), %eax
+        movl %eax, 0x6000(%edi)
+        movl 4(%edi), %eax
+        movl %eax, 0x6004(%edi)
+        leal 0x6007(%edi), %eax
+        movl %eax, 0(%edi)
+
+        movl $la57_flag, %edi
+        movl $1, (%edi)
+        3:
+
         /* Signal APs */
         movl $setup_flag, %edi
         movl $1, (%edi)
@@ -94,9 +117,13 @@ global_asm!(
         jz .Lap_wait
 
 2:
-        /* Enable 64bit PTEs, CR4.PAE. */
+        /* Enable 64bit PTEs, CR4.PAE, and 5-level paging if selected. */
         movl %cr4, %eax
         bts $5, %eax
+        cmpl $0, la57_flag
+        je 3f
+        bts $12, %eax
+        3:
         movl %eax, %cr4
 
         /* Enable long mode, EFER.LME. */
@@ -141,7 +168,79 @@ global_asm!(
         jnz .Lvtom
 
         /* Determine the PTE C-bit position from the CPUID page. */
+        movl $0x8000001f, %ebx
+        call find_cpuid_entry
+        test %ecx, %ecx
+        je .Lno_sev_snp
 
+        /* Extract the c-bit location from the cpuid entry. */
+        movl 28(%ecx), %ebx
+        andl $0x3f, %ebx
+
+        /*
+         * Verify that the C-bit position is within reasonable bounds:
+         * >= 32 and < 64.
+         */
+        cmpl $32, %ebx
+        jl .Lno_sev_snp
+        cmpl $64, %ebx
+        jae .Lno_sev_snp
+
+        subl $32, %ebx
+        xorl %eax, %eax
+        btsl %ebx, %eax
+        ret
+
+    .Lvtom:
+        xorl %eax, %eax
+        ret
+
+    .Lno_sev_snp:
+        hlt
+        jmp .Lno_sev_snp
+
+        /*
+         * Determine whether the CPU supports 5-level paging. SNP reads the
+         * CPUID page, the native platform executes CPUID. Other platforms
+         * keep 4-level paging. Returns 1 in %eax if LA57 is supported.
+         */
+    check_la57:
+        cmpl $1, 8(%ebp)
+        je .Lla57_cpuid_page
+        cmpl $0, 8(%ebp)
+        jne .Lno_la57
+
+        xorl %eax, %eax
+        cpuid
+        cmpl $7, %eax
+        jb .Lno_la57
+        movl $7, %eax
+        xorl %ecx, %ecx
+        cpuid
+        jmp .Lla57_test
+
+    .Lla57_cpuid_page:
+        movl $7, %ebx
+        call find_cpuid_entry
+        test %ecx, %ecx
+        je .Lno_la57
+        movl 32(%ecx), %ecx /* ECX_OUT */
+
+    .Lla57_test:
+        xorl %eax, %eax
+        btl $16, %ecx
+        setc %al
+        ret
+
+    .Lno_la57:
+        xorl %eax, %eax
+        ret
+
+        /*
+         * Find the CPUID page entry for leaf %ebx, subleaf 0. Returns a
+         * pointer to the entry in %ecx, or 0 if there is none.
+         */
+    find_cpuid_entry:
         /* Read the number of entries. */
         mov CPUID_PAGE, %eax
         /* Create a pointer to the first entry. */
@@ -150,10 +249,10 @@ global_asm!(
     .Lcheck_entry:
         /* Check that there is another entry. */
         test %eax, %eax
-        je .Lno_sev_snp
+        je .Lno_entry
 
         /* Check the input parameters of the current entry. */
-        cmpl $0x8000001f, (%ecx) /* EAX_IN */
+        cmpl %ebx, (%ecx) /* EAX_IN */
         jne .Lwrong_entry
         cmpl $0, 4(%ecx) /* ECX_IN */
         jne .Lwrong_entry
@@ -167,7 +266,7 @@ global_asm!(
         jne .Lwrong_entry
 
         /* All parameters were correct. */
-        jmp .Lfound_entry
+        ret
 
     .Lwrong_entry:
         /*
@@ -178,33 +277,10 @@ global_asm!(
         addl $0x30, %ecx
         jmp .Lcheck_entry
 
-    .Lfound_entry:
-        /* Extract the c-bit location from the cpuid entry. */
-        movl 28(%ecx), %ebx
-        andl $0x3f, %ebx
-
-        /*
-         * Verify that the C-bit position is within reasonable bounds:
-         * >= 32 and < 64.
-         */
-        cmpl $32, %ebx
-        jl .Lno_sev_snp
-        cmpl $64, %ebx
-        jae .Lno_sev_snp
-
-        subl $32, %ebx
-        xorl %eax, %eax
-        btsl %ebx, %eax
+    .Lno_entry:
+        xorl %ecx, %ecx
         ret
 
-    .Lvtom:
-        xorl %eax, %eax
-        ret
-
-    .Lno_sev_snp:
-        hlt
-        jmp .Lno_sev_snp
-
         .code64
 
     startup_64:
@@ -241,6 +317,9 @@ global_asm!(
     setup_flag:
         .long 0
 
+    la57_flag:
+        .long 0
+
     idt32:
         .rept 32
         .quad 0
@@ -17,7 +17,9 @@ use crate::igvm_params::IgvmParams;
 use crate::mm::{PerCPUPageMappingGuard, PAGE_SIZE, SIZE_1G};
 use crate::serial::SERIAL_PORT;
 use crate::utils::MemoryRegion;
+use alloc::vec;
 use alloc::vec::Vec;
+use bootlib::igvm_params::IGVM_VTPM_RESTORE_RESET;
 use cpuarch::vmsa::VMSA;
 
 fn check_ovmf_regions(
@@ -164,6 +166,19 @@ impl SvsmConfig<'_> {
         }
     }
 
+    /// Returns the ranges of the firmware images, which are write-protected
+    /// once the firmware is validated. Unlike [`Self::get_fw_regions`] this
+    /// excludes the stage 2 range, which the guest may keep writing.
+    pub fn get_fw_image_regions(
+        &self,
+        kernel_region: &MemoryRegion<PhysAddr>,
+    ) -> Vec<MemoryRegion<PhysAddr>> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => self.get_fw_regions(kernel_region),
+            SvsmConfig::IgvmConfig(igvm_params) => vec![igvm_params.get_fw_image_region()],
+        }
+    }
+
     pub fn fw_in_low_memory(&self) -> bool {
         match self {
             SvsmConfig::FirmwareConfig(_) => false,
@@ -191,4 +206,92 @@ impl SvsmConfig<'_> {
             SvsmConfig::IgvmConfig(igvm_params) => igvm_params.use_alternate_injection(),
         }
     }
+
+    /// Returns the number of pages in the per-CPU window for temporary 4K
+    /// mappings, or `None` for the default.
+    pub fn percpu_map_window_pages(&self) -> Option<usize> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => match igvm_params.percpu_map_window_pages() {
+                0 => None,
+                pages => Some(pages as usize),
+            },
+        }
+    }
+
+    /// Returns the guest physical address of the page the health state is
+    /// published to, if any.
+    pub fn health_page(&self) -> Option<PhysAddr> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => match igvm_params.health_page() {
+                0 => None,
+                paddr => Some(PhysAddr::from(paddr)),
+            },
+        }
+    }
+
+    /// Returns the region shared with the host at boot, if any.
+    pub fn scratch_region(&self) -> Option<MemoryRegion<PhysAddr>> {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => None,
+            SvsmConfig::IgvmConfig(igvm_params) => match igvm_params.scratch_region() {
+                (0, _) | (_, 0) => None,
+                (paddr, pages) => {
+                    MemoryRegion::checked_new(PhysAddr::from(paddr), pages as usize * PAGE_SIZE)
+                }
+            },
+        }
+    }
+
+    /// Returns the bandwidth ceiling of backup copies as a percentage of the
+    /// measured copy throughput, zero meaning no limit.
+    pub fn backup_bandwidth_percent(&self) -> u32 {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => 0,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.backup_bandwidth_percent(),
+        }
+    }
+
+    /// Returns how the vTPM state is handled on restore, as one of the
+    /// `IGVM_VTPM_RESTORE_*` values.
+    pub fn vtpm_restore_policy(&self) -> u32 {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => IGVM_VTPM_RESTORE_RESET,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.vtpm_restore_policy(),
+        }
+    }
+
+    /// Returns the backup safety checks enabled at boot, as a combination of
+    /// the `IGVM_PARANOID_*` flags.
+    pub fn paranoid_checks(&self) -> u32 {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => 0,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.paranoid_checks(),
+        }
+    }
+
+    /// Returns whether backed-up pages are stored compressed.
+    pub fn backup_compression(&self) -> bool {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => false,
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.backup_compression(),
+        }
+    }
+
+    /// Returns the per-snapshot and global snapshot memory budgets in bytes,
+    /// zero meaning no limit.
+    pub fn snapshot_budget(&self) -> (u64, u64) {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => (0, 0),
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.snapshot_budget(),
+        }
+    }
+
+    pub fn snapshot_digests(&self) -> &[[u8; 32]] {
+        match self {
+            SvsmConfig::FirmwareConfig(_) => &[],
+            SvsmConfig::IgvmConfig(igvm_params) => igvm_params.snapshot_digests(),
+        }
+    }
 }
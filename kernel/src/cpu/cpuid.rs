@@ -5,12 +5,78 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::utils::immut_after_init::ImmutAfterInitRef;
-use cpuarch::snp_cpuid::SnpCpuidTable;
+use cpuarch::snp_cpuid::{SnpCpuidTable, SNP_CPUID_MAX_COUNT};
 
 use core::arch::asm;
+use core::mem::size_of;
+use core::slice;
 
 static CPUID_PAGE: ImmutAfterInitRef<'_, SnpCpuidTable> = ImmutAfterInitRef::uninit();
 
+/// Errors found while validating the SNP CPUID page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidError {
+    /// The table has more entries than fit into the page.
+    TooManyEntries(u32),
+    /// The table contains two entries for the same inputs.
+    DuplicateEntry { eax: u32, ecx: u32 },
+    /// A leaf the SVSM depends on is missing from the table.
+    MissingLeaf(u32),
+    /// A leaf beyond the maximum leaf reported by the table is present.
+    LeafOutOfRange(u32),
+}
+
+/// Leaves which must be present in the CPUID page.
+const REQUIRED_LEAVES: [u32; 3] = [0x0000_0000, 0x8000_0000, 0x8000_001f];
+
+/// Checks that the launch-provided CPUID page is consistent before it is
+/// used by the SVSM or handed to the guest.
+pub fn validate_cpuid_table(table: &SnpCpuidTable) -> Result<(), CpuidError> {
+    let count = table.count;
+    if count as usize > SNP_CPUID_MAX_COUNT {
+        return Err(CpuidError::TooManyEntries(count));
+    }
+    let entries = &table.func[..count as usize];
+
+    for (i, entry) in entries.iter().enumerate() {
+        let key = (entry.eax_in, entry.ecx_in, entry.xcr0_in, entry.xss_in);
+        let duplicate = entries[..i]
+            .iter()
+            .any(|other| (other.eax_in, other.ecx_in, other.xcr0_in, other.xss_in) == key);
+        if duplicate {
+            return Err(CpuidError::DuplicateEntry {
+                eax: entry.eax_in,
+                ecx: entry.ecx_in,
+            });
+        }
+    }
+
+    let max_leaf = |base: u32| {
+        entries
+            .iter()
+            .find(|entry| entry.eax_in == base && entry.ecx_in == 0)
+            .map(|entry| entry.eax_out)
+    };
+    for leaf in REQUIRED_LEAVES {
+        if max_leaf(leaf).is_none() {
+            return Err(CpuidError::MissingLeaf(leaf));
+        }
+    }
+    for entry in entries {
+        let base = match entry.eax_in {
+            0x0000_0000..=0x3fff_ffff => 0x0000_0000,
+            0x8000_0000..=0xffff_ffff => 0x8000_0000,
+            // Hypervisor leaves have no maximum in the table.
+            _ => continue,
+        };
+        if max_leaf(base).is_some_and(|max| entry.eax_in > max) {
+            return Err(CpuidError::LeafOutOfRange(entry.eax_in));
+        }
+    }
+
+    Ok(())
+}
+
 pub fn register_cpuid_table(table: &'static SnpCpuidTable) {
     CPUID_PAGE
         .init_from_ref(table)
@@ -102,6 +168,80 @@ pub fn cpuid_table(eax: u32) -> Option<CpuidResult> {
     cpuid_table_raw(eax, 0, 0, 0)
 }
 
+pub fn cpuid_table_subleaf(eax: u32, ecx: u32) -> Option<CpuidResult> {
+    cpuid_table_raw(eax, ecx, 0, 0)
+}
+
+/// Returns the raw contents of the CPUID page, which define the CPUID
+/// policy visible to the guest.
+pub fn cpuid_table_bytes() -> &'static [u8] {
+    let table: &'static SnpCpuidTable = &CPUID_PAGE;
+    // SAFETY: SnpCpuidTable is a packed plain-old-data structure without
+    // padding, so all of its bytes are initialized.
+    unsafe {
+        slice::from_raw_parts(
+            (table as *const SnpCpuidTable).cast::<u8>(),
+            size_of::<SnpCpuidTable>(),
+        )
+    }
+}
+
+/// A CPUID output register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidReg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A feature bit reported through CPUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuidFeature {
+    pub leaf: u32,
+    pub subleaf: u32,
+    pub reg: CpuidReg,
+    pub bit: u32,
+}
+
+impl CpuidFeature {
+    pub const X2APIC: Self = Self::new(0x0000_0001, 0, CpuidReg::Ecx, 21);
+    pub const XSAVE: Self = Self::new(0x0000_0001, 0, CpuidReg::Ecx, 26);
+    pub const SMEP: Self = Self::new(0x0000_0007, 0, CpuidReg::Ebx, 7);
+    pub const SMAP: Self = Self::new(0x0000_0007, 0, CpuidReg::Ebx, 20);
+    pub const NX: Self = Self::new(0x8000_0001, 0, CpuidReg::Edx, 20);
+    pub const PAGE_1GB: Self = Self::new(0x8000_0001, 0, CpuidReg::Edx, 26);
+    pub const INVLPGB: Self = Self::new(0x8000_0008, 0, CpuidReg::Ebx, 3);
+    pub const SEV_SNP: Self = Self::new(0x8000_001f, 0, CpuidReg::Eax, 4);
+
+    pub const fn new(leaf: u32, subleaf: u32, reg: CpuidReg, bit: u32) -> Self {
+        Self {
+            leaf,
+            subleaf,
+            reg,
+            bit,
+        }
+    }
+}
+
+impl CpuidResult {
+    pub fn reg(&self, reg: CpuidReg) -> u32 {
+        match reg {
+            CpuidReg::Eax => self.eax,
+            CpuidReg::Ebx => self.ebx,
+            CpuidReg::Ecx => self.ecx,
+            CpuidReg::Edx => self.edx,
+        }
+    }
+}
+
+/// Returns whether the CPUID page reports the given feature. Features of
+/// leaves missing from the page are reported as absent.
+pub fn cpuid_table_has_feature(feature: CpuidFeature) -> bool {
+    cpuid_table_subleaf(feature.leaf, feature.subleaf)
+        .is_some_and(|result| result.reg(feature.reg) & (1 << feature.bit) != 0)
+}
+
 pub fn dump_cpuid_table() {
     let count = CPUID_PAGE.count as usize;
 
@@ -120,3 +260,65 @@ pub fn dump_cpuid_table() {
                     eax_in, ecx_in, xcr0_in, xss_in, eax_out, ebx_out, ecx_out, edx_out);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpuarch::snp_cpuid::SnpCpuidFn;
+
+    fn entry(eax_in: u32, ecx_in: u32, eax_out: u32) -> SnpCpuidFn {
+        SnpCpuidFn {
+            eax_in,
+            ecx_in,
+            eax_out,
+            ..Default::default()
+        }
+    }
+
+    fn table(entries: &[SnpCpuidFn]) -> SnpCpuidTable {
+        let mut table = SnpCpuidTable::default();
+        table.func[..entries.len()].copy_from_slice(entries);
+        table.count = entries.len() as u32;
+        table
+    }
+
+    #[test]
+    fn test_validate_cpuid_table() {
+        let required = [
+            entry(0x0, 0, 0xd),
+            entry(0x8000_0000, 0, 0x8000_001f),
+            entry(0x8000_001f, 0, 0),
+        ];
+        assert_eq!(validate_cpuid_table(&table(&required)), Ok(()));
+
+        let mut entries = required.to_vec();
+        entries.push(entry(0x7, 0, 0));
+        entries.push(entry(0x4000_0001, 0, 0));
+        assert_eq!(validate_cpuid_table(&table(&entries)), Ok(()));
+
+        entries.push(entry(0x7, 0, 1));
+        assert_eq!(
+            validate_cpuid_table(&table(&entries)),
+            Err(CpuidError::DuplicateEntry { eax: 0x7, ecx: 0 })
+        );
+
+        let mut entries = required.to_vec();
+        entries.push(entry(0xe, 0, 0));
+        assert_eq!(
+            validate_cpuid_table(&table(&entries)),
+            Err(CpuidError::LeafOutOfRange(0xe))
+        );
+
+        assert_eq!(
+            validate_cpuid_table(&table(&required[..2])),
+            Err(CpuidError::MissingLeaf(0x8000_001f))
+        );
+
+        let mut too_long = table(&required);
+        too_long.count = SNP_CPUID_MAX_COUNT as u32 + 1;
+        assert_eq!(
+            validate_cpuid_table(&too_long),
+            Err(CpuidError::TooManyEntries(too_long.count))
+        );
+    }
+}
@@ -8,6 +8,7 @@ use super::cpuid::cpuid_table;
 
 const X86_FEATURE_NX: u32 = 20;
 const X86_FEATURE_PGE: u32 = 13;
+const X86_FEATURE_SSE4_2: u32 = 20;
 
 pub fn cpu_has_nx() -> bool {
     let ret = cpuid_table(0x80000001);
@@ -26,3 +27,12 @@ pub fn cpu_has_pge() -> bool {
         Some(c) => (c.edx >> X86_FEATURE_PGE) & 1 == 1,
     }
 }
+
+pub fn cpu_has_sse42() -> bool {
+    let ret = cpuid_table(0x00000001);
+
+    match ret {
+        None => false,
+        Some(c) => (c.ecx >> X86_FEATURE_SSE4_2) & 1 == 1,
+    }
+}
@@ -17,6 +17,7 @@ pub mod percpu;
 pub mod registers;
 pub mod smp;
 pub mod tlb;
+pub mod tsc;
 pub mod tss;
 pub mod vc;
 pub mod vmsa;
@@ -16,7 +16,7 @@ use crate::cpu::LocalApic;
 use crate::error::{ApicError, SvsmError};
 use crate::locking::{LockGuard, RWLock, SpinLock};
 use crate::mm::pagetable::{get_init_pgtable_locked, PTEntryFlags, PageTableRef};
-use crate::mm::virtualrange::VirtualRange;
+use crate::mm::virtualrange::{virt_window_4k_pages, VirtualRange};
 use crate::mm::vm::{Mapping, VMKernelStack, VMPhysMem, VMRMapping, VMReserved, VMR};
 use crate::mm::{
     virt_to_phys, PageBox, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_END,
@@ -225,6 +225,11 @@ impl PerCpuShared {
         locked.update_caa(Some(caa));
     }
 
+    /// Returns the address of the guest VMSA of this CPU, if it has one.
+    pub fn guest_vmsa_phys(&self) -> Option<PhysAddr> {
+        self.guest_vmsa.lock().vmsa_phys()
+    }
+
     pub fn clear_guest_vmsa_if_match(&self, paddr: PhysAddr) {
         let mut locked = self.guest_vmsa.lock();
         if locked.vmsa.is_none() {
@@ -745,7 +750,7 @@ impl PerCpu {
 
     fn virt_range_init(&self) {
         // Initialize 4k range
-        let page_count = (SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE;
+        let page_count = virt_window_4k_pages();
         assert!(page_count <= VirtualRange::CAPACITY);
         self.vrange_4k
             .borrow_mut()
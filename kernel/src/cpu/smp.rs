@@ -11,6 +11,7 @@ use crate::platform::SvsmPlatform;
 use crate::platform::SVSM_PLATFORM;
 use crate::requests::{request_loop, request_processing_main};
 use crate::task::{create_kernel_task, schedule_init};
+use crate::types::MAX_CPUS;
 use crate::utils::immut_after_init::immut_after_init_set_multithreaded;
 
 fn start_cpu(platform: &dyn SvsmPlatform, apic_id: u32, vtom: u64) -> Result<(), SvsmError> {
@@ -28,13 +29,27 @@ fn start_cpu(platform: &dyn SvsmPlatform, apic_id: u32, vtom: u64) -> Result<(),
 
 pub fn start_secondary_cpus(platform: &dyn SvsmPlatform, cpus: &[ACPICPUInfo], vtom: u64) {
     immut_after_init_set_multithreaded();
+    // The BSP is not necessarily the CPU with APIC-ID 0, so use the ID the
+    // BSP is actually running on.
+    let bsp_apic_id = this_cpu().get_apic_id();
     let mut count: usize = 0;
-    for c in cpus.iter().filter(|c| c.apic_id != 0 && c.enabled) {
+    let aps = cpus
+        .iter()
+        .filter(|c| c.apic_id != bsp_apic_id && c.enabled);
+    for c in aps.clone().take(MAX_CPUS - 1) {
         log::info!("Launching AP with APIC-ID {}", c.apic_id);
         start_cpu(platform, c.apic_id, vtom).expect("Failed to bring CPU online");
         count += 1;
     }
     log::info!("Brought {} AP(s) online", count);
+    let left_out = aps.count() - count;
+    if left_out > 0 {
+        log::warn!(
+            "{} enabled AP(s) left offline, the SVSM supports at most {} CPUs",
+            left_out,
+            MAX_CPUS
+        );
+    }
 }
 
 #[no_mangle]
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Conversion between TSC ticks and wall-clock time.
+//!
+//! With the `fault-injection` feature, tests can replace the TSC with a
+//! deterministic clock which only advances when told to.
+
+use crate::cpu::cpuid::cpuid_table;
+use crate::cpu::msr::rdtsc;
+
+/// TSC frequency assumed when the CPUID table does not report one.
+const DEFAULT_TSC_KHZ: u64 = 1_000_000;
+
+/// Returns the TSC frequency in kHz from CPUID Fn0000_0015 or, failing
+/// that, from the base frequency in CPUID Fn0000_0016.
+pub fn tsc_khz() -> u64 {
+    #[cfg(any(test, feature = "fault-injection"))]
+    if test_clock::now().is_some() {
+        return DEFAULT_TSC_KHZ;
+    }
+    if let Some(leaf) = cpuid_table(0x15) {
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax) / 1000;
+        }
+    }
+    match cpuid_table(0x16) {
+        Some(leaf) if leaf.eax & 0xffff != 0 => u64::from(leaf.eax & 0xffff) * 1000,
+        _ => DEFAULT_TSC_KHZ,
+    }
+}
+
+/// Returns the current TSC value, or the test clock while it is enabled.
+pub fn tsc_now() -> u64 {
+    #[cfg(any(test, feature = "fault-injection"))]
+    if let Some(ticks) = test_clock::now() {
+        return ticks;
+    }
+    rdtsc()
+}
+
+/// Converts a number of TSC ticks into nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    let ns = u128::from(ticks) * 1_000_000 / u128::from(tsc_khz().max(1));
+    u64::try_from(ns).unwrap_or(u64::MAX)
+}
+
+/// Returns the time since the TSC was reset at launch in nanoseconds. This
+/// is not wall-clock time, but it only moves forward: restoring guest
+/// memory does not roll it back.
+pub fn uptime_ns() -> u64 {
+    ticks_to_ns(tsc_now())
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+mod test_clock {
+    use super::DEFAULT_TSC_KHZ;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+
+    pub(super) fn now() -> Option<u64> {
+        ENABLED
+            .load(Ordering::SeqCst)
+            .then(|| TICKS.load(Ordering::SeqCst))
+    }
+
+    /// Replaces the TSC with a test clock starting at `ticks`, which runs
+    /// at the default TSC frequency. `None` switches back to the TSC.
+    pub fn set_test_clock(ticks: Option<u64>) {
+        TICKS.store(ticks.unwrap_or(0), Ordering::SeqCst);
+        ENABLED.store(ticks.is_some(), Ordering::SeqCst);
+    }
+
+    /// Advances the test clock by `ms` milliseconds.
+    pub fn advance_test_clock(ms: u64) {
+        TICKS.fetch_add(ms * DEFAULT_TSC_KHZ, Ordering::SeqCst);
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+pub use test_clock::{advance_test_clock, set_test_clock};
@@ -77,6 +77,90 @@ pub mod aead {
     pub struct Aes256Gcm;
 }
 
+pub mod digest {
+    //! API for message digests
+
+    /// SHA-256 digest size (256 bits)
+    pub const SHA256_SIZE: usize = 32;
+
+    /// Incremental SHA-256
+    pub trait Sha256Trait: Sized {
+        /// Create a hash state with no data fed into it
+        fn new() -> Self;
+
+        /// Feed `data` into the hash state
+        fn update(&mut self, data: &[u8]);
+
+        /// Consume the hash state and return the digest of all data fed
+        /// into it
+        fn finalize(self) -> [u8; SHA256_SIZE];
+    }
+
+    /// Sha256 type, provided by the compiled-in implementation
+    pub use super::rustcrypto::Sha256;
+
+    /// SHA-512 digest size (512 bits)
+    pub const SHA512_SIZE: usize = 64;
+
+    /// Incremental SHA-512
+    pub trait Sha512Trait: Sized {
+        /// Create a hash state with no data fed into it
+        fn new() -> Self;
+
+        /// Feed `data` into the hash state
+        fn update(&mut self, data: &[u8]);
+
+        /// Consume the hash state and return the digest of all data fed
+        /// into it
+        fn finalize(self) -> [u8; SHA512_SIZE];
+    }
+
+    /// Sha512 type, provided by the compiled-in implementation
+    pub use super::rustcrypto::Sha512;
+}
+
+pub mod rng {
+    //! API for random numbers from the hardware random number generator
+
+    use crate::error::SvsmError;
+    use core::arch::asm;
+
+    /// Number of RDRAND attempts before the generator is considered broken,
+    /// as recommended by the vendor documentation.
+    const RDRAND_RETRIES: usize = 10;
+
+    fn rdrand64() -> Result<u64, SvsmError> {
+        for _ in 0..RDRAND_RETRIES {
+            let val: u64;
+            let ok: u8;
+            // SAFETY: RDRAND only writes the destination register and the
+            // flags.
+            unsafe {
+                asm!("rdrand {0}",
+                     "setc {1}",
+                     out(reg) val,
+                     out(reg_byte) ok,
+                     options(nomem, nostack));
+            }
+            if ok != 0 {
+                return Ok(val);
+            }
+        }
+        Err(SvsmError::NotSupported)
+    }
+
+    /// Fill `buf` with random bytes. Fails with
+    /// [`SvsmError::NotSupported`] if the hardware generator does not
+    /// deliver.
+    pub fn fill_random(buf: &mut [u8]) -> Result<(), SvsmError> {
+        for chunk in buf.chunks_mut(8) {
+            let val = rdrand64()?.to_le_bytes();
+            chunk.copy_from_slice(&val[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
 // Crypto implementations supported. Only one of them must be compiled-in.
 
 pub mod rustcrypto;
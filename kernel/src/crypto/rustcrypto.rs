@@ -11,10 +11,13 @@ use aes_gcm::{
     Aes256Gcm, Key, KeyInit, Nonce,
 };
 
+use sha2::Digest;
+
 use crate::{
     crypto::aead::{
         Aes256Gcm as CryptoAes256Gcm, Aes256GcmTrait as CryptoAes256GcmTrait, IV_SIZE, KEY_SIZE,
     },
+    crypto::digest::{Sha256Trait, Sha512Trait, SHA256_SIZE, SHA512_SIZE},
     protocols::errors::SvsmReqError,
 };
 
@@ -75,3 +78,39 @@ impl CryptoAes256GcmTrait for CryptoAes256Gcm {
         aes_gcm_do(AesGcmOperation::Decrypt, iv, key, aad, inbuf, outbuf)
     }
 }
+
+/// SHA-256 hash state
+#[derive(Clone, Debug, Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256Trait for Sha256 {
+    fn new() -> Self {
+        Self(sha2::Sha256::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self) -> [u8; SHA256_SIZE] {
+        Digest::finalize(self.0).into()
+    }
+}
+
+/// SHA-512 hash state
+#[derive(Clone, Debug, Default)]
+pub struct Sha512(sha2::Sha512);
+
+impl Sha512Trait for Sha512 {
+    fn new() -> Self {
+        Self(sha2::Sha512::new())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self) -> [u8; SHA512_SIZE] {
+        Digest::finalize(self.0).into()
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Fault injection for testing error paths.
+//!
+//! Code paths which are hard to make fail on real hardware call
+//! [`inject_fault()`] at their fault points. With the `fault-injection`
+//! feature, tests can arm a fault point to fail on its Nth hit, e.g. the
+//! allocation of the Nth backed-up page. Without the feature the hooks
+//! compile to nothing.
+
+use crate::error::SvsmError;
+
+/// A point at which a fault can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// Allocation of snapshot memory.
+    Alloc,
+    /// Change of the RMP permissions of a guest page.
+    Rmp,
+    /// Read of guest memory.
+    GuestRead,
+}
+
+impl FaultPoint {
+    /// Returns the error reported by an injected fault.
+    #[cfg(any(test, feature = "fault-injection"))]
+    fn error(self) -> SvsmError {
+        use crate::mm::alloc::AllocError;
+        use crate::sev::utils::SevSnpError;
+
+        match self {
+            Self::Alloc => SvsmError::Alloc(AllocError::OutOfMemory),
+            // RMPADJUST reports 2 for insufficient permissions.
+            Self::Rmp => SvsmError::SevSnp(SevSnpError::FAIL_PERMISSION(2)),
+            Self::GuestRead => SvsmError::InvalidAddress,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+mod injector {
+    use super::FaultPoint;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    const FAULT_POINTS: usize = 3;
+
+    /// Hits of each fault point since it was armed.
+    static HITS: [AtomicU64; FAULT_POINTS] = [const { AtomicU64::new(0) }; FAULT_POINTS];
+    /// Hit on which each fault point fails, zero while disarmed.
+    static ARMED: [AtomicU64; FAULT_POINTS] = [const { AtomicU64::new(0) }; FAULT_POINTS];
+
+    /// Arms `point` to fail once, on its `nth` hit from now. Zero disarms
+    /// the point.
+    pub fn arm_fault(point: FaultPoint, nth: u64) {
+        HITS[point as usize].store(0, Ordering::SeqCst);
+        ARMED[point as usize].store(nth, Ordering::SeqCst);
+    }
+
+    /// Disarms all fault points.
+    pub fn disarm_faults() {
+        for armed in ARMED.iter() {
+            armed.store(0, Ordering::SeqCst);
+        }
+    }
+
+    pub(super) fn fault_hit(point: FaultPoint) -> bool {
+        let nth = ARMED[point as usize].load(Ordering::SeqCst);
+        if nth == 0 {
+            return false;
+        }
+        let hits = HITS[point as usize].fetch_add(1, Ordering::SeqCst) + 1;
+        hits == nth
+            && ARMED[point as usize]
+                .compare_exchange(nth, 0, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+pub use injector::{arm_fault, disarm_faults};
+
+/// Fails with the error of `point` if a fault was injected for this hit.
+#[inline]
+pub fn inject_fault(point: FaultPoint) -> Result<(), SvsmError> {
+    #[cfg(any(test, feature = "fault-injection"))]
+    if injector::fault_hit(point) {
+        log::info!("Injected {:?} fault", point);
+        return Err(point.error());
+    }
+    #[cfg(not(any(test, feature = "fault-injection")))]
+    let _ = point;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_fault() {
+        assert!(inject_fault(FaultPoint::GuestRead).is_ok());
+
+        arm_fault(FaultPoint::GuestRead, 3);
+        assert!(inject_fault(FaultPoint::GuestRead).is_ok());
+        assert!(inject_fault(FaultPoint::GuestRead).is_ok());
+        assert!(matches!(
+            inject_fault(FaultPoint::GuestRead),
+            Err(SvsmError::InvalidAddress)
+        ));
+        // The fault fires only once.
+        assert!(inject_fault(FaultPoint::GuestRead).is_ok());
+
+        arm_fault(FaultPoint::GuestRead, 1);
+        disarm_faults();
+        assert!(inject_fault(FaultPoint::GuestRead).is_ok());
+    }
+}
@@ -4,5 +4,6 @@
 //
 // Author: Nicolai Stange <nstange@suse.de>
 
+pub mod fault;
 pub mod gdbstub;
 pub mod stacktrace;
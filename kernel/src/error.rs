@@ -17,13 +17,16 @@
 //! usually the one corresponding to that module. Each module should provide
 //! a way to convert a leaf error into a SvsmError via the [`From`] trait.
 
+use crate::cpu::cpuid::CpuidError;
 use crate::cpu::vc::VcError;
 use crate::fs::FsError;
 use crate::fw_cfg::FwCfgError;
 use crate::insn_decode::InsnError;
+use crate::invariant::InvariantCode;
 use crate::mm::alloc::AllocError;
 use crate::sev::ghcb::GhcbError;
 use crate::sev::msr_protocol::GhcbMsrError;
+use crate::sev::rmp::RmpError;
 use crate::sev::SevSnpError;
 use crate::task::TaskError;
 use elf::ElfError;
@@ -89,6 +92,14 @@ pub enum SvsmError {
     NotSupported,
     /// Generic errors related to APIC emulation.
     Apic(ApicError),
+    /// The page is pinned and cannot be remapped at the moment.
+    PagePinned,
+    /// Errors related to the SNP CPUID page.
+    Cpuid(CpuidError),
+    /// Decoded failures of RMP operations.
+    Rmp(RmpError),
+    /// A checked invariant was violated.
+    Invariant(InvariantCode),
 }
 
 impl From<ElfError> for SvsmError {
@@ -97,6 +108,12 @@ impl From<ElfError> for SvsmError {
     }
 }
 
+impl From<CpuidError> for SvsmError {
+    fn from(err: CpuidError) -> Self {
+        Self::Cpuid(err)
+    }
+}
+
 impl From<ApicError> for SvsmError {
     fn from(err: ApicError) -> Self {
         Self::Apic(err)
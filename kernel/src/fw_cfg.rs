@@ -8,20 +8,40 @@ extern crate alloc;
 
 use crate::address::{Address, PhysAddr};
 use crate::error::SvsmError;
+use crate::mm::page_visibility::{make_page_private, make_page_shared};
 use crate::mm::pagetable::max_phys_addr;
+use crate::mm::{virt_to_phys, PageBox};
+use crate::types::PAGE_SIZE;
 use crate::utils::MemoryRegion;
 
 use super::io::IOPort;
 use super::string::FixedString;
 use alloc::vec::Vec;
-use core::mem::size_of;
+use core::mem::{offset_of, size_of};
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
 
 const FW_CFG_CTL: u16 = 0x510;
 const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_DMA_HI: u16 = 0x514;
+const FW_CFG_DMA_LO: u16 = 0x518;
 
-const _FW_CFG_ID: u16 = 0x01;
+const FW_CFG_ID: u16 = 0x01;
 const FW_CFG_FILE_DIR: u16 = 0x19;
 
+// Feature bits reported through FW_CFG_ID
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+// Control bits of a DMA access descriptor
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+
+// Size of the shared bounce buffer used for DMA transfers, including the
+// access descriptor at its start.
+const FW_CFG_DMA_BUFFER_PAGES: usize = 4;
+const FW_CFG_DMA_BUFFER_SIZE: usize = FW_CFG_DMA_BUFFER_PAGES * PAGE_SIZE;
+
 // Must be a power-of-2
 const KERNEL_REGION_SIZE: u64 = 16 * 1024 * 1024;
 const KERNEL_REGION_SIZE_MASK: u64 = !(KERNEL_REGION_SIZE - 1);
@@ -34,6 +54,7 @@ const MAX_FW_CFG_FILES: u32 = 0x1000;
 #[derive(Debug)]
 pub struct FwCfg<'a> {
     driver: &'a dyn IOPort,
+    dma: Option<FwCfgDmaBuffer>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -46,6 +67,10 @@ pub enum FwCfgError {
     KernelRegion,
     /// The firmware provided too many files to the guest
     TooManyFiles,
+    /// The firmware does not implement the DMA interface.
+    DmaUnsupported,
+    /// The host reported an error for a DMA transfer.
+    DmaTransfer,
 }
 
 impl From<FwCfgError> for SvsmError {
@@ -69,9 +94,110 @@ impl FwCfgFile {
     }
 }
 
+/// DMA access descriptor as defined by the QEMU fw_cfg specification. All
+/// fields are big-endian.
+#[repr(C)]
+#[derive(Debug)]
+struct FwCfgDmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+/// Layout of the shared bounce buffer: the access descriptor followed by the
+/// data area the host copies into.
+#[repr(C)]
+#[derive(Debug)]
+struct FwCfgDmaPage {
+    access: FwCfgDmaAccess,
+    data: [u8; FW_CFG_DMA_BUFFER_SIZE - size_of::<FwCfgDmaAccess>()],
+}
+
+const _: () = assert!(size_of::<FwCfgDmaPage>() == FW_CFG_DMA_BUFFER_SIZE);
+
+/// Bounce buffer shared with the host for fw_cfg DMA transfers. The pages
+/// are made private again when the buffer is dropped.
+#[derive(Debug)]
+pub struct FwCfgDmaBuffer {
+    page: PageBox<FwCfgDmaPage>,
+}
+
+impl FwCfgDmaBuffer {
+    /// Allocates a new bounce buffer and shares it with the host.
+    pub fn new() -> Result<Self, SvsmError> {
+        // SAFETY: all zeroes is a valid representation for `FwCfgDmaPage`.
+        let page = unsafe { PageBox::<FwCfgDmaPage>::try_new_zeroed()?.assume_init() };
+        let vaddr = page.vaddr();
+        for i in 0..FW_CFG_DMA_BUFFER_PAGES {
+            if let Err(e) = make_page_shared(vaddr + i * PAGE_SIZE) {
+                for j in 0..i {
+                    make_page_private(vaddr + j * PAGE_SIZE)
+                        .expect("Failed to restore fw_cfg DMA page visibility");
+                }
+                return Err(e);
+            }
+        }
+        Ok(Self { page })
+    }
+
+    /// Maximum number of bytes a single DMA transfer can move.
+    const fn capacity() -> usize {
+        FW_CFG_DMA_BUFFER_SIZE - size_of::<FwCfgDmaAccess>()
+    }
+
+    fn access_ptr(&self) -> *mut FwCfgDmaAccess {
+        self.page.vaddr().as_mut_ptr::<FwCfgDmaAccess>()
+    }
+
+    fn data_ptr(&self) -> *const u8 {
+        self.page.data.as_ptr()
+    }
+
+    fn access_paddr(&self) -> PhysAddr {
+        virt_to_phys(self.page.vaddr())
+    }
+
+    fn data_paddr(&self) -> PhysAddr {
+        self.access_paddr() + offset_of!(FwCfgDmaPage, data)
+    }
+}
+
+impl Drop for FwCfgDmaBuffer {
+    fn drop(&mut self) {
+        let vaddr = self.page.vaddr();
+        for i in 0..FW_CFG_DMA_BUFFER_PAGES {
+            make_page_private(vaddr + i * PAGE_SIZE)
+                .expect("Failed to restore fw_cfg DMA page visibility");
+        }
+    }
+}
+
 impl<'a> FwCfg<'a> {
     pub fn new(driver: &'a dyn IOPort) -> Self {
-        FwCfg { driver }
+        FwCfg { driver, dma: None }
+    }
+
+    /// Returns whether the firmware implements the DMA interface.
+    pub fn dma_supported(&self) -> bool {
+        self.select(FW_CFG_ID);
+        let features: u32 = self.read_le();
+        (features & FW_CFG_VERSION_DMA) != 0
+    }
+
+    /// Switches this instance to DMA transfers through a shared bounce
+    /// buffer. Requires the page allocator and per-CPU page tables to be
+    /// available, so it must not be used from stage2.
+    pub fn enable_dma(&mut self) -> Result<(), SvsmError> {
+        if !self.dma_supported() {
+            return Err(SvsmError::FwCfg(FwCfgError::DmaUnsupported));
+        }
+        self.dma = Some(FwCfgDmaBuffer::new()?);
+        Ok(())
+    }
+
+    /// Returns whether transfers of this instance go through DMA.
+    pub fn dma_enabled(&self) -> bool {
+        self.dma.is_some()
     }
 
     pub fn select(&self, cfg: u16) {
@@ -108,6 +234,98 @@ impl<'a> FwCfg<'a> {
         self.driver.inb(FW_CFG_DATA) as char
     }
 
+    /// Reads the contents of `file` into `buf`. Uses DMA if it has been
+    /// enabled and falls back to byte-wise port I/O otherwise.
+    pub fn read_file(&self, file: &FwCfgFile, buf: &mut [u8]) -> Result<(), SvsmError> {
+        if buf.len() > file.size as usize {
+            return Err(SvsmError::FwCfg(FwCfgError::FileSize(file.size)));
+        }
+
+        match self.dma {
+            Some(ref dma) => self.read_file_dma(dma, file, buf),
+            None => {
+                self.select(file.selector);
+                for byte in buf.iter_mut() {
+                    *byte = self.driver.inb(FW_CFG_DATA);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads the whole fw_cfg file named `name` into a new vector.
+    pub fn read_file_by_name(&self, name: &str) -> Result<Vec<u8>, SvsmError> {
+        let file = self.file_selector(name)?;
+        let size = file.size as usize;
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(size).map_err(|_| SvsmError::Mem)?;
+        buf.resize(size, 0);
+        self.read_file(&file, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_file_dma(
+        &self,
+        dma: &FwCfgDmaBuffer,
+        file: &FwCfgFile,
+        buf: &mut [u8],
+    ) -> Result<(), SvsmError> {
+        let mut control = FW_CFG_DMA_CTL_SELECT | ((file.selector as u32) << 16);
+        for chunk in buf.chunks_mut(FwCfgDmaBuffer::capacity()) {
+            self.dma_transfer(dma, control | FW_CFG_DMA_CTL_READ, chunk.len())?;
+            // SAFETY: the transfer is complete, so the host no longer writes
+            // to the data area, which is at least `chunk.len()` bytes long.
+            unsafe {
+                ptr::copy_nonoverlapping(dma.data_ptr(), chunk.as_mut_ptr(), chunk.len());
+            }
+            // Following transfers continue at the current file offset.
+            control = 0;
+        }
+        Ok(())
+    }
+
+    fn dma_transfer(
+        &self,
+        dma: &FwCfgDmaBuffer,
+        control: u32,
+        len: usize,
+    ) -> Result<(), SvsmError> {
+        let access_ptr = dma.access_ptr();
+        let access = FwCfgDmaAccess {
+            control: control.to_be(),
+            length: (len as u32).to_be(),
+            address: (dma.data_paddr().bits() as u64).to_be(),
+        };
+        // SAFETY: the descriptor lives in the bounce buffer owned by `dma`,
+        // which is only accessed by the host while a transfer is pending.
+        unsafe { access_ptr.write_volatile(access) };
+        fence(Ordering::SeqCst);
+
+        // The descriptor address is written big-endian, the write to the
+        // low half starts the transfer.
+        let paddr = dma.access_paddr().bits() as u64;
+        self.driver
+            .outl(FW_CFG_DMA_HI, ((paddr >> 32) as u32).to_be());
+        self.driver.outl(FW_CFG_DMA_LO, (paddr as u32).to_be());
+
+        loop {
+            // SAFETY: see above, the host clears the control field once the
+            // transfer is complete.
+            let control =
+                u32::from_be(unsafe { ptr::addr_of!((*access_ptr).control).read_volatile() });
+            if control & FW_CFG_DMA_CTL_ERROR != 0 {
+                return Err(SvsmError::FwCfg(FwCfgError::DmaTransfer));
+            }
+            if control == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+
+        Ok(())
+    }
+
     pub fn file_selector(&self, name: &str) -> Result<FwCfgFile, SvsmError> {
         self.select(FW_CFG_FILE_DIR);
         let n: u32 = self.read_be();
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Permanent write protection of the guest firmware.
+//!
+//! Once the firmware has been loaded and validated, its ranges are made
+//! read-only for all guest VMPLs in the RMP. Unlike the temporary read-only
+//! state used for copy-on-write backups, this protection is never lifted:
+//! restores skip protected pages and the guest cannot rescind their
+//! validation, so the measured firmware cannot be corrupted.
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use crate::sev::rmp::{rmp_set_guest_access_paddr, GuestAccess};
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::MemoryRegion;
+use alloc::vec::Vec;
+
+static FW_PROTECTED_REGIONS: RWLock<Vec<MemoryRegion<PhysAddr>>> = RWLock::new(Vec::new());
+
+/// Write-protects `region` for the guest and records it as permanently
+/// protected. The region must be page aligned.
+pub fn protect_fw_region(region: MemoryRegion<PhysAddr>) -> Result<(), SvsmError> {
+    if !region.start().is_page_aligned() || !region.end().is_page_aligned() {
+        return Err(SvsmError::InvalidAddress);
+    }
+
+    let mut paddr = region.start();
+    while paddr < region.end() {
+        if paddr.is_aligned(PAGE_SIZE_2M) && paddr + PAGE_SIZE_2M <= region.end() {
+            rmp_set_guest_access_paddr(paddr, PageSize::Huge, GuestAccess::ReadOnly)?;
+            paddr = paddr + PAGE_SIZE_2M;
+        } else {
+            rmp_set_guest_access_paddr(paddr, PageSize::Regular, GuestAccess::ReadOnly)?;
+            paddr = paddr + PAGE_SIZE;
+        }
+    }
+
+    FW_PROTECTED_REGIONS.lock_write().push(region);
+    log::info!(
+        "Write-protected firmware region {:#018x}-{:#018x}",
+        region.start(),
+        region.end()
+    );
+    Ok(())
+}
+
+/// Write-protects all given firmware regions.
+pub fn protect_fw_regions(regions: &[MemoryRegion<PhysAddr>]) -> Result<(), SvsmError> {
+    for region in regions.iter() {
+        protect_fw_region(*region)?;
+    }
+    Ok(())
+}
+
+/// Returns whether the page at `paddr` belongs to protected firmware.
+pub fn fw_page_protected(paddr: PhysAddr) -> bool {
+    FW_PROTECTED_REGIONS
+        .lock_read()
+        .iter()
+        .any(|region| region.contains(paddr))
+}
+
+/// Returns all permanently protected firmware regions.
+pub fn fw_protected_regions() -> Vec<MemoryRegion<PhysAddr>> {
+    FW_PROTECTED_REGIONS.lock_read().clone()
+}
+
+/// Returns whether any part of `region` belongs to protected firmware.
+pub fn fw_range_protected(region: MemoryRegion<PhysAddr>) -> bool {
+    FW_PROTECTED_REGIONS
+        .lock_read()
+        .iter()
+        .any(|protected| protected.overlap(&region))
+}
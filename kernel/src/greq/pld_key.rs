@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! `SNP_GUEST_REQUEST` command to request a derived key.
+
+use core::mem::size_of;
+
+use crate::protocols::errors::SvsmReqError;
+
+/// Size of the `SnpDerivedKeyResponse.derived_key`
+pub const DERIVED_KEY_SIZE: usize = 32;
+/// Size of the `MSG_KEY_REQ` payload
+pub const KEY_REQUEST_SIZE: usize = 0x20;
+
+/// `ROOT_KEY_SELECT`: derive the key from the VM root key (VMRK), which
+/// is moved along with the guest by its migration agent, instead of the
+/// chip-unique VCEK.
+pub const ROOT_KEY_VMRK: u32 = 1 << 0;
+
+/// `GUEST_FIELD_SELECT`: mix the guest policy into the key.
+pub const FIELD_GUEST_POLICY: u64 = 1 << 0;
+/// `GUEST_FIELD_SELECT`: mix the launch measurement into the key.
+pub const FIELD_MEASUREMENT: u64 = 1 << 3;
+
+/// MSG_KEY_REQ payload format (AMD SEV-SNP spec. table 18)
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnpDerivedKeyRequest {
+    /// 31:1 - Reserved
+    ///    0 - ROOT_KEY_SELECT. 0: VCEK, 1: VMRK
+    root_key_select: u32,
+    /// Reserved, must be zero
+    _reserved: u32,
+    /// Guest data to mix into the key
+    guest_field_select: u64,
+    /// The VMPL to mix into the key, at least the VMPL of the requester
+    vmpl: u32,
+    /// The guest SVN to mix into the key
+    guest_svn: u32,
+    /// The TCB version to mix into the key
+    tcb_version: u64,
+}
+
+impl SnpDerivedKeyRequest {
+    /// Returns a request for a VMPL0 key derived from the root key selected
+    /// by `root_key_select` with the guest fields `guest_field_select`
+    /// mixed in.
+    pub fn new(root_key_select: u32, guest_field_select: u64) -> Self {
+        Self {
+            root_key_select,
+            guest_field_select,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the request as a byte array.
+    pub fn to_bytes(&self) -> [u8; KEY_REQUEST_SIZE] {
+        let mut bytes = [0u8; KEY_REQUEST_SIZE];
+        bytes[0x00..0x04].copy_from_slice(&{ self.root_key_select }.to_le_bytes());
+        bytes[0x08..0x10].copy_from_slice(&{ self.guest_field_select }.to_le_bytes());
+        bytes[0x10..0x14].copy_from_slice(&{ self.vmpl }.to_le_bytes());
+        bytes[0x14..0x18].copy_from_slice(&{ self.guest_svn }.to_le_bytes());
+        bytes[0x18..0x20].copy_from_slice(&{ self.tcb_version }.to_le_bytes());
+        bytes
+    }
+}
+
+/// MSG_KEY_RSP payload format (AMD SEV-SNP spec. table 19)
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct SnpDerivedKeyResponse {
+    /// The status of the key derivation operation, zero on success
+    status: u32,
+    /// Reserved
+    _reserved: [u8; 28],
+    /// The requested derived key
+    derived_key: [u8; DERIVED_KEY_SIZE],
+}
+
+impl SnpDerivedKeyResponse {
+    pub fn try_from_as_ref(buffer: &[u8]) -> Result<&Self, SvsmReqError> {
+        let buffer = buffer
+            .get(..size_of::<Self>())
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+
+        // SAFETY: SnpDerivedKeyResponse has no invalid representations, as
+        // it is comprised entirely of integer types. It is repr(packed), so
+        // its required alignment is simply 1. We have checked the size, so
+        // this is entirely safe.
+        let response = unsafe { &*buffer.as_ptr().cast::<Self>() };
+        Ok(response)
+    }
+
+    /// Returns the derived key, or fails with INVALID_REQUEST if the
+    /// firmware refused to derive it, e.g. because the guest has no VMRK.
+    pub fn derived_key(&self) -> Result<[u8; DERIVED_KEY_SIZE], SvsmReqError> {
+        if self.status != 0 {
+            log::warn!("MSG_KEY_REQ failed with status {:#x}", { self.status });
+            return Err(SvsmReqError::invalid_request());
+        }
+        Ok(self.derived_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn test_snp_derived_key_request_offsets() {
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, root_key_select), 0x0);
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, _reserved), 0x4);
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, guest_field_select), 0x8);
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, vmpl), 0x10);
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, guest_svn), 0x14);
+        assert_eq!(offset_of!(SnpDerivedKeyRequest, tcb_version), 0x18);
+        assert_eq!(size_of::<SnpDerivedKeyRequest>(), KEY_REQUEST_SIZE);
+    }
+
+    #[test]
+    fn test_snp_derived_key_response_offsets() {
+        assert_eq!(offset_of!(SnpDerivedKeyResponse, status), 0x0);
+        assert_eq!(offset_of!(SnpDerivedKeyResponse, _reserved), 0x4);
+        assert_eq!(offset_of!(SnpDerivedKeyResponse, derived_key), 0x20);
+    }
+}
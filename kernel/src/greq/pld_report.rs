@@ -12,6 +12,8 @@ use crate::protocols::errors::SvsmReqError;
 
 /// Size of the `SnpReportRequest.user_data`
 pub const USER_DATA_SIZE: usize = 64;
+/// Size of the `AttestationReport.measurement`
+pub const MEASUREMENT_SIZE: usize = 48;
 
 /// MSG_REPORT_REQ payload format (AMD SEV-SNP spec. table 20)
 #[repr(C, packed)]
@@ -111,6 +113,12 @@ impl SnpReportResponse {
 
         Ok(())
     }
+
+    /// Returns the attestation report. Only meaningful after a successful
+    /// [`validate()`](Self::validate).
+    pub fn report(&self) -> &AttestationReport {
+        &self.report
+    }
 }
 
 /// The `TCB_VERSION` contains the security version numbers of each
@@ -164,7 +172,7 @@ pub struct AttestationReport {
     /// Guest-provided data
     report_data: [u8; 64],
     /// The measurement calculated at launch
-    measurement: [u8; 48],
+    measurement: [u8; MEASUREMENT_SIZE],
     /// Data provided by the hypervisor at launch
     host_data: [u8; 32],
     /// SHA-384 digest of the ID public key that signed the ID block
@@ -190,6 +198,18 @@ pub struct AttestationReport {
     signature: Signature,
 }
 
+impl AttestationReport {
+    /// Returns the guest policy.
+    pub fn policy(&self) -> u64 {
+        self.policy
+    }
+
+    /// Returns the launch measurement.
+    pub fn measurement(&self) -> [u8; MEASUREMENT_SIZE] {
+        self.measurement
+    }
+}
+
 const _: () = assert!(size_of::<AttestationReport>() <= u32::MAX as usize);
 
 #[cfg(test)]
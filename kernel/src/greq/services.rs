@@ -10,6 +10,9 @@ use crate::{
     greq::{
         driver::{send_extended_guest_request, send_regular_guest_request},
         msg::SnpGuestRequestMsgType,
+        pld_key::{
+            SnpDerivedKeyRequest, SnpDerivedKeyResponse, DERIVED_KEY_SIZE, KEY_REQUEST_SIZE,
+        },
         pld_report::{SnpReportRequest, SnpReportResponse},
     },
     protocols::errors::SvsmReqError,
@@ -18,6 +21,7 @@ use core::mem::size_of;
 
 const REPORT_REQUEST_SIZE: usize = size_of::<SnpReportRequest>();
 const REPORT_RESPONSE_SIZE: usize = size_of::<SnpReportResponse>();
+const KEY_RESPONSE_SIZE: usize = size_of::<SnpDerivedKeyResponse>();
 
 fn get_report(buffer: &mut [u8], certs: Option<&mut [u8]>) -> Result<usize, SvsmReqError> {
     let request: &SnpReportRequest = SnpReportRequest::try_from_as_ref(buffer)?;
@@ -104,3 +108,34 @@ pub fn get_regular_report(buffer: &mut [u8]) -> Result<usize, SvsmReqError> {
 pub fn get_extended_report(buffer: &mut [u8], certs: &mut [u8]) -> Result<usize, SvsmReqError> {
     get_report(buffer, Some(certs))
 }
+
+/// Request a VMPL0 derived key from the PSP.
+///
+/// Use the `SNP_GUEST_REQUEST` driver to send a `MSG_KEY_REQ` command for
+/// the key described by `request`. As the key is derived for VMPL0, the
+/// guest kernel cannot request the same key itself.
+///
+/// # Returns
+///
+/// * Success
+///     * The derived key.
+/// * Error
+///     * [`SvsmReqError`]
+pub fn get_derived_key(
+    request: &SnpDerivedKeyRequest,
+) -> Result<[u8; DERIVED_KEY_SIZE], SvsmReqError> {
+    let mut buffer = [0u8; KEY_RESPONSE_SIZE];
+    buffer[..KEY_REQUEST_SIZE].copy_from_slice(&request.to_bytes());
+    let response_len = send_regular_guest_request(
+        SnpGuestRequestMsgType::KeyRequest,
+        &mut buffer,
+        KEY_REQUEST_SIZE,
+    )?;
+    if KEY_RESPONSE_SIZE > response_len {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let result = SnpDerivedKeyResponse::try_from_as_ref(&buffer)?.derived_key();
+    // Do not leave the key behind on the stack.
+    buffer.fill(0);
+    result
+}
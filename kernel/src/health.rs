@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Health and readiness state published to the host.
+//!
+//! Host orchestration cannot tell an SVSM which is still busy validating
+//! memory from one which is wedged, and it cannot rely on the guest to
+//! report either. If the IGVM parameters name a health page, the SVSM
+//! publishes its boot phase, the state of the backup subsystem and the TSC
+//! value of the last request loop iteration into that page. The page is
+//! shared memory, so the host can read it at any time.
+//!
+//! The state itself lives in SVSM memory. It is copied to the health page
+//! on every state transition and, while the SVSM is running, at most once
+//! every [`PUBLISH_INTERVAL_MS`] milliseconds.
+
+use crate::address::{Address, PhysAddr};
+use crate::cpu::tsc::{tsc_khz, tsc_now};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::scratch::map_shared_page;
+use crate::utils::MemoryRegion;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// "SVHL" in little endian.
+const HEALTH_MAGIC: u32 = 0x4c48_5653;
+const HEALTH_VERSION: u32 = 1;
+
+/// Minimum time between two publications of the request loop timestamp.
+const PUBLISH_INTERVAL_MS: u64 = 10;
+
+/// Boot phase of the SVSM, in the order the phases are passed.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootPhase {
+    /// Early initialization, before the configuration is known.
+    Starting = 0,
+    /// Starting the secondary CPUs.
+    StartingCpus = 1,
+    /// Validating and protecting firmware memory.
+    ValidatingMemory = 2,
+    /// Setting up services and launching the guest firmware.
+    LaunchingGuest = 3,
+    /// Serving guest requests.
+    Running = 4,
+    /// The SVSM panicked and will not make further progress.
+    Panicked = 5,
+}
+
+/// State of the backup subsystem.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupState {
+    /// No backup exists.
+    Idle = 0,
+    /// A backup is being created.
+    BackingUp = 1,
+    /// A backup exists.
+    Ready = 2,
+    /// A backup exists and copy-on-write protection is enabled.
+    CopyOnWrite = 3,
+    /// Guest memory is being restored from the backup.
+    Restoring = 4,
+    /// The last restore failed, guest memory is inconsistent.
+    Failed = 5,
+}
+
+/// Layout of the health page as seen by the host. All fields are little
+/// endian.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct HealthRecord {
+    magic: u32,
+    version: u32,
+    /// A [`BootPhase`].
+    phase: u32,
+    /// A [`BackupState`].
+    backup_state: u32,
+    /// Incremented on every publication.
+    sequence: u64,
+    /// TSC value at the end of the last request loop iteration which did
+    /// not hit a fatal error, zero before the first one.
+    last_ok_tsc: u64,
+    /// TSC frequency, to convert TSC values into time.
+    tsc_khz: u64,
+}
+
+static HEALTH_PAGE: SpinLock<Option<PhysAddr>> = SpinLock::new(None);
+
+static PHASE: AtomicU32 = AtomicU32::new(BootPhase::Starting as u32);
+static BACKUP_STATE: AtomicU32 = AtomicU32::new(BackupState::Idle as u32);
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static LAST_OK_TSC: AtomicU64 = AtomicU64::new(0);
+static LAST_PUBLISH_TSC: AtomicU64 = AtomicU64::new(0);
+
+fn record() -> HealthRecord {
+    HealthRecord {
+        magic: HEALTH_MAGIC,
+        version: HEALTH_VERSION,
+        phase: PHASE.load(Ordering::Relaxed),
+        backup_state: BACKUP_STATE.load(Ordering::Relaxed),
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1,
+        last_ok_tsc: LAST_OK_TSC.load(Ordering::Relaxed),
+        tsc_khz: tsc_khz(),
+    }
+}
+
+fn write_record(paddr: PhysAddr, record: &HealthRecord) -> Result<(), SvsmError> {
+    let guard = map_shared_page(paddr)?;
+    let vaddr = guard.virt_addr();
+    // SAFETY: the guard maps the health page, which is large enough for
+    // the record, for the duration of the write.
+    unsafe { vaddr.as_mut_ptr::<HealthRecord>().write_volatile(*record) };
+    Ok(())
+}
+
+fn publish() {
+    let page = HEALTH_PAGE.lock();
+    if let Some(paddr) = *page {
+        if let Err(e) = write_record(paddr, &record()) {
+            log::warn!("Failed to publish health state: {:?}", e);
+        }
+    }
+}
+
+/// Sets the guest physical address of the health page, which must be page
+/// aligned and lie outside of SVSM memory, and publishes the current state.
+pub fn set_health_page(paddr: PhysAddr, kernel_region: &MemoryRegion<PhysAddr>) {
+    if !paddr.is_page_aligned() || kernel_region.contains(paddr) {
+        log::warn!("Ignoring invalid health page {:#x}", paddr);
+        return;
+    }
+    *HEALTH_PAGE.lock() = Some(paddr);
+    log::info!("Publishing health state at {:#x}", paddr);
+    publish();
+}
+
+/// Enters a new boot phase.
+pub fn set_boot_phase(phase: BootPhase) {
+    PHASE.store(phase as u32, Ordering::Relaxed);
+    publish();
+}
+
+/// Records a new state of the backup subsystem.
+pub fn set_backup_state(state: BackupState) {
+    BACKUP_STATE.store(state as u32, Ordering::Relaxed);
+    publish();
+}
+
+/// Returns the current state of the backup subsystem.
+pub fn backup_state() -> BackupState {
+    match BACKUP_STATE.load(Ordering::Relaxed) {
+        1 => BackupState::BackingUp,
+        2 => BackupState::Ready,
+        3 => BackupState::CopyOnWrite,
+        4 => BackupState::Restoring,
+        5 => BackupState::Failed,
+        _ => BackupState::Idle,
+    }
+}
+
+/// Records that the request loop completed an iteration. The timestamp is
+/// published at most every [`PUBLISH_INTERVAL_MS`] milliseconds.
+pub fn health_tick() {
+    let now = tsc_now();
+    LAST_OK_TSC.store(now, Ordering::Relaxed);
+    let last = LAST_PUBLISH_TSC.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) < PUBLISH_INTERVAL_MS * tsc_khz() {
+        return;
+    }
+    // Only one CPU publishes per interval.
+    if LAST_PUBLISH_TSC
+        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        publish();
+    }
+}
+
+/// Publishes the panicked phase. Called from the panic handler, so it gives
+/// up instead of waiting if another CPU holds the health page lock.
+pub fn health_panic() {
+    PHASE.store(BootPhase::Panicked as u32, Ordering::Relaxed);
+    if let Some(page) = HEALTH_PAGE.try_lock() {
+        if let Some(paddr) = *page {
+            let _ = write_record(paddr, &record());
+        }
+    }
+}
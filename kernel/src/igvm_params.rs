@@ -6,7 +6,7 @@
 
 extern crate alloc;
 
-use crate::acpi::tables::ACPICPUInfo;
+use crate::acpi::tables::{load_madt_cpu_info, ACPICPUInfo};
 use crate::address::{PhysAddr, VirtAddr};
 use crate::cpu::efer::EFERFlags;
 use crate::error::SvsmError;
@@ -20,6 +20,7 @@ use cpuarch::vmsa::VMSA;
 
 use bootlib::igvm_params::{IgvmGuestContext, IgvmParamBlock, IgvmParamPage};
 use core::mem::size_of;
+use core::slice;
 use igvm_defs::{IgvmEnvironmentInfo, MemoryMapEntryType, IGVM_VHS_MEMORY_MAP_ENTRY};
 
 const IGVM_MEMORY_ENTRIES_PER_PAGE: usize = PAGE_SIZE / size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>();
@@ -38,6 +39,7 @@ pub struct IgvmParams<'a> {
     igvm_param_page: &'a IgvmParamPage,
     igvm_memory_map: &'a IgvmMemoryMap,
     igvm_guest_context: Option<&'a IgvmGuestContext>,
+    igvm_madt: Option<&'a [u8]>,
 }
 
 impl IgvmParams<'_> {
@@ -53,12 +55,27 @@ impl IgvmParams<'_> {
         } else {
             None
         };
+        let madt = if param_block.madt_offset != 0 {
+            let offset = usize::try_from(param_block.madt_offset).unwrap();
+            let size = usize::try_from(param_block.madt_size).unwrap();
+            if offset.checked_add(size).ok_or(SvsmError::Firmware)?
+                > param_block.param_area_size as usize
+            {
+                return Err(SvsmError::Firmware);
+            }
+            // SAFETY: the MADT lies within the parameter area, which we trust
+            // the caller to have mapped.
+            Some(unsafe { slice::from_raw_parts((addr + offset).as_ptr::<u8>(), size) })
+        } else {
+            None
+        };
 
         Ok(Self {
             igvm_param_block: param_block,
             igvm_param_page: param_page,
             igvm_memory_map: memory_map,
             igvm_guest_context: guest_context,
+            igvm_madt: madt,
         })
     }
 
@@ -231,6 +248,17 @@ impl IgvmParams<'_> {
     }
 
     pub fn load_cpu_info(&self) -> Result<Vec<ACPICPUInfo>, SvsmError> {
+        // Prefer the MADT supplied by the host loader, which carries the real
+        // APIC IDs. Fall back to contiguous APIC IDs derived from the CPU
+        // count if the loader did not provide one.
+        if let Some(madt) = self.igvm_madt {
+            match load_madt_cpu_info(madt) {
+                Ok(cpus) if !cpus.is_empty() => return Ok(cpus),
+                Ok(_) => log::warn!("IGVM MADT contains no CPUs, using CPU count"),
+                Err(e) => log::warn!("Failed to parse IGVM MADT, using CPU count: {:?}", e),
+            }
+        }
+
         let mut cpus: Vec<ACPICPUInfo> = Vec::new();
         for i in 0..self.igvm_param_page.cpu_count {
             let cpu = ACPICPUInfo {
@@ -309,14 +337,22 @@ impl IgvmParams<'_> {
             regions.push(MemoryRegion::new(PhysAddr::new(0), STAGE2_END_ADDR));
         }
 
-        regions.push(MemoryRegion::new(
-            PhysAddr::new(self.igvm_param_block.firmware.start as usize),
-            self.igvm_param_block.firmware.size as usize,
-        ));
+        regions.push(self.get_fw_image_region());
 
         regions
     }
 
+    /// Returns the range of the firmware image itself, without the stage 2
+    /// range which [`Self::get_fw_regions`] also includes.
+    pub fn get_fw_image_region(&self) -> MemoryRegion<PhysAddr> {
+        assert!(self.should_launch_fw());
+
+        MemoryRegion::new(
+            PhysAddr::new(self.igvm_param_block.firmware.start as usize),
+            self.igvm_param_block.firmware.size as usize,
+        )
+    }
+
     pub fn fw_in_low_memory(&self) -> bool {
         self.igvm_param_block.firmware.in_low_memory != 0
     }
@@ -395,4 +431,48 @@ impl IgvmParams<'_> {
     pub fn use_alternate_injection(&self) -> bool {
         self.igvm_param_block.use_alternate_injection != 0
     }
+
+    pub fn percpu_map_window_pages(&self) -> u32 {
+        self.igvm_param_block.percpu_map_window_pages
+    }
+
+    pub fn health_page(&self) -> u64 {
+        self.igvm_param_block.health_page
+    }
+
+    pub fn backup_bandwidth_percent(&self) -> u32 {
+        self.igvm_param_block.backup_bandwidth_percent
+    }
+
+    pub fn vtpm_restore_policy(&self) -> u32 {
+        self.igvm_param_block.vtpm_restore_policy
+    }
+
+    pub fn paranoid_checks(&self) -> u32 {
+        self.igvm_param_block.paranoid_checks
+    }
+
+    pub fn backup_compression(&self) -> bool {
+        self.igvm_param_block.backup_compression != 0
+    }
+
+    pub fn scratch_region(&self) -> (u64, u32) {
+        (
+            self.igvm_param_block.scratch_region,
+            self.igvm_param_block.scratch_region_pages,
+        )
+    }
+
+    pub fn snapshot_budget(&self) -> (u64, u64) {
+        (
+            self.igvm_param_block.snapshot_budget,
+            self.igvm_param_block.snapshot_global_budget,
+        )
+    }
+
+    pub fn snapshot_digests(&self) -> &[[u8; 32]] {
+        let count = self.igvm_param_block.snapshot_digest_count as usize;
+        let digests = &self.igvm_param_block.snapshot_digests;
+        &digests[..count.min(digests.len())]
+    }
 }
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Checked invariants for code reachable from guest requests.
+//!
+//! A broken invariant in a protocol handler is a bug, but a panic would
+//! take the whole SVSM down with it, which a misbehaving guest must never
+//! be able to cause. [`checked_invariant!`] therefore panics in debug
+//! builds only, where the bug should be caught early. Release builds log
+//! the violation and return [`SvsmError::Invariant`] with a unique code to
+//! the caller, which reports it to the guest as a protocol error. Panics
+//! remain for conditions the SVSM cannot recover from.
+
+use crate::error::SvsmError;
+
+/// Unique codes of checked invariants, grouped by subsystem.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvariantCode {
+    /// A snapshot arena slot was returned while none was allocated.
+    ArenaSlotUnderflow = 0x0101,
+    /// More snapshot memory was refunded than charged.
+    ChargeUnderflow = 0x0102,
+    /// A streamed export advanced past the end of its container.
+    ExportCursorOverrun = 0x0103,
+    /// A page checksum was recorded for a slot other than the newest one.
+    ChecksumSlotMismatch = 0x0104,
+    /// Backed-up pages were written while a fork of the snapshot shared
+    /// them.
+    SharedPagesWritten = 0x0105,
+    /// A mapping was requested with an alignment beyond the largest page
+    /// size.
+    MappingAlignment = 0x0201,
+    /// A 2M page being split was not in the set of pages to back up.
+    SplitMissingHugePage = 0x0301,
+    /// A page was unpinned which was not pinned.
+    UnpinUnpinned = 0x0302,
+}
+
+impl InvariantCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+}
+
+/// Reports the violation of the invariant `code`. Panics in debug builds,
+/// logs the violation and returns the corresponding error otherwise. Used
+/// directly where no error can be returned, e.g. in `Drop`.
+#[cold]
+pub fn invariant_violated(code: InvariantCode, expr: &str, file: &str, line: u32) -> SvsmError {
+    if cfg!(debug_assertions) {
+        panic!(
+            "Invariant {:#06x} ({:?}) violated at {}:{}: {}",
+            code.code(),
+            code,
+            file,
+            line,
+            expr
+        );
+    }
+    log::error!(
+        "Invariant {:#06x} ({:?}) violated at {}:{}: {}",
+        code.code(),
+        code,
+        file,
+        line,
+        expr
+    );
+    SvsmError::Invariant(code)
+}
+
+/// Checks that `cond` holds, and otherwise returns the
+/// [`InvariantCode`] variant `code` as an error from the enclosing function
+/// (or panics in debug builds). The error is converted with `into()`, so
+/// the enclosing function may return any error type which converts from
+/// [`SvsmError`].
+#[macro_export]
+macro_rules! checked_invariant {
+    ($cond:expr, $code:ident) => {
+        if !($cond) {
+            return Err($crate::invariant::invariant_violated(
+                $crate::invariant::InvariantCode::$code,
+                stringify!($cond),
+                file!(),
+                line!(),
+            )
+            .into());
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(value: usize) -> Result<usize, SvsmError> {
+        checked_invariant!(value > 0, ChargeUnderflow);
+        Ok(value - 1)
+    }
+
+    #[test]
+    fn test_checked_invariant_holds() {
+        assert_eq!(check(1).unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Invariant 0x0102")]
+    fn test_checked_invariant_violated() {
+        let _ = check(0);
+    }
+}
@@ -21,9 +21,12 @@ pub mod error;
 pub mod fs;
 pub mod fw_cfg;
 pub mod fw_meta;
+pub mod fw_protect;
 pub mod greq;
+pub mod health;
 pub mod igvm_params;
 pub mod insn_decode;
+pub mod invariant;
 pub mod io;
 pub mod kernel_region;
 pub mod locking;
@@ -31,6 +34,7 @@ pub mod mm;
 pub mod platform;
 pub mod protocols;
 pub mod requests;
+pub mod scratch;
 pub mod serial;
 pub mod sev;
 pub mod string;
@@ -64,6 +64,14 @@ pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
 }
 
 // Address space definitions for SVSM virtual memory layout
+//
+// The layout below is defined in terms of level 3 (PML4) indices. With
+// 4-level paging the PML4 is the page-table root. When stage2 enables
+// 5-level paging (CR4.LA57), the root is a PML5 and the same layout is
+// placed below PML5 index PGTABLE_LVL4_IDX_SVSM, while user memory lives
+// below PGTABLE_LVL4_IDX_USER. The addresses need no change: a
+// sign-extended 48-bit address is also canonical with 57 bits and selects
+// PML5 entry 0 or 511 depending on its sign.
 
 /// Size helpers
 pub const SIZE_1K: usize = 1024;
@@ -92,6 +100,12 @@ const fn virt_from_idx(idx: usize) -> VirtAddr {
     VirtAddr::new(idx << ((3 * 9) + 12))
 }
 
+/// Level4 (PML5) index holding the SVSM layout with 5-level paging
+pub const PGTABLE_LVL4_IDX_SVSM: usize = 511;
+
+/// Level4 (PML5) index holding user memory with 5-level paging
+pub const PGTABLE_LVL4_IDX_USER: usize = 0;
+
 /// Level3 page-table index shared between all CPUs
 pub const PGTABLE_LVL3_IDX_SHARED: usize = 511;
 
@@ -132,14 +146,15 @@ pub const SVSM_STACK_IST_DF_BASE: VirtAddr = SVSM_STACKS_IST_BASE;
 /// Base Address for temporary mappings - used by page-table guards
 pub const SVSM_PERCPU_TEMP_BASE: VirtAddr = SVSM_PERCPU_BASE.const_add(SIZE_LEVEL2);
 
-// Below is space for 512 temporary 4k mappings and 511 temporary 2M mappings
+// Below is space for up to 1024 temporary 4k mappings and 510 temporary 2M
+// mappings. The number of 4k mappings in use is configurable at boot.
 
 /// Start and End for PAGE_SIZEed temporary mappings
 pub const SVSM_PERCPU_TEMP_BASE_4K: VirtAddr = SVSM_PERCPU_TEMP_BASE;
-pub const SVSM_PERCPU_TEMP_END_4K: VirtAddr = SVSM_PERCPU_TEMP_BASE_4K.const_add(SIZE_LEVEL1);
+pub const SVSM_PERCPU_TEMP_END_4K: VirtAddr = SVSM_PERCPU_TEMP_BASE_4K.const_add(2 * SIZE_LEVEL1);
 
 /// Start and End for PAGE_SIZEed temporary mappings
-pub const SVSM_PERCPU_TEMP_BASE_2M: VirtAddr = SVSM_PERCPU_TEMP_BASE.const_add(SIZE_LEVEL1);
+pub const SVSM_PERCPU_TEMP_BASE_2M: VirtAddr = SVSM_PERCPU_TEMP_BASE.const_add(2 * SIZE_LEVEL1);
 pub const SVSM_PERCPU_TEMP_END_2M: VirtAddr = SVSM_PERCPU_TEMP_BASE.const_add(SIZE_LEVEL2);
 
 /// Task mappings level 3 index
@@ -43,6 +43,27 @@ impl From<AllocError> for SvsmError {
 // Maximum order of page allocations (up to 1 GiB) (2^(MAX_ORDER-1)*4KiB)
 pub const MAX_ORDER: usize = 19;
 
+/// Subsystem an allocation of pages is accounted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemTag {
+    /// Allocations which are not accounted to a subsystem.
+    Untagged = 0,
+    /// Guest snapshots of the backup protocol.
+    Backup = 1,
+}
+
+impl MemTag {
+    /// Number of tags.
+    pub const COUNT: usize = 2;
+
+    fn from_bits(bits: u64) -> Self {
+        match bits {
+            1 => Self::Backup,
+            _ => Self::Untagged,
+        }
+    }
+}
+
 /// Calculates the order of a given size for page allocation.
 ///
 /// # Arguments
@@ -155,6 +176,20 @@ impl PageStorageType {
         Self(self.0 | (item_size << Self::TYPE_SHIFT))
     }
 
+    /// Encodes the tag of an allocated page, which is kept where free pages
+    /// keep the index of the next page.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The tag to encode.
+    ///
+    /// # Returns
+    ///
+    /// The updated [`PageStorageType`].
+    fn encode_tag(self, tag: MemTag) -> Self {
+        Self(self.0 | (tag as u64) << Self::NEXT_SHIFT)
+    }
+
     /// Encodes the reference count.
     ///
     /// # Arguments
@@ -178,6 +213,11 @@ impl PageStorageType {
         ((self.0 & Self::NEXT_MASK) >> Self::NEXT_SHIFT) as usize
     }
 
+    /// Decodes the tag of an allocated page.
+    fn decode_tag(&self) -> MemTag {
+        MemTag::from_bits(self.0 >> Self::NEXT_SHIFT)
+    }
+
     /// Decodes the slab
     fn decode_slab(&self) -> u64 {
         (self.0 >> Self::TYPE_SHIFT) & Self::SLAB_MASK
@@ -223,18 +263,30 @@ impl FreeInfo {
 #[derive(Clone, Copy, Debug)]
 struct AllocatedInfo {
     order: usize,
+    tag: MemTag,
 }
 
 impl AllocatedInfo {
+    /// Creates a new [`AllocatedInfo`] of the given order without a tag.
+    const fn new(order: usize) -> Self {
+        Self {
+            order,
+            tag: MemTag::Untagged,
+        }
+    }
+
     /// Encodes the [`AllocatedInfo`] into a [`PageStorageType`].
     fn encode(&self) -> PageStorageType {
-        PageStorageType::new(PageType::Allocated).encode_order(self.order)
+        PageStorageType::new(PageType::Allocated)
+            .encode_order(self.order)
+            .encode_tag(self.tag)
     }
 
     /// Decodes a [`PageStorageType`] into an [`AllocatedInfo`].
     fn decode(mem: PageStorageType) -> Self {
         let order = mem.decode_order();
-        Self { order }
+        let tag = mem.decode_tag();
+        Self { order, tag }
     }
 }
 
@@ -363,6 +415,34 @@ impl PageInfo {
 pub struct MemInfo {
     total_pages: [usize; MAX_ORDER],
     free_pages: [usize; MAX_ORDER],
+    /// Allocated 4K pages per [`MemTag`]. Untagged pages are not counted.
+    tagged_pages: [usize; MemTag::COUNT],
+}
+
+impl MemInfo {
+    /// Returns the number of bytes managed by the allocator.
+    pub fn total_bytes(&self) -> usize {
+        self.total_pages
+            .iter()
+            .enumerate()
+            .map(|(order, &pages)| (pages << order) * PAGE_SIZE)
+            .sum()
+    }
+
+    /// Returns the number of bytes allocated with `tag`, which must not be
+    /// [`MemTag::Untagged`].
+    pub fn tagged_bytes(&self, tag: MemTag) -> usize {
+        self.tagged_pages[tag as usize] * PAGE_SIZE
+    }
+
+    /// Returns the number of free bytes across all orders.
+    pub fn free_bytes(&self) -> usize {
+        self.free_pages
+            .iter()
+            .enumerate()
+            .map(|(order, &pages)| (pages << order) * PAGE_SIZE)
+            .sum()
+    }
 }
 
 /// Memory region with its physical/virtual addresses, page count, as well
@@ -375,6 +455,7 @@ struct MemoryRegion {
     nr_pages: [usize; MAX_ORDER],
     next_page: [usize; MAX_ORDER],
     free_pages: [usize; MAX_ORDER],
+    tagged_pages: [usize; MemTag::COUNT],
 }
 
 impl MemoryRegion {
@@ -387,6 +468,7 @@ impl MemoryRegion {
             nr_pages: [0; MAX_ORDER],
             next_page: [0; MAX_ORDER],
             free_pages: [0; MAX_ORDER],
+            tagged_pages: [0; MemTag::COUNT],
         }
     }
 
@@ -575,8 +657,23 @@ impl MemoryRegion {
 
     /// Allocates pages with a specific order.
     fn allocate_pages(&mut self, order: usize) -> Result<VirtAddr, AllocError> {
-        let pg = PageInfo::Allocated(AllocatedInfo { order });
-        self.allocate_pages_info(order, pg)
+        self.allocate_pages_tagged(order, MemTag::Untagged)
+    }
+
+    /// Allocates pages with a specific order and accounts them to `tag`.
+    fn allocate_pages_tagged(&mut self, order: usize, tag: MemTag) -> Result<VirtAddr, AllocError> {
+        let pg = PageInfo::Allocated(AllocatedInfo { order, tag });
+        let vaddr = self.allocate_pages_info(order, pg)?;
+        self.account_tag(tag, 1isize << order);
+        Ok(vaddr)
+    }
+
+    /// Adds `pages` 4K pages to the pages accounted to `tag`.
+    fn account_tag(&mut self, tag: MemTag, pages: isize) {
+        if tag != MemTag::Untagged {
+            let count = &mut self.tagged_pages[tag as usize];
+            *count = count.wrapping_add_signed(pages);
+        }
     }
 
     /// Allocates a single page.
@@ -672,7 +769,7 @@ impl MemoryRegion {
         let pfn = pfn1.min(pfn2);
 
         // Write new compound head
-        let pg = PageInfo::Allocated(AllocatedInfo { order: order + 1 });
+        let pg = PageInfo::Allocated(AllocatedInfo::new(order + 1));
         self.write_page_info(pfn, pg);
 
         // Write compound pages
@@ -741,7 +838,7 @@ impl MemoryRegion {
             });
             self.write_page_info(old_pfn, pg);
 
-            let pg = PageInfo::Allocated(AllocatedInfo { order });
+            let pg = PageInfo::Allocated(AllocatedInfo::new(order));
             self.write_page_info(current_pfn, pg);
 
             self.free_pages[order] -= 1;
@@ -815,6 +912,7 @@ impl MemoryRegion {
 
         match res {
             PageInfo::Allocated(ai) => {
+                self.account_tag(ai.tag, -(1isize << ai.order));
                 self.free_page_order(pfn, ai.order);
             }
             PageInfo::Slab(_si) => {
@@ -823,6 +921,9 @@ impl MemoryRegion {
             PageInfo::Compound(ci) => {
                 let mask = (1usize << ci.order) - 1;
                 let start_pfn = pfn & !mask;
+                if let PageInfo::Allocated(ai) = self.read_page_info(start_pfn) {
+                    self.account_tag(ai.tag, -(1isize << ci.order));
+                }
                 self.free_page_order(start_pfn, ci.order);
             }
             PageInfo::File(_) => {
@@ -840,6 +941,7 @@ impl MemoryRegion {
         MemInfo {
             total_pages: self.nr_pages,
             free_pages: self.free_pages,
+            tagged_pages: self.tagged_pages,
         }
     }
 
@@ -858,7 +960,7 @@ impl MemoryRegion {
 
         /* Mark all pages as allocated */
         for i in meta_pages..self.page_count {
-            let pg = PageInfo::Allocated(AllocatedInfo { order: 0 });
+            let pg = PageInfo::Allocated(AllocatedInfo::new(0));
             self.write_page_info(i, pg);
         }
 
@@ -1009,6 +1111,21 @@ pub fn print_memory_info(info: &MemInfo) {
     );
 }
 
+/// Logs how much memory the allocator manages, how much of it is free and
+/// how much is accounted to each subsystem.
+///
+/// # Arguments
+///
+/// * `info` - Reference to [`MemInfo`] structure containing memory information.
+pub fn print_memory_usage(info: &MemInfo) {
+    log::info!(
+        "SVSM memory: {}KiB, free: {}KiB, backup: {}KiB",
+        info.total_bytes() / 1024,
+        info.free_bytes() / 1024,
+        info.tagged_bytes(MemTag::Backup) / 1024
+    );
+}
+
 /// Static spinlock-protected instance of [`MemoryRegion`] representing the
 /// root memory region.
 static ROOT_MEM: SpinLock<MemoryRegion> = SpinLock::new(MemoryRegion::new());
@@ -1038,6 +1155,22 @@ pub fn allocate_pages(order: usize) -> Result<VirtAddr, SvsmError> {
     Ok(ROOT_MEM.lock().allocate_pages(order)?)
 }
 
+/// Allocates multiple memory pages with a specified order from the root
+/// memory region and accounts them to a subsystem until they are freed.
+///
+/// # Arguments
+///
+/// * `order` - Order of the allocation, determining the number of pages (2^order).
+/// * `tag` - Subsystem the pages are accounted to.
+///
+/// # Returns
+///
+/// Result containing the virtual address of the allocated pages or an
+/// `SvsmError` if allocation fails.
+pub fn allocate_pages_tagged(order: usize, tag: MemTag) -> Result<VirtAddr, SvsmError> {
+    Ok(ROOT_MEM.lock().allocate_pages_tagged(order, tag)?)
+}
+
 /// Allocate a slab page.
 ///
 /// # Arguments
@@ -1105,6 +1238,13 @@ pub fn memory_info() -> MemInfo {
     ROOT_MEM.lock().memory_info()
 }
 
+/// Retrieves information about the root memory without waiting for the
+/// allocator. Returns `None` if it is locked, e.g. when panicking in the
+/// middle of an allocation.
+pub fn stats() -> Option<MemInfo> {
+    ROOT_MEM.try_lock().map(|root_mem| root_mem.memory_info())
+}
+
 /// Represents a slab memory page, used for efficient allocation of
 /// fixed-size objects.
 #[derive(Debug, Default)]
@@ -1765,6 +1905,25 @@ mod test {
         assert_eq!(info_before.free_pages, root_mem.memory_info().free_pages);
     }
 
+    /// Tests that tagged allocations are accounted to their tag until they
+    /// are freed.
+    #[test]
+    fn test_page_alloc_tagged() {
+        let _test_mem = TestRootMem::setup(DEFAULT_TEST_MEMORY_SIZE);
+        let mut root_mem = ROOT_MEM.lock();
+
+        let before = root_mem.memory_info().tagged_bytes(MemTag::Backup);
+        let pages = root_mem.allocate_pages_tagged(2, MemTag::Backup).unwrap();
+        let page = root_mem.allocate_page().unwrap();
+        assert_eq!(
+            root_mem.memory_info().tagged_bytes(MemTag::Backup),
+            before + 4 * PAGE_SIZE
+        );
+        root_mem.free_page(pages);
+        root_mem.free_page(page);
+        assert_eq!(root_mem.memory_info().tagged_bytes(MemTag::Backup), before);
+    }
+
     #[test]
     #[cfg_attr(test_in_svsm, ignore = "FIXME")]
     /// Allocate and free all available compound pages, verify that memory_info()
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Page copy engine.
+//!
+//! Backup, restore and fork all move whole pages between guest memory and
+//! SVSM-owned buffers. [`PageCopier`] implements the mapping, pinning and
+//! copying for all of them, so every path maps huge pages, reports faults
+//! and detects zero pages the same way.
+
+extern crate alloc;
+
+use crate::address::{PhysAddr, VirtAddr};
+use crate::error::SvsmError;
+use crate::mm::guestmem::GuestPtr;
+use crate::mm::pin::pin_pages;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::mem::size_of;
+
+bitflags! {
+    /// Options of a [`PageCopier`].
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CopyFlags: u32 {
+        /// Report whether the copied data is all zeroes.
+        const DETECT_ZERO = 1 << 0;
+        /// Pin destination guest pages while they are written.
+        const PIN_DEST    = 1 << 1;
+    }
+}
+
+/// Where the data of a page copy comes from.
+#[derive(Clone, Copy, Debug)]
+pub enum CopySource<'a> {
+    /// A guest page at the given physical address.
+    Guest(PhysAddr),
+    /// An SVSM-owned buffer of at least the page size.
+    Buffer(&'a [u8]),
+    /// A page of zeroes.
+    Zero,
+}
+
+/// Where the data of a page copy goes to.
+#[derive(Debug)]
+pub enum CopyDest<'a> {
+    /// A guest page at the given physical address.
+    Guest(PhysAddr),
+    /// An SVSM-owned buffer of at least the page size.
+    Buffer(&'a mut [u8]),
+}
+
+/// The result of a page copy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CopyOutcome {
+    /// Number of bytes copied.
+    pub bytes: usize,
+    /// Whether all copied bytes were zero. Only valid with
+    /// [`CopyFlags::DETECT_ZERO`].
+    pub zero: bool,
+}
+
+/// A mapping of a guest page into the SVSM address space.
+pub trait MappedPage {
+    fn virt_addr(&self) -> VirtAddr;
+}
+
+impl MappedPage for PerCPUPageMappingGuard {
+    fn virt_addr(&self) -> VirtAddr {
+        PerCPUPageMappingGuard::virt_addr(self)
+    }
+}
+
+/// Maps guest pages for a [`PageCopier`].
+pub trait PageMapper {
+    type Mapping: MappedPage;
+
+    fn map(&self, paddr: PhysAddr, size: PageSize) -> Result<Self::Mapping, SvsmError>;
+}
+
+/// Maps guest pages through per-CPU page mappings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerCpuMapper;
+
+impl PageMapper for PerCpuMapper {
+    type Mapping = PerCPUPageMappingGuard;
+
+    fn map(&self, paddr: PhysAddr, size: PageSize) -> Result<Self::Mapping, SvsmError> {
+        match size {
+            PageSize::Regular => PerCPUPageMappingGuard::create_4k(paddr),
+            PageSize::Huge => PerCPUPageMappingGuard::create_2m(paddr),
+        }
+    }
+}
+
+/// Bytes checked at a time by [`is_zeroed`]. Within a block the words are
+/// or-ed together without branches, so the check vectorizes.
+const ZERO_CHECK_BLOCK: usize = 64;
+
+/// Returns whether `data` is all zeroes. Reads eight bytes at a time and
+/// stops at the first block holding a non-zero byte.
+pub fn is_zeroed(data: &[u8]) -> bool {
+    let blocks = data.chunks_exact(ZERO_CHECK_BLOCK);
+    let rest = blocks.remainder();
+    blocks
+        .map(|block| {
+            block
+                .chunks_exact(size_of::<u64>())
+                .fold(0u64, |acc, word| {
+                    acc | u64::from_ne_bytes(word.try_into().unwrap())
+                })
+        })
+        .all(|bits| bits == 0)
+        && rest.iter().all(|&b| b == 0)
+}
+
+fn page_bytes(size: PageSize) -> usize {
+    match size {
+        PageSize::Regular => PAGE_SIZE,
+        PageSize::Huge => PAGE_SIZE_2M,
+    }
+}
+
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// Copies pages between guest memory and SVSM buffers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PageCopier<M: PageMapper = PerCpuMapper> {
+    mapper: M,
+    flags: CopyFlags,
+}
+
+impl PageCopier {
+    /// Creates a copier which maps guest pages through per-CPU mappings.
+    pub fn new(flags: CopyFlags) -> Self {
+        Self::with_mapper(PerCpuMapper, flags)
+    }
+}
+
+impl<M: PageMapper> PageCopier<M> {
+    pub fn with_mapper(mapper: M, flags: CopyFlags) -> Self {
+        Self { mapper, flags }
+    }
+
+    /// Copies one page of the given size from `src` to `dst`. Faults while
+    /// accessing guest memory are reported as [`SvsmError::InvalidAddress`].
+    pub fn copy(
+        &self,
+        src: CopySource<'_>,
+        dst: CopyDest<'_>,
+        size: PageSize,
+    ) -> Result<CopyOutcome, SvsmError> {
+        let len = page_bytes(size);
+
+        let _pins = match dst {
+            CopyDest::Guest(paddr) if self.flags.contains(CopyFlags::PIN_DEST) => {
+                pin_pages(paddr, size)?
+            }
+            _ => Vec::new(),
+        };
+
+        let src_mapping = match src {
+            CopySource::Guest(paddr) => Some(self.mapper.map(paddr, size)?),
+            _ => None,
+        };
+        let src_ptr: Option<*const u8> = match src {
+            CopySource::Guest(_) => src_mapping.as_ref().map(|m| m.virt_addr().as_ptr()),
+            CopySource::Buffer(buf) => Some(buf.get(..len).ok_or(SvsmError::Mem)?.as_ptr()),
+            CopySource::Zero => None,
+        };
+
+        let dst_mapping = match dst {
+            CopyDest::Guest(paddr) => Some(self.mapper.map(paddr, size)?),
+            CopyDest::Buffer(_) => None,
+        };
+        let dst_ptr: *mut u8 = match dst {
+            CopyDest::Guest(_) => dst_mapping.as_ref().unwrap().virt_addr().as_mut_ptr(),
+            CopyDest::Buffer(buf) => buf.get_mut(..len).ok_or(SvsmError::Mem)?.as_mut_ptr(),
+        };
+
+        let mut zero = true;
+        for off in (0..len).step_by(PAGE_SIZE) {
+            let from = match src_ptr {
+                Some(ptr) => ptr.wrapping_add(off),
+                None => ZERO_PAGE.as_ptr(),
+            };
+            let to = dst_ptr.wrapping_add(off).cast::<[u8; PAGE_SIZE]>();
+            // SAFETY: `from` and `to` point to PAGE_SIZE bytes of either
+            // mapped guest memory or a buffer checked to be large enough.
+            // Faults on guest memory are caught by GuestPtr.
+            unsafe {
+                GuestPtr::from_ptr(from.cast_mut().cast::<[u8; PAGE_SIZE]>()).read_to(&mut *to)?;
+                if self.flags.contains(CopyFlags::DETECT_ZERO) && zero {
+                    zero = is_zeroed(&*to);
+                }
+            }
+        }
+
+        Ok(CopyOutcome {
+            bytes: len,
+            zero: zero && self.flags.contains(CopyFlags::DETECT_ZERO),
+        })
+    }
+
+    /// Copies a batch of pages of the same size and stops at the first
+    /// error. Returns the total number of bytes copied and whether all of
+    /// them were zero.
+    pub fn copy_batch<'s, 'd, I>(&self, batch: I, size: PageSize) -> Result<CopyOutcome, SvsmError>
+    where
+        I: IntoIterator<Item = (CopySource<'s>, CopyDest<'d>)>,
+    {
+        let mut total = CopyOutcome {
+            bytes: 0,
+            zero: self.flags.contains(CopyFlags::DETECT_ZERO),
+        };
+        for (src, dst) in batch {
+            let outcome = self.copy(src, dst, size)?;
+            total.bytes += outcome.bytes;
+            total.zero &= outcome.zero;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Treats guest physical addresses as host addresses.
+    #[derive(Clone, Copy, Debug)]
+    struct IdentityMapper;
+
+    impl MappedPage for VirtAddr {
+        fn virt_addr(&self) -> VirtAddr {
+            *self
+        }
+    }
+
+    impl PageMapper for IdentityMapper {
+        type Mapping = VirtAddr;
+
+        fn map(&self, paddr: PhysAddr, _size: PageSize) -> Result<VirtAddr, SvsmError> {
+            Ok(VirtAddr::from(usize::from(paddr)))
+        }
+    }
+
+    fn guest_addr(buf: &[u8]) -> PhysAddr {
+        PhysAddr::from(buf.as_ptr() as usize)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "inline assembly")]
+    fn test_copy_guest_to_buffer() {
+        let mut guest = alloc::vec![0u8; PAGE_SIZE];
+        let copier = PageCopier::with_mapper(IdentityMapper, CopyFlags::DETECT_ZERO);
+        let mut buf = alloc::vec![0xffu8; PAGE_SIZE];
+
+        let outcome = copier
+            .copy(
+                CopySource::Guest(guest_addr(&guest)),
+                CopyDest::Buffer(&mut buf),
+                PageSize::Regular,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome,
+            CopyOutcome {
+                bytes: PAGE_SIZE,
+                zero: true
+            }
+        );
+        assert!(buf.iter().all(|&b| b == 0));
+
+        guest[100] = 7;
+        let outcome = copier
+            .copy(
+                CopySource::Guest(guest_addr(&guest)),
+                CopyDest::Buffer(&mut buf),
+                PageSize::Regular,
+            )
+            .unwrap();
+        assert!(!outcome.zero);
+        assert_eq!(buf[100], 7);
+    }
+
+    #[test]
+    fn test_is_zeroed() {
+        let mut data = alloc::vec![0u8; PAGE_SIZE + 3];
+        assert!(is_zeroed(&data));
+        assert!(is_zeroed(&data[1..]));
+        for i in [0, 7, 8, 63, 64, PAGE_SIZE - 1, PAGE_SIZE + 2] {
+            data[i] = 1;
+            assert!(!is_zeroed(&data), "non-zero byte {} not found", i);
+            data[i] = 0;
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "inline assembly")]
+    fn test_copy_to_guest() {
+        let guest = alloc::vec![0x55u8; PAGE_SIZE_2M];
+        let paddr = guest_addr(&guest);
+        let copier = PageCopier::with_mapper(IdentityMapper, CopyFlags::empty());
+
+        copier
+            .copy(CopySource::Zero, CopyDest::Guest(paddr), PageSize::Huge)
+            .unwrap();
+        assert!(guest.iter().all(|&b| b == 0));
+
+        let data = alloc::vec![0xaau8; PAGE_SIZE];
+        let outcome = copier
+            .copy_batch(
+                [
+                    (CopySource::Buffer(&data), CopyDest::Guest(paddr)),
+                    (
+                        CopySource::Buffer(&data),
+                        CopyDest::Guest(paddr + PAGE_SIZE),
+                    ),
+                ],
+                PageSize::Regular,
+            )
+            .unwrap();
+        assert_eq!(outcome.bytes, 2 * PAGE_SIZE);
+        assert!(!outcome.zero);
+        assert!(guest[..2 * PAGE_SIZE].iter().all(|&b| b == 0xaa));
+        assert!(guest[2 * PAGE_SIZE..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "inline assembly")]
+    fn test_copy_short_buffer() {
+        let guest = alloc::vec![0u8; PAGE_SIZE];
+        let copier = PageCopier::with_mapper(IdentityMapper, CopyFlags::empty());
+        let data = [0u8; 16];
+
+        let err = copier.copy(
+            CopySource::Buffer(&data),
+            CopyDest::Guest(guest_addr(&guest)),
+            PageSize::Regular,
+        );
+        assert!(matches!(err, Err(SvsmError::Mem)));
+    }
+}
@@ -4,8 +4,14 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::address::{Address, VirtAddr};
+use crate::address::{Address, PhysAddr, VirtAddr};
 use crate::error::SvsmError;
+use crate::mm::pin::pin_page;
+use crate::mm::{valid_phys_address, PerCPUPageMappingGuard};
+use crate::types::PAGE_SIZE;
+
+extern crate alloc;
+use alloc::vec::Vec;
 
 use core::arch::asm;
 use core::mem::{size_of, MaybeUninit};
@@ -182,6 +188,156 @@ unsafe fn do_movsb<T>(src: *const T, dst: *mut T) -> Result<(), SvsmError> {
     }
 }
 
+#[inline]
+unsafe fn do_stosb(dst: *mut u8, val: u8, size: usize) -> Result<(), SvsmError> {
+    let mut rcx: u64;
+
+    asm!("1:cld
+            rep stosb
+          2:
+         .pushsection \"__exception_table\",\"a\"
+         .balign 16
+         .quad (1b)
+         .quad (2b)
+         .popsection",
+            in("al") val,
+            inout("rdi") dst => _,
+            inout("rcx") size => rcx,
+            options(att_syntax, nostack));
+
+    if rcx == 0 {
+        Ok(())
+    } else {
+        Err(SvsmError::InvalidAddress)
+    }
+}
+
+/// Maps the 4K guest page at `paddr` for writing and calls `f` with its
+/// virtual address. The page must be guest memory, and it stays pinned while
+/// it is written, so it cannot be rescinded from the guest in the middle of
+/// the write.
+///
+/// The mapping uses the same encryption attribute as the guest's private
+/// mapping, so no cache flush is needed for the guest to see the new
+/// contents. The per-CPU mapping and its TLB entry are removed again before
+/// returning.
+fn with_phys_page<F>(paddr: PhysAddr, f: F) -> Result<(), SvsmError>
+where
+    F: FnOnce(VirtAddr) -> Result<(), SvsmError>,
+{
+    if !paddr.is_page_aligned() || !valid_phys_address(paddr) {
+        return Err(SvsmError::InvalidAddress);
+    }
+    let _pin = pin_page(paddr)?;
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    f(guard.virt_addr())
+}
+
+/// Copies the 4K guest page at `paddr` into `data` with a single string
+/// copy instead of byte-wise reads.
+///
+/// # Returns
+///
+/// Returns an error if `paddr` is not a page-aligned address of guest
+/// memory, or if the read faults.
+pub fn read_phys_page(paddr: PhysAddr, data: &mut [u8; PAGE_SIZE]) -> Result<(), SvsmError> {
+    with_phys_page(paddr, |vaddr| {
+        // SAFETY: `vaddr` maps the whole guest page, which is not SVSM
+        // memory.
+        unsafe { GuestPtr::<[u8; PAGE_SIZE]>::new(vaddr).read_to(data) }
+    })
+}
+
+/// Overwrites the 4K guest page at `paddr` with `data`.
+///
+/// # Returns
+///
+/// Returns an error if `paddr` is not a page-aligned address of guest
+/// memory, or if the write faults.
+pub fn write_phys_page(paddr: PhysAddr, data: &[u8; PAGE_SIZE]) -> Result<(), SvsmError> {
+    with_phys_page(paddr, |vaddr| {
+        // SAFETY: `vaddr` maps the whole guest page, which is not SVSM
+        // memory.
+        unsafe { GuestPtr::<[u8; PAGE_SIZE]>::new(vaddr).write_ref(data) }
+    })
+}
+
+/// Fills the 4K guest page at `paddr` with `val`.
+///
+/// # Returns
+///
+/// Returns an error if `paddr` is not a page-aligned address of guest
+/// memory, or if the write faults.
+pub fn fill_phys_page(paddr: PhysAddr, val: u8) -> Result<(), SvsmError> {
+    with_phys_page(paddr, |vaddr| {
+        // SAFETY: `vaddr` maps the whole guest page, which is not SVSM
+        // memory.
+        unsafe { do_stosb(vaddr.as_mut_ptr(), val, PAGE_SIZE) }
+    })
+}
+
+/// Maps the `len` bytes of guest memory starting at `paddr` with a single
+/// mapping and calls `f` with its virtual address. The range must consist of
+/// whole pages of guest memory, which all stay pinned while `f` runs. A 2M
+/// aligned range is mapped with 2M pages, otherwise the range has to fit
+/// into the per-CPU 4K mapping window. The TLB is flushed once, when the
+/// mapping is removed.
+fn with_phys_range<F>(paddr: PhysAddr, len: usize, f: F) -> Result<(), SvsmError>
+where
+    F: FnOnce(VirtAddr) -> Result<(), SvsmError>,
+{
+    if !paddr.is_page_aligned() || len == 0 || len % PAGE_SIZE != 0 {
+        return Err(SvsmError::InvalidAddress);
+    }
+    let end = paddr.checked_add(len).ok_or(SvsmError::InvalidAddress)?;
+    let mut pins = Vec::with_capacity(len / PAGE_SIZE);
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        let page = paddr + offset;
+        if !valid_phys_address(page) {
+            return Err(SvsmError::InvalidAddress);
+        }
+        pins.push(pin_page(page)?);
+    }
+    let guard = PerCPUPageMappingGuard::create(paddr, end, 0)?;
+    f(guard.virt_addr())
+}
+
+/// Fills the `len` bytes of guest memory starting at `paddr` with `val`
+/// through a single mapping, see [`with_phys_range`] for the requirements
+/// on the range.
+///
+/// # Returns
+///
+/// Returns an error if the range is not made of page-aligned guest memory,
+/// if it cannot be mapped, or if the write faults.
+pub fn fill_phys_range(paddr: PhysAddr, len: usize, val: u8) -> Result<(), SvsmError> {
+    with_phys_range(paddr, len, |vaddr| {
+        // SAFETY: `vaddr` maps the whole range, which is guest memory and
+        // not SVSM memory.
+        unsafe { do_stosb(vaddr.as_mut_ptr(), val, len) }
+    })
+}
+
+/// Overwrites the contiguous guest pages starting at `paddr` with `pages`
+/// through a single mapping, see [`with_phys_range`] for the requirements
+/// on the range.
+///
+/// # Returns
+///
+/// Returns an error if the range is not made of page-aligned guest memory,
+/// if it cannot be mapped, or if a write faults. Pages before the faulting
+/// one have been written.
+pub fn write_phys_range(paddr: PhysAddr, pages: &[&[u8; PAGE_SIZE]]) -> Result<(), SvsmError> {
+    with_phys_range(paddr, pages.len() * PAGE_SIZE, |vaddr| {
+        for (i, data) in pages.iter().enumerate() {
+            // SAFETY: `vaddr` maps the whole range, which is guest memory
+            // and not SVSM memory.
+            unsafe { GuestPtr::<[u8; PAGE_SIZE]>::new(vaddr + i * PAGE_SIZE).write_ref(data)? }
+        }
+        Ok(())
+    })
+}
+
 #[derive(Debug)]
 pub struct GuestPtr<T: Copy> {
     ptr: *mut T,
@@ -218,6 +374,22 @@ impl<T: Copy> GuestPtr<T> {
         }
     }
 
+    /// Reads the value into `buf` without going through a temporary, which
+    /// keeps large reads (e.g. whole pages) off the stack.
+    ///
+    /// # Safety
+    ///
+    /// The caller must verify not to read arbitrary memory, as this function
+    /// doesn't make any checks in that regard.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the specified address is not mapped.
+    #[inline]
+    pub unsafe fn read_to(&self, buf: &mut T) -> Result<(), SvsmError> {
+        unsafe { do_movsb(self.ptr, buf) }
+    }
+
     /// # Safety
     ///
     /// The caller must verify not to corrupt arbitrary memory, as this function
@@ -311,4 +483,15 @@ mod tests {
         let err = unsafe { ptr.read() };
         assert!(err.is_err());
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "inline assembly")]
+    fn test_do_stosb() {
+        let mut test_buffer: [u8; 8] = [0; 8];
+
+        // SAFETY: the first six bytes of test_buffer are valid for writes.
+        unsafe { do_stosb(test_buffer.as_mut_ptr(), 0x5a, 6).unwrap() };
+
+        assert_eq!(test_buffer, [0x5a, 0x5a, 0x5a, 0x5a, 0x5a, 0x5a, 0, 0]);
+    }
 }
@@ -10,7 +10,9 @@ use crate::address::{Address, PhysAddr};
 use crate::config::SvsmConfig;
 use crate::cpu::percpu::PERCPU_VMSAS;
 use crate::error::SvsmError;
+use crate::fw_protect::fw_protected_regions;
 use crate::locking::RWLock;
+use crate::types::PAGE_SIZE;
 use crate::utils::MemoryRegion;
 use alloc::vec::Vec;
 use bootlib::kernel_launch::KernelLaunchInfo;
@@ -20,6 +22,12 @@ use super::pagetable::LAUNCH_VMSA_ADDR;
 /// Global memory map containing various memory regions.
 static MEMORY_MAP: RWLock<Vec<MemoryRegion<PhysAddr>>> = RWLock::new(Vec::new());
 
+/// Guest physical memory occupied by the SVSM kernel.
+static KERNEL_REGION: RWLock<Option<MemoryRegion<PhysAddr>>> = RWLock::new(None);
+
+/// Start of the shared alias of guest memory, zero if vTOM is not in use.
+static VTOM: RWLock<u64> = RWLock::new(0);
+
 /// Initializes the global memory map based on the provided configuration
 /// and kernel launch information.
 ///
@@ -87,6 +95,8 @@ pub fn init_memory_map(
 
     let mut map = MEMORY_MAP.lock_write();
     *map = regions;
+    *KERNEL_REGION.lock_write() = Some(kernel_region);
+    *VTOM.lock_write() = launch_info.vtom;
 
     Ok(())
 }
@@ -114,6 +124,14 @@ pub fn valid_phys_address(paddr: PhysAddr) -> bool {
         .any(|region| region.contains(paddr))
 }
 
+/// Returns `true` if `paddr` belongs to the memory owned by the SVSM,
+/// otherwise returns `false`.
+pub fn svsm_phys_addr(paddr: PhysAddr) -> bool {
+    KERNEL_REGION
+        .lock_read()
+        .is_some_and(|region| region.contains(paddr))
+}
+
 /// The starting address of the ISA range.
 const ISA_RANGE_START: PhysAddr = PhysAddr::new(0xa0000);
 
@@ -131,6 +149,102 @@ pub fn writable_phys_addr(paddr: PhysAddr) -> bool {
     valid_phys_address(paddr)
 }
 
+/// Kind of a range in the guest physical address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum GuestMemoryKind {
+    /// Private guest memory.
+    Ram = 0,
+    /// Guest firmware, permanently write-protected by the SVSM.
+    Firmware = 1,
+    /// Ranges the guest must not use as memory, like the ISA hole and the
+    /// launch VMSA page.
+    Reserved = 2,
+    /// The shared alias of guest memory above vTOM.
+    Shared = 3,
+    /// Memory owned by the SVSM.
+    Svsm = 4,
+}
+
+/// A range of the guest physical address space.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestMemoryRange {
+    pub region: MemoryRegion<PhysAddr>,
+    pub kind: GuestMemoryKind,
+}
+
+/// Removes the parts of `regions` which overlap with `hole`.
+fn subtract_region(
+    regions: Vec<MemoryRegion<PhysAddr>>,
+    hole: MemoryRegion<PhysAddr>,
+) -> Vec<MemoryRegion<PhysAddr>> {
+    let mut result = Vec::with_capacity(regions.len() + 1);
+    for region in regions {
+        if !region.overlap(&hole) {
+            result.push(region);
+            continue;
+        }
+        if region.start() < hole.start() {
+            result.push(MemoryRegion::from_addresses(region.start(), hole.start()));
+        }
+        if hole.end() < region.end() {
+            result.push(MemoryRegion::from_addresses(hole.end(), region.end()));
+        }
+    }
+    result
+}
+
+/// Returns the SVSM's view of the guest physical address space, sorted by
+/// address. RAM ranges never overlap the other kinds of ranges, so every
+/// RAM range is memory the guest may validate and use.
+pub fn guest_memory_layout() -> Vec<GuestMemoryRange> {
+    let mut special = alloc::vec![
+        GuestMemoryRange {
+            region: MemoryRegion::from_addresses(ISA_RANGE_START, ISA_RANGE_END),
+            kind: GuestMemoryKind::Reserved,
+        },
+        GuestMemoryRange {
+            region: MemoryRegion::new(LAUNCH_VMSA_ADDR, PAGE_SIZE),
+            kind: GuestMemoryKind::Reserved,
+        },
+    ];
+    if let Some(region) = *KERNEL_REGION.lock_read() {
+        special.push(GuestMemoryRange {
+            region,
+            kind: GuestMemoryKind::Svsm,
+        });
+    }
+    for region in fw_protected_regions() {
+        special.push(GuestMemoryRange {
+            region,
+            kind: GuestMemoryKind::Firmware,
+        });
+    }
+
+    let mut ram = MEMORY_MAP.lock_read().clone();
+    for range in special.iter() {
+        ram = subtract_region(ram, range.region);
+    }
+
+    let vtom = *VTOM.lock_read();
+    let mut layout = special;
+    for region in ram {
+        layout.push(GuestMemoryRange {
+            region,
+            kind: GuestMemoryKind::Ram,
+        });
+        if vtom != 0 {
+            let start = region.start() + vtom as usize;
+            layout.push(GuestMemoryRange {
+                region: MemoryRegion::new(start, region.len()),
+                kind: GuestMemoryKind::Shared,
+            });
+        }
+    }
+    layout.sort_unstable_by_key(|range| range.region.start());
+    layout
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,4 +263,29 @@ mod tests {
         // Outside the region
         assert!(!valid_phys_address(PhysAddr::new(0x3000)));
     }
+
+    #[test]
+    fn test_subtract_region() {
+        let region = |start: usize, end: usize| {
+            MemoryRegion::from_addresses(PhysAddr::new(start), PhysAddr::new(end))
+        };
+        let bounds = |regions: Vec<MemoryRegion<PhysAddr>>| {
+            regions
+                .iter()
+                .map(|r| (usize::from(r.start()), usize::from(r.end())))
+                .collect::<Vec<_>>()
+        };
+        let regions = alloc::vec![region(0x0, 0x10000), region(0x20000, 0x30000)];
+
+        // A hole in the middle splits the region.
+        assert_eq!(
+            bounds(subtract_region(regions.clone(), region(0x4000, 0x8000))),
+            [(0x0, 0x4000), (0x8000, 0x10000), (0x20000, 0x30000)]
+        );
+        // A hole covering a whole region removes it.
+        assert_eq!(
+            bounds(subtract_region(regions, region(0x18000, 0x30000))),
+            [(0x0, 0x10000)]
+        );
+    }
 }
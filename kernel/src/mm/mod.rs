@@ -6,12 +6,14 @@
 
 pub mod address_space;
 pub mod alloc;
+pub mod copy;
 pub mod guestmem;
 pub mod mappings;
 pub mod memory;
 pub mod page_visibility;
 mod pagebox;
 pub mod pagetable;
+pub mod pin;
 pub mod ptguards;
 pub mod stack;
 pub mod validate;
@@ -21,7 +23,7 @@ pub mod set;
 
 pub use address_space::*;
 pub use guestmem::GuestPtr;
-pub use memory::{valid_phys_address, writable_phys_addr};
+pub use memory::{svsm_phys_addr, valid_phys_address, writable_phys_addr};
 pub use pagebox::*;
 pub use ptguards::*;
 
@@ -31,4 +33,4 @@ pub use alloc::{allocate_file_page, allocate_file_page_ref, PageRef};
 
 pub use mappings::{mmap_kernel, mmap_user, munmap_kernel, munmap_user, VMMappingGuard};
 
-pub use set::Set;
+pub use set::{PageSet, RangeSet, Set};
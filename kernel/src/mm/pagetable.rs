@@ -5,13 +5,16 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::address::{Address, PhysAddr, VirtAddr};
-use crate::cpu::control_regs::write_cr3;
+use crate::cpu::control_regs::{read_cr4, write_cr3, CR4Flags};
 use crate::cpu::features::{cpu_has_nx, cpu_has_pge};
 use crate::cpu::flush_tlb_global_sync;
 use crate::error::SvsmError;
 use crate::locking::{LockGuard, SpinLock};
 use crate::mm::PageBox;
-use crate::mm::{phys_to_virt, virt_to_phys, PGTABLE_LVL3_IDX_SHARED};
+use crate::mm::{
+    phys_to_virt, virt_to_phys, PGTABLE_LVL3_IDX_SHARED, PGTABLE_LVL4_IDX_SVSM,
+    PGTABLE_LVL4_IDX_USER,
+};
 use crate::platform::SvsmPlatform;
 use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
 use crate::utils::immut_after_init::{ImmutAfterInitCell, ImmutAfterInitResult};
@@ -43,6 +46,9 @@ pub const LAUNCH_VMSA_ADDR: PhysAddr = PhysAddr::new(0xFFFFFFFFF000);
 static FEATURE_MASK: ImmutAfterInitCell<PTEntryFlags> =
     ImmutAfterInitCell::new(PTEntryFlags::empty());
 
+/// Whether paging runs with 5 levels (CR4.LA57), making the root a PML5.
+static LA57_ENABLED: ImmutAfterInitCell<bool> = ImmutAfterInitCell::new(false);
+
 /// Re-initializes early paging settings.
 pub fn paging_init_early(platform: &dyn SvsmPlatform, vtom: u64) -> ImmutAfterInitResult<()> {
     init_encrypt_mask(platform, vtom.try_into().unwrap())?;
@@ -50,7 +56,9 @@ pub fn paging_init_early(platform: &dyn SvsmPlatform, vtom: u64) -> ImmutAfterIn
     let mut feature_mask = PTEntryFlags::all();
     feature_mask.remove(PTEntryFlags::NX);
     feature_mask.remove(PTEntryFlags::GLOBAL);
-    FEATURE_MASK.reinit(&feature_mask)
+    FEATURE_MASK.reinit(&feature_mask)?;
+
+    init_paging_levels()
 }
 
 /// Initializes paging settings.
@@ -64,7 +72,16 @@ pub fn paging_init(platform: &dyn SvsmPlatform, vtom: u64) -> ImmutAfterInitResu
     if !cpu_has_pge() {
         feature_mask.remove(PTEntryFlags::GLOBAL);
     }
-    FEATURE_MASK.reinit(&feature_mask)
+    FEATURE_MASK.reinit(&feature_mask)?;
+
+    init_paging_levels()
+}
+
+/// Records whether the CPU was put into 5-level paging mode. CR4.LA57
+/// cannot change while long mode is active, so stage2 decides it once and
+/// everything afterwards inherits it.
+fn init_paging_levels() -> ImmutAfterInitResult<()> {
+    LA57_ENABLED.reinit(&read_cr4().contains(CR4Flags::LA57))
 }
 
 /// Initializes the encrypt mask.
@@ -194,6 +211,12 @@ impl PTEntry {
         self.0.bits() as u64
     }
 
+    /// Create a page table entry from its raw bits, e.g. read from a guest
+    /// page table.
+    pub fn from_raw(raw: u64) -> Self {
+        Self(PhysAddr::from(raw))
+    }
+
     /// Get the flags of the page table entry.
     pub fn flags(&self) -> PTEntryFlags {
         PTEntryFlags::from_bits_truncate(self.0.bits() as u64)
@@ -282,6 +305,7 @@ impl IndexMut<usize> for PTPage {
 /// Mapping levels of page table entries.
 #[derive(Debug)]
 pub enum Mapping<'a> {
+    Level4(&'a mut PTEntry),
     Level3(&'a mut PTEntry),
     Level2(&'a mut PTEntry),
     Level1(&'a mut PTEntry),
@@ -289,6 +313,12 @@ pub enum Mapping<'a> {
 }
 
 /// Page table structure containing a root page with multiple entries.
+///
+/// With 4-level paging the root page is the PML4. With 5-level paging it is
+/// a PML5 and every page table owns the two PML4 pages below it: one for
+/// the user half and one for the SVSM half of the address space. The
+/// SVSM layout is defined in terms of PML4 indices and is placed in the
+/// SVSM half unchanged, see [`PGTABLE_LVL4_IDX_SVSM`].
 #[repr(C)]
 #[derive(Default, Debug)]
 pub struct PageTable {
@@ -314,13 +344,73 @@ impl PageTable {
     /// Returns [`SvsmError`] if the page cannot be allocated.
     pub fn clone_shared(&self) -> Result<PageTableRef, SvsmError> {
         let mut pgtable = PageTableRef::alloc()?;
-        pgtable.root.entries[PGTABLE_LVL3_IDX_SHARED] = self.root.entries[PGTABLE_LVL3_IDX_SHARED];
+        if *LA57_ENABLED {
+            pgtable.alloc_lvl3_table(PGTABLE_LVL4_IDX_USER)?;
+            pgtable.alloc_lvl3_table(PGTABLE_LVL4_IDX_SVSM)?;
+        }
+        pgtable.copy_entry(self, PGTABLE_LVL3_IDX_SHARED);
         Ok(pgtable)
     }
 
-    /// Copy an entry `entry` from another [`PageTable`].
+    /// Copy an entry `entry` of the 4-level layout from another
+    /// [`PageTable`].
+    ///
+    /// # Panics
+    /// Panics if `other` has a mapping at `entry` but the level 3 table
+    /// holding it is missing in this page table.
     pub fn copy_entry(&mut self, other: &Self, entry: usize) {
-        self.root.entries[entry] = other.root.entries[entry];
+        let Some(src) = other.lvl3_table(entry) else {
+            return;
+        };
+        let dst = self.lvl3_table_mut(entry).expect("Missing level 3 table");
+        dst.entries[entry] = src.entries[entry];
+    }
+
+    /// Returns the PML5 index of the half of the address space that holds
+    /// the level 3 index `idx`.
+    fn lvl4_index(idx: usize) -> usize {
+        if idx < ENTRY_COUNT / 2 {
+            PGTABLE_LVL4_IDX_USER
+        } else {
+            PGTABLE_LVL4_IDX_SVSM
+        }
+    }
+
+    /// Returns the level 3 table (PML4) holding the level 3 index `idx`, or
+    /// `None` if it has not been allocated yet.
+    fn lvl3_table(&self, idx: usize) -> Option<&PTPage> {
+        if !*LA57_ENABLED {
+            return Some(&self.root);
+        }
+        PTPage::from_entry(self.root[Self::lvl4_index(idx)]).map(|page| &*page)
+    }
+
+    /// Mutable variant of [`PageTable::lvl3_table`].
+    fn lvl3_table_mut(&mut self, idx: usize) -> Option<&mut PTPage> {
+        if !*LA57_ENABLED {
+            return Some(&mut self.root);
+        }
+        PTPage::from_entry(self.root[Self::lvl4_index(idx)])
+    }
+
+    /// Allocates the level 3 table (PML4) holding the level 3 index `idx`
+    /// if it is not present yet. Does nothing with 4-level paging.
+    ///
+    /// # Errors
+    /// Returns [`SvsmError`] if the page cannot be allocated.
+    fn alloc_lvl3_table(&mut self, idx: usize) -> Result<(), SvsmError> {
+        let entry = &mut self.root[Self::lvl4_index(idx)];
+        if !*LA57_ENABLED || entry.present() {
+            return Ok(());
+        }
+
+        let (_, paddr) = PTPage::alloc()?;
+        let flags = PTEntryFlags::PRESENT
+            | PTEntryFlags::WRITABLE
+            | PTEntryFlags::USER
+            | PTEntryFlags::ACCESSED;
+        entry.set(paddr, flags);
+        Ok(())
     }
 
     /// Computes the index within a page table at the given level for a
@@ -383,7 +473,7 @@ impl PageTable {
         }
     }
 
-    /// Walks the page table to find a mapping for a given virtual address.
+    /// Walks a page table at level 3 to find a mapping.
     ///
     /// # Parameters
     /// - `page`: A mutable reference to the root page table.
@@ -400,6 +490,24 @@ impl PageTable {
         }
     }
 
+    /// Walks a page table at level 4 to find a mapping. Only used with
+    /// 5-level paging.
+    ///
+    /// # Parameters
+    /// - `page`: A mutable reference to the root page table.
+    /// - `vaddr`: The virtual address to find a mapping for.
+    ///
+    /// # Returns
+    /// A `Mapping` representing the found mapping.
+    fn walk_addr_lvl4(page: &mut PTPage, vaddr: VirtAddr) -> Mapping<'_> {
+        let idx = Self::index::<4>(vaddr);
+        let entry = page[idx];
+        match PTPage::from_entry(entry) {
+            Some(page) => Self::walk_addr_lvl3(page, vaddr),
+            None => Mapping::Level4(&mut page[idx]),
+        }
+    }
+
     /// Walk the virtual address and return the corresponding mapping.
     ///
     /// # Parameters
@@ -408,7 +516,32 @@ impl PageTable {
     /// # Returns
     /// A `Mapping` representing the found mapping.
     fn walk_addr(&mut self, vaddr: VirtAddr) -> Mapping<'_> {
-        Self::walk_addr_lvl3(&mut self.root, vaddr)
+        if *LA57_ENABLED {
+            Self::walk_addr_lvl4(&mut self.root, vaddr)
+        } else {
+            Self::walk_addr_lvl3(&mut self.root, vaddr)
+        }
+    }
+
+    fn alloc_pte_lvl4(entry: &mut PTEntry, vaddr: VirtAddr, size: PageSize) -> Mapping<'_> {
+        let flags = entry.flags();
+
+        if flags.contains(PTEntryFlags::PRESENT) {
+            return Mapping::Level4(entry);
+        }
+
+        let Ok((page, paddr)) = PTPage::alloc() else {
+            return Mapping::Level4(entry);
+        };
+
+        let flags = PTEntryFlags::PRESENT
+            | PTEntryFlags::WRITABLE
+            | PTEntryFlags::USER
+            | PTEntryFlags::ACCESSED;
+        entry.set(paddr, flags);
+
+        let idx = Self::index::<3>(vaddr);
+        Self::alloc_pte_lvl3(&mut page[idx], vaddr, size)
     }
 
     fn alloc_pte_lvl3(entry: &mut PTEntry, vaddr: VirtAddr, size: PageSize) -> Mapping<'_> {
@@ -489,6 +622,7 @@ impl PageTable {
             Mapping::Level1(entry) => Self::alloc_pte_lvl1(entry, vaddr, PageSize::Regular),
             Mapping::Level2(entry) => Self::alloc_pte_lvl2(entry, vaddr, PageSize::Regular),
             Mapping::Level3(entry) => Self::alloc_pte_lvl3(entry, vaddr, PageSize::Regular),
+            Mapping::Level4(entry) => Self::alloc_pte_lvl4(entry, vaddr, PageSize::Regular),
         }
     }
 
@@ -507,6 +641,7 @@ impl PageTable {
             Mapping::Level1(entry) => Mapping::Level1(entry),
             Mapping::Level2(entry) => Self::alloc_pte_lvl2(entry, vaddr, PageSize::Huge),
             Mapping::Level3(entry) => Self::alloc_pte_lvl3(entry, vaddr, PageSize::Huge),
+            Mapping::Level4(entry) => Self::alloc_pte_lvl4(entry, vaddr, PageSize::Huge),
         }
     }
 
@@ -554,6 +689,7 @@ impl PageTable {
             Mapping::Level1(entry) => Self::do_split_4k(entry),
             Mapping::Level2(_entry) => Err(SvsmError::Mem),
             Mapping::Level3(_entry) => Err(SvsmError::Mem),
+            Mapping::Level4(_entry) => Err(SvsmError::Mem),
         }
     }
 
@@ -670,6 +806,7 @@ impl PageTable {
             Mapping::Level1(entry) => entry.clear(),
             Mapping::Level2(entry) => assert!(!entry.present()),
             Mapping::Level3(entry) => assert!(!entry.present()),
+            Mapping::Level4(entry) => assert!(!entry.present()),
         }
     }
 
@@ -710,6 +847,7 @@ impl PageTable {
             Mapping::Level1(entry) => assert!(!entry.present()),
             Mapping::Level2(entry) => assert!(!entry.present()),
             Mapping::Level3(entry) => assert!(!entry.present()),
+            Mapping::Level4(entry) => assert!(!entry.present()),
         }
     }
 
@@ -744,6 +882,7 @@ impl PageTable {
             }
             Mapping::Level2(_entry) => Err(SvsmError::Mem),
             Mapping::Level3(_entry) => Err(SvsmError::Mem),
+            Mapping::Level4(_entry) => Err(SvsmError::Mem),
         }
     }
 
@@ -873,6 +1012,11 @@ impl PageTable {
 
     /// Populates this paghe table with the contents of the given subtree
     /// in `part`.
+    ///
+    /// # Panics
+    /// Panics if the level 3 table the part belongs to is missing, which
+    /// can only happen with 5-level paging for page tables not created by
+    /// [`PageTable::clone_shared`].
     pub fn populate_pgtbl_part(&mut self, part: &PageTablePart) {
         if let Some(paddr) = part.address() {
             let idx = part.index();
@@ -880,7 +1024,8 @@ impl PageTable {
                 | PTEntryFlags::WRITABLE
                 | PTEntryFlags::USER
                 | PTEntryFlags::ACCESSED;
-            let entry = &mut self.root[idx];
+            let table = self.lvl3_table_mut(idx).expect("Missing level 3 table");
+            let entry = &mut table[idx];
             // The C bit is not required here because all page table fetches are
             // made as C=1.
             entry.set(paddr, flags);
@@ -888,6 +1033,25 @@ impl PageTable {
     }
 }
 
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        if !*LA57_ENABLED {
+            return;
+        }
+        // With 5-level paging the PML4 pages below the root belong to this
+        // page table. Everything they point to is owned by the shared
+        // mappings or by the [`PageTablePart`]s populated into them.
+        for entry in self.root.entries {
+            if let Some(page) = PTPage::from_entry(entry) {
+                // SAFETY: the PML4 pages were allocated with
+                // `PTPage::alloc()` by this page table and are not
+                // referenced by any other page table.
+                unsafe { PTPage::free(page) };
+            }
+        }
+    }
+}
+
 static INIT_PGTABLE: SpinLock<PageTableRef> = SpinLock::new(PageTableRef::unset());
 
 /// Sets the initial page table unless it is already set.
@@ -1046,7 +1210,9 @@ impl RawPageTablePart {
             Mapping::Level0(entry) => Mapping::Level0(entry),
             Mapping::Level1(entry) => PageTable::alloc_pte_lvl1(entry, vaddr, PageSize::Regular),
             Mapping::Level2(entry) => PageTable::alloc_pte_lvl2(entry, vaddr, PageSize::Regular),
-            Mapping::Level3(_) => panic!("PT level 3 not possible in PageTablePart"),
+            Mapping::Level3(_) | Mapping::Level4(_) => {
+                panic!("PT level 3 not possible in PageTablePart")
+            }
         }
     }
 
@@ -1066,6 +1232,7 @@ impl RawPageTablePart {
             Mapping::Level1(entry) => Mapping::Level1(entry),
             Mapping::Level2(entry) => PageTable::alloc_pte_lvl2(entry, vaddr, PageSize::Huge),
             Mapping::Level3(entry) => PageTable::alloc_pte_lvl3(entry, vaddr, PageSize::Huge),
+            Mapping::Level4(_) => panic!("PT level 4 not possible in PageTablePart"),
         }
     }
 
@@ -1126,7 +1293,7 @@ impl RawPageTablePart {
                 assert!(!entry.present());
                 None
             }
-            Mapping::Level3(entry) => {
+            Mapping::Level3(entry) | Mapping::Level4(entry) => {
                 assert!(!entry.present());
                 None
             }
@@ -1198,7 +1365,7 @@ impl RawPageTablePart {
                 assert!(!entry.present());
                 None
             }
-            Mapping::Level3(entry) => {
+            Mapping::Level3(entry) | Mapping::Level4(entry) => {
                 assert!(!entry.present());
                 None
             }
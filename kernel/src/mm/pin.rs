@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Pinning of guest physical pages.
+//!
+//! A pinned page must not be remapped, invalidated or otherwise have its
+//! backing changed while the SVSM copies data from or to it. Paths that copy
+//! guest memory (e.g. restore) pin the page for the duration of their copy
+//! window, while paths that change the backing of guest memory check for
+//! pins and back off with [`SvsmError::PagePinned`]. Those paths check with
+//! [`lock_page_not_pinned`] and hold the returned guard until the change is
+//! done, which keeps new pins out in the meantime.
+//!
+//! Long-lived pins of many pages, e.g. of all pages covered by a backup,
+//! pin a whole [`PageSet`] instead of counting every page. The pin covers
+//! the pages in the set at the time of the check.
+
+extern crate alloc;
+
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::invariant::{invariant_violated, InvariantCode};
+use crate::locking::{RWLock, ReadLockGuard, SpinLock};
+use crate::mm::set::PageSet;
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Pin counts, keyed by the 4K-aligned physical page address.
+static PINNED_PAGES: SpinLock<BTreeMap<PhysAddr, usize>> = SpinLock::new(BTreeMap::new());
+
+/// Page sets pinned as a whole.
+static PINNED_SETS: RWLock<Vec<&'static PageSet>> = RWLock::new(Vec::new());
+
+/// Held for read while pages are remapped after a pin check, and for write
+/// while adding pins, so no pin appears between the check and the remap.
+static REMAP_LOCK: RWLock<()> = RWLock::new(());
+
+/// Number of times a remapping attempt hit a pinned page.
+static PIN_CONTENTION: AtomicU64 = AtomicU64::new(0);
+
+/// Diagnostic counters of the pinning subsystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PinStats {
+    /// Number of currently pinned pages.
+    pub pinned: usize,
+    /// Number of currently pinned page sets.
+    pub pinned_sets: usize,
+    /// Number of remapping attempts rejected because of a pin.
+    pub contended: u64,
+}
+
+/// Keeps new pins out until dropped, see [`lock_page_not_pinned`].
+#[derive(Debug)]
+#[must_use = "if unused new pins are allowed right away"]
+pub struct RemapGuard {
+    _lock: ReadLockGuard<'static, ()>,
+}
+
+/// A pin on a single 4K guest page. The page is unpinned when the guard is
+/// dropped.
+#[derive(Debug)]
+#[must_use = "if unused the page will immediately be unpinned"]
+pub struct PinnedPage {
+    paddr: PhysAddr,
+}
+
+impl PinnedPage {
+    /// Returns the physical address of the pinned page.
+    pub fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        let mut pins = PINNED_PAGES.lock();
+        let Some(count) = pins.get_mut(&self.paddr) else {
+            invariant_violated(
+                InvariantCode::UnpinUnpinned,
+                "PINNED_PAGES.contains_key(&self.paddr)",
+                file!(),
+                line!(),
+            );
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&self.paddr);
+        }
+    }
+}
+
+/// Pins the 4K page at `paddr`. Pins are counted, so a page can be pinned
+/// by several users at the same time.
+pub fn pin_page(paddr: PhysAddr) -> Result<PinnedPage, SvsmError> {
+    if !paddr.is_page_aligned() {
+        return Err(SvsmError::InvalidAddress);
+    }
+    let _remap = REMAP_LOCK.lock_write();
+    *PINNED_PAGES.lock().entry(paddr).or_insert(0) += 1;
+    Ok(PinnedPage { paddr })
+}
+
+/// Pins all 4K pages of the page of the given size at `paddr`.
+pub fn pin_pages(paddr: PhysAddr, size: PageSize) -> Result<Vec<PinnedPage>, SvsmError> {
+    let len = match size {
+        PageSize::Regular => PAGE_SIZE,
+        PageSize::Huge => PAGE_SIZE_2M,
+    };
+    (0..len)
+        .step_by(PAGE_SIZE)
+        .map(|off| pin_page(paddr + off))
+        .collect()
+}
+
+/// Pins all pages in `set` until [`unpin_page_set`] is called. Pinning a
+/// set which is already pinned has no effect.
+pub fn pin_page_set(set: &'static PageSet) {
+    let _remap = REMAP_LOCK.lock_write();
+    let mut sets = PINNED_SETS.lock_write();
+    if !sets.iter().any(|pinned| core::ptr::eq(*pinned, set)) {
+        sets.push(set);
+    }
+}
+
+/// Drops the pin of `set`, if any.
+pub fn unpin_page_set(set: &'static PageSet) {
+    PINNED_SETS
+        .lock_write()
+        .retain(|pinned| !core::ptr::eq(*pinned, set));
+}
+
+/// Returns whether the page of the given size at `paddr` overlaps a page in
+/// `set`, of either size.
+fn set_overlaps(set: &PageSet, paddr: PhysAddr, size: PageSize) -> bool {
+    if set.contains_addr(paddr.page_align_2m(), PageSize::Huge) {
+        return true;
+    }
+    match size {
+        PageSize::Regular => set.contains_addr(paddr, PageSize::Regular),
+        PageSize::Huge => !set.range_addresses(paddr, paddr + PAGE_SIZE_2M).is_empty(),
+    }
+}
+
+/// Returns whether any 4K page of the page of the given size at `paddr` is
+/// pinned.
+pub fn page_pinned(paddr: PhysAddr, size: PageSize) -> bool {
+    let len = match size {
+        PageSize::Regular => PAGE_SIZE,
+        PageSize::Huge => PAGE_SIZE_2M,
+    };
+    let start = paddr.page_align();
+    let pinned = PINNED_PAGES
+        .lock()
+        .range(start..start + len)
+        .next()
+        .is_some();
+    pinned
+        || PINNED_SETS
+            .lock_read()
+            .iter()
+            .any(|set| set_overlaps(set, start, size))
+}
+
+/// Checks that the page of the given size at `paddr` can be remapped.
+/// Records the contention and returns [`SvsmError::PagePinned`] if it is
+/// pinned.
+pub fn check_page_not_pinned(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmError> {
+    if page_pinned(paddr, size) {
+        let contended = PIN_CONTENTION.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!(
+            "Page {:#x} ({:?}) is pinned, rejecting remap ({} contended)",
+            paddr,
+            size,
+            contended
+        );
+        return Err(SvsmError::PagePinned);
+    }
+    Ok(())
+}
+
+/// Checks that the page of the given size at `paddr` can be remapped like
+/// [`check_page_not_pinned`], and keeps it from being pinned until the
+/// returned guard is dropped. The guard must not be held while pinning.
+pub fn lock_page_not_pinned(paddr: PhysAddr, size: PageSize) -> Result<RemapGuard, SvsmError> {
+    let lock = REMAP_LOCK.lock_read();
+    check_page_not_pinned(paddr, size)?;
+    Ok(RemapGuard { _lock: lock })
+}
+
+/// Returns diagnostic counters of the pinning subsystem.
+pub fn pin_stats() -> PinStats {
+    PinStats {
+        pinned: PINNED_PAGES.lock().len(),
+        pinned_sets: PINNED_SETS.lock_read().len(),
+        contended: PIN_CONTENTION.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_unpin() {
+        let paddr = PhysAddr::from(0x7654_3000u64);
+        assert!(!page_pinned(paddr, PageSize::Regular));
+
+        let pin1 = pin_page(paddr).unwrap();
+        let pin2 = pin_page(paddr).unwrap();
+        assert_eq!(pin1.paddr(), paddr);
+        assert!(page_pinned(paddr, PageSize::Regular));
+        assert!(page_pinned(paddr.page_align_2m(), PageSize::Huge));
+        assert!(check_page_not_pinned(paddr, PageSize::Regular).is_err());
+        assert!(lock_page_not_pinned(paddr, PageSize::Regular).is_err());
+
+        drop(pin1);
+        assert!(page_pinned(paddr, PageSize::Regular));
+        drop(pin2);
+        assert!(!page_pinned(paddr, PageSize::Regular));
+        assert!(check_page_not_pinned(paddr, PageSize::Regular).is_ok());
+        let guard = lock_page_not_pinned(paddr, PageSize::Regular).unwrap();
+        drop(guard);
+        let pins = pin_pages(paddr.page_align_2m(), PageSize::Huge).unwrap();
+        assert_eq!(pins.len(), PAGE_SIZE_2M / PAGE_SIZE);
+        assert!(page_pinned(paddr, PageSize::Regular));
+        drop(pins);
+        assert!(!page_pinned(paddr, PageSize::Regular));
+    }
+
+    #[test]
+    fn test_pin_page_set() {
+        static SET: PageSet = PageSet::new();
+        let huge = PhysAddr::from(0x8_0020_0000u64);
+        let small = PhysAddr::from(0x8_0060_3000u64);
+        SET.insert_addr(huge, PageSize::Huge);
+        SET.insert_addr(small, PageSize::Regular);
+
+        pin_page_set(&SET);
+        pin_page_set(&SET);
+        assert!(page_pinned(huge + 0x5000, PageSize::Regular));
+        assert!(page_pinned(small, PageSize::Regular));
+        assert!(page_pinned(small.page_align_2m(), PageSize::Huge));
+        assert!(!page_pinned(small + PAGE_SIZE, PageSize::Regular));
+        assert!(!page_pinned(huge + PAGE_SIZE_2M, PageSize::Huge));
+
+        unpin_page_set(&SET);
+        assert!(!page_pinned(huge, PageSize::Huge));
+        assert!(!page_pinned(small, PageSize::Regular));
+    }
+
+    #[test]
+    fn test_pin_unaligned() {
+        assert!(pin_page(PhysAddr::from(0x1234u64)).is_err());
+    }
+}
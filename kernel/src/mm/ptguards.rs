@@ -6,13 +6,14 @@
 
 use super::pagetable::PTEntryFlags;
 use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::checked_invariant;
 use crate::cpu::percpu::this_cpu;
 use crate::cpu::tlb::flush_address_sync;
 use crate::error::SvsmError;
 use crate::mm::virtualrange::{
     virt_alloc_range_2m, virt_alloc_range_4k, virt_free_range_2m, virt_free_range_4k,
 };
-use crate::types::{PAGE_SIZE, PAGE_SIZE_2M};
+use crate::types::{PageSize, PAGE_SHIFT, PAGE_SHIFT_2M, PAGE_SIZE, PAGE_SIZE_2M};
 
 use crate::utils::MemoryRegion;
 
@@ -37,26 +38,64 @@ impl PerCPUPageMappingGuard {
     /// # Returns
     ///
     /// A `Result` containing the [`PerCPUPageMappingGuard`] if successful,
-    /// or an `SvsmError` if an error occurs.
-    ///
-    /// # Panics
-    ///
-    /// Panics if either `paddr_start`, the size, or `paddr_end`, are not
-    /// aligned.
+    /// or an `SvsmError` if an error occurs. `SvsmError::InvalidAddress` is
+    /// returned if either `paddr_start`, the size, or `paddr_end`, are not
+    /// aligned, or if the range is empty.
     pub fn create(
         paddr_start: PhysAddr,
         paddr_end: PhysAddr,
         alignment: usize,
     ) -> Result<Self, SvsmError> {
+        checked_invariant!(alignment <= PAGE_SHIFT_2M - PAGE_SHIFT, MappingAlignment);
         let align_mask = (PAGE_SIZE << alignment) - 1;
-        let size = paddr_end - paddr_start;
-        assert!((size & align_mask) == 0);
-        assert!((paddr_start.bits() & align_mask) == 0);
-        assert!((paddr_end.bits() & align_mask) == 0);
+        if paddr_end <= paddr_start
+            || (paddr_start.bits() & align_mask) != 0
+            || (paddr_end.bits() & align_mask) != 0
+        {
+            return Err(SvsmError::InvalidAddress);
+        }
 
-        let flags = PTEntryFlags::data();
         let huge = ((paddr_start.bits() & (PAGE_SIZE_2M - 1)) == 0)
             && ((paddr_end.bits() & (PAGE_SIZE_2M - 1)) == 0);
+        Self::map(paddr_start, paddr_end, huge)
+    }
+
+    /// Creates a new [`PerCPUPageMappingGuard`] for the specified physical
+    /// address range, using mappings of exactly the given page size instead
+    /// of deriving it from the alignment of the range.
+    ///
+    /// # Arguments
+    ///
+    /// * `paddr_start` - The starting physical address of the range.
+    /// * `paddr_end` - The ending physical address of the range.
+    /// * `page_size` - The page size of the mappings to use.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`PerCPUPageMappingGuard`] if successful,
+    /// or `SvsmError::InvalidAddress` if the range is empty or not aligned
+    /// to `page_size`.
+    pub fn create_with_page_size(
+        paddr_start: PhysAddr,
+        paddr_end: PhysAddr,
+        page_size: PageSize,
+    ) -> Result<Self, SvsmError> {
+        let (align, huge) = match page_size {
+            PageSize::Regular => (PAGE_SIZE, false),
+            PageSize::Huge => (PAGE_SIZE_2M, true),
+        };
+        if paddr_end <= paddr_start
+            || !paddr_start.is_aligned(align)
+            || !paddr_end.is_aligned(align)
+        {
+            return Err(SvsmError::InvalidAddress);
+        }
+        Self::map(paddr_start, paddr_end, huge)
+    }
+
+    fn map(paddr_start: PhysAddr, paddr_end: PhysAddr, huge: bool) -> Result<Self, SvsmError> {
+        let size = paddr_end - paddr_start;
+        let flags = PTEntryFlags::data();
         let raw_mapping = if huge {
             let region = virt_alloc_range_2m(size, 0)?;
             if let Err(e) = this_cpu()
@@ -88,7 +127,13 @@ impl PerCPUPageMappingGuard {
     /// Creates a new [`PerCPUPageMappingGuard`] for a 4KB page at the
     /// specified physical address, or an `SvsmError` if an error occurs.
     pub fn create_4k(paddr: PhysAddr) -> Result<Self, SvsmError> {
-        Self::create(paddr, paddr + PAGE_SIZE, 0)
+        Self::create_with_page_size(paddr, paddr + PAGE_SIZE, PageSize::Regular)
+    }
+
+    /// Creates a new [`PerCPUPageMappingGuard`] for a 2MB page at the
+    /// specified physical address, or an `SvsmError` if an error occurs.
+    pub fn create_2m(paddr: PhysAddr) -> Result<Self, SvsmError> {
+        Self::create_with_page_size(paddr, paddr + PAGE_SIZE_2M, PageSize::Huge)
     }
 
     /// Returns the virtual address associated with the guard.
@@ -1,45 +1,418 @@
 extern crate alloc;
-use alloc::collections::BTreeSet;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::cmp::max;
+use core::ops::RangeBounds;
 use crate::locking::SpinLock;
-use crate::address::PhysAddr;
-use crate::types::PageSize;
+use crate::address::{Address, PhysAddr};
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
 
+/// A lock-protected ordered set of values.
 #[derive(Debug)]
-pub struct Set {
-    set: SpinLock<BTreeSet<(PhysAddr, PageSize)>>
+pub struct Set<T> {
+    set: SpinLock<BTreeSet<T>>
 }
 
-impl Set {
+impl<T: Ord + Clone> Set<T> {
     pub const fn new() -> Self {
         Self {
             set: SpinLock::new(BTreeSet::new())
         }
     }
-    
-    pub fn insert_addr(&self, value: PhysAddr, size: PageSize) {
-        log::info!("Inserting address {:#x} with size {:?}", value, size);
+
+    pub fn insert(&self, value: T) -> bool {
         let mut guard = self.set.lock();
-        guard.insert((value, size));
+        guard.insert(value)
     }
 
-    pub fn remove_addr(&self, value: PhysAddr, size: PageSize) -> bool {
+    pub fn remove(&self, value: &T) -> bool {
         let mut guard = self.set.lock();
-        guard.remove(&(value, size))
+        guard.remove(value)
     }
 
-    pub fn contains_addr(&self, value: PhysAddr, size: PageSize) -> bool {
+    pub fn contains(&self, value: &T) -> bool {
         let guard = self.set.lock();
-        guard.contains(&(value, size))
+        guard.contains(value)
     }
 
-    pub fn iter_addresses(&self) -> impl Iterator<Item = (PhysAddr, PageSize)> {
+    /// Returns a snapshot of all values, in order.
+    pub fn iter(&self) -> impl Iterator<Item = T> {
         let guard = self.set.lock();
         let cloned_set = guard.clone();
         cloned_set.into_iter()
     }
 
+    /// Returns the values within `range`, in order. Only the matching values
+    /// are copied out of the set.
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Vec<T> {
+        let guard = self.set.lock();
+        guard.range(range).cloned().collect()
+    }
+
+    /// Returns up to `count` of the smallest values, in order.
+    pub fn first(&self, count: usize) -> Vec<T> {
+        let guard = self.set.lock();
+        guard.iter().take(count).cloned().collect()
+    }
+
+    /// Calls `f` for every value within `range` while holding the lock.
+    pub fn for_each_in_range<R, F>(&self, range: R, mut f: F)
+    where
+        R: RangeBounds<T>,
+        F: FnMut(&T),
+    {
+        let guard = self.set.lock();
+        for value in guard.range(range) {
+            f(value);
+        }
+    }
+
     pub fn size(&self) -> usize {
         let guard = self.set.lock();
         guard.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut guard = self.set.lock();
+        guard.clear();
+    }
+}
+
+impl<T: Ord + Clone> Default for Set<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A lock-protected set of address ranges. Overlapping and adjacent ranges
+/// are coalesced, so a contiguous region takes a single entry however large
+/// it is.
+#[derive(Debug)]
+pub struct RangeSet {
+    /// Exclusive end of every range, by start address.
+    ranges: SpinLock<BTreeMap<PhysAddr, PhysAddr>>,
+}
+
+impl RangeSet {
+    pub const fn new() -> Self {
+        Self {
+            ranges: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds `[start, end)` to the set.
+    pub fn insert_range(&self, start: PhysAddr, end: PhysAddr) {
+        if end <= start {
+            return;
+        }
+        let mut ranges = self.ranges.lock();
+        let (mut start, mut end) = (start, end);
+        if let Some((&s, &e)) = ranges.range(..=start).next_back() {
+            if e >= start {
+                start = s;
+            }
+        }
+        while let Some((&s, &e)) = ranges.range(start..=end).next() {
+            ranges.remove(&s);
+            end = max(end, e);
+        }
+        ranges.insert(start, end);
+    }
+
+    /// Removes `[start, end)` from the set, splitting ranges which reach
+    /// beyond it. Returns whether any of it was in the set.
+    pub fn remove_range(&self, start: PhysAddr, end: PhysAddr) -> bool {
+        if end <= start {
+            return false;
+        }
+        let mut ranges = self.ranges.lock();
+        let mut removed = false;
+        if let Some((&s, &e)) = ranges.range(..start).next_back() {
+            if e > start {
+                ranges.insert(s, start);
+                if e > end {
+                    ranges.insert(end, e);
+                }
+                removed = true;
+            }
+        }
+        while let Some((&s, &e)) = ranges.range(start..end).next() {
+            ranges.remove(&s);
+            if e > end {
+                ranges.insert(end, e);
+            }
+            removed = true;
+        }
+        removed
+    }
+
+    /// Returns whether `paddr` lies in a range of the set.
+    pub fn contains(&self, paddr: PhysAddr) -> bool {
+        let ranges = self.ranges.lock();
+        ranges
+            .range(..=paddr)
+            .next_back()
+            .is_some_and(|(_, &end)| paddr < end)
+    }
+
+    /// Returns the lowest address of the set at or above `paddr`.
+    fn next_addr(&self, paddr: PhysAddr) -> Option<PhysAddr> {
+        let ranges = self.ranges.lock();
+        match ranges.range(..=paddr).next_back() {
+            Some((_, &end)) if paddr < end => Some(paddr),
+            _ => ranges.range(paddr..).next().map(|(&start, _)| start),
+        }
+    }
+
+    /// Returns the ranges of the set as `(start, end)`, in order. The lock
+    /// is only taken to find the next range, so the set may change while
+    /// it is iterated.
+    pub fn iter_ranges(&self) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + '_ {
+        let mut next = PhysAddr::null();
+        core::iter::from_fn(move || {
+            let ranges = self.ranges.lock();
+            let (&start, &end) = ranges.range(next..).next()?;
+            next = end;
+            Some((start, end))
+        })
+    }
+
+    /// Returns the number of bytes in the set.
+    pub fn bytes(&self) -> usize {
+        let ranges = self.ranges.lock();
+        ranges
+            .iter()
+            .map(|(start, end)| end.bits() - start.bits())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.lock().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.ranges.lock().clear();
+    }
+}
+
+impl Default for RangeSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A set of guest pages, ordered by their physical address first. The
+/// pages of each size are kept as ranges, so registering gigabytes of 4K
+/// pages takes one entry per contiguous region.
+#[derive(Debug, Default)]
+pub struct PageSet {
+    regular: RangeSet,
+    huge: RangeSet,
+}
+
+impl PageSet {
+    pub const fn new() -> Self {
+        Self {
+            regular: RangeSet::new(),
+            huge: RangeSet::new(),
+        }
+    }
+
+    fn ranges(&self, size: PageSize) -> &RangeSet {
+        match size {
+            PageSize::Regular => &self.regular,
+            PageSize::Huge => &self.huge,
+        }
+    }
+
+    /// Adds the pages of `size` in `[start, end)`, which must be aligned to
+    /// `size`.
+    pub fn insert_range(&self, start: PhysAddr, end: PhysAddr, size: PageSize) {
+        self.ranges(size).insert_range(start, end);
+    }
+
+    /// Removes the pages of `size` in `[start, end)`. Returns whether any of
+    /// them was in the set.
+    pub fn remove_range(&self, start: PhysAddr, end: PhysAddr, size: PageSize) -> bool {
+        self.ranges(size).remove_range(start, end)
+    }
+
+    /// Returns the ranges of pages of `size` as `(start, end)`, in order,
+    /// without copying the set.
+    pub fn iter_ranges(&self, size: PageSize) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + '_ {
+        self.ranges(size).iter_ranges()
+    }
+
+    pub fn insert_addr(&self, value: PhysAddr, size: PageSize) {
+        self.insert_range(value, value + usize::from(size), size);
+    }
+
+    pub fn remove_addr(&self, value: PhysAddr, size: PageSize) -> bool {
+        self.remove_range(value, value + usize::from(size), size)
+    }
+
+    pub fn contains_addr(&self, value: PhysAddr, size: PageSize) -> bool {
+        value.is_aligned(usize::from(size)) && self.ranges(size).contains(value)
+    }
+
+    /// Returns the pages at or above `start`, in order. The set may change
+    /// while it is iterated. Pages added behind the iterator are not
+    /// returned.
+    fn iter_from(&self, start: PhysAddr) -> impl Iterator<Item = (PhysAddr, PageSize)> + '_ {
+        let mut regular = start.align_up(PAGE_SIZE);
+        let mut huge = start.align_up(PAGE_SIZE_2M);
+        core::iter::from_fn(move || {
+            let next_regular = self.regular.next_addr(regular);
+            let next_huge = self.huge.next_addr(huge);
+            let page = match (next_regular, next_huge) {
+                (Some(r), Some(h)) if h < r => (h, PageSize::Huge),
+                (Some(r), _) => (r, PageSize::Regular),
+                (None, Some(h)) => (h, PageSize::Huge),
+                (None, None) => return None,
+            };
+            regular = page.0 + PAGE_SIZE;
+            huge = match page.1 {
+                PageSize::Regular => page.0.align_up(PAGE_SIZE_2M),
+                PageSize::Huge => page.0 + PAGE_SIZE_2M,
+            };
+            Some(page)
+        })
+    }
+
+    /// Returns all pages, in order, without copying the set.
+    pub fn iter_addresses(&self) -> impl Iterator<Item = (PhysAddr, PageSize)> + '_ {
+        self.iter_from(PhysAddr::null())
+    }
+
+    /// Returns all pages whose start address lies in `[start, end)`,
+    /// regardless of their size.
+    pub fn range_addresses(&self, start: PhysAddr, end: PhysAddr) -> Vec<(PhysAddr, PageSize)> {
+        self.iter_from(start)
+            .take_while(|&(paddr, _)| paddr < end)
+            .collect()
+    }
+
+    /// Returns up to `count` of the lowest pages, in order.
+    pub fn first(&self, count: usize) -> Vec<(PhysAddr, PageSize)> {
+        self.iter_addresses().take(count).collect()
+    }
+
+    /// Returns the number of pages in the set.
+    pub fn size(&self) -> usize {
+        self.regular.bytes() / PAGE_SIZE + self.huge.bytes() / PAGE_SIZE_2M
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regular.is_empty() && self.huge.is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.regular.clear();
+        self.huge.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_range() {
+        let set: Set<u64> = Set::new();
+        for v in [5, 1, 9, 3, 7] {
+            assert!(set.insert(v));
+        }
+        assert!(!set.insert(3));
+        assert_eq!(set.size(), 5);
+        assert_eq!(set.range(3..8), [3, 5, 7]);
+        assert!(set.remove(&5));
+        assert_eq!(set.range(..), [1, 3, 7, 9]);
+    }
+
+    #[test]
+    fn test_page_set_range_addresses() {
+        let set = PageSet::new();
+        set.insert_addr(PhysAddr::from(0x200000u64), PageSize::Huge);
+        set.insert_addr(PhysAddr::from(0x1000u64), PageSize::Regular);
+        set.insert_addr(PhysAddr::from(0x400000u64), PageSize::Regular);
+        set.insert_addr(PhysAddr::from(0x401000u64), PageSize::Regular);
+
+        let pages = set.range_addresses(PhysAddr::from(0x2000u64), PhysAddr::from(0x401000u64));
+        assert_eq!(
+            pages,
+            [
+                (PhysAddr::from(0x200000u64), PageSize::Huge),
+                (PhysAddr::from(0x400000u64), PageSize::Regular),
+            ]
+        );
+        assert!(set
+            .range_addresses(PhysAddr::from(0x3000u64), PhysAddr::from(0x3000u64))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_range_set_coalesce() {
+        let set = RangeSet::new();
+        let addr = |a: u64| PhysAddr::from(a);
+        set.insert_range(addr(0x3000), addr(0x5000));
+        set.insert_range(addr(0x1000), addr(0x2000));
+        set.insert_range(addr(0x2000), addr(0x3000));
+        set.insert_range(addr(0x8000), addr(0x9000));
+        assert_eq!(
+            set.iter_ranges().collect::<Vec<_>>(),
+            [(addr(0x1000), addr(0x5000)), (addr(0x8000), addr(0x9000))]
+        );
+        set.insert_range(addr(0x4000), addr(0x8000));
+        assert_eq!(
+            set.iter_ranges().collect::<Vec<_>>(),
+            [(addr(0x1000), addr(0x9000))]
+        );
+        assert_eq!(set.bytes(), 0x8000);
+
+        assert!(set.remove_range(addr(0x2000), addr(0x4000)));
+        assert!(!set.remove_range(addr(0x2000), addr(0x4000)));
+        assert!(set.remove_range(addr(0x8000), addr(0xa000)));
+        assert_eq!(
+            set.iter_ranges().collect::<Vec<_>>(),
+            [(addr(0x1000), addr(0x2000)), (addr(0x4000), addr(0x8000))]
+        );
+        assert!(set.contains(addr(0x1fff)));
+        assert!(!set.contains(addr(0x2000)));
+        assert!(!set.contains(addr(0x8000)));
+    }
+
+    #[test]
+    fn test_page_set_order() {
+        let set = PageSet::new();
+        let addr = |a: u64| PhysAddr::from(a);
+        set.insert_range(addr(0x3fe000), addr(0x401000), PageSize::Regular);
+        set.insert_range(addr(0x200000), addr(0x600000), PageSize::Huge);
+        assert_eq!(set.size(), 5);
+        assert_eq!(
+            set.iter_addresses().collect::<Vec<_>>(),
+            [
+                (addr(0x200000), PageSize::Huge),
+                (addr(0x3fe000), PageSize::Regular),
+                (addr(0x3ff000), PageSize::Regular),
+                (addr(0x400000), PageSize::Regular),
+                (addr(0x400000), PageSize::Huge),
+            ]
+        );
+        assert!(set.contains_addr(addr(0x400000), PageSize::Huge));
+        assert!(!set.contains_addr(addr(0x401000), PageSize::Huge));
+        assert!(set.remove_addr(addr(0x3ff000), PageSize::Regular));
+        assert_eq!(
+            set.first(2),
+            [
+                (addr(0x200000), PageSize::Huge),
+                (addr(0x3fe000), PageSize::Regular)
+            ]
+        );
+        set.clear();
+        assert!(set.is_empty());
+    }
 }
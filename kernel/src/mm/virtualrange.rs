@@ -11,6 +11,7 @@ use crate::types::{PAGE_SHIFT, PAGE_SHIFT_2M, PAGE_SIZE, PAGE_SIZE_2M};
 use crate::utils::bitmap_allocator::{BitmapAllocator, BitmapAllocator1024};
 use crate::utils::MemoryRegion;
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use super::{
     SVSM_PERCPU_TEMP_BASE_2M, SVSM_PERCPU_TEMP_BASE_4K, SVSM_PERCPU_TEMP_END_2M,
@@ -20,12 +21,25 @@ use super::{
 pub const VIRT_ALIGN_4K: usize = PAGE_SHIFT - 12;
 pub const VIRT_ALIGN_2M: usize = PAGE_SHIFT_2M - 12;
 
+/// Default number of pages in the per-CPU 4K mapping window.
+pub const VIRT_WINDOW_4K_DEFAULT: usize = 512;
+/// Smallest supported number of pages in the per-CPU 4K mapping window.
+pub const VIRT_WINDOW_4K_MIN: usize = 64;
+
+/// Number of pages in the 4K mapping window of CPUs set up from now on.
+static VIRT_WINDOW_4K_PAGES: AtomicUsize = AtomicUsize::new(VIRT_WINDOW_4K_DEFAULT);
+
+/// Number of failed allocations in the per-CPU mapping windows.
+static VIRT_ALLOC_FAILURES: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Default)]
 pub struct VirtualRange {
     start_virt: VirtAddr,
     page_count: usize,
     page_shift: usize,
     bits: BitmapAllocator1024,
+    /// Number of allocations which failed because the range was exhausted
+    failures: usize,
 }
 
 impl VirtualRange {
@@ -37,6 +51,7 @@ impl VirtualRange {
             page_count: 0,
             page_shift: PAGE_SHIFT,
             bits: BitmapAllocator1024::new(),
+            failures: 0,
         }
     }
 
@@ -51,10 +66,36 @@ impl VirtualRange {
         // Always reserve an extra page to leave a guard between virtual memory allocations
         match self.bits.alloc(page_count + 1, alignment) {
             Some(offset) => Ok(self.start_virt + (offset << self.page_shift)),
-            None => Err(SvsmError::Mem),
+            None => {
+                self.failures += 1;
+                VIRT_ALLOC_FAILURES.fetch_add(1, Ordering::Relaxed);
+                Err(SvsmError::Mem)
+            }
         }
     }
 
+    /// Changes the number of pages in the range. Must not be called while
+    /// pages beyond the new size are allocated.
+    pub fn resize(&mut self, page_count: usize) {
+        assert!(page_count <= Self::CAPACITY);
+        if page_count > self.page_count {
+            self.bits
+                .set(self.page_count, page_count - self.page_count, false);
+        } else if page_count < self.page_count {
+            self.bits
+                .set(page_count, self.page_count - page_count, true);
+        }
+        self.page_count = page_count;
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
     pub fn free(&mut self, vaddr: VirtAddr, page_count: usize) {
         let offset = (vaddr - self.start_virt) >> self.page_shift;
         // Add 1 to the page count for the VM guard
@@ -66,17 +107,46 @@ impl VirtualRange {
     }
 }
 
+/// Returns the largest supported number of pages in the per-CPU 4K
+/// mapping window.
+pub fn virt_window_4k_max() -> usize {
+    (SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE
+}
+
+/// Returns the number of pages in the 4K mapping window of new CPUs.
+pub fn virt_window_4k_pages() -> usize {
+    VIRT_WINDOW_4K_PAGES.load(Ordering::Relaxed)
+}
+
+/// Sets the number of pages in the per-CPU 4K mapping window, clamped to
+/// the supported range. Applies to CPUs set up from now on and to the
+/// current CPU, which must not have temporary mappings beyond the new size.
+pub fn set_virt_window_4k_pages(pages: usize) {
+    let pages = pages.clamp(VIRT_WINDOW_4K_MIN, virt_window_4k_max());
+    VIRT_WINDOW_4K_PAGES.store(pages, Ordering::Relaxed);
+    this_cpu().vrange_4k.borrow_mut().resize(pages);
+    log::info!("Per-CPU 4K mapping window: {} pages", pages);
+}
+
+/// Returns the number of failed allocations in the per-CPU mapping windows
+/// of all CPUs.
+pub fn virt_alloc_failures() -> u64 {
+    VIRT_ALLOC_FAILURES.load(Ordering::Relaxed)
+}
+
 pub fn virt_log_usage() {
-    let page_count4k = (SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE;
-    let page_count2m = (SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M) / PAGE_SIZE_2M;
-    let unused_cap_4k = BitmapAllocator1024::CAPACITY - page_count4k;
-    let unused_cap_2m = BitmapAllocator1024::CAPACITY - page_count2m;
+    let range_4k = this_cpu().vrange_4k.borrow();
+    let range_2m = this_cpu().vrange_2m.borrow();
+    let unused_cap_4k = BitmapAllocator1024::CAPACITY - range_4k.page_count();
+    let unused_cap_2m = BitmapAllocator1024::CAPACITY - range_2m.page_count();
 
     log::info!(
-        "[CPU {}] Virtual memory pages used: {} * 4K, {} * 2M",
+        "[CPU {}] Virtual memory pages used: {} * 4K, {} * 2M, failed allocations: {}, {}",
         this_cpu().get_apic_id(),
-        this_cpu().vrange_4k.borrow().used_pages() - unused_cap_4k,
-        this_cpu().vrange_2m.borrow().used_pages() - unused_cap_2m
+        range_4k.used_pages() - unused_cap_4k,
+        range_2m.used_pages() - unused_cap_2m,
+        range_4k.failures(),
+        range_2m.failures()
     );
 }
 
@@ -184,6 +254,26 @@ mod tests {
         assert_eq!(range.used_pages(), 0);
     }
 
+    #[test]
+    fn test_resize() {
+        let mut range = VirtualRange::new();
+        range.init(VirtAddr::new(0x1000000), 16, PAGE_SHIFT);
+
+        // 16 pages hold 2 allocations of 7 pages plus guard pages
+        range.alloc(7, 0).unwrap();
+        range.alloc(7, 0).unwrap();
+        assert!(range.alloc(7, 0).is_err());
+        assert_eq!(range.failures(), 1);
+
+        range.resize(24);
+        range.alloc(7, 0).unwrap();
+        assert_eq!(range.page_count(), 24);
+
+        range.resize(16);
+        assert!(range.alloc(1, 0).is_err());
+        assert_eq!(range.failures(), 2);
+    }
+
     #[test]
     fn test_free_2m() {
         let mut range = VirtualRange::new();
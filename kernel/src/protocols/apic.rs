@@ -7,6 +7,7 @@
 use crate::cpu::percpu::this_cpu;
 use crate::platform::SVSM_PLATFORM;
 use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::BuiltinProtocol;
 use crate::protocols::RequestParams;
 
 const SVSM_REQ_APIC_QUERY_FEATURES: u32 = 0;
@@ -19,6 +20,21 @@ pub const APIC_PROTOCOL: u32 = 3;
 pub const APIC_PROTOCOL_VERSION_MIN: u32 = 1;
 pub const APIC_PROTOCOL_VERSION_MAX: u32 = 1;
 
+/// The APIC protocol is only supported if the calling CPU supports
+/// alternate injection.
+pub static APIC_PROTOCOL_HANDLER: BuiltinProtocol = BuiltinProtocol::new(
+    APIC_PROTOCOL,
+    "apic",
+    APIC_PROTOCOL_VERSION_MIN,
+    APIC_PROTOCOL_VERSION_MAX,
+    apic_protocol_request,
+)
+.with_check(apic_available);
+
+fn apic_available() -> bool {
+    this_cpu().use_apic_emulation()
+}
+
 fn apic_query_features(params: &mut RequestParams) -> Result<(), SvsmReqError> {
     // No features are supported beyond the base feature set.
     params.rcx = 0;
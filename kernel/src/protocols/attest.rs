@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Attestation protocol implementation (SVSM spec, chapter 7).
+//!
+//! The guest cannot request VMPL0 attestation reports from the PSP itself.
+//! With `SVSM_ATTEST_SERVICES` it asks the SVSM for one, which the SVSM
+//! requests through its own channel with the PSP. The report binds a nonce
+//! of the guest to a manifest the SVSM returns along with it: REPORT_DATA
+//! holds the SHA-512 digest of the nonce followed by the manifest.
+//!
+//! The manifest describes the state of the SVSM, in little endian:
+//!
+//! * offset 0, 4 bytes: manifest version, currently 1,
+//! * offset 4, 4 bytes: flags, bit 0 set if the backup protocol is
+//!   available,
+//! * offset 8, 8 bytes: number of restores of guest memory since the SVSM
+//!   launched, zero without the backup protocol.
+//!
+//! A relying party which checks the VMPL of the report and the digest can
+//! thus tell whether the guest was rolled back. Certificates are not
+//! returned.
+
+extern crate alloc;
+
+use core::mem::size_of;
+
+use alloc::vec;
+
+use crate::{
+    address::PhysAddr,
+    crypto::digest::{Sha512, Sha512Trait},
+    greq::{
+        pld_report::{AttestationReport, SnpReportResponse, USER_DATA_SIZE},
+        services::get_regular_report,
+    },
+    protocols::{
+        buffer::{GuestBuffer, GuestData},
+        errors::SvsmReqError,
+        registry::BuiltinProtocol,
+        RequestParams, SVSM_ATTEST_PROTOCOL,
+    },
+};
+
+#[cfg(feature = "backup")]
+use crate::protocols::backup::restore_generation;
+
+pub const ATTEST_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const ATTEST_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub static ATTEST_PROTOCOL_HANDLER: BuiltinProtocol = BuiltinProtocol::new(
+    SVSM_ATTEST_PROTOCOL,
+    "attestation",
+    ATTEST_PROTOCOL_VERSION_MIN,
+    ATTEST_PROTOCOL_VERSION_MAX,
+    attest_protocol_request,
+);
+
+// Attestation protocol services (SVSM spec, chapter 7)
+const SVSM_ATTEST_SERVICES: u32 = 0;
+
+/// Size of the attestation report written to the report buffer.
+const REPORT_SIZE: usize = size_of::<AttestationReport>();
+/// Offset of the attestation report in the `MSG_REPORT_RSP` payload.
+const REPORT_OFFSET: usize = size_of::<SnpReportResponse>() - REPORT_SIZE;
+
+const MANIFEST_VERSION: u32 = 1;
+const MANIFEST_SIZE: usize = 16;
+/// Manifest flag: the backup protocol is available.
+#[cfg(feature = "backup")]
+const MANIFEST_BACKUP: u32 = 1 << 0;
+
+/// SVSM_ATTEST_SERVICES request structure
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+struct AttestServicesRequest {
+    report_gpa: u64,
+    report_size: u32,
+    _reserved1: u32,
+    nonce_gpa: u64,
+    nonce_size: u16,
+    _reserved2: [u8; 6],
+    manifest_gpa: u64,
+    manifest_size: u32,
+    _reserved3: u32,
+    certs_gpa: u64,
+    certs_size: u32,
+    _reserved4: u32,
+}
+
+// SAFETY: AttestServicesRequest is packed and comprised entirely of integer
+// types, so it has no padding and no invalid representations.
+unsafe impl GuestData for AttestServicesRequest {}
+
+/// Returns the buffer of `len` bytes of guest memory at `gpa`.
+fn guest_buffer(gpa: u64, len: usize) -> Result<GuestBuffer, SvsmReqError> {
+    GuestBuffer::unaligned(PhysAddr::from(gpa), len)
+}
+
+/// Returns the buffer of `len` bytes of guest memory at `gpa` to read from.
+fn guest_input(gpa: u64, len: usize) -> Result<GuestBuffer, SvsmReqError> {
+    GuestBuffer::read_only(PhysAddr::from(gpa), len)
+}
+
+/// Returns the flags and the restore count of the manifest.
+#[cfg(feature = "backup")]
+fn manifest_state() -> (u32, u64) {
+    (MANIFEST_BACKUP, restore_generation())
+}
+
+/// Returns the flags and the restore count of the manifest.
+#[cfg(not(feature = "backup"))]
+fn manifest_state() -> (u32, u64) {
+    (0, 0)
+}
+
+fn manifest() -> [u8; MANIFEST_SIZE] {
+    let (flags, generation) = manifest_state();
+    let mut manifest = [0u8; MANIFEST_SIZE];
+    manifest[..4].copy_from_slice(&MANIFEST_VERSION.to_le_bytes());
+    manifest[4..8].copy_from_slice(&flags.to_le_bytes());
+    manifest[8..].copy_from_slice(&generation.to_le_bytes());
+    manifest
+}
+
+/// Requests a VMPL0 attestation report for the request structure at `rcx`.
+/// On success `rcx` holds the size of the manifest, `rdx` the size of the
+/// certificates, always zero, and `r8` the size of the report. If the
+/// manifest or report buffer is too small, the call fails with
+/// INVALID_PARAMETER and the required size is returned in `rcx` or `r8`.
+fn attest_services(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let request: AttestServicesRequest =
+        guest_input(params.rcx, size_of::<AttestServicesRequest>())?
+            .read_obj(0)
+            .map_err(SvsmReqError::from_mapping)?;
+
+    params.rcx = 0;
+    params.rdx = 0;
+    params.r8 = 0;
+    if (request.manifest_size as usize) < MANIFEST_SIZE {
+        params.rcx = MANIFEST_SIZE as u64;
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if (request.report_size as usize) < REPORT_SIZE {
+        params.r8 = REPORT_SIZE as u64;
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let mut hash = Sha512::new();
+    if request.nonce_size != 0 {
+        let mut nonce = vec![0u8; request.nonce_size.into()];
+        guest_input(request.nonce_gpa, nonce.len())?
+            .read(0, &mut nonce)
+            .map_err(SvsmReqError::from_mapping)?;
+        hash.update(&nonce);
+    }
+    let manifest = manifest();
+    hash.update(&manifest);
+
+    // A zeroed request asks for a VMPL0 report signed with the default key.
+    let mut message = vec![0u8; size_of::<SnpReportResponse>()];
+    message[..USER_DATA_SIZE].copy_from_slice(&hash.finalize());
+    get_regular_report(&mut message)?;
+
+    guest_buffer(request.report_gpa, REPORT_SIZE)?
+        .write(0, &message[REPORT_OFFSET..])
+        .map_err(SvsmReqError::from_mapping)?;
+    guest_buffer(request.manifest_gpa, MANIFEST_SIZE)?
+        .write_obj(0, &manifest)
+        .map_err(SvsmReqError::from_mapping)?;
+
+    params.rcx = MANIFEST_SIZE as u64;
+    params.r8 = REPORT_SIZE as u64;
+    Ok(())
+}
+
+pub fn attest_protocol_request(
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_ATTEST_SERVICES => attest_services(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
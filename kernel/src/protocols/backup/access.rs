@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Per-region write statistics for snapshot tuning.
+//!
+//! While copy-on-write is enabled, the guest driver resolves every first
+//! write to a protected page through the SVSM. With statistics enabled,
+//! these writes are counted per 2M region of guest memory until the next
+//! backup is taken. The guest runtime can use the counts to decide which
+//! regions are worth registering for backups and which are better left as
+//! scratch memory that is cleared on restore.
+
+use crate::address::{Address, PhysAddr};
+use crate::locking::SpinLock;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+
+/// Control flag: start recording writes.
+const ACCESS_STATS_ENABLE: u64 = 1 << 0;
+/// Control flag: stop recording writes.
+const ACCESS_STATS_DISABLE: u64 = 1 << 1;
+/// Control flag: clear the counts after they were written to the guest.
+const ACCESS_STATS_RESET: u64 = 1 << 2;
+const ACCESS_STATS_FLAGS: u64 = ACCESS_STATS_ENABLE | ACCESS_STATS_DISABLE | ACCESS_STATS_RESET;
+
+/// Size of a statistics entry in the guest buffer: start address of the 2M
+/// region and number of writes as `u64`.
+const ACCESS_ENTRY_SIZE: usize = 16;
+
+static ACCESS_STATS_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Number of writes per 2M-aligned region start address.
+static REGION_WRITES: SpinLock<BTreeMap<PhysAddr, u64>> = SpinLock::new(BTreeMap::new());
+
+/// Counts a write to the guest page at `paddr` if statistics are enabled.
+pub fn record_write(paddr: PhysAddr) {
+    if !ACCESS_STATS_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    *REGION_WRITES
+        .lock()
+        .entry(paddr.page_align_2m())
+        .or_insert(0) += 1;
+}
+
+/// Clears the counts, e.g. when a new backup starts a new interval.
+pub fn reset_access_stats() {
+    REGION_WRITES.lock().clear();
+}
+
+fn entry_bytes(region: PhysAddr, writes: u64) -> [u8; ACCESS_ENTRY_SIZE] {
+    let mut buf = [0u8; ACCESS_ENTRY_SIZE];
+    buf[0..8].copy_from_slice(&u64::from(region).to_le_bytes());
+    buf[8..16].copy_from_slice(&writes.to_le_bytes());
+    buf
+}
+
+/// Applies the control flags in `r8` and writes the per-region write counts
+/// since the last backup into the guest buffer at `rcx` of size `rdx`, in
+/// ascending address order. Only regions written to are listed. On success
+/// `rcx` holds the number of regions and `rdx` one if statistics are being
+/// recorded. If the buffer is too small, `rcx` holds the number of regions
+/// and INVALID_PARAMETER is returned.
+pub fn query_access_stats(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let flags = params.r8;
+    if flags & !ACCESS_STATS_FLAGS != 0
+        || flags & (ACCESS_STATS_ENABLE | ACCESS_STATS_DISABLE)
+            == ACCESS_STATS_ENABLE | ACCESS_STATS_DISABLE
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let buffer = GuestBuffer::from_params(params)?;
+
+    if flags & ACCESS_STATS_ENABLE != 0 {
+        ACCESS_STATS_ENABLED.store(true, Ordering::Relaxed);
+    } else if flags & ACCESS_STATS_DISABLE != 0 {
+        ACCESS_STATS_ENABLED.store(false, Ordering::Relaxed);
+    }
+
+    let mut writes = REGION_WRITES.lock();
+    params.rcx = writes.len() as u64;
+    params.rdx = u64::from(ACCESS_STATS_ENABLED.load(Ordering::Relaxed));
+    if writes.len() * ACCESS_ENTRY_SIZE > buffer.size() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    for (i, (&region, &count)) in writes.iter().enumerate() {
+        buffer
+            .write((i * ACCESS_ENTRY_SIZE) as u64, &entry_bytes(region, count))
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+    if flags & ACCESS_STATS_RESET != 0 {
+        writes.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PAGE_SIZE_2M;
+
+    #[test]
+    fn test_entry_bytes() {
+        let bytes = entry_bytes(PhysAddr::from(3 * PAGE_SIZE_2M), 42);
+        assert_eq!(bytes[0..8], (3 * PAGE_SIZE_2M as u64).to_le_bytes());
+        assert_eq!(bytes[8..16], 42u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Contiguous storage for backed-up page contents.
+//!
+//! Instead of allocating every backed-up page on its own, the contents are
+//! stored in slots of large physically contiguous arenas and addressed by
+//! slot number. This keeps the allocator out of the per-page path.
+
+use crate::address::VirtAddr;
+use crate::checked_invariant;
+use crate::debug::fault::{inject_fault, FaultPoint};
+use crate::error::SvsmError;
+use crate::mm::alloc::{allocate_pages_tagged, free_page, MemTag};
+use crate::types::PAGE_SIZE;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Allocation order of an arena, 512 slots (2M).
+const ARENA_ORDER: usize = 9;
+
+/// A physically contiguous block of slots.
+#[derive(Debug)]
+struct Arena {
+    vaddr: VirtAddr,
+    /// Number of the first slot in the arena.
+    first_slot: usize,
+    slots: usize,
+}
+
+impl Arena {
+    /// Allocates an arena of up to 2^[`ARENA_ORDER`] slots, accounted to
+    /// the backup in the allocator. Falls back to smaller arenas if memory
+    /// is fragmented.
+    fn new(first_slot: usize) -> Result<Self, SvsmError> {
+        let mut order = ARENA_ORDER;
+        loop {
+            match allocate_pages_tagged(order, MemTag::Backup) {
+                Ok(vaddr) => {
+                    return Ok(Self {
+                        vaddr,
+                        first_slot,
+                        slots: 1 << order,
+                    })
+                }
+                Err(err) if order == 0 => return Err(err),
+                Err(_) => order -= 1,
+            }
+        }
+    }
+
+    fn end_slot(&self) -> usize {
+        self.first_slot + self.slots
+    }
+
+    fn page_addr(&self, slot: usize) -> VirtAddr {
+        assert!(slot >= self.first_slot && slot < self.end_slot());
+        self.vaddr + (slot - self.first_slot) * PAGE_SIZE
+    }
+
+    fn page(&self, slot: usize) -> &[u8; PAGE_SIZE] {
+        // SAFETY: the arena owns the page at the slot address.
+        unsafe { &*self.page_addr(slot).as_ptr::<[u8; PAGE_SIZE]>() }
+    }
+
+    fn page_mut(&mut self, slot: usize) -> &mut [u8; PAGE_SIZE] {
+        // SAFETY: the arena owns the page at the slot address, and the
+        // mutable borrow of the arena guarantees exclusive access.
+        unsafe { &mut *self.page_addr(slot).as_mut_ptr::<[u8; PAGE_SIZE]>() }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        free_page(self.vaddr);
+    }
+}
+
+/// Page-sized slots in a growing list of arenas. Slots are handed out in
+/// order and only the most recent one can be returned.
+#[derive(Debug, Default)]
+pub struct SnapshotArena {
+    arenas: Vec<Arena>,
+    used: usize,
+}
+
+impl SnapshotArena {
+    pub const fn new() -> Self {
+        Self {
+            arenas: Vec::new(),
+            used: 0,
+        }
+    }
+
+    fn arena_index(&self, slot: usize) -> usize {
+        self.arenas.partition_point(|a| a.end_slot() <= slot)
+    }
+
+    fn arena(&self, slot: usize) -> &Arena {
+        &self.arenas[self.arena_index(slot)]
+    }
+
+    /// Allocates the next slot and returns its number.
+    pub fn alloc_slot(&mut self) -> Result<usize, SvsmError> {
+        inject_fault(FaultPoint::Alloc)?;
+        let capacity = self.arenas.last().map_or(0, Arena::end_slot);
+        if self.used == capacity {
+            self.arenas.push(Arena::new(capacity)?);
+        }
+        self.used += 1;
+        Ok(self.used - 1)
+    }
+
+    /// Returns the most recently allocated slot.
+    pub fn free_last_slot(&mut self) -> Result<(), SvsmError> {
+        checked_invariant!(self.used > 0, ArenaSlotUnderflow);
+        self.used -= 1;
+        Ok(())
+    }
+
+    /// Returns the number of allocated slots.
+    pub fn slots(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the contents of `slot`.
+    pub fn page(&self, slot: usize) -> &[u8; PAGE_SIZE] {
+        self.arena(slot).page(slot)
+    }
+
+    /// Returns the contents of `slot`, mutable.
+    pub fn page_mut(&mut self, slot: usize) -> &mut [u8; PAGE_SIZE] {
+        let index = self.arena_index(slot);
+        self.arenas[index].page_mut(slot)
+    }
+
+    /// Returns the slots of the last arena which are not allocated.
+    fn spare_slots(&self) -> core::ops::Range<usize> {
+        self.used..self.arenas.last().map_or(0, Arena::end_slot)
+    }
+
+    /// Fills the slots of the last arena which are not allocated with
+    /// `byte`.
+    pub fn fill_spare(&mut self, byte: u8) {
+        for slot in self.spare_slots() {
+            self.page_mut(slot).fill(byte);
+        }
+    }
+
+    /// Returns whether all slots of the last arena which are not allocated
+    /// hold `byte` only.
+    pub fn spare_holds(&self, byte: u8) -> bool {
+        self.spare_slots()
+            .all(|slot| self.page(slot).iter().all(|b| *b == byte))
+    }
+
+    /// Frees all arenas.
+    pub fn clear(&mut self) {
+        self.arenas.clear();
+        self.used = 0;
+    }
+}
@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Restore generation in attestation reports.
+//!
+//! A restored guest is rolled back to an earlier state, which a relying
+//! party cannot tell from the reports the guest requests itself. The SVSM
+//! counts restores in its own memory, which restores leave alone, and puts
+//! the count into VMPL0 attestation reports requested with
+//! `SVSM_ATTEST_GENERATION`. The guest cannot request VMPL0 reports from
+//! the PSP itself, so a relying party which checks the VMPL of the report
+//! can trust the count. The count starts at zero with every launch of the
+//! SVSM.
+//!
+//! The 64 bytes of REPORT_DATA hold the SHA-256 digest of the 64 bytes of
+//! guest data followed by the generation as a little-endian 64-bit integer,
+//! then the generation itself in the same encoding and zeroes. The guest
+//! data usually carries a nonce of the relying party, which checks the
+//! digest to bind the generation to it.
+
+use super::reseed::restore_generation;
+use crate::crypto::digest::{Sha256, Sha256Trait, SHA256_SIZE};
+use crate::greq::pld_report::{SnpReportResponse, USER_DATA_SIZE};
+use crate::greq::services::get_regular_report;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use core::mem::size_of;
+
+extern crate alloc;
+use alloc::vec;
+
+/// Size of the response written into the guest buffer.
+const RESPONSE_SIZE: usize = size_of::<SnpReportResponse>();
+
+/// Returns the REPORT_DATA binding `generation` to the guest data
+/// `user_data`.
+fn report_data(user_data: &[u8; USER_DATA_SIZE], generation: u64) -> [u8; USER_DATA_SIZE] {
+    let mut hash = Sha256::new();
+    hash.update(user_data);
+    hash.update(&generation.to_le_bytes());
+    let mut data = [0u8; USER_DATA_SIZE];
+    data[..SHA256_SIZE].copy_from_slice(&hash.finalize());
+    data[SHA256_SIZE..SHA256_SIZE + 8].copy_from_slice(&generation.to_le_bytes());
+    data
+}
+
+/// Requests a VMPL0 attestation report whose REPORT_DATA binds the restore
+/// generation to the 64 bytes of guest data at the start of the guest
+/// buffer at `rcx` of size `rdx`. The `MSG_REPORT_RSP` of the PSP replaces
+/// the guest data in the buffer. On success `rcx` holds the generation and
+/// `rdx` the size of the response. Fails with INVALID_PARAMETER if the
+/// buffer cannot hold the response.
+pub fn attest_generation(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+    if buffer.size() < RESPONSE_SIZE {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let user_data: [u8; USER_DATA_SIZE] = buffer.read_obj(0).map_err(SvsmReqError::from_mapping)?;
+
+    // A zeroed request asks for a VMPL0 report signed with the default key.
+    let generation = restore_generation();
+    let mut message = vec![0u8; RESPONSE_SIZE];
+    message[..USER_DATA_SIZE].copy_from_slice(&report_data(&user_data, generation));
+    let response_len = get_regular_report(&mut message)?;
+    buffer
+        .write(0, &message[..response_len])
+        .map_err(SvsmReqError::from_mapping)?;
+
+    log::info!("Attested restore generation {}", generation);
+    params.rcx = generation;
+    params.rdx = response_len as u64;
+    Ok(())
+}
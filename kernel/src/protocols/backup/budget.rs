@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Memory budgets for snapshot state.
+//!
+//! A snapshot holds copies of guest pages in SVSM memory. The budgets from
+//! the IGVM parameter block cap the memory of a single snapshot and of all
+//! snapshot state together. A backup is only started if its estimated cost
+//! fits, and every page copied afterwards is charged against the budgets,
+//! so running over budget fails the backup cleanly instead of exhausting
+//! SVSM memory in the middle of the copy.
+
+use super::spill::spill_capacity;
+use crate::checked_invariant;
+use crate::error::SvsmError;
+use crate::locking::RWLock;
+use crate::mm::alloc::{memory_info, MemTag};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Error returned to the guest when a snapshot does not fit into its
+/// budget.
+pub const SVSM_ERR_BACKUP_OVER_BUDGET: u64 = 0x100;
+
+/// Free SVSM memory which snapshot state never uses, so the SVSM itself
+/// keeps working after a large backup.
+const RESERVED_MEMORY: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Budget {
+    /// Maximum bytes of a single snapshot, zero for no limit.
+    per_snapshot: usize,
+    /// Maximum bytes of all snapshot state, zero for no limit.
+    global: usize,
+}
+
+static BUDGET: RWLock<Budget> = RWLock::new(Budget {
+    per_snapshot: 0,
+    global: 0,
+});
+
+/// Bytes currently held by snapshot state.
+static SNAPSHOT_MEMORY: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of [`SNAPSHOT_MEMORY`] held by parked named snapshots.
+static PARKED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of [`SNAPSHOT_MEMORY`] held by backed-up pages shared with forks
+/// of a snapshot. The rest belongs to the current backup.
+static SHARED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Zero and total page counts of the last backup, used to estimate the
+/// share of pages which do not need a copy.
+static LAST_ZERO_PAGES: AtomicUsize = AtomicUsize::new(0);
+static LAST_TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+fn over_budget() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_OVER_BUDGET)
+}
+
+/// Converts an error from copying guest memory into snapshot state.
+/// Running out of SVSM memory counts as running over budget, since the
+/// copies made so far are freed again just the same.
+pub fn copy_error(err: SvsmError) -> SvsmReqError {
+    match err {
+        SvsmError::Alloc(_) => over_budget(),
+        err => SvsmReqError::from_mapping(err),
+    }
+}
+
+fn limit(budget: usize) -> usize {
+    if budget == 0 {
+        usize::MAX
+    } else {
+        budget
+    }
+}
+
+/// Installs the snapshot memory budgets, in bytes. Zero means no limit.
+pub fn set_snapshot_budget(per_snapshot: u64, global: u64) {
+    let per_snapshot = usize::try_from(per_snapshot).unwrap_or(usize::MAX);
+    let global = usize::try_from(global).unwrap_or(usize::MAX);
+    log::info!(
+        "Snapshot memory budget: {:#x} bytes per snapshot, {:#x} bytes total",
+        per_snapshot,
+        global
+    );
+    *BUDGET.lock_write() = Budget {
+        per_snapshot,
+        global,
+    };
+}
+
+/// Returns the number of bytes a new snapshot may still use.
+fn available() -> usize {
+    let budget = *BUDGET.lock_read();
+    let used = SNAPSHOT_MEMORY.load(Ordering::Relaxed);
+    let global = limit(budget.global).saturating_sub(used);
+    let free = memory_info().free_bytes().saturating_sub(RESERVED_MEMORY);
+    limit(budget.per_snapshot).min(global).min(free)
+}
+
+/// Records the page counts of a completed backup for later estimates.
+pub fn record_backup(zero_pages: usize, total_pages: usize) {
+    LAST_ZERO_PAGES.store(zero_pages, Ordering::Relaxed);
+    LAST_TOTAL_PAGES.store(total_pages, Ordering::Relaxed);
+}
+
+/// Estimates the memory a backup of `bytes` bytes of guest memory needs,
+/// assuming the share of zero pages of the last backup. Without a previous
+/// backup every page is assumed to need a copy.
+pub fn estimate_backup_cost(bytes: usize) -> usize {
+    let zero = LAST_ZERO_PAGES.load(Ordering::Relaxed);
+    let total = LAST_TOTAL_PAGES.load(Ordering::Relaxed);
+    if total == 0 || zero > total {
+        return bytes;
+    }
+    let pages = bytes / PAGE_SIZE;
+    let copied = pages - (pages as u128 * zero as u128 / total as u128) as usize;
+    copied * PAGE_SIZE
+}
+
+/// Refuses a backup whose estimated cost does not fit into the budget and
+/// the spill area.
+pub fn admit_backup(estimate: usize) -> Result<(), SvsmReqError> {
+    let available = available().saturating_add(spill_capacity());
+    if estimate > available {
+        log::info!(
+            "Refusing backup: estimated {:#x} bytes, {:#x} bytes available",
+            estimate,
+            available
+        );
+        return Err(over_budget());
+    }
+    Ok(())
+}
+
+/// Memory charged to the snapshot being created.
+#[derive(Debug, Default)]
+pub struct SnapshotCharge {
+    bytes: usize,
+}
+
+impl SnapshotCharge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charges `bytes` against the budgets before they are allocated.
+    pub fn charge(&mut self, bytes: usize) -> Result<(), SvsmReqError> {
+        let budget = *BUDGET.lock_read();
+        if self.bytes + bytes > limit(budget.per_snapshot) {
+            return Err(over_budget());
+        }
+        SNAPSHOT_MEMORY
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes)
+                    .filter(|&total| total <= limit(budget.global))
+            })
+            .map_err(|_| over_budget())?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    /// Returns `bytes` of an earlier charge which were not needed.
+    pub fn refund(&mut self, bytes: usize) -> Result<(), SvsmError> {
+        checked_invariant!(bytes <= self.bytes, ChargeUnderflow);
+        self.bytes -= bytes;
+        SNAPSHOT_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the whole charge after the memory it was taken for has been
+    /// freed.
+    pub fn cancel(self) {
+        SNAPSHOT_MEMORY.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Returns the memory of the current backup after its state has been
+/// discarded. This also drops charges for pages whose copy failed before
+/// they became part of the snapshot. Parked snapshots and shared pages
+/// keep their memory.
+pub fn release_all() {
+    let kept = PARKED_MEMORY.load(Ordering::Relaxed) + SHARED_MEMORY.load(Ordering::Relaxed);
+    SNAPSHOT_MEMORY.store(kept, Ordering::Relaxed);
+}
+
+/// Returns the bytes charged to the current backup.
+fn current_memory() -> usize {
+    let kept = PARKED_MEMORY.load(Ordering::Relaxed) + SHARED_MEMORY.load(Ordering::Relaxed);
+    SNAPSHOT_MEMORY.load(Ordering::Relaxed).saturating_sub(kept)
+}
+
+/// Moves the memory of the current backup to the parked snapshots and
+/// returns its size in bytes.
+pub fn park_memory() -> usize {
+    let bytes = current_memory();
+    PARKED_MEMORY.fetch_add(bytes, Ordering::Relaxed);
+    bytes
+}
+
+/// Moves `bytes` of parked memory back to the current backup.
+pub fn unpark_memory(bytes: usize) {
+    PARKED_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Returns `bytes` of parked memory after a parked snapshot was deleted.
+pub fn release_parked(bytes: usize) {
+    PARKED_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+    SNAPSHOT_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+/// Moves the memory of the current backup to the pages it shares with a
+/// fork and returns its size in bytes.
+pub fn share_memory() -> usize {
+    let bytes = current_memory();
+    SHARED_MEMORY.fetch_add(bytes, Ordering::Relaxed);
+    bytes
+}
+
+/// Moves `bytes` of parked memory to the pages a parked snapshot shares
+/// with a fork.
+pub fn share_parked(bytes: usize) {
+    PARKED_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+    SHARED_MEMORY.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Returns `bytes` of shared memory after the last snapshot holding the
+/// pages was dropped.
+pub fn release_shared(bytes: usize) {
+    if bytes != 0 {
+        SHARED_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+        SNAPSHOT_MEMORY.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of bytes currently held by snapshot state.
+pub fn snapshot_memory() -> usize {
+    SNAPSHOT_MEMORY.load(Ordering::Relaxed)
+}
+
+/// Reports where SVSM memory went: `rcx` holds the bytes the allocator has
+/// handed out for backed-up page contents, `rdx` the bytes charged to the
+/// snapshot budgets, which include the indices and other bookkeeping of
+/// all snapshots, and `r8` the free SVSM memory.
+pub fn backup_memory_stats(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let info = memory_info();
+    params.rcx = info.tagged_bytes(MemTag::Backup) as u64;
+    params.rdx = snapshot_memory() as u64;
+    params.r8 = info.free_bytes() as u64;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Version and capabilities of the backup protocol.
+//!
+//! The backup protocol grows with every SVSM release. Instead of probing
+//! calls which may fail with an unsupported call error, guest tooling asks
+//! for the protocol version and a bitmap of the features this SVSM
+//! supports with `SVSM_BACKUP_QUERY_CAPS`. The version is the one the core
+//! protocol reports for the backup protocol. Features which depend on the
+//! configuration are only reported if they are available.
+
+use super::compress::compression_enabled;
+use super::vtpm::vtpm_present;
+use super::{BACKUP_PROTOCOL_VERSION_MAX, SVSM_BACKUP_LAST_CALL};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+/// Feature bits reported by `SVSM_BACKUP_QUERY_CAPS`.
+pub mod backup_caps {
+    /// Copy-on-write tracking, partial restores and in-place restores.
+    pub const COPY_ON_WRITE: u64 = 1 << 0;
+    /// Incremental backups into delta layers.
+    pub const INCREMENTAL: u64 = 1 << 1;
+    /// Compression of backed-up pages, if enabled in the configuration.
+    pub const COMPRESSION: u64 = 1 << 2;
+    /// Named snapshots besides the current backup.
+    pub const MULTI_SNAPSHOT: u64 = 1 << 3;
+    /// Export and import of snapshot containers.
+    pub const EXPORT: u64 = 1 << 4;
+    /// Lazy and asynchronous backups.
+    pub const LAZY: u64 = 1 << 5;
+    /// Rollback of the vTPM state with the guest.
+    pub const VTPM: u64 = 1 << 6;
+    /// Spilling of pages over budget to a host-shared area.
+    pub const SPILL: u64 = 1 << 7;
+    /// Resuming interrupted restores and re-arming copy-on-write.
+    pub const RESUME_RESTORE: u64 = 1 << 8;
+    /// Excluding ranges from backups and restores.
+    pub const EXCLUDE_RANGES: u64 = 1 << 9;
+    /// Comparing guest memory with the backup.
+    pub const DIFF: u64 = 1 << 10;
+    /// Restore generations in attestation reports.
+    pub const ATTEST_GENERATION: u64 = 1 << 11;
+    /// Pinning of the registered pages.
+    pub const PIN_PAGES: u64 = 1 << 12;
+    /// Metadata records of snapshots.
+    pub const METADATA: u64 = 1 << 13;
+    /// Runtime control of the backup log verbosity.
+    pub const VERBOSITY: u64 = 1 << 14;
+    /// Reporting of the SVSM memory held by snapshots.
+    pub const MEMORY_STATS: u64 = 1 << 15;
+    /// Forks of snapshots sharing their pages.
+    pub const FORK: u64 = 1 << 16;
+    /// Dry runs of restores.
+    pub const RESTORE_CHECK: u64 = 1 << 17;
+    /// Periodic checkpoints taken by the SVSM.
+    pub const PERIODIC_CHECKPOINT: u64 = 1 << 18;
+    /// Registering, excluding and including ranges of 1G pages.
+    pub const GIANT_RANGES: u64 = 1 << 19;
+    /// Migration of the SVSM state to another host.
+    pub const MIGRATION: u64 = 1 << 20;
+    /// Deferred completion of `SVSM_BACKUP_FINALIZE`.
+    pub const DEFERRED_FINALIZE: u64 = 1 << 21;
+}
+
+/// Returns the features supported in the current configuration.
+fn supported_features() -> u64 {
+    let mut caps = backup_caps::COPY_ON_WRITE
+        | backup_caps::INCREMENTAL
+        | backup_caps::MULTI_SNAPSHOT
+        | backup_caps::EXPORT
+        | backup_caps::LAZY
+        | backup_caps::SPILL
+        | backup_caps::RESUME_RESTORE
+        | backup_caps::EXCLUDE_RANGES
+        | backup_caps::DIFF
+        | backup_caps::ATTEST_GENERATION
+        | backup_caps::PIN_PAGES
+        | backup_caps::METADATA
+        | backup_caps::VERBOSITY
+        | backup_caps::MEMORY_STATS
+        | backup_caps::FORK
+        | backup_caps::RESTORE_CHECK
+        | backup_caps::PERIODIC_CHECKPOINT
+        | backup_caps::GIANT_RANGES
+        | backup_caps::MIGRATION
+        | backup_caps::DEFERRED_FINALIZE;
+    if compression_enabled() {
+        caps |= backup_caps::COMPRESSION;
+    }
+    if vtpm_present() {
+        caps |= backup_caps::VTPM;
+    }
+    caps
+}
+
+/// Reports the protocol version in `rcx`, the supported features in `rdx`,
+/// see [`backup_caps`], and the highest call number in `r8`.
+pub fn query_caps(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    params.rcx = u64::from(BACKUP_PROTOCOL_VERSION_MAX);
+    params.rdx = supported_features();
+    params.r8 = u64::from(SVSM_BACKUP_LAST_CALL);
+    Ok(())
+}
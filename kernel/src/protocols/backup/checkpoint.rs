@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Periodic checkpoints.
+//!
+//! With copy-on-write enabled, an incremental backup saves the pages
+//! written since the last checkpoint in a new delta layer (see the `delta`
+//! module). Instead of issuing `SVSM_INCREMENTAL_BACKUP` itself, the guest
+//! can have the SVSM take one at a fixed interval with
+//! `SVSM_SET_CHECKPOINT_INTERVAL`, so a recent restore point always exists.
+//!
+//! The SVSM only runs while a vCPU calls into it, so a due checkpoint is
+//! taken the next time the SVSM gains control from the guest, on whichever
+//! vCPU gets there first. The checkpoint is accounted like a protocol
+//! request, so it waits for a restore to complete and a restore waits for
+//! it. Intervals without dirty pages, or without a backup under
+//! copy-on-write, pass without a checkpoint.
+
+use super::delta::{delta_layers_full, incremental_backup};
+use super::dirty::dirty_pages;
+use super::verbosity::detail;
+use super::watchdog::cow_active;
+use super::{BACKUP_CREATED, SVSM_INCREMENTAL_BACKUP};
+use crate::cpu::tsc::{tsc_khz, tsc_now};
+use crate::protocols::barrier::RequestGuard;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::{RequestParams, SVSM_CUSTOM_PROTOCOL};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Checkpoint interval in TSC ticks, zero while disabled.
+static INTERVAL: AtomicU64 = AtomicU64::new(0);
+/// TSC value after which the next checkpoint is due.
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of periodic checkpoints taken.
+static TAKEN: AtomicU64 = AtomicU64::new(0);
+/// Number of periodic checkpoints which failed.
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the interval of periodic checkpoints to `rcx` milliseconds, zero
+/// disables them. The first checkpoint is due one interval after the call.
+/// On return `rcx` holds the number of periodic checkpoints taken so far
+/// and `rdx` the number of those which failed.
+pub fn set_checkpoint_interval(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let interval = params
+        .rcx
+        .checked_mul(tsc_khz())
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+    NEXT.store(tsc_now().saturating_add(interval), Ordering::SeqCst);
+    INTERVAL.store(interval, Ordering::SeqCst);
+    if interval == 0 {
+        log::info!("Periodic checkpoints disabled");
+    } else {
+        log::info!("Periodic checkpoints every {} ms", params.rcx);
+    }
+
+    params.rcx = TAKEN.load(Ordering::Relaxed);
+    params.rdx = FAILED.load(Ordering::Relaxed);
+    Ok(())
+}
+
+/// Takes an incremental backup if a periodic checkpoint is due. Called
+/// whenever the SVSM gains control from the guest.
+pub fn take_periodic_checkpoint() {
+    let interval = INTERVAL.load(Ordering::SeqCst);
+    let next = NEXT.load(Ordering::SeqCst);
+    let now = tsc_now();
+    if interval == 0 || now < next {
+        return;
+    }
+    // Only one vCPU takes the checkpoint.
+    if NEXT
+        .compare_exchange(
+            next,
+            now.saturating_add(interval),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_err()
+    {
+        return;
+    }
+    let Ok(_guard) = RequestGuard::enter(SVSM_CUSTOM_PROTOCOL, SVSM_INCREMENTAL_BACKUP) else {
+        return;
+    };
+    if !*BACKUP_CREATED.lock() || !cow_active() || dirty_pages().next().is_none() {
+        return;
+    }
+    if delta_layers_full() {
+        detail!("Periodic checkpoint skipped, no delta layer left");
+        FAILED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let mut params = RequestParams::default();
+    match incremental_backup(&mut params) {
+        Ok(()) => {
+            TAKEN.fetch_add(1, Ordering::Relaxed);
+            detail!("Periodic checkpoint saved as delta layer {}", params.rcx);
+        }
+        Err(e) => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            log::warn!("Periodic checkpoint failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Per-page checksums of backed-up pages.
+//!
+//! Every page stored in a backup gets a 64-bit XXH64 checksum of its
+//! contents, computed when the page is stored and verified whenever it is
+//! restored, so a corrupted page in snapshot memory fails the restore
+//! instead of being written into the guest. `SVSM_VERIFY_BACKUP` checks all
+//! pages without restoring anything. Unlike the SHA-256 digests of paranoid
+//! mode, the checksums are always recorded: XXH64 runs at memory speed and
+//! costs 8 bytes per page. They detect accidental corruption, not
+//! deliberate tampering.
+
+use super::compress::PACKED_SLOT;
+use super::delta::DELTA_LAYERS;
+use super::spill::SPILLED_SLOT;
+use super::{BackupPages, BACKUP_CREATED, BACKUP_PAGES};
+use crate::address::PhysAddr;
+use crate::checked_invariant;
+use crate::error::SvsmError;
+use crate::mm::alloc::AllocError;
+use crate::mm::allocate_file_page_ref;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::PAGE_SIZE;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Error returned to the guest when a backed-up page does not match its
+/// checksum.
+pub const SVSM_ERR_BACKUP_CORRUPTED: u64 = 0x103;
+
+const PRIME1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME1)
+}
+
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val))
+        .wrapping_mul(PRIME1)
+        .wrapping_add(PRIME4)
+}
+
+/// Returns the XXH64 hash of `data` with seed 0.
+fn xxh64(data: &[u8]) -> u64 {
+    let mut stripes = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut acc = [
+            PRIME1.wrapping_add(PRIME2),
+            PRIME2,
+            0,
+            0u64.wrapping_sub(PRIME1),
+        ];
+        for stripe in stripes.by_ref() {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = round(*acc, read_u64(&stripe[i * 8..]));
+            }
+        }
+        let hash = acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18));
+        acc.iter().fold(hash, |hash, &acc| merge_round(hash, acc))
+    } else {
+        PRIME5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    let mut rest = stripes.remainder();
+    while rest.len() >= 8 {
+        hash ^= round(0, read_u64(rest));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(PRIME1)
+            .wrapping_add(PRIME4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        hash ^= u64::from(word).wrapping_mul(PRIME1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(PRIME2)
+            .wrapping_add(PRIME3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        hash ^= u64::from(byte).wrapping_mul(PRIME5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^ (hash >> 32)
+}
+
+/// Returns the checksum of a page.
+pub fn page_checksum(data: &[u8; PAGE_SIZE]) -> u64 {
+    xxh64(data)
+}
+
+/// Checksums of the pages of a backup, by storage slot. Arena slots, packed
+/// store entries and spill store entries are all allocated in order, so
+/// each kind is a plain vector.
+#[derive(Debug, Default)]
+pub struct PageChecksums {
+    arena: Vec<u64>,
+    packed: Vec<u64>,
+    spilled: Vec<u64>,
+}
+
+impl PageChecksums {
+    pub const fn new() -> Self {
+        Self {
+            arena: Vec::new(),
+            packed: Vec::new(),
+            spilled: Vec::new(),
+        }
+    }
+
+    fn sums(&self, slot: usize) -> (&Vec<u64>, usize) {
+        if slot & PACKED_SLOT != 0 {
+            (&self.packed, slot & !PACKED_SLOT)
+        } else if slot & SPILLED_SLOT != 0 {
+            (&self.spilled, slot & !SPILLED_SLOT)
+        } else {
+            (&self.arena, slot)
+        }
+    }
+
+    fn sums_mut(&mut self, slot: usize) -> (&mut Vec<u64>, usize) {
+        if slot & PACKED_SLOT != 0 {
+            (&mut self.packed, slot & !PACKED_SLOT)
+        } else if slot & SPILLED_SLOT != 0 {
+            (&mut self.spilled, slot & !SPILLED_SLOT)
+        } else {
+            (&mut self.arena, slot)
+        }
+    }
+
+    /// Records `sum` for the newest slot `slot`.
+    pub fn push(&mut self, slot: usize, sum: u64) -> Result<(), SvsmError> {
+        let (sums, index) = self.sums_mut(slot);
+        checked_invariant!(index == sums.len(), ChecksumSlotMismatch);
+        sums.try_reserve(1)
+            .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+        sums.push(sum);
+        Ok(())
+    }
+
+    /// Drops the checksum of the newest slot `slot`.
+    pub fn pop(&mut self, slot: usize) {
+        self.sums_mut(slot).0.pop();
+    }
+
+    /// Returns whether `data` matches the checksum recorded for `slot`.
+    pub fn matches(&self, slot: usize, data: &[u8; PAGE_SIZE]) -> bool {
+        let (sums, index) = self.sums(slot);
+        sums.get(index) == Some(&page_checksum(data))
+    }
+
+    /// Returns the memory used by the checksums in bytes.
+    pub fn memory(&self) -> usize {
+        (self.arena.capacity() + self.packed.capacity() + self.spilled.capacity())
+            * core::mem::size_of::<u64>()
+    }
+
+    pub fn clear(&mut self) {
+        self.arena = Vec::new();
+        self.packed = Vec::new();
+        self.spilled = Vec::new();
+    }
+}
+
+/// Returns the error for a page whose backup copy is corrupted.
+pub fn corrupted(paddr: PhysAddr) -> SvsmReqError {
+    log::error!("Backup of page {:#018x} does not match its checksum", paddr);
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_CORRUPTED)
+}
+
+/// Fails with [`SVSM_ERR_BACKUP_CORRUPTED`] if the contents `data` of the
+/// page at `paddr` stored in `slot` do not match their checksum.
+pub fn check_page_checksum(
+    checksums: &PageChecksums,
+    paddr: PhysAddr,
+    slot: usize,
+    data: &[u8; PAGE_SIZE],
+) -> Result<(), SvsmReqError> {
+    if checksums.matches(slot, data) {
+        Ok(())
+    } else {
+        Err(corrupted(paddr))
+    }
+}
+
+/// Checks all pages of `backup`. Returns the number of pages checked, the
+/// number of corrupted pages and the address of the first one.
+fn verify_pages(
+    backup: &BackupPages,
+    scratch: &mut [u8; PAGE_SIZE],
+) -> (u64, u64, Option<PhysAddr>) {
+    let (mut checked, mut bad, mut first) = (0, 0, None);
+    for page in backup.pages() {
+        checked += 1;
+        let intact = backup.data(&page, scratch).is_some_and(|data| {
+            check_page_checksum(&backup.checksums, page.phys_addr, page.slot, data).is_ok()
+        });
+        if !intact {
+            bad += 1;
+            first.get_or_insert(page.phys_addr);
+        }
+    }
+    (checked, bad, first)
+}
+
+/// Verifies every page of the backup, including its delta layers, against
+/// its checksum without restoring anything. `rcx` holds the number of pages
+/// checked, `rdx` the number of corrupted pages and `r8` the address of the
+/// first corrupted page, or 0. Fails with [`SVSM_ERR_BACKUP_CORRUPTED`] if
+/// any page is corrupted and with INVALID_REQUEST if there is no backup.
+pub fn verify_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let created = BACKUP_CREATED.lock();
+    if !*created {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let backup = BACKUP_PAGES.lock_read();
+    let layers = DELTA_LAYERS.lock();
+    let mut scratch = allocate_file_page_ref()?;
+
+    let (mut checked, mut bad, mut first) = verify_pages(&backup, scratch.as_mut());
+    for layer in layers.newest_first() {
+        let (layer_checked, layer_bad, layer_first) = verify_pages(layer.pages(), scratch.as_mut());
+        checked += layer_checked;
+        bad += layer_bad;
+        first = first.or(layer_first);
+    }
+
+    log::info!("Verified backup: {} pages, {} corrupted", checked, bad);
+    params.rcx = checked;
+    params.rdx = bad;
+    params.r8 = first.map_or(0, u64::from);
+    if bad != 0 {
+        return Err(SvsmReqError::protocol(SVSM_ERR_BACKUP_CORRUPTED));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(xxh64(b"a"), 0xd24e_c4f1_a98c_6e5b);
+        assert_eq!(xxh64(b"abc"), 0x44bc_2cf5_ad77_0999);
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition"),
+            0xfbce_a83c_8a37_8bf1
+        );
+    }
+
+    #[test]
+    fn test_checksum_slots() {
+        let page = [0x5au8; PAGE_SIZE];
+        let mut sums = PageChecksums::new();
+        sums.push(0, page_checksum(&page)).unwrap();
+        sums.push(PACKED_SLOT, 0).unwrap();
+        sums.push(SPILLED_SLOT, page_checksum(&page)).unwrap();
+        assert!(sums.matches(0, &page));
+        assert!(!sums.matches(PACKED_SLOT, &page));
+        assert!(sums.matches(SPILLED_SLOT, &page));
+        assert!(!sums.matches(1, &page));
+        sums.pop(0);
+        assert!(!sums.matches(0, &page));
+    }
+}
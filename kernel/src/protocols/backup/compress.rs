@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Optional compression of backed-up pages.
+//!
+//! Guest pages which are not zero often still consist of long runs of a
+//! repeated byte, e.g. partially filled buffers or poisoned heap memory.
+//! With compression enabled, every backed-up page is run-length encoded
+//! with the PackBits variant of the snapshot container format and the
+//! encoding is appended to a [`PackedStore`] instead of occupying a whole
+//! arena slot, if it saves at least a quarter of the page. Pages which do
+//! not compress that well stay in the arena, so the worst case costs one
+//! encoding attempt per page and no memory.
+//!
+//! Decoding never fails: a corrupted encoding yields wrong page contents,
+//! which the page digests of paranoid mode detect.
+//!
+//! Encodings are encrypted before they are stored (see the `crypt` module).
+
+use super::arena::SnapshotArena;
+use super::crypt::{open, seal, Seal};
+use crate::error::SvsmError;
+use crate::mm::alloc::AllocError;
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicBool, Ordering};
+use snapshot::{pack_page, unpack_page};
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Largest encoding which is kept. Pages which do not compress to this
+/// size are stored uncompressed.
+const MAX_PACKED: usize = PAGE_SIZE * 3 / 4;
+
+/// Slot numbers of the page index with this bit set refer to entries of
+/// the packed store instead of arena slots.
+pub const PACKED_SLOT: usize = 1 << 31;
+
+static COMPRESSION: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables compression of pages backed up from now on.
+pub fn set_backup_compression(enabled: bool) {
+    if enabled {
+        log::info!("Backup page compression enabled");
+    }
+    COMPRESSION.store(enabled, Ordering::Relaxed);
+}
+
+pub fn compression_enabled() -> bool {
+    COMPRESSION.load(Ordering::Relaxed)
+}
+
+/// Encodes `page` into `out`. Returns false if the encoding would be longer
+/// than [`MAX_PACKED`].
+fn compress(page: &[u8; PAGE_SIZE], out: &mut Vec<u8>) -> bool {
+    out.resize(MAX_PACKED, 0);
+    let Some(len) = pack_page(page, out) else {
+        return false;
+    };
+    out.truncate(len);
+    true
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PackedEntry {
+    offset: usize,
+    len: usize,
+}
+
+/// Compressed pages, stored encrypted and back to back in the slots of an
+/// arena.
+#[derive(Debug)]
+pub struct PackedStore {
+    arena: SnapshotArena,
+    entries: Vec<PackedEntry>,
+    /// Seals of the entries.
+    seals: Vec<Seal>,
+    /// End of the last entry in bytes.
+    end: usize,
+    /// Encoding buffer, reused for every page.
+    buf: Vec<u8>,
+}
+
+impl PackedStore {
+    pub const fn new() -> Self {
+        Self {
+            arena: SnapshotArena::new(),
+            entries: Vec::new(),
+            seals: Vec::new(),
+            end: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Compresses `page` and appends it to the store, if compression is
+    /// enabled and pays off. Returns the index of the new entry.
+    pub fn push(&mut self, page: &[u8; PAGE_SIZE]) -> Result<Option<usize>, SvsmError> {
+        if !compression_enabled() || !compress(page, &mut self.buf) {
+            return Ok(None);
+        }
+        let oom = |_| SvsmError::Alloc(AllocError::OutOfMemory);
+        self.entries.try_reserve(1).map_err(oom)?;
+        self.seals.try_reserve(1).map_err(oom)?;
+        let seal = seal(&mut self.buf)?;
+        let offset = self.end;
+        let mut written = 0;
+        while written < self.buf.len() {
+            let pos = offset + written;
+            if pos == self.arena.slots() * PAGE_SIZE {
+                self.arena.alloc_slot()?;
+            }
+            let slot = self.arena.page_mut(pos / PAGE_SIZE);
+            let start = pos % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - start).min(self.buf.len() - written);
+            slot[start..start + chunk].copy_from_slice(&self.buf[written..written + chunk]);
+            written += chunk;
+        }
+        self.entries.push(PackedEntry {
+            offset,
+            len: self.buf.len(),
+        });
+        self.seals.push(seal);
+        self.end += self.buf.len();
+        Ok(Some(self.entries.len() - 1))
+    }
+
+    /// Removes the most recently added entry.
+    pub fn pop(&mut self) -> Result<(), SvsmError> {
+        if let Some(entry) = self.entries.pop() {
+            self.seals.pop();
+            self.end = entry.offset;
+            while self.arena.slots() * PAGE_SIZE >= self.end + PAGE_SIZE {
+                self.arena.free_last_slot()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypts and decompresses entry `index` into `page`.
+    pub fn read(&self, index: usize, page: &mut [u8; PAGE_SIZE]) {
+        let entry = self.entries[index];
+        let first = entry.offset / PAGE_SIZE;
+        let last = (entry.offset + entry.len).div_ceil(PAGE_SIZE);
+        let sealed: Vec<u8> = (first..last)
+            .flat_map(|slot| {
+                let start = (slot * PAGE_SIZE).max(entry.offset) - slot * PAGE_SIZE;
+                let end = ((slot + 1) * PAGE_SIZE).min(entry.offset + entry.len) - slot * PAGE_SIZE;
+                self.arena.page(slot)[start..end].iter().copied()
+            })
+            .collect();
+        let mut bytes = vec![0u8; entry.len];
+        open(&sealed, &self.seals[index], &mut bytes);
+        unpack_page(&bytes, page);
+    }
+
+    /// Returns the number of compressed pages.
+    pub fn pages(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the size of all encodings in bytes.
+    pub fn bytes(&self) -> usize {
+        self.end
+    }
+
+    /// Returns the memory held by the store in bytes.
+    pub fn memory(&self) -> usize {
+        self.arena.slots() * PAGE_SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.arena.clear();
+        self.entries.clear();
+        self.seals.clear();
+        self.end = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(page: &[u8; PAGE_SIZE]) -> Option<usize> {
+        let mut out = Vec::new();
+        if !compress(page, &mut out) {
+            return None;
+        }
+        let mut decoded = [0xffu8; PAGE_SIZE];
+        unpack_page(&out, &mut decoded);
+        assert_eq!(decoded[..], page[..]);
+        Some(out.len())
+    }
+
+    #[test]
+    fn test_compress_runs() {
+        let mut page = [0x5au8; PAGE_SIZE];
+        page[100..110].copy_from_slice(b"0123456789");
+        page[2000] = 1;
+        page[4095] = 2;
+        let len = round_trip(&page).unwrap();
+        assert!(len < 128);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        let mut page = [0u8; PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i * 7 + i / 256) as u8;
+        }
+        assert_eq!(round_trip(&page), None);
+    }
+
+    #[test]
+    fn test_decompress_truncated() {
+        let mut page = [0xffu8; PAGE_SIZE];
+        unpack_page(&[0x81, 7, 5], &mut page);
+        assert!(page[..128].iter().all(|&b| b == 7));
+        assert!(page[128..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Encryption of backed-up pages at rest.
+//!
+//! A backup stays in SVSM memory for as long as it exists. To keep its
+//! contents confidential should SVSM memory ever be exposed, e.g. through
+//! a debug interface, every stored page, compressed or not, is encrypted
+//! with AES-256-GCM and only decrypted when it is read for a restore, an
+//! export or a check.
+//!
+//! The key is ephemeral: it is derived from VMPCK0 of the SNP secrets page
+//! and a random salt when the first page is stored and never leaves the
+//! SVSM. Nonces come from a counter, so none is reused under the key.
+//!
+//! Contents which fail authentication read as zeroes, so the page
+//! checksums detect them like any other corruption.
+
+use crate::crypto::aead::{Aes256Gcm, Aes256GcmTrait, AUTHTAG_SIZE, IV_SIZE, KEY_SIZE};
+use crate::crypto::digest::{Sha256, Sha256Trait};
+use crate::crypto::rng::fill_random;
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::sev::secrets_page::secrets_page;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Domain separation of the key derivation.
+const KEY_LABEL: &[u8] = b"svsm backup at rest";
+/// Size of the random salt of the key derivation.
+const SALT_SIZE: usize = 32;
+
+static KEY: SpinLock<Option<[u8; KEY_SIZE]>> = SpinLock::new(None);
+static NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// What is needed besides the key to decrypt sealed data.
+#[derive(Clone, Copy, Debug)]
+pub struct Seal {
+    nonce: u64,
+    tag: [u8; AUTHTAG_SIZE],
+}
+
+/// Returns the key, deriving it on first use. Fails with
+/// [`SvsmError::MissingSecrets`] if VMPCK0 is not available.
+fn key() -> Result<[u8; KEY_SIZE], SvsmError> {
+    let mut key = KEY.lock();
+    if let Some(key) = *key {
+        return Ok(key);
+    }
+    let secrets = secrets_page();
+    if secrets.is_vmpck_clear(0) {
+        return Err(SvsmError::MissingSecrets);
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    fill_random(&mut salt)?;
+    let mut hash = Sha256::new();
+    hash.update(KEY_LABEL);
+    hash.update(&secrets.get_vmpck(0));
+    hash.update(&salt);
+    let derived = hash.finalize();
+    *key = Some(derived);
+    Ok(derived)
+}
+
+fn iv(nonce: u64) -> [u8; IV_SIZE] {
+    let mut iv = [0u8; IV_SIZE];
+    iv[..8].copy_from_slice(&nonce.to_le_bytes());
+    iv
+}
+
+/// Encrypts `data` in place and returns the seal to decrypt it with.
+pub fn seal(data: &mut [u8]) -> Result<Seal, SvsmError> {
+    let key = key()?;
+    let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+    let mut out = vec![0u8; data.len() + AUTHTAG_SIZE];
+    // Only fails for inputs beyond the length limit of GCM.
+    Aes256Gcm::encrypt(&iv(nonce), &key, &[], data, &mut out)
+        .map_err(|_| SvsmError::InvalidBytes)?;
+    let (cipher, tag) = out.split_at(data.len());
+    data.copy_from_slice(cipher);
+    let mut seal = Seal {
+        nonce,
+        tag: [0; AUTHTAG_SIZE],
+    };
+    seal.tag.copy_from_slice(tag);
+    Ok(seal)
+}
+
+/// Decrypts `data` sealed with `seal` into `out`, which must be as long.
+/// Returns false and zeroes `out` if the data fails authentication.
+pub fn open(data: &[u8], seal: &Seal, out: &mut [u8]) -> bool {
+    let mut input = Vec::with_capacity(data.len() + AUTHTAG_SIZE);
+    input.extend_from_slice(data);
+    input.extend_from_slice(&seal.tag);
+    let opened =
+        key().is_ok_and(|key| Aes256Gcm::decrypt(&iv(seal.nonce), &key, &[], &input, out).is_ok());
+    if !opened {
+        log::error!(
+            "Backup data with nonce {:#x} failed authentication",
+            seal.nonce
+        );
+        out.fill(0);
+    }
+    opened
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Incremental backups.
+//!
+//! With copy-on-write enabled, the pages written since the last checkpoint
+//! are known (see the `dirty` module), so a new checkpoint only needs to
+//! copy those. Every incremental backup stores them in a delta layer on top
+//! of the base backup. A full restore applies the base and then the layers
+//! in order, a partial restore rewinds dirty pages to their newest version.
+//! Reading, remapping and exporting snapshot pages only use the base
+//! backup.
+//!
+//! Every dirty page is write-protected again before it is copied, so a
+//! write racing with the copy is tracked for the next checkpoint instead of
+//! being lost.
+
+use super::budget::{admit_backup, copy_error, estimate_backup_cost, SnapshotCharge};
+use super::dirty::{dirty_pages, mark_dirty, pages_4k, protect_clean};
+use super::errors::no_backup;
+use super::paranoid::check_rmp_state;
+use super::report::PageOutcome;
+use super::stats::record_shared_pages;
+use super::store::SharedPages;
+use super::tracking::{check_backup_address, excluded, guest_private};
+use super::verbosity::detail;
+use super::watchdog::cow_active;
+use super::{copy_guest_page, restore_page, zero_page, BackupPages, BACKUP_CREATED};
+use crate::address::PhysAddr;
+use crate::health::{set_backup_state, BackupState};
+use crate::locking::SpinLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::{PageSize, PAGE_SIZE};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Maximum number of delta layers on top of a base backup.
+const MAX_DELTA_LAYERS: usize = 32;
+
+/// The pages saved by one incremental backup.
+#[derive(Clone)]
+pub(super) struct DeltaLayer {
+    pages: SharedPages,
+    /// Pages which were zero, sorted.
+    zero_pages: Vec<PhysAddr>,
+}
+
+impl DeltaLayer {
+    const fn new() -> Self {
+        Self {
+            pages: SharedPages::new(),
+            zero_pages: Vec::new(),
+        }
+    }
+
+    /// Returns the data pages of the layer.
+    pub(super) fn pages(&self) -> &BackupPages {
+        &self.pages
+    }
+
+    /// Returns the pages of the layer which were zero, sorted.
+    pub(super) fn zero_pages(&self) -> &[PhysAddr] {
+        &self.zero_pages
+    }
+
+    /// Returns whether the page at `paddr` is part of the layer.
+    pub(super) fn contains(&self, paddr: PhysAddr) -> bool {
+        self.pages.contains(paddr) || self.zero_pages.binary_search(&paddr).is_ok()
+    }
+
+    /// Restores the version of the page at `paddr` saved in this layer,
+    /// which must contain it. Data is decompressed into `scratch` if needed
+    /// and verified against its checksum.
+    pub(super) fn restore(
+        &self,
+        paddr: PhysAddr,
+        scratch: &mut [u8; PAGE_SIZE],
+    ) -> Result<PageOutcome, SvsmReqError> {
+        match self.pages.checked_lookup(paddr, scratch)? {
+            Some(data) => restore_page(paddr, data).map_err(SvsmReqError::from_mapping),
+            None => zero_page(paddr).map_err(SvsmReqError::from_mapping),
+        }
+    }
+
+    /// Copies the registered page at `paddr` of `size` into the layer,
+    /// charging the memory to `charge`. 4K pages which are no longer
+    /// private guest memory or are excluded are skipped.
+    fn save(
+        &mut self,
+        paddr: PhysAddr,
+        size: PageSize,
+        charge: &mut SnapshotCharge,
+    ) -> Result<(), SvsmReqError> {
+        for paddr in pages_4k(paddr, size) {
+            if excluded(paddr) || !guest_private(paddr, PageSize::Regular).map_err(copy_error)? {
+                record_shared_pages(1);
+                continue;
+            }
+            charge.charge(PAGE_SIZE)?;
+            let pages = self.pages.get_mut().map_err(copy_error)?;
+            let memory = pages.memory();
+            if !copy_guest_page(pages, paddr).map_err(copy_error)? {
+                self.zero_pages.push(paddr);
+            }
+            let stored = pages.memory() - memory;
+            charge
+                .refund(PAGE_SIZE.saturating_sub(stored))
+                .map_err(SvsmReqError::from)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delta layers of the current backup, oldest first.
+#[derive(Clone, Default)]
+pub(super) struct DeltaLayers(Vec<DeltaLayer>);
+
+impl DeltaLayers {
+    /// Returns the layers, newest first.
+    pub(super) fn newest_first(&self) -> impl Iterator<Item = &DeltaLayer> {
+        self.0.iter().rev()
+    }
+}
+
+/// Lock order: after `BACKUP_PAGES`, before `ZERO_PAGES`.
+pub(super) static DELTA_LAYERS: SpinLock<DeltaLayers> = SpinLock::new(DeltaLayers(Vec::new()));
+
+/// Drops the delta layers of a discarded backup.
+pub fn discard_delta_layers() {
+    DELTA_LAYERS.lock().0.clear();
+}
+
+/// Returns whether the current backup has no room for another delta layer.
+pub(super) fn delta_layers_full() -> bool {
+    DELTA_LAYERS.lock().0.len() >= MAX_DELTA_LAYERS
+}
+
+/// Takes the delta layers of the current backup.
+pub(super) fn take_delta_layers() -> DeltaLayers {
+    core::mem::take(&mut *DELTA_LAYERS.lock())
+}
+
+/// Makes `layers` the delta layers of the current backup.
+pub(super) fn put_delta_layers(layers: DeltaLayers) {
+    *DELTA_LAYERS.lock() = layers;
+}
+
+/// Protects and copies the dirty pages into `layer`. On failure the pages
+/// not saved are marked dirty again.
+fn save_dirty_pages(
+    layer: &mut DeltaLayer,
+    charge: &mut SnapshotCharge,
+) -> Result<(), SvsmReqError> {
+    let dirty: Vec<(PhysAddr, PageSize)> = dirty_pages().collect();
+    for (i, &(paddr, size)) in dirty.iter().enumerate() {
+        let result = protect_clean(paddr, size).and_then(|_| layer.save(paddr, size, charge));
+        if let Err(err) = result {
+            for &(paddr, size) in dirty[i..].iter() {
+                mark_dirty(paddr, size);
+            }
+            return Err(err);
+        }
+    }
+    layer.zero_pages.sort_unstable();
+    Ok(())
+}
+
+/// Saves the pages written since the last checkpoint in a new delta layer.
+/// Fails with `SVSM_ERR_NO_BACKUP` if there is no backup and with
+/// INVALID_REQUEST if copy-on-write is not enabled or there are too many
+/// layers. `rdx` holds the number of pages copied and `r8` the number of
+/// zero pages, also if saving them failed, and on success `rcx` holds the
+/// number of layers. Fails with `SVSM_ERR_BACKUP_OVER_BUDGET` if the dirty pages are not
+/// expected to fit into the budget, or did not.
+pub fn incremental_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    if !cow_active() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let mut layers = DELTA_LAYERS.lock();
+    if layers.0.len() >= MAX_DELTA_LAYERS {
+        return Err(SvsmReqError::invalid_request());
+    }
+
+    let dirty: usize = dirty_pages().map(|(_, size)| usize::from(size)).sum();
+    admit_backup(estimate_backup_cost(dirty))?;
+
+    set_backup_state(BackupState::BackingUp);
+    let mut layer = DeltaLayer::new();
+    let mut charge = SnapshotCharge::new();
+    let result = save_dirty_pages(&mut layer, &mut charge);
+    set_backup_state(BackupState::CopyOnWrite);
+    params.rdx = layer.pages.len() as u64;
+    params.r8 = layer.zero_pages.len() as u64;
+    if let Err(err) = result {
+        log::info!(
+            "Incremental backup failed after {} pages",
+            params.rdx + params.r8
+        );
+        charge.cancel();
+        return Err(err);
+    }
+    log::info!(
+        "Incremental backup: {} pages copied, {} zero pages",
+        params.rdx,
+        params.r8
+    );
+    layers.0.push(layer);
+    params.rcx = layers.0.len() as u64;
+    Ok(())
+}
+
+/// Applies the delta layers on top of the restored base backup, oldest
+/// first.
+pub(super) fn restore_delta_layers(scratch: &mut [u8; PAGE_SIZE]) -> Result<(), SvsmReqError> {
+    let layers = DELTA_LAYERS.lock();
+    for (i, layer) in layers.0.iter().enumerate() {
+        let mut applied = 0;
+        for paddr in layer.pages.pages().map(|page| page.phys_addr) {
+            applied += apply_page(layer, paddr, scratch)?;
+        }
+        for &paddr in layer.zero_pages.iter() {
+            applied += apply_page(layer, paddr, scratch)?;
+        }
+        detail!("Applied delta layer {}: {} pages", i + 1, applied);
+    }
+    Ok(())
+}
+
+/// Restores the page at `paddr` from `layer`. Returns 1 if it was written.
+fn apply_page(
+    layer: &DeltaLayer,
+    paddr: PhysAddr,
+    scratch: &mut [u8; PAGE_SIZE],
+) -> Result<usize, SvsmReqError> {
+    check_backup_address(paddr, PageSize::Regular)?;
+    match layer.restore(paddr, scratch)? {
+        PageOutcome::Skipped => Ok(0),
+        _ => {
+            check_rmp_state(paddr)?;
+            Ok(1)
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Emulated device state saved with a backup.
+//!
+//! State the SVSM emulates for the guest must be rolled back together with
+//! guest memory, or the restored guest finds its devices out of sync.
+//! Besides the vTPM, whose state is handled according to its restore policy
+//! (see the `vtpm` module), subsystems register [`DeviceHooks`] once at
+//! initialization. The state they return is saved with every backup,
+//! handed back to them by every restore and parked with named snapshots.
+//! Devices registered after a backup was taken are left alone by its
+//! restore.
+
+use super::budget::copy_error;
+use crate::error::SvsmError;
+use crate::locking::{RWLock, SpinLock};
+use crate::protocols::errors::SvsmReqError;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Backup hooks of an emulated device.
+#[derive(Debug)]
+pub struct DeviceHooks {
+    /// Name of the device, for log messages.
+    pub name: &'static str,
+    /// Returns the current state of the device.
+    pub snapshot: fn() -> Result<Vec<u8>, SvsmError>,
+    /// Rolls the device back to a state returned by `snapshot`.
+    pub restore: fn(&[u8]) -> Result<(), SvsmError>,
+}
+
+/// Device state saved with a backup which is not the current one.
+#[derive(Clone, Debug, Default)]
+pub struct SavedDevices {
+    states: Vec<(&'static DeviceHooks, Vec<u8>)>,
+}
+
+static HOOKS: RWLock<Vec<&'static DeviceHooks>> = RWLock::new(Vec::new());
+static SAVED_DEVICES: SpinLock<SavedDevices> = SpinLock::new(SavedDevices { states: Vec::new() });
+
+/// Registers the backup hooks of an emulated device.
+pub fn register_device_hooks(hooks: &'static DeviceHooks) {
+    HOOKS.lock_write().push(hooks);
+    log::info!("Registered backup hooks of {}", hooks.name);
+}
+
+/// Saves the state of every registered device with a new backup.
+pub fn snapshot_devices() -> Result<(), SvsmReqError> {
+    let mut states = Vec::new();
+    for &hooks in HOOKS.lock_read().iter() {
+        let state = (hooks.snapshot)().map_err(|e| {
+            log::error!("Failed to save the state of {}: {:?}", hooks.name, e);
+            copy_error(e)
+        })?;
+        states.push((hooks, state));
+    }
+    *SAVED_DEVICES.lock() = SavedDevices { states };
+    Ok(())
+}
+
+/// Rolls every device back to the state saved with the current backup.
+pub fn restore_devices() -> Result<(), SvsmReqError> {
+    let saved = SAVED_DEVICES.lock();
+    for (hooks, state) in saved.states.iter() {
+        (hooks.restore)(state).map_err(|e| {
+            log::error!("Failed to restore the state of {}: {:?}", hooks.name, e);
+            SvsmReqError::from_mapping(e)
+        })?;
+    }
+    Ok(())
+}
+
+/// Drops the device state saved with a discarded backup.
+pub fn discard_devices() {
+    *SAVED_DEVICES.lock() = SavedDevices::default();
+}
+
+/// Takes the device state saved with the current backup.
+pub fn take_saved_devices() -> SavedDevices {
+    core::mem::take(&mut *SAVED_DEVICES.lock())
+}
+
+/// Makes `saved` the device state saved with the current backup.
+pub fn put_saved_devices(saved: SavedDevices) {
+    *SAVED_DEVICES.lock() = saved;
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Comparison of guest memory with the backup.
+//!
+//! `SVSM_DIFF_BACKUP` compares every page saved in the backup with the
+//! current contents of guest memory, without changing either. Right after a
+//! restore no page should differ, and with copy-on-write enabled only the
+//! dirty pages should, which makes the call a check of the restore paths
+//! and of the write tracking. Every page is compared with its newest saved
+//! version, i.e. the one a full restore writes back. Pages a restore skips
+//! are not compared, nor are pages a lazy backup has not copied yet, since
+//! they cannot have changed.
+
+use super::delta::DELTA_LAYERS;
+use super::errors::no_backup;
+use super::tracking::excluded;
+use super::{BACKUP_CREATED, BACKUP_PAGES, ZERO_PAGES};
+use crate::address::PhysAddr;
+use crate::fw_protect::fw_page_protected;
+use crate::mm::copy::is_zeroed;
+use crate::mm::guestmem::read_phys_page;
+use crate::mm::{allocate_file_page_ref, writable_phys_addr};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::task::preemption_point;
+use crate::types::PAGE_SIZE;
+use core::mem::size_of;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Newest saved version of a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Saved {
+    /// Data, decrypted into the scratch page.
+    Data,
+    /// All zeroes.
+    Zero,
+}
+
+/// Returns the addresses of all pages saved in the backup, sorted.
+fn saved_pages() -> Vec<PhysAddr> {
+    let backup = BACKUP_PAGES.lock_read();
+    let layers = DELTA_LAYERS.lock();
+    let mut pages: Vec<PhysAddr> = backup.pages().map(|page| page.phys_addr).collect();
+    pages.extend_from_slice(&ZERO_PAGES.lock_read());
+    for layer in layers.newest_first() {
+        pages.extend(layer.pages().pages().map(|page| page.phys_addr));
+        pages.extend_from_slice(layer.zero_pages());
+    }
+    pages.sort_unstable();
+    pages.dedup();
+    pages
+}
+
+/// Looks up the newest saved version of the page at `paddr`, which must be
+/// saved in the backup. Data is decrypted into `scratch` and checked
+/// against its checksum.
+fn newest_version(paddr: PhysAddr, scratch: &mut [u8; PAGE_SIZE]) -> Result<Saved, SvsmReqError> {
+    let backup = BACKUP_PAGES.lock_read();
+    let layers = DELTA_LAYERS.lock();
+    if let Some(layer) = layers.newest_first().find(|l| l.contains(paddr)) {
+        return Ok(match layer.pages().checked_lookup(paddr, scratch)? {
+            Some(_) => Saved::Data,
+            None => Saved::Zero,
+        });
+    }
+    Ok(match backup.checked_lookup(paddr, scratch)? {
+        Some(_) => Saved::Data,
+        None => Saved::Zero,
+    })
+}
+
+/// Compares guest memory with the backup. The guest buffer at `rcx` of
+/// size `rdx` receives the addresses of the first differing pages in
+/// ascending order, as 64-bit values; a size of 0 only counts them. On
+/// success `rcx` holds the number of differing pages, `rdx` the number of
+/// pages compared and `r8` the number of addresses written. Pages which
+/// cannot be read count as differing. Fails with `SVSM_ERR_NO_BACKUP` if
+/// there is no backup and with `SVSM_ERR_BACKUP_CORRUPTED` if a saved page
+/// fails its checksum.
+pub fn diff_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let buffer = match len {
+        0 => None,
+        _ => Some(GuestBuffer::new(PhysAddr::from(params.rcx), len)?),
+    };
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+
+    let pages = saved_pages();
+    let mut saved = allocate_file_page_ref()?;
+    let mut current = allocate_file_page_ref()?;
+    let capacity = len / size_of::<u64>();
+    let (mut differing, mut compared, mut written) = (0u64, 0u64, 0usize);
+    for paddr in pages {
+        if !writable_phys_addr(paddr) || fw_page_protected(paddr) || excluded(paddr) {
+            continue;
+        }
+        let version = newest_version(paddr, saved.as_mut())?;
+        compared += 1;
+        let same = match read_phys_page(paddr, current.as_mut()) {
+            Ok(()) => match version {
+                Saved::Data => saved.as_ref() == current.as_ref(),
+                Saved::Zero => is_zeroed(current.as_ref()),
+            },
+            Err(e) => {
+                log::warn!("Failed to read page {:#x}: {:?}", paddr, e);
+                false
+            }
+        };
+        if !same {
+            differing += 1;
+            if let Some(buffer) = buffer.as_ref().filter(|_| written < capacity) {
+                buffer
+                    .write(
+                        (written * size_of::<u64>()) as u64,
+                        &u64::from(paddr).to_le_bytes(),
+                    )
+                    .map_err(SvsmReqError::from_mapping)?;
+                written += 1;
+            }
+        }
+        preemption_point();
+    }
+
+    log::info!(
+        "Compared {} pages with the backup, {} differ",
+        compared,
+        differing
+    );
+    params.rcx = differing;
+    params.rdx = compared;
+    params.r8 = written as u64;
+    Ok(())
+}
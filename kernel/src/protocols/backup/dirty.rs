@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Tracking of the registered pages the guest writes under copy-on-write.
+//!
+//! Enabling copy-on-write makes every registered page read-only for the
+//! guest in the RMP. A guest write to such a page does not raise an
+//! exception in the SVSM: the RMP check fails at the guest's VMPL, the
+//! resulting nested page fault exits to the hypervisor and is reflected
+//! into the guest, so the SVSM #VC and #PF handlers never see it. The guest
+//! driver forwards the fault with `SVSM_RESOLVE_COW_FAULT` instead, and
+//! [`track_write`] records the page before write access is restored. Since
+//! a page stays writable until it is protected again, the first write is
+//! the only one which needs to be seen.
+//!
+//! The dirty set is what partial restores rewind and what incremental
+//! backups have to copy.
+
+use super::errors::rmp_failed;
+use super::{set_read_only, PAGES_TO_BACKUP};
+use crate::address::{Address, PhysAddr};
+use crate::mm::set::PageSet;
+use crate::protocols::errors::SvsmReqError;
+use crate::sev::rmp::{rmp_set_guest_access_paddr, GuestAccess};
+use crate::task::preemption_point;
+use crate::types::{PageSize, PAGE_SIZE};
+
+/// Registered pages written by the guest since copy-on-write protection was
+/// last applied to them.
+static DIRTY_PAGES: PageSet = PageSet::new();
+
+/// Returns the registered page containing the guest page at `paddr`.
+pub fn registered_page(paddr: PhysAddr) -> Option<(PhysAddr, PageSize)> {
+    let paddr = paddr.page_align();
+    if PAGES_TO_BACKUP.contains_addr(paddr, PageSize::Regular) {
+        Some((paddr, PageSize::Regular))
+    } else if PAGES_TO_BACKUP.contains_addr(paddr.page_align_2m(), PageSize::Huge) {
+        Some((paddr.page_align_2m(), PageSize::Huge))
+    } else {
+        None
+    }
+}
+
+/// Returns the 4K pages of the registered page at `paddr` of `size`.
+pub fn pages_4k(paddr: PhysAddr, size: PageSize) -> impl Iterator<Item = PhysAddr> {
+    (0..usize::from(size) / PAGE_SIZE).map(move |i| paddr + i * PAGE_SIZE)
+}
+
+/// Records a guest write fault at `paddr` and makes the whole registered
+/// page containing it writable, i.e. 2M for huge pages. Returns the
+/// registered page. Fails with INVALID_PARAMETER if the page is not
+/// registered for backups.
+pub fn track_write(paddr: PhysAddr) -> Result<(PhysAddr, PageSize), SvsmReqError> {
+    let (page, size) = registered_page(paddr).ok_or_else(SvsmReqError::invalid_parameter)?;
+    // Record the page first, so it cannot be written without being tracked.
+    DIRTY_PAGES.insert_addr(page, size);
+    if let Err(err) = rmp_set_guest_access_paddr(page, size, GuestAccess::ReadWrite) {
+        DIRTY_PAGES.remove_addr(page, size);
+        return Err(rmp_failed(err));
+    }
+    Ok((page, size))
+}
+
+/// Write-protects the dirty registered page at `paddr` again and marks it
+/// clean.
+pub fn protect_clean(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmReqError> {
+    set_read_only(paddr, size).map_err(rmp_failed)?;
+    DIRTY_PAGES.remove_addr(paddr, size);
+    Ok(())
+}
+
+/// Write-protects all dirty registered pages again and marks them clean,
+/// after a restore rewrote them with their checkpointed contents. Returns
+/// the number of pages protected.
+pub fn rearm_dirty_pages() -> Result<usize, SvsmReqError> {
+    let mut rearmed = 0;
+    for (paddr, size) in DIRTY_PAGES.iter_addresses() {
+        protect_clean(paddr, size)?;
+        rearmed += 1;
+        preemption_point();
+    }
+    Ok(rearmed)
+}
+
+/// Marks the registered page at `paddr` dirty again, e.g. after it was
+/// protected but its contents could not be saved. Its protection is left
+/// as it is.
+pub fn mark_dirty(paddr: PhysAddr, size: PageSize) {
+    DIRTY_PAGES.insert_addr(paddr, size);
+}
+
+/// Makes all registered pages writable for the guest again after the dirty
+/// ones were rewound, and marks them clean. Returns the number of 4K pages
+/// which were still protected.
+pub fn release_registered_pages() -> Result<u64, SvsmReqError> {
+    let mut released = 0;
+    for (paddr, size) in PAGES_TO_BACKUP.iter_addresses() {
+        if DIRTY_PAGES.contains_addr(paddr, size) {
+            continue;
+        }
+        rmp_set_guest_access_paddr(paddr, size, GuestAccess::ReadWrite).map_err(rmp_failed)?;
+        released += (usize::from(size) / PAGE_SIZE) as u64;
+    }
+    reset_dirty_pages();
+    Ok(released)
+}
+
+/// Returns the dirty registered pages in ascending address order.
+pub fn dirty_pages() -> impl Iterator<Item = (PhysAddr, PageSize)> {
+    DIRTY_PAGES.iter_addresses()
+}
+
+/// Returns the number of dirty registered pages.
+pub fn dirty_page_count() -> usize {
+    DIRTY_PAGES.size()
+}
+
+/// Marks all pages clean, after all registered pages were protected or
+/// the backup was discarded.
+pub fn reset_dirty_pages() {
+    DIRTY_PAGES.clear();
+}
@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Error codes of the backup protocol.
+//!
+//! Malformed requests fail with the generic SVSM result codes. Failures
+//! specific to backups are reported with codes from the protocol range, so
+//! the guest driver can tell them apart:
+//!
+//! * `0x100` [`SVSM_ERR_BACKUP_OVER_BUDGET`]: the snapshot does not fit
+//!   into its memory budget or SVSM memory ran out,
+//! * `0x101` [`SVSM_ERR_PARANOID_CHECK_FAILED`]: a paranoid check failed,
+//! * `0x102` [`SVSM_ERR_BACKUP_FLUSH_FAILED`]: restored translations could
+//!   not be flushed,
+//! * `0x103` [`SVSM_ERR_BACKUP_CORRUPTED`]: snapshot data failed its checks,
+//! * `0x104` [`SVSM_ERR_BACKUP_EXISTS`]: the call needs no backup to exist,
+//! * `0x105` [`SVSM_ERR_NO_BACKUP`]: the call needs a backup,
+//! * `0x106` [`SVSM_ERR_BACKUP_RMP_FAILED`]: updating the RMP access of a
+//!   guest page failed,
+//! * `0x107` [`SVSM_ERR_BACKUP_NOT_PRIVATE`]: a page is not private guest
+//!   memory,
+//! * `0x108` [`SVSM_ERR_BACKUP_BAD_ADDRESS`]: a page is beyond the guest
+//!   physical address width, owned by the SVSM or not guest RAM, e.g.
+//!   MMIO.
+//!
+//! Calls which process many pages report their progress in a register even
+//! if they fail, see the individual calls.
+
+#[cfg(doc)]
+use super::{
+    budget::SVSM_ERR_BACKUP_OVER_BUDGET, checksum::SVSM_ERR_BACKUP_CORRUPTED,
+    paranoid::SVSM_ERR_PARANOID_CHECK_FAILED, shootdown::SVSM_ERR_BACKUP_FLUSH_FAILED,
+};
+use crate::error::SvsmError;
+use crate::protocols::errors::SvsmReqError;
+
+/// Error returned to the guest when a backup already exists.
+pub const SVSM_ERR_BACKUP_EXISTS: u64 = 0x104;
+/// Error returned to the guest when there is no backup.
+pub const SVSM_ERR_NO_BACKUP: u64 = 0x105;
+/// Error returned to the guest when the RMP access of a page could not be
+/// changed.
+pub const SVSM_ERR_BACKUP_RMP_FAILED: u64 = 0x106;
+/// Error returned to the guest when a page is not private guest memory.
+pub const SVSM_ERR_BACKUP_NOT_PRIVATE: u64 = 0x107;
+/// Error returned to the guest when a page can never be backed up.
+pub const SVSM_ERR_BACKUP_BAD_ADDRESS: u64 = 0x108;
+
+pub fn backup_exists() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_EXISTS)
+}
+
+pub fn no_backup() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_NO_BACKUP)
+}
+
+pub fn not_private() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_NOT_PRIVATE)
+}
+
+pub fn bad_address() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_BAD_ADDRESS)
+}
+
+/// Converts an error from changing the RMP access of a guest page. Invalid
+/// addresses are still reported like [`SvsmReqError::from_mapping`] does.
+pub fn rmp_failed(err: SvsmError) -> SvsmReqError {
+    match err {
+        SvsmError::InvalidAddress => SvsmReqError::invalid_parameter(),
+        _ => SvsmReqError::protocol(SVSM_ERR_BACKUP_RMP_FAILED),
+    }
+}
@@ -0,0 +1,1258 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Export and import of the backup state as a snapshot container.
+//!
+//! The container format is defined by the `snapshot` crate. The container
+//! is written to and read from a guest-supplied buffer one page at a time,
+//! so no copy of the whole container is ever held in SVSM memory.
+//!
+//! Once the guest has set an export key with `SVSM_SET_EXPORT_KEY`, the
+//! payload section is encrypted and authenticated under it as defined by
+//! the container format, so the host can persist the container, e.g. to
+//! disk on another node, without learning or undetectably changing guest
+//! memory. Import and verification then require the same key and reject
+//! any record which fails authentication. Without a key the payload is
+//! written in plain, which is only allowed into guest buffers.
+//!
+//! With backup compression enabled, the payload section is compressed as
+//! well: every page is stored as a record holding its PackBits encoding,
+//! which is then encrypted if an export key is set. Import and
+//! verification accept compressed payloads regardless of the setting.
+//!
+//! Every section carries the SHA-256 digest of its stored contents, which
+//! import and verification check before they decode the section.
+
+use super::budget::{copy_error, SnapshotCharge};
+use super::compress::compression_enabled;
+use super::errors::no_backup;
+use super::lazy::settle_lazy_backup;
+use super::metadata::record_metadata;
+use super::policy::snapshot_approved;
+use super::stats::backup_stats;
+use super::tracking::excluded;
+use super::vtpm::manifest_vtpm_policy;
+use super::{discard_backup_pages, BackupPages, BACKUP_CREATED, BACKUP_PAGES, ZERO_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::checked_invariant;
+use crate::cpu::cpuid::cpuid_table_bytes;
+use crate::crypto::aead::{Aes256Gcm, Aes256GcmTrait, KEY_SIZE};
+use crate::crypto::digest::{Sha256, Sha256Trait, SHA256_SIZE};
+use crate::crypto::rng::fill_random;
+use crate::error::SvsmError;
+use crate::fw_protect::fw_page_protected;
+use crate::health::{set_backup_state, BackupState};
+use crate::locking::SpinLock;
+use crate::mm::alloc::AllocError;
+use crate::mm::{allocate_file_page_ref, valid_phys_address, writable_phys_addr};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::scratch::scratch_export_window;
+use crate::types::PAGE_SIZE;
+use crate::utils::checksum::Crc32c;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use core::cmp::min;
+use core::fmt;
+use core::mem::size_of;
+use snapshot::{
+    compressed_record_range, encrypted_nonce, encrypted_record_offset, encrypted_section_length,
+    pack_page, record_table_offset, section_flags, section_kind, unpack_record, vtpm_policy,
+    ContainerLayout, DigestAlgorithm, Extent, ExtentKind, FormatError, Section, SnapshotHeader,
+    AUTH_TAG_SIZE, DIGEST_SIZE, EXTENT_ENTRY_SIZE, HEADER_SIZE, NONCE_PREFIX_SIZE,
+    RECORD_ENTRY_SIZE, SECTION_ENTRY_SIZE, STATS_SIZE,
+};
+
+/// Index of the payload section written by [`export_snapshot`].
+const PAYLOAD_SECTION: u16 = 0;
+/// Index of the CPUID section written by [`export_snapshot`].
+const CPUID_SECTION: u16 = 1;
+/// Index of the vTPM policy section written by [`export_snapshot`].
+const VTPM_SECTION: u16 = 2;
+/// Index of the statistics section written by [`export_snapshot`].
+const STATS_SECTION: u16 = 3;
+/// Number of sections written by [`export_snapshot`].
+const SECTION_COUNT: u32 = 4;
+
+/// Export chunk flag: write the chunk into the export window of the shared
+/// scratch region instead of a guest window.
+const EXPORT_CHUNK_TO_SCRATCH: u64 = 1 << 0;
+const EXPORT_CHUNK_FLAGS: u64 = EXPORT_CHUNK_TO_SCRATCH;
+
+/// Size of a record of an encrypted payload section.
+const RECORD_SIZE: usize = PAGE_SIZE + AUTH_TAG_SIZE;
+
+/// Key set by the guest for encrypting exported payloads.
+static EXPORT_KEY: SpinLock<Option<[u8; KEY_SIZE]>> = SpinLock::new(None);
+
+/// Key and nonce prefix of an encrypted payload section.
+#[derive(Clone, Copy)]
+struct PayloadCipher {
+    key: [u8; KEY_SIZE],
+    prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadCipher")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PayloadCipher {
+    /// Returns a cipher with a fresh nonce prefix if the guest set an
+    /// export key.
+    fn for_export() -> Result<Option<Self>, SvsmError> {
+        let Some(key) = *EXPORT_KEY.lock() else {
+            return Ok(None);
+        };
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        fill_random(&mut prefix)?;
+        Ok(Some(Self { key, prefix }))
+    }
+
+    /// Returns the cipher of the encrypted payload section `section` of the
+    /// container in `buffer`. Fails with INVALID_REQUEST if the guest set no
+    /// export key.
+    fn for_import(buffer: &GuestBuffer, section: &Section) -> Result<Self, SvsmReqError> {
+        let key = EXPORT_KEY.lock().ok_or_else(|| {
+            log::info!("Rejecting encrypted snapshot: no export key set");
+            SvsmReqError::invalid_request()
+        })?;
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        if section.length < prefix.len() as u64 {
+            return Err(format_error(FormatError::Truncated));
+        }
+        buffer
+            .read(section.offset, &mut prefix)
+            .map_err(|_| format_error(FormatError::OutOfBounds))?;
+        Ok(Self { key, prefix })
+    }
+
+    /// Encrypts payload page `record` into `out`.
+    fn encrypt(&self, record: u64, data: &[u8], out: &mut [u8]) -> Result<(), SvsmError> {
+        let nonce = encrypted_nonce(&self.prefix, record).ok_or(SvsmError::InvalidBytes)?;
+        Aes256Gcm::encrypt(&nonce, &self.key, &[], data, out)
+            .map_err(|_| SvsmError::InvalidBytes)?;
+        Ok(())
+    }
+
+    /// Decrypts record `record` into `data`. Fails with INVALID_FORMAT if
+    /// the record fails authentication.
+    fn decrypt(&self, record: u64, stored: &[u8], data: &mut [u8]) -> Result<(), SvsmReqError> {
+        let nonce =
+            encrypted_nonce(&self.prefix, record).ok_or_else(SvsmReqError::invalid_format)?;
+        Aes256Gcm::decrypt(&nonce, &self.key, &[], stored, data).map_err(|_| {
+            log::info!(
+                "Rejecting snapshot: payload record {} failed authentication",
+                record
+            );
+            SvsmReqError::invalid_format()
+        })?;
+        Ok(())
+    }
+}
+
+/// Location of the contents of a data page in a container.
+#[derive(Clone, Copy, Debug)]
+struct PayloadPage {
+    /// Offset of the page, or of its record if the payload is encrypted or
+    /// compressed.
+    offset: u64,
+    /// Stored length of the page or record.
+    len: usize,
+    /// Whether the page is stored as a record of a compressed payload.
+    compressed: bool,
+    /// Cipher of the payload section and index of the record, if the
+    /// payload is encrypted.
+    record: Option<(PayloadCipher, u64)>,
+}
+
+impl PayloadPage {
+    /// Reads the contents of the page from `buffer` into `data`,
+    /// decrypting and decompressing them as needed.
+    fn read(&self, buffer: &GuestBuffer, data: &mut [u8; PAGE_SIZE]) -> Result<(), SvsmReqError> {
+        if self.record.is_none() && !self.compressed {
+            return buffer
+                .read(self.offset, &mut data[..])
+                .map_err(|_| format_error(FormatError::OutOfBounds));
+        }
+        let mut stored = vec![0u8; self.len];
+        buffer
+            .read(self.offset, &mut stored)
+            .map_err(|_| format_error(FormatError::OutOfBounds))?;
+        self.decode(stored, data)
+    }
+
+    /// Decodes the stored record `stored` of the page into `data`.
+    fn decode(&self, stored: Vec<u8>, data: &mut [u8; PAGE_SIZE]) -> Result<(), SvsmReqError> {
+        let plain = match self.record {
+            Some((cipher, record)) => {
+                // Checked in locate_page()
+                let mut plain = vec![0u8; self.len - AUTH_TAG_SIZE];
+                cipher.decrypt(record, &stored, &mut plain)?;
+                plain
+            }
+            None => stored,
+        };
+        if self.compressed {
+            unpack_record(&plain, data);
+        } else {
+            data.copy_from_slice(&plain);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the record of a compressed payload section holding the page
+/// `data`: its PackBits encoding, encoded into `packed`, if that is shorter
+/// than a page, otherwise the page itself.
+fn pack_record<'a>(data: &'a [u8], packed: &'a mut [u8]) -> &'a [u8] {
+    match pack_page(data, &mut packed[..PAGE_SIZE - 1]) {
+        Some(len) => &packed[..len],
+        None => data,
+    }
+}
+
+/// Returns the record table of a compressed payload section holding the
+/// storage slots `payload`, with records sealed if `encrypted` is set.
+fn record_table(
+    backup: &BackupPages,
+    payload: &[usize],
+    encrypted: bool,
+) -> Result<Vec<u64>, SvsmError> {
+    let (flags, tag) = if encrypted {
+        (section_flags::ENCRYPTED, AUTH_TAG_SIZE)
+    } else {
+        (0, 0)
+    };
+    let mut table = Vec::new();
+    table
+        .try_reserve(payload.len() + 1)
+        .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+    let mut offset = record_table_offset(flags) + ((payload.len() + 1) * RECORD_ENTRY_SIZE) as u64;
+    table.push(offset);
+
+    let mut scratch = allocate_file_page_ref()?;
+    let mut packed = vec![0u8; PAGE_SIZE];
+    for &slot in payload {
+        let data = backup
+            .slot_data(slot, scratch.as_mut())
+            .ok_or(SvsmError::InvalidAddress)?;
+        offset += (pack_record(data, &mut packed).len() + tag) as u64;
+        table.push(offset);
+    }
+    Ok(table)
+}
+
+fn format_error(err: FormatError) -> SvsmReqError {
+    log::info!("Rejecting snapshot container: {}", err);
+    SvsmReqError::invalid_format()
+}
+
+/// Coalesces sorted page addresses into extents of contiguous pages.
+/// Data extents get consecutive offsets into the payload section.
+fn coalesce(pages: &[PhysAddr], kind: ExtentKind, extents: &mut Vec<Extent>) {
+    let mut payload_offset = 0u64;
+    for &paddr in pages {
+        let gpa = u64::from(paddr);
+        if let Some(last) = extents.last_mut() {
+            if last.kind == kind && last.gpa + last.page_count * PAGE_SIZE as u64 == gpa {
+                last.page_count += 1;
+                if kind == ExtentKind::Data {
+                    payload_offset += PAGE_SIZE as u64;
+                }
+                continue;
+            }
+        }
+        extents.push(Extent {
+            gpa,
+            page_count: 1,
+            kind,
+            section: PAYLOAD_SECTION,
+            payload_offset: if kind == ExtentKind::Data {
+                payload_offset
+            } else {
+                0
+            },
+        });
+        if kind == ExtentKind::Data {
+            payload_offset += PAGE_SIZE as u64;
+        }
+    }
+}
+
+/// Describes the container for the current backup. The container bytes
+/// are generated on demand from the plan and the backup pages, so any range
+/// of the container can be written without staging it in SVSM memory.
+#[derive(Debug)]
+struct ExportPlan {
+    extents: Vec<Extent>,
+    /// Storage slots of the backed-up pages in payload order.
+    payload: Vec<usize>,
+    layout: ContainerLayout,
+    /// Cipher of the payload section if it is encrypted.
+    cipher: Option<PayloadCipher>,
+    /// Record table of the payload section if it is compressed.
+    records: Option<Vec<u64>>,
+    /// End of the payload section, where the CPUID section starts.
+    payload_end: u64,
+    /// End of the CPUID section, where the vTPM policy section starts.
+    cpuid_end: u64,
+    /// Contents of the vTPM policy section.
+    vtpm_policy: [u8; 4],
+    /// End of the vTPM policy section, where the statistics section starts.
+    vtpm_end: u64,
+    /// Contents of the statistics section.
+    stats: [u8; STATS_SIZE],
+    /// Digests of the stored contents of the sections.
+    digests: [[u8; DIGEST_SIZE]; SECTION_COUNT as usize],
+    total_size: u64,
+}
+
+impl ExportPlan {
+    fn new(backup: &BackupPages, zero: &[PhysAddr]) -> Result<Self, SvsmReqError> {
+        // The index yields the pages in address order already.
+        let data_addrs: Vec<PhysAddr> = backup.pages().map(|page| page.phys_addr).collect();
+        let payload: Vec<usize> = backup.pages().map(|page| page.slot).collect();
+        let mut zero_addrs = zero.to_vec();
+        zero_addrs.sort_unstable();
+
+        let mut extents = Vec::new();
+        coalesce(&data_addrs, ExtentKind::Data, &mut extents);
+        coalesce(&zero_addrs, ExtentKind::Zero, &mut extents);
+        extents.sort_unstable_by_key(|e| e.gpa);
+
+        let extent_count =
+            u32::try_from(extents.len()).map_err(|_| SvsmReqError::invalid_request())?;
+        let layout = ContainerLayout::new(extent_count, SECTION_COUNT, PAGE_SIZE as u32);
+        let cipher = PayloadCipher::for_export()?;
+        let records = if compression_enabled() {
+            Some(record_table(backup, &payload, cipher.is_some())?)
+        } else {
+            None
+        };
+        let payload_len = match (&records, cipher) {
+            // The table holds at least the end of the records.
+            (Some(records), _) => records[records.len() - 1],
+            (None, None) => (payload.len() * PAGE_SIZE) as u64,
+            (None, Some(_)) => encrypted_section_length(payload.len() as u64, PAGE_SIZE as u32)
+                .ok_or_else(SvsmReqError::invalid_request)?,
+        };
+        let payload_end = layout.payload_offset + payload_len;
+        let cpuid_end = payload_end + cpuid_table_bytes().len() as u64;
+        let vtpm_policy = manifest_vtpm_policy().to_le_bytes();
+        let vtpm_end = cpuid_end + vtpm_policy.len() as u64;
+        let stats = backup_stats(backup, zero.len(), payload_len).to_bytes();
+        let total_size = vtpm_end + stats.len() as u64;
+        let mut plan = Self {
+            extents,
+            payload,
+            layout,
+            cipher,
+            records,
+            payload_end,
+            cpuid_end,
+            vtpm_policy,
+            vtpm_end,
+            stats,
+            digests: [[0; DIGEST_SIZE]; SECTION_COUNT as usize],
+            total_size,
+        };
+        // The section table precedes the sections, so the digests have to
+        // be known before the first byte is written.
+        for index in 0..SECTION_COUNT as u16 {
+            plan.digests[usize::from(index)] = plan.section_digest(backup, index)?;
+        }
+        Ok(plan)
+    }
+
+    fn extent_count(&self) -> u32 {
+        // Checked in ExportPlan::new()
+        self.extents.len() as u32
+    }
+
+    fn payload_flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.cipher.is_some() {
+            flags |= section_flags::ENCRYPTED;
+        }
+        if self.records.is_some() {
+            flags |= section_flags::COMPRESSED;
+        }
+        flags
+    }
+
+    fn section(&self, index: u16) -> Section {
+        let mut flags = 0;
+        let (kind, offset, end) = match index {
+            PAYLOAD_SECTION => {
+                flags = self.payload_flags();
+                (
+                    section_kind::PAYLOAD,
+                    self.layout.payload_offset,
+                    self.payload_end,
+                )
+            }
+            CPUID_SECTION => (section_kind::CPUID, self.payload_end, self.cpuid_end),
+            VTPM_SECTION => (section_kind::VTPM_POLICY, self.cpuid_end, self.vtpm_end),
+            STATS_SECTION => (section_kind::STATISTICS, self.vtpm_end, self.total_size),
+            _ => unreachable!(),
+        };
+        Section {
+            kind,
+            flags,
+            offset,
+            length: end - offset,
+            digest: self.digests[usize::from(index)],
+        }
+    }
+
+    /// Returns the contents of payload page `page`, decrypted into
+    /// `scratch`. Fails if they cannot be read back, e.g. from the spill
+    /// area.
+    fn payload_page<'a>(
+        &self,
+        backup: &'a BackupPages,
+        page: usize,
+        scratch: &'a mut [u8; PAGE_SIZE],
+    ) -> Result<&'a [u8], SvsmError> {
+        backup
+            .slot_data(self.payload[page], scratch)
+            .map(|data| &data[..])
+            .ok_or(SvsmError::InvalidAddress)
+    }
+
+    /// Returns record `page` of the compressed payload section, sealed into
+    /// `record` if the payload is encrypted.
+    fn compressed_record<'a>(
+        &self,
+        backup: &'a BackupPages,
+        page: usize,
+        scratch: &'a mut [u8; PAGE_SIZE],
+        packed: &'a mut [u8],
+        record: &'a mut [u8],
+    ) -> Result<&'a [u8], SvsmError> {
+        let data = self.payload_page(backup, page, scratch)?;
+        let packed = pack_record(data, packed);
+        let Some(cipher) = &self.cipher else {
+            return Ok(packed);
+        };
+        let sealed = &mut record[..packed.len() + AUTH_TAG_SIZE];
+        cipher.encrypt(page as u64, packed, sealed)?;
+        Ok(sealed)
+    }
+
+    /// Generates the container bytes `[pos, pos + len)` and passes them to
+    /// `chunk_fn` in order, at most one table entry, page or record at a
+    /// time.
+    fn for_each_chunk<F>(
+        &self,
+        backup: &BackupPages,
+        pos: u64,
+        len: usize,
+        mut chunk_fn: F,
+    ) -> Result<(), SvsmError>
+    where
+        F: FnMut(&[u8]) -> Result<(), SvsmError>,
+    {
+        static PADDING: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        let layout = &self.layout;
+        let tables_end =
+            layout.section_table_offset + (SECTION_COUNT as usize * SECTION_ENTRY_SIZE) as u64;
+        let mut entry = [0u8; SECTION_ENTRY_SIZE];
+        let mut scratch = allocate_file_page_ref()?;
+        let mut record = vec![0u8; RECORD_SIZE];
+        let mut packed = vec![0u8; PAGE_SIZE];
+
+        let mut written = 0;
+        while written < len {
+            let cur = pos + written as u64;
+            let (start, bytes): (u64, &[u8]) = if cur < layout.extent_table_offset {
+                let header = layout.header(
+                    self.extent_count(),
+                    SECTION_COUNT,
+                    PAGE_SIZE as u32,
+                    self.total_size,
+                );
+                entry[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+                (0, &entry[..HEADER_SIZE])
+            } else if cur < layout.section_table_offset {
+                let index = (cur - layout.extent_table_offset) / EXTENT_ENTRY_SIZE as u64;
+                entry[..EXTENT_ENTRY_SIZE]
+                    .copy_from_slice(&self.extents[index as usize].to_bytes());
+                let start = layout.extent_table_offset + index * EXTENT_ENTRY_SIZE as u64;
+                (start, &entry[..EXTENT_ENTRY_SIZE])
+            } else if cur < tables_end {
+                let index = (cur - layout.section_table_offset) / SECTION_ENTRY_SIZE as u64;
+                entry.copy_from_slice(&self.section(index as u16).to_bytes());
+                let start = layout.section_table_offset + index * SECTION_ENTRY_SIZE as u64;
+                (start, &entry[..])
+            } else if cur < layout.payload_offset {
+                let padding = (layout.payload_offset - tables_end) as usize;
+                (tables_end, &PADDING[..padding])
+            } else if cur >= self.vtpm_end {
+                (self.vtpm_end, &self.stats[..])
+            } else if cur >= self.cpuid_end {
+                (self.cpuid_end, &self.vtpm_policy[..])
+            } else if cur >= self.payload_end {
+                (self.payload_end, cpuid_table_bytes())
+            } else if let Some(records) = &self.records {
+                let rel = cur - layout.payload_offset;
+                let table = record_table_offset(self.payload_flags());
+                match &self.cipher {
+                    Some(cipher) if rel < table => (layout.payload_offset, &cipher.prefix[..]),
+                    _ if rel < records[0] => {
+                        let index = (rel - table) / RECORD_ENTRY_SIZE as u64;
+                        entry[..RECORD_ENTRY_SIZE]
+                            .copy_from_slice(&records[index as usize].to_le_bytes());
+                        let start =
+                            layout.payload_offset + table + index * RECORD_ENTRY_SIZE as u64;
+                        (start, &entry[..RECORD_ENTRY_SIZE])
+                    }
+                    _ => {
+                        let page = records.partition_point(|&end| end <= rel) - 1;
+                        let bytes = self.compressed_record(
+                            backup,
+                            page,
+                            scratch.as_mut(),
+                            &mut packed,
+                            &mut record,
+                        )?;
+                        (layout.payload_offset + records[page], bytes)
+                    }
+                }
+            } else if let Some(cipher) = &self.cipher {
+                let rel = cur - layout.payload_offset;
+                if rel < NONCE_PREFIX_SIZE as u64 {
+                    (layout.payload_offset, &cipher.prefix[..])
+                } else {
+                    let page = (rel - NONCE_PREFIX_SIZE as u64) / RECORD_SIZE as u64;
+                    let data = self.payload_page(backup, page as usize, scratch.as_mut())?;
+                    cipher.encrypt(page, data, &mut record)?;
+                    let start = layout.payload_offset
+                        + NONCE_PREFIX_SIZE as u64
+                        + page * RECORD_SIZE as u64;
+                    (start, &record[..])
+                }
+            } else {
+                let page = (cur - layout.payload_offset) / PAGE_SIZE as u64;
+                let start = layout.payload_offset + page * PAGE_SIZE as u64;
+                (
+                    start,
+                    self.payload_page(backup, page as usize, scratch.as_mut())?,
+                )
+            };
+
+            let skip = (cur - start) as usize;
+            let chunk = min(bytes.len() - skip, len - written);
+            chunk_fn(&bytes[skip..skip + chunk])?;
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes the container bytes `[pos, pos + len)` to the start of
+    /// `buffer` and returns their CRC32C.
+    fn write_range(
+        &self,
+        backup: &BackupPages,
+        pos: u64,
+        len: usize,
+        buffer: &GuestBuffer,
+    ) -> Result<u32, SvsmError> {
+        let mut crc = Crc32c::new();
+        let mut written = 0;
+        self.for_each_chunk(backup, pos, len, |bytes| {
+            buffer.write(written, bytes)?;
+            crc.update(bytes);
+            written += bytes.len() as u64;
+            Ok(())
+        })?;
+        Ok(crc.finish())
+    }
+
+    /// Returns the SHA-256 digest of the stored contents of section
+    /// `index`.
+    fn section_digest(
+        &self,
+        backup: &BackupPages,
+        index: u16,
+    ) -> Result<[u8; DIGEST_SIZE], SvsmError> {
+        let section = self.section(index);
+        let mut digest = Sha256::new();
+        self.for_each_chunk(backup, section.offset, section.length as usize, |bytes| {
+            digest.update(bytes);
+            Ok(())
+        })?;
+        Ok(digest.finalize())
+    }
+}
+
+/// Progress of a streamed export.
+#[derive(Debug)]
+struct ExportCursor {
+    plan: ExportPlan,
+    offset: u64,
+}
+
+/// The streamed export in progress, if any.
+static EXPORT_CURSOR: SpinLock<Option<ExportCursor>> = SpinLock::new(None);
+
+/// Returns whether a streamed export is in progress.
+pub(super) fn export_in_progress() -> bool {
+    EXPORT_CURSOR.lock().is_some()
+}
+
+/// Aborts a streamed export in progress and discards the backup.
+pub fn discard_snapshot() {
+    let mut cursor = EXPORT_CURSOR.lock();
+    if cursor.take().is_some() {
+        log::info!("Aborted streamed snapshot export");
+    }
+    let mut created = BACKUP_CREATED.lock();
+    discard_backup_pages();
+    *created = false;
+    set_backup_state(BackupState::Idle);
+}
+
+/// Sets the key under which exported payloads are encrypted to the
+/// [`KEY_SIZE`] bytes at `rcx`, or clears it if `rcx` is zero. An export in
+/// progress keeps the key it started with.
+pub fn set_export_key(params: &RequestParams) -> Result<(), SvsmReqError> {
+    if params.rcx == 0 {
+        *EXPORT_KEY.lock() = None;
+        log::info!("Cleared snapshot export key");
+        return Ok(());
+    }
+    let buffer = GuestBuffer::new(PhysAddr::from(params.rcx), KEY_SIZE)?;
+    let key = buffer.read_obj(0).map_err(SvsmReqError::from_mapping)?;
+    *EXPORT_KEY.lock() = Some(key);
+    log::info!("Set snapshot export key");
+    Ok(())
+}
+
+/// Writes the current backup into the guest buffer at `rcx` of size `rdx`.
+/// On success `rcx` holds the size of the container. If the buffer is too
+/// small, `rcx` holds the required size and INVALID_PARAMETER is returned.
+pub fn export_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    if export_in_progress() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let buffer = GuestBuffer::from_params(params)?;
+
+    settle_lazy_backup()?;
+    let backup = BACKUP_PAGES.lock_read();
+    let plan = ExportPlan::new(&backup, &ZERO_PAGES.lock_read())?;
+    params.rcx = plan.total_size;
+    if plan.total_size > buffer.size() as u64 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    plan.write_range(&backup, 0, plan.total_size as usize, &buffer)
+        .map_err(SvsmReqError::from_mapping)?;
+
+    log::info!(
+        "Exported snapshot: {} extents, {} data pages",
+        plan.extent_count(),
+        plan.payload.len()
+    );
+    Ok(())
+}
+
+/// Writes the next chunk of the container into the guest window at `rcx`
+/// of size `rdx`. If `r8` holds [`EXPORT_CHUNK_TO_SCRATCH`], the chunk is
+/// written into the export window of the shared scratch region instead,
+/// up to `rdx` bytes, so the host can read it without any page state
+/// change; this requires an export key, so the host only ever sees an
+/// encrypted payload. The first call starts a streamed export of the
+/// current backup, later calls continue where the previous one stopped.
+/// On success `rcx` holds the number of bytes written and `rdx` the number
+/// of bytes still to come; the export is complete once `rdx` is zero. `r8`
+/// holds the CRC32C of the bytes written, so the host can check each chunk
+/// without hashing the whole container. A call with an empty window aborts
+/// the export in progress.
+pub fn export_snapshot_chunk(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let flags = params.r8;
+    if flags & !EXPORT_CHUNK_FLAGS != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let mut cursor = EXPORT_CURSOR.lock();
+    if params.rdx == 0 {
+        if cursor.take().is_some() {
+            log::info!("Aborted streamed snapshot export");
+        }
+        params.rcx = 0;
+        return Ok(());
+    }
+
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let buffer = if flags & EXPORT_CHUNK_TO_SCRATCH != 0 {
+        let window = scratch_export_window().ok_or_else(SvsmReqError::invalid_request)?;
+        GuestBuffer::shared(window.start(), min(len, window.len()))
+    } else {
+        GuestBuffer::new(PhysAddr::from(params.rcx), len)?
+    };
+    let len = buffer.size();
+
+    if cursor.is_none() {
+        if !*BACKUP_CREATED.lock() {
+            return Err(no_backup());
+        }
+        settle_lazy_backup()?;
+    }
+
+    let backup = BACKUP_PAGES.lock_read();
+    if cursor.is_none() {
+        let plan = ExportPlan::new(&backup, &ZERO_PAGES.lock_read())?;
+        log::info!(
+            "Starting streamed snapshot export of {} bytes",
+            plan.total_size
+        );
+        *cursor = Some(ExportCursor { plan, offset: 0 });
+    }
+
+    let state = cursor.as_mut().unwrap();
+    if flags & EXPORT_CHUNK_TO_SCRATCH != 0 && state.plan.cipher.is_none() {
+        log::info!("Refusing to export a plain payload to shared memory");
+        return Err(SvsmReqError::invalid_request());
+    }
+    checked_invariant!(state.offset <= state.plan.total_size, ExportCursorOverrun);
+    let remaining = state.plan.total_size - state.offset;
+    let chunk = min(remaining, len as u64);
+    let crc = state
+        .plan
+        .write_range(&backup, state.offset, chunk as usize, &buffer)
+        .map_err(SvsmReqError::from_mapping)?;
+    state.offset += chunk;
+
+    params.rcx = chunk;
+    params.rdx = remaining - chunk;
+    params.r8 = u64::from(crc);
+    if params.rdx == 0 {
+        log::info!("Finished streamed snapshot export");
+        *cursor = None;
+    }
+    Ok(())
+}
+
+fn read_header(buffer: &GuestBuffer) -> Result<SnapshotHeader, SvsmReqError> {
+    let mut bytes = [0u8; HEADER_SIZE];
+    buffer
+        .read(0, &mut bytes)
+        .map_err(SvsmReqError::from_mapping)?;
+    let header = SnapshotHeader::from_bytes(&bytes).map_err(format_error)?;
+    if header.page_size as usize != PAGE_SIZE || header.total_size > buffer.size() as u64 {
+        return Err(SvsmReqError::invalid_format());
+    }
+    Ok(header)
+}
+
+fn read_section(
+    buffer: &GuestBuffer,
+    header: &SnapshotHeader,
+    index: u32,
+) -> Result<Section, SvsmReqError> {
+    if index >= header.section_count {
+        return Err(format_error(FormatError::OutOfBounds));
+    }
+    let offset = header
+        .section_offset(index)
+        .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+    let mut bytes = [0u8; SECTION_ENTRY_SIZE];
+    buffer
+        .read(offset, &mut bytes)
+        .map_err(|_| format_error(FormatError::Truncated))?;
+    Section::from_bytes(&bytes).map_err(format_error)
+}
+
+/// Checks the stored contents of every section against its digest, so a
+/// corrupted container is rejected before any of it is decoded. Containers
+/// written without digests are accepted as they are.
+fn check_section_digests(
+    buffer: &GuestBuffer,
+    header: &SnapshotHeader,
+) -> Result<(), SvsmReqError> {
+    if header.digest_alg == DigestAlgorithm::None {
+        return Ok(());
+    }
+    let mut scratch = allocate_file_page_ref()?;
+    let chunk = scratch.as_mut();
+    for index in 0..header.section_count {
+        let section = read_section(buffer, header, index)?;
+        let mut digest = Sha256::new();
+        let mut pos = 0;
+        while pos < section.length {
+            let len = min(section.length - pos, chunk.len() as u64) as usize;
+            let offset = section
+                .offset
+                .checked_add(pos)
+                .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+            buffer
+                .read(offset, &mut chunk[..len])
+                .map_err(|_| format_error(FormatError::OutOfBounds))?;
+            digest.update(&chunk[..len]);
+            pos += len as u64;
+        }
+        if digest.finalize() != section.digest {
+            log::info!("Section {} does not match its digest", index);
+            return Err(format_error(FormatError::DigestMismatch));
+        }
+    }
+    Ok(())
+}
+
+fn import_page(
+    buffer: &GuestBuffer,
+    page: &PayloadPage,
+    paddr: PhysAddr,
+    measurement: &mut Sha256,
+    charge: &mut SnapshotCharge,
+) -> Result<(), SvsmReqError> {
+    charge.charge(PAGE_SIZE)?;
+    let mut pages = BACKUP_PAGES.lock_write();
+    let backup = pages.get_mut()?;
+    if backup.contains(paddr) {
+        log::info!("Rejecting snapshot with duplicate page {:#x}", paddr);
+        return Err(SvsmReqError::invalid_format());
+    }
+    backup.push_with(paddr, |data| {
+        page.read(buffer, data)?;
+        measurement.update(&data[..]);
+        Ok(true)
+    })?;
+    Ok(())
+}
+
+/// Checks that a snapshot recording its CPUID policy was taken with the
+/// same CPUID page as the one in use, since the guest state in the snapshot
+/// depends on the CPUID values it observed.
+fn check_cpuid_policy(buffer: &GuestBuffer, header: &SnapshotHeader) -> Result<(), SvsmReqError> {
+    let table = cpuid_table_bytes();
+    for index in 0..header.section_count {
+        let section = read_section(buffer, header, index)?;
+        if section.kind != section_kind::CPUID {
+            continue;
+        }
+        if section.flags != 0 || section.length != table.len() as u64 {
+            return Err(SvsmReqError::invalid_format());
+        }
+        let mut chunk = [0u8; SECTION_ENTRY_SIZE];
+        for (pos, expected) in table.chunks(chunk.len()).enumerate() {
+            let offset = section
+                .offset
+                .checked_add((pos * chunk.len()) as u64)
+                .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+            let chunk = &mut chunk[..expected.len()];
+            buffer
+                .read(offset, chunk)
+                .map_err(|_| format_error(FormatError::OutOfBounds))?;
+            if chunk != expected {
+                log::info!("Rejecting snapshot taken with a different CPUID policy");
+                return Err(SvsmReqError::invalid_request());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Logs how the vTPM was handled by the SVSM which took the snapshot. The
+/// vTPM state itself is not part of the container, so the vTPM is reset
+/// when an imported snapshot is restored.
+fn log_vtpm_policy(buffer: &GuestBuffer, header: &SnapshotHeader) -> Result<(), SvsmReqError> {
+    for index in 0..header.section_count {
+        let section = read_section(buffer, header, index)?;
+        if section.kind != section_kind::VTPM_POLICY {
+            continue;
+        }
+        let mut bytes = [0u8; 4];
+        if section.flags != 0 || section.length != bytes.len() as u64 {
+            return Err(SvsmReqError::invalid_format());
+        }
+        buffer
+            .read(section.offset, &mut bytes)
+            .map_err(|_| format_error(FormatError::OutOfBounds))?;
+        if u32::from_le_bytes(bytes) == vtpm_policy::SNAPSHOT {
+            log::info!("Imported snapshot rewinds the vTPM, which is reset on restore instead");
+        }
+    }
+    Ok(())
+}
+
+/// Charges and reserves the entries of `count` zero pages up front, so
+/// importing them can neither run over budget nor fail to allocate half
+/// way.
+fn reserve_zero_pages(count: usize, charge: &mut SnapshotCharge) -> Result<(), SvsmReqError> {
+    let bytes = count
+        .checked_mul(size_of::<PhysAddr>())
+        .ok_or_else(SvsmReqError::invalid_format)?;
+    charge.charge(bytes)?;
+    ZERO_PAGES
+        .lock_write()
+        .try_reserve(count)
+        .map_err(|_| copy_error(SvsmError::Alloc(AllocError::OutOfMemory)))
+}
+
+/// Replaces the (empty) backup with the contents of the container held in
+/// the guest buffer at `rcx` of size `rdx`. The snapshot is only accepted if
+/// its measurement is approved by the snapshot policy, so an unapproved
+/// image can never be restored.
+///
+/// The container is measured in a first pass which reads every payload page
+/// into a scratch page, so an unapproved or malformed container never costs
+/// any snapshot memory. The second pass imports the pages and measures them
+/// again, since the guest can change the buffer in between.
+pub fn import_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let mut created = BACKUP_CREATED.lock();
+    if *created {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let buffer = GuestBuffer::from_params(params)?;
+    let header = read_header(&buffer)?;
+    check_section_digests(&buffer, &header)?;
+    check_cpuid_policy(&buffer, &header)?;
+    log_vtpm_policy(&buffer, &header)?;
+
+    let mut scratch = allocate_file_page_ref()?;
+    let mut zero_pages = 0usize;
+    let approved = walk_extents(&buffer, &header, |_, page, measurement| {
+        match page {
+            Some(page) => {
+                page.read(&buffer, scratch.as_mut())?;
+                measurement.update(&scratch.as_ref()[..]);
+            }
+            None => zero_pages += 1,
+        }
+        Ok(())
+    })?;
+    drop(scratch);
+    if !snapshot_approved(&approved) {
+        log::info!("Rejecting snapshot with unapproved measurement");
+        return Err(SvsmReqError::invalid_request());
+    }
+
+    let mut charge = SnapshotCharge::new();
+    let result = reserve_zero_pages(zero_pages, &mut charge)
+        .and_then(|()| {
+            walk_extents(&buffer, &header, |paddr, page, measurement| match page {
+                None => {
+                    // Only the zero pages counted in the first pass are
+                    // reserved.
+                    zero_pages = zero_pages
+                        .checked_sub(1)
+                        .ok_or_else(SvsmReqError::invalid_format)?;
+                    ZERO_PAGES.lock_write().push(paddr);
+                    Ok(())
+                }
+                Some(page) => import_page(&buffer, &page, paddr, measurement, &mut charge),
+            })
+        })
+        .and_then(|measurement| {
+            if measurement == approved {
+                Ok(())
+            } else {
+                log::info!("Rejecting snapshot which changed during import");
+                Err(SvsmReqError::invalid_format())
+            }
+        });
+    if let Err(err) = result {
+        discard_backup_pages();
+        return Err(err);
+    }
+
+    let pages = BACKUP_PAGES.lock_read().len() + ZERO_PAGES.lock_read().len();
+    record_metadata(pages as u64);
+    *created = true;
+    set_backup_state(BackupState::Ready);
+    log::info!("Imported snapshot with {} extents", header.extent_count);
+    Ok(())
+}
+
+/// Result of a verification run over a snapshot container.
+#[derive(Debug, Default)]
+struct VerifyReport {
+    /// Pages which would be restored from the payload.
+    restored: u64,
+    /// Pages which would be restored as zero pages.
+    zeroed: u64,
+    /// Pages which would be skipped because they are not writable guest
+    /// memory or belong to protected firmware.
+    skipped: u64,
+}
+
+/// Checks the container held in the guest buffer at `rcx` of size `rdx`
+/// end to end without touching the backup state or live guest memory.
+/// The section digests are checked, every payload page is read, decrypted
+/// and decompressed into a scratch page exactly as on import, the
+/// measurement is checked against the snapshot policy and the CPUID policy
+/// is checked as on import. On success `rcx` holds the number of pages that
+/// would be restored from the payload, `rdx` the number of zero pages and
+/// `r8` the number of pages a restore would skip.
+pub fn verify_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+    let header = read_header(&buffer)?;
+    check_section_digests(&buffer, &header)?;
+    check_cpuid_policy(&buffer, &header)?;
+
+    let mut scratch = allocate_file_page_ref()?;
+    let mut report = VerifyReport::default();
+    let measurement = walk_extents(&buffer, &header, |paddr, page, measurement| {
+        if let Some(page) = &page {
+            page.read(&buffer, scratch.as_mut())?;
+            measurement.update(&scratch.as_ref()[..]);
+        }
+        if !writable_phys_addr(paddr) || fw_page_protected(paddr) || excluded(paddr) {
+            report.skipped += 1;
+        } else if page.is_some() {
+            report.restored += 1;
+        } else {
+            report.zeroed += 1;
+        }
+        Ok(())
+    })?;
+    if !snapshot_approved(&measurement) {
+        log::info!("Snapshot verification failed: unapproved measurement");
+        return Err(SvsmReqError::invalid_request());
+    }
+
+    log::info!(
+        "Snapshot verified: {} pages restored, {} zeroed, {} skipped",
+        report.restored,
+        report.zeroed,
+        report.skipped
+    );
+    params.rcx = report.restored;
+    params.rdx = report.zeroed;
+    params.r8 = report.skipped;
+    Ok(())
+}
+
+/// Locates record `record` of the compressed payload section `section` of
+/// the container in `buffer`, which is encrypted with `cipher` if given.
+fn locate_record(
+    buffer: &GuestBuffer,
+    section: &Section,
+    cipher: Option<PayloadCipher>,
+    record: u64,
+) -> Result<PayloadPage, SvsmReqError> {
+    let range = compressed_record_range(section, PAGE_SIZE as u32, record, |off| {
+        let offset = section
+            .offset
+            .checked_add(off)
+            .ok_or(FormatError::OutOfBounds)?;
+        let mut entry = [0u8; RECORD_ENTRY_SIZE];
+        buffer
+            .read(offset, &mut entry)
+            .map_err(|_| FormatError::OutOfBounds)?;
+        Ok(u64::from_le_bytes(entry))
+    })
+    .map_err(format_error)?;
+    let offset = section
+        .offset
+        .checked_add(range.start)
+        .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+    Ok(PayloadPage {
+        offset,
+        len: (range.end - range.start) as usize,
+        compressed: true,
+        record: cipher.map(|cipher| (cipher, record)),
+    })
+}
+
+/// Locates page `page` of the data extent `extent` in its payload section
+/// `section` of the container in `buffer`, which is encrypted with `cipher`
+/// if given.
+fn locate_page(
+    buffer: &GuestBuffer,
+    section: &Section,
+    cipher: Option<PayloadCipher>,
+    extent: &Extent,
+    page: u64,
+) -> Result<PayloadPage, SvsmReqError> {
+    let rel = page
+        .checked_mul(PAGE_SIZE as u64)
+        .and_then(|off| extent.payload_offset.checked_add(off))
+        .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+    if section.flags & section_flags::COMPRESSED != 0 {
+        // Records hold whole pages.
+        if rel % PAGE_SIZE as u64 != 0 {
+            return Err(SvsmReqError::invalid_format());
+        }
+        return locate_record(buffer, section, cipher, rel / PAGE_SIZE as u64);
+    }
+    let (stored, size, record) = match cipher {
+        None => (Some(rel), PAGE_SIZE, None),
+        Some(cipher) => {
+            // Records hold whole pages.
+            if rel % PAGE_SIZE as u64 != 0 {
+                return Err(SvsmReqError::invalid_format());
+            }
+            let record = rel / PAGE_SIZE as u64;
+            (
+                encrypted_record_offset(record, PAGE_SIZE as u32),
+                RECORD_SIZE,
+                Some((cipher, record)),
+            )
+        }
+    };
+    let offset = stored
+        .filter(|&off| off.saturating_add(size as u64) <= section.length)
+        .and_then(|off| section.offset.checked_add(off))
+        .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+    Ok(PayloadPage {
+        offset,
+        len: size,
+        compressed: false,
+        record,
+    })
+}
+
+/// Walks the extents of the container and returns the measurement of the
+/// snapshot. `page_fn` is called for every page with its guest address and,
+/// for data pages, the location of its contents in the buffer. It has to
+/// add the contents of data pages to the measurement.
+fn walk_extents<F>(
+    buffer: &GuestBuffer,
+    header: &SnapshotHeader,
+    mut page_fn: F,
+) -> Result<[u8; SHA256_SIZE], SvsmReqError>
+where
+    F: FnMut(PhysAddr, Option<PayloadPage>, &mut Sha256) -> Result<(), SvsmReqError>,
+{
+    let mut measurement = Sha256::new();
+    let mut payload: Option<(u16, Section, Option<PayloadCipher>)> = None;
+    // End of the previous extent. Extents have to be sorted and must not
+    // overlap, so no page is imported twice.
+    let mut end = 0u64;
+    for i in 0..header.extent_count {
+        let offset = header
+            .extent_offset(i)
+            .ok_or_else(|| format_error(FormatError::OutOfBounds))?;
+        let mut bytes = [0u8; EXTENT_ENTRY_SIZE];
+        buffer
+            .read(offset, &mut bytes)
+            .map_err(|_| format_error(FormatError::Truncated))?;
+        let extent = Extent::from_bytes(&bytes).map_err(format_error)?;
+
+        let gpa = PhysAddr::from(extent.gpa);
+        if !gpa.is_page_aligned() {
+            return Err(SvsmReqError::invalid_format());
+        }
+        if extent.gpa < end {
+            log::info!("Rejecting snapshot: extent {} overlaps or is not sorted", i);
+            return Err(SvsmReqError::invalid_format());
+        }
+        end = extent
+            .page_count
+            .checked_mul(PAGE_SIZE as u64)
+            .and_then(|len| extent.gpa.checked_add(len))
+            .ok_or_else(SvsmReqError::invalid_format)?;
+        measurement.update(&extent.measurement_header());
+
+        let section = match (extent.kind, payload) {
+            (ExtentKind::Zero, _) => None,
+            (ExtentKind::Data, Some((index, section, cipher))) if index == extent.section => {
+                Some((section, cipher))
+            }
+            (ExtentKind::Data, _) => {
+                let section = read_section(buffer, header, u32::from(extent.section))?;
+                if !section.is_payload() {
+                    return Err(SvsmReqError::invalid_format());
+                }
+                let cipher = if section.flags & section_flags::ENCRYPTED != 0 {
+                    Some(PayloadCipher::for_import(buffer, &section)?)
+                } else {
+                    None
+                };
+                payload = Some((extent.section, section, cipher));
+                Some((section, cipher))
+            }
+        };
+
+        for page in 0..extent.page_count {
+            let paddr = page
+                .checked_mul(PAGE_SIZE as u64)
+                .and_then(|off| extent.gpa.checked_add(off))
+                .map(PhysAddr::from)
+                .ok_or_else(SvsmReqError::invalid_format)?;
+            if !valid_phys_address(paddr) {
+                return Err(SvsmReqError::invalid_address());
+            }
+            let location = match section {
+                None => None,
+                Some((section, cipher)) => {
+                    Some(locate_page(buffer, &section, cipher, &extent, page)?)
+                }
+            };
+            page_fn(paddr, location, &mut measurement)?;
+        }
+    }
+    Ok(measurement.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed_page(record: Option<(PayloadCipher, u64)>, stored: &[u8]) -> PayloadPage {
+        PayloadPage {
+            offset: 0,
+            len: stored.len(),
+            compressed: true,
+            record,
+        }
+    }
+
+    #[test]
+    fn test_decode_compressed_records() {
+        let mut page = [0x5au8; PAGE_SIZE];
+        page[100..110].copy_from_slice(b"0123456789");
+        let mut packed = vec![0u8; PAGE_SIZE];
+        let mut data = [0u8; PAGE_SIZE];
+
+        // Plain compressed record.
+        let stored = pack_record(&page, &mut packed).to_vec();
+        assert!(stored.len() < PAGE_SIZE);
+        compressed_page(None, &stored)
+            .decode(stored.clone(), &mut data)
+            .unwrap();
+        assert_eq!(data, page);
+
+        // Compressed and sealed record.
+        let cipher = PayloadCipher {
+            key: [7; KEY_SIZE],
+            prefix: [1; NONCE_PREFIX_SIZE],
+        };
+        let mut sealed = vec![0u8; stored.len() + AUTH_TAG_SIZE];
+        cipher.encrypt(3, &stored, &mut sealed).unwrap();
+        data.fill(0);
+        let location = compressed_page(Some((cipher, 3)), &sealed);
+        location.decode(sealed.clone(), &mut data).unwrap();
+        assert_eq!(data, page);
+
+        // Records fail authentication if they are changed or moved.
+        let mut changed = sealed.clone();
+        changed[0] ^= 1;
+        assert!(location.decode(changed, &mut data).is_err());
+        let moved = compressed_page(Some((cipher, 4)), &sealed);
+        assert!(moved.decode(sealed, &mut data).is_err());
+
+        // Incompressible pages are stored as they are.
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i * 7 + i / 256) as u8;
+        }
+        let stored = pack_record(&page, &mut packed).to_vec();
+        assert_eq!(stored.len(), PAGE_SIZE);
+        compressed_page(None, &stored)
+            .decode(stored, &mut data)
+            .unwrap();
+        assert_eq!(data, page);
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Index of backed-up guest pages.
+//!
+//! Snapshots often cover a few dense ranges spread over a large guest
+//! physical address space. The index maps guest page frame numbers to
+//! arena slots through two levels, like a simplified page table: a
+//! top-level table of leaf pointers and leaves of [`LEAF_ENTRIES`] 32-bit
+//! slot numbers. A lookup is two array accesses, and address ranges without
+//! backed-up pages only cost a null leaf pointer.
+
+use crate::error::SvsmError;
+use crate::mm::alloc::AllocError;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+const LEAF_BITS: usize = 10;
+/// Number of page frames covered by a leaf. A leaf fills exactly one page.
+const LEAF_ENTRIES: usize = 1 << LEAF_BITS;
+/// Leaf entry of a page frame without a backed-up page.
+const NO_SLOT: u32 = u32::MAX;
+
+#[derive(Debug)]
+struct Leaf {
+    slots: [u32; LEAF_ENTRIES],
+}
+
+impl Leaf {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            slots: [NO_SLOT; LEAF_ENTRIES],
+        })
+    }
+}
+
+/// Two-level map from guest page frame numbers to arena slots.
+#[derive(Debug, Default)]
+pub struct PfnIndex {
+    leaves: Vec<Option<Box<Leaf>>>,
+    len: usize,
+}
+
+impl PfnIndex {
+    pub const fn new() -> Self {
+        Self {
+            leaves: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn split(pfn: usize) -> (usize, usize) {
+        (pfn >> LEAF_BITS, pfn & (LEAF_ENTRIES - 1))
+    }
+
+    /// Maps `pfn` to `slot` and returns the slot it was mapped to before,
+    /// if any. Fails if the top-level table cannot grow to cover `pfn`.
+    pub fn insert(&mut self, pfn: usize, slot: usize) -> Result<Option<usize>, SvsmError> {
+        let slot = u32::try_from(slot)
+            .ok()
+            .filter(|&slot| slot != NO_SLOT)
+            .ok_or(SvsmError::Mem)?;
+        let (top, entry) = Self::split(pfn);
+        if top >= self.leaves.len() {
+            self.leaves
+                .try_reserve(top + 1 - self.leaves.len())
+                .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+            self.leaves.resize_with(top + 1, || None);
+        }
+        let leaf = self.leaves[top].get_or_insert_with(Leaf::new);
+        let old = core::mem::replace(&mut leaf.slots[entry], slot);
+        if old == NO_SLOT {
+            self.len += 1;
+            Ok(None)
+        } else {
+            Ok(Some(old as usize))
+        }
+    }
+
+    /// Returns the slot `pfn` is mapped to.
+    pub fn get(&self, pfn: usize) -> Option<usize> {
+        let (top, entry) = Self::split(pfn);
+        let slot = self.leaves.get(top)?.as_ref()?.slots[entry];
+        (slot != NO_SLOT).then_some(slot as usize)
+    }
+
+    /// Returns the number of mapped page frames.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns all `(pfn, slot)` pairs in ascending page frame order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.leaves
+            .iter()
+            .enumerate()
+            .filter_map(|(top, leaf)| leaf.as_ref().map(|leaf| (top, leaf)))
+            .flat_map(|(top, leaf)| {
+                leaf.slots
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &slot)| slot != NO_SLOT)
+                    .map(move |(entry, &slot)| ((top << LEAF_BITS) | entry, slot as usize))
+            })
+    }
+
+    /// Returns the memory used by the index in bytes.
+    pub fn memory(&self) -> usize {
+        let leaves = self.leaves.iter().filter(|leaf| leaf.is_some()).count();
+        self.leaves.capacity() * core::mem::size_of::<Option<Box<Leaf>>>()
+            + leaves * core::mem::size_of::<Leaf>()
+    }
+
+    pub fn clear(&mut self) {
+        self.leaves = Vec::new();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pfn_index() {
+        let mut index = PfnIndex::new();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.get(5), None);
+
+        assert_eq!(index.insert(0x12345, 7).unwrap(), None);
+        assert_eq!(index.insert(3, 1).unwrap(), None);
+        assert_eq!(index.insert(LEAF_ENTRIES, 2).unwrap(), None);
+        assert_eq!(index.insert(3, 4).unwrap(), Some(1));
+        assert_eq!(index.len(), 3);
+
+        assert_eq!(index.get(3), Some(4));
+        assert_eq!(index.get(0x12345), Some(7));
+        assert_eq!(index.get(LEAF_ENTRIES), Some(2));
+        assert_eq!(index.get(LEAF_ENTRIES - 1), None);
+        assert_eq!(index.get(usize::MAX), None);
+
+        let entries: Vec<_> = index.iter().collect();
+        assert_eq!(entries, [(3, 4), (LEAF_ENTRIES, 2), (0x12345, 7)]);
+
+        index.clear();
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.iter().count(), 0);
+    }
+}
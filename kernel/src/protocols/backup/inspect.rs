@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Read-only inspection of the stored backup.
+//!
+//! Guest tooling can read single pages of the backup into a buffer of its
+//! own, e.g. to diff the current memory against the checkpoint while
+//! debugging, without restoring anything.
+
+use super::errors::no_backup;
+use super::lazy::capture_pending;
+use super::watchdog::cow_active;
+use super::{BACKUP_CREATED, BACKUP_PAGES, PAGES_TO_BACKUP, ZERO_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::fw_protect::fw_page_protected;
+use crate::mm::{allocate_file_page_ref, writable_phys_addr};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::{PageSize, PAGE_SIZE};
+
+/// The page was copied from the backup.
+const SNAPSHOT_PAGE_DATA: u64 = 0;
+/// The page is a zero page in the backup, the buffer was cleared.
+const SNAPSHOT_PAGE_ZERO: u64 = 1;
+
+/// Returns whether the guest may receive snapshot contents in the page at
+/// `paddr`. The page must be writable guest memory outside of protected
+/// firmware, and must not be write-protected for copy-on-write, since the
+/// SVSM would bypass the protection.
+pub(super) fn destination_allowed(paddr: PhysAddr) -> bool {
+    if !writable_phys_addr(paddr) || fw_page_protected(paddr) {
+        return false;
+    }
+    !cow_active()
+        || !(PAGES_TO_BACKUP.contains_addr(paddr, PageSize::Regular)
+            || PAGES_TO_BACKUP.contains_addr(paddr.page_align_2m(), PageSize::Huge))
+}
+
+/// Copies the backed-up contents of the guest page at `rcx` into the
+/// page-sized guest buffer at `rdx`. On success `r8` tells whether the page
+/// was copied (0) or is a zero page (1). Fails with INVALID_PARAMETER if
+/// the page is not part of the backup and with `SVSM_ERR_NO_BACKUP` if there
+/// is no backup.
+pub fn read_snapshot_page(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let paddr = PhysAddr::from(params.rcx);
+    if !paddr.is_page_aligned() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let dest = PhysAddr::from(params.rdx);
+    let buffer = GuestBuffer::new(dest, PAGE_SIZE)?;
+    if !destination_allowed(dest) {
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    capture_pending(paddr)?;
+    let created = BACKUP_CREATED.lock();
+    if !*created {
+        return Err(no_backup());
+    }
+    let backup = BACKUP_PAGES.lock_read();
+    let mut scratch = allocate_file_page_ref()?;
+    if let Some(data) = backup.checked_lookup(paddr, scratch.as_mut())? {
+        buffer
+            .write(0, &data[..])
+            .map_err(SvsmReqError::from_mapping)?;
+        params.r8 = SNAPSHOT_PAGE_DATA;
+    } else if ZERO_PAGES.lock_read().contains(&paddr) {
+        buffer
+            .write(0, &[0u8; PAGE_SIZE])
+            .map_err(SvsmReqError::from_mapping)?;
+        params.r8 = SNAPSHOT_PAGE_ZERO;
+    } else {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    Ok(())
+}
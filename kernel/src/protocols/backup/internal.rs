@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! SVSM-internal state saved with a backup.
+//!
+//! Some of the state the SVSM keeps about a guest, e.g. bookkeeping of the
+//! protocols it serves, describes guest memory and is wrong once guest
+//! memory is rolled back. Such state can be kept in a [`TaggedState`] cell
+//! instead of a plain lock, and registered once with
+//! [`register_tagged_state`]. Its value is then copied with every backup,
+//! put back by every restore and parked with named snapshots, so a restore
+//! returns the SVSM's view of the guest to the same point as guest memory.
+//! State which is not tagged is left alone.
+//!
+//! The guest cannot write SVSM memory, so tagged state and its copies are
+//! safe from the guest while it runs. The SVSM itself only changes the
+//! value under the lock of the cell, which saving and restoring take as
+//! well, so a copy is never torn. Copies stay in SVSM memory and are not
+//! part of exported snapshots.
+
+use crate::locking::{LockGuard, RWLock, SpinLock};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+
+/// State saved with a backup and restored with it, see [`TaggedState`].
+pub trait SnapshotState: Sync {
+    /// Name of the state, for log messages.
+    fn tag(&self) -> &'static str;
+    /// Returns a copy of the current value.
+    fn save(&self) -> Box<dyn Any + Send>;
+    /// Rolls the value back to a copy returned by [`Self::save`].
+    fn restore(&self, saved: &(dyn Any + Send));
+    /// Returns another copy of a copy returned by [`Self::save`], for a
+    /// fork of the snapshot.
+    fn duplicate(&self, saved: &(dyn Any + Send)) -> Option<Box<dyn Any + Send>>;
+}
+
+/// A value of SVSM-internal state which is rolled back with the guest once
+/// it is registered with [`register_tagged_state`].
+#[derive(Debug)]
+pub struct TaggedState<T> {
+    tag: &'static str,
+    value: SpinLock<T>,
+}
+
+impl<T> TaggedState<T> {
+    pub const fn new(tag: &'static str, value: T) -> Self {
+        Self {
+            tag,
+            value: SpinLock::new(value),
+        }
+    }
+
+    /// Locks the value.
+    pub fn lock(&self) -> LockGuard<'_, T> {
+        self.value.lock()
+    }
+}
+
+impl<T: Clone + Send + 'static> SnapshotState for TaggedState<T> {
+    fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    fn save(&self) -> Box<dyn Any + Send> {
+        Box::new(self.value.lock().clone())
+    }
+
+    fn restore(&self, saved: &(dyn Any + Send)) {
+        match saved.downcast_ref::<T>() {
+            Some(value) => *self.value.lock() = value.clone(),
+            None => log::error!("Saved state of {} has the wrong type", self.tag),
+        }
+    }
+
+    fn duplicate(&self, saved: &(dyn Any + Send)) -> Option<Box<dyn Any + Send>> {
+        let value = saved.downcast_ref::<T>()?;
+        Some(Box::new(value.clone()))
+    }
+}
+
+/// Tagged state saved with a backup which is not the current one.
+#[derive(Default)]
+pub struct SavedInternalState {
+    states: Vec<(&'static dyn SnapshotState, Box<dyn Any + Send>)>,
+}
+
+impl Clone for SavedInternalState {
+    fn clone(&self) -> Self {
+        let states = self
+            .states
+            .iter()
+            .filter_map(|&(state, ref value)| Some((state, state.duplicate(value.as_ref())?)))
+            .collect();
+        Self { states }
+    }
+}
+
+static TAGGED: RWLock<Vec<&'static dyn SnapshotState>> = RWLock::new(Vec::new());
+static SAVED_STATE: SpinLock<SavedInternalState> =
+    SpinLock::new(SavedInternalState { states: Vec::new() });
+
+/// Registers SVSM-internal state to be saved with every backup.
+pub fn register_tagged_state(state: &'static dyn SnapshotState) {
+    TAGGED.lock_write().push(state);
+    log::info!("Registered tagged state {}", state.tag());
+}
+
+/// Saves every registered state with a new backup.
+pub fn snapshot_internal_state() {
+    let states = TAGGED
+        .lock_read()
+        .iter()
+        .map(|&state| (state, state.save()))
+        .collect();
+    *SAVED_STATE.lock() = SavedInternalState { states };
+}
+
+/// Rolls every registered state back to the value saved with the current
+/// backup. State registered after the backup was taken is left alone.
+pub fn restore_internal_state() {
+    let saved = SAVED_STATE.lock();
+    for (state, value) in saved.states.iter() {
+        state.restore(value.as_ref());
+    }
+    if !saved.states.is_empty() {
+        log::info!("Restored {} tagged states", saved.states.len());
+    }
+}
+
+/// Drops the state saved with a discarded backup.
+pub fn discard_internal_state() {
+    *SAVED_STATE.lock() = SavedInternalState::default();
+}
+
+/// Takes the state saved with the current backup.
+pub fn take_internal_state() -> SavedInternalState {
+    core::mem::take(&mut *SAVED_STATE.lock())
+}
+
+/// Makes `saved` the state saved with the current backup.
+pub fn put_internal_state(saved: SavedInternalState) {
+    *SAVED_STATE.lock() = saved;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tagged_state_roundtrip() {
+        static STATE: TaggedState<u64> = TaggedState::new("test", 1);
+        let saved = STATE.save();
+        *STATE.lock() = 2;
+        STATE.restore(saved.as_ref());
+        assert_eq!(*STATE.lock(), 1);
+
+        // A copy of another type leaves the value alone.
+        STATE.restore(&0u32);
+        assert_eq!(*STATE.lock(), 1);
+
+        let copy = STATE.duplicate(saved.as_ref()).unwrap();
+        assert_eq!(copy.downcast_ref::<u64>(), Some(&1));
+        assert!(STATE.duplicate(&0u32).is_none());
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Journal of the restore in progress.
+//!
+//! A restore which fails partway, e.g. because a page cannot be mapped,
+//! leaves guest memory half old and half new. The restore keeps a journal
+//! of its steps and of the pages each step has completed. When it fails,
+//! the journal is kept and tells the guest exactly where it stopped, and
+//! `SVSM_RESUME_RESTORE` rolls the restore forward from there instead of
+//! starting over. Every step writes guest memory from the backup only, so
+//! repeating the page in flight when the failure hit is harmless, and so is
+//! retrying the whole restore with `SVSM_RESTORE`.
+
+use crate::address::PhysAddr;
+use crate::locking::SpinLock;
+use crate::protocols::RequestParams;
+
+/// The steps of a restore, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RestoreStep {
+    /// Re-validating pages whose validation was rescinded.
+    Validation = 1,
+    /// Writing the pages with data from the base backup.
+    Pages,
+    /// Zeroing the zero pages of the base backup.
+    ZeroPages,
+    /// Applying the delta layers.
+    DeltaLayers,
+    /// Zeroing and rescinding the pages validated after the backup.
+    NewPages,
+    /// Write-protecting the restored pages for copy-on-write again.
+    Rearm,
+    /// Restoring emulated state and the vCPUs.
+    State,
+    /// The restore completed.
+    Done,
+}
+
+impl RestoreStep {
+    fn next(self) -> Self {
+        match self {
+            Self::Validation => Self::Pages,
+            Self::Pages => Self::ZeroPages,
+            Self::ZeroPages => Self::DeltaLayers,
+            Self::DeltaLayers => Self::NewPages,
+            Self::NewPages => Self::Rearm,
+            Self::Rearm => Self::State,
+            Self::State | Self::Done => Self::Done,
+        }
+    }
+}
+
+/// Progress of a restore.
+#[derive(Clone, Copy, Debug)]
+pub struct RestoreJournal {
+    /// The step in progress.
+    step: RestoreStep,
+    /// Number of pages of the step completed, in the order the step
+    /// handles them.
+    done: usize,
+    /// The page being written, if any.
+    current: Option<PhysAddr>,
+}
+
+/// The journal of an interrupted restore of the current backup.
+static JOURNAL: SpinLock<Option<RestoreJournal>> = SpinLock::new(None);
+
+impl RestoreJournal {
+    /// Returns the journal of a new restore.
+    pub fn new() -> Self {
+        Self {
+            step: RestoreStep::Validation,
+            done: 0,
+            current: None,
+        }
+    }
+
+    /// Takes the journal of the interrupted restore, if any.
+    pub fn take_interrupted() -> Option<Self> {
+        JOURNAL.lock().take()
+    }
+
+    /// Runs `step` with `f` unless it was already completed, and moves on
+    /// to the next step if `f` succeeds.
+    pub fn run<E, F>(&mut self, step: RestoreStep, f: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut Self) -> Result<(), E>,
+    {
+        if step < self.step {
+            return Ok(());
+        }
+        f(self)?;
+        self.step = step.next();
+        self.done = 0;
+        self.current = None;
+        Ok(())
+    }
+
+    /// Returns the number of pages of the current step completed.
+    pub fn done(&self) -> usize {
+        self.done
+    }
+
+    /// Records that the page at `paddr` is being written.
+    pub fn attempt(&mut self, paddr: PhysAddr) {
+        self.current = Some(paddr);
+    }
+
+    /// Records that `pages` more pages of the current step are completed.
+    pub fn advance(&mut self, pages: usize) {
+        self.done += pages;
+        self.current = None;
+    }
+
+    /// Keeps the journal of a failed restore for `SVSM_RESUME_RESTORE` and
+    /// reports where it stopped: `rcx` holds the step, `rdx` the number of
+    /// pages of the step completed and `r8` the address of the page being
+    /// written, or `u64::MAX` if the failure was not in a page write.
+    pub fn interrupt(self, params: &mut RequestParams) {
+        log::error!(
+            "Restore interrupted in {:?} after {} pages, at {:?}",
+            self.step,
+            self.done,
+            self.current
+        );
+        params.rcx = self.step as u64;
+        params.rdx = self.done as u64;
+        params.r8 = self.current.map_or(u64::MAX, u64::from);
+        *JOURNAL.lock() = Some(self);
+    }
+}
+
+impl Default for RestoreJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drops the journal of an interrupted restore of a backup which is no
+/// longer current.
+pub fn discard_journal() {
+    *JOURNAL.lock() = None;
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Query of the guest physical address space layout.
+//!
+//! Guest drivers use the layout to find the RAM ranges they may register
+//! for backups, instead of hard-coding the firmware, SVSM and reserved
+//! ranges of a particular configuration.
+
+use crate::mm::memory::{guest_memory_layout, GuestMemoryRange};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+/// Size of a layout entry in the guest buffer: start and end address as
+/// `u64`, followed by the kind as `u32` and four reserved bytes.
+const LAYOUT_ENTRY_SIZE: usize = 24;
+
+fn entry_bytes(range: &GuestMemoryRange) -> [u8; LAYOUT_ENTRY_SIZE] {
+    let mut buf = [0u8; LAYOUT_ENTRY_SIZE];
+    buf[0..8].copy_from_slice(&u64::from(range.region.start()).to_le_bytes());
+    buf[8..16].copy_from_slice(&u64::from(range.region.end()).to_le_bytes());
+    buf[16..20].copy_from_slice(&(range.kind as u32).to_le_bytes());
+    buf
+}
+
+/// Writes the guest physical address space layout into the guest buffer at
+/// `rcx` of size `rdx`. On success `rcx` holds the number of entries. If the
+/// buffer is too small, `rcx` holds the number of entries and
+/// INVALID_PARAMETER is returned.
+pub fn query_memory_layout(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+
+    let layout = guest_memory_layout();
+    params.rcx = layout.len() as u64;
+    if layout.len() * LAYOUT_ENTRY_SIZE > buffer.size() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    for (i, range) in layout.iter().enumerate() {
+        buffer
+            .write((i * LAYOUT_ENTRY_SIZE) as u64, &entry_bytes(range))
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+    Ok(())
+}
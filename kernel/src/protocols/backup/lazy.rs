@@ -0,0 +1,347 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Lazy backups.
+//!
+//! A full backup copies every registered page before the guest runs again,
+//! so the pause grows with the size of the guest. A lazy backup only saves
+//! the ring, vTPM and vCPU state and write-protects the registered pages
+//! like `SVSM_ENABLE_COPY_ON_WRITE`. A page is copied into the backup when
+//! the guest first writes to it: the write fault reaches the SVSM through
+//! `SVSM_RESOLVE_COW_FAULT` (see the `dirty` module for why it cannot be
+//! intercepted directly), which copies the original contents before write
+//! access is restored. Until then the page still holds its backed-up
+//! contents, so restores are correct with only the written pages stored.
+//!
+//! Pages which were not copied yet are pending. Everything which needs the
+//! complete backup, or lifts the protection which keeps the pending pages
+//! unchanged, copies them first.
+//!
+//! The same protection makes a full backup consistent while other vCPUs
+//! keep running: in the protected mode of `SVSM_FULL_BACKUP` all registered
+//! pages become pending and protected before the first copy, and the backup
+//! copies them all before it returns. A vCPU writing a page in the meantime
+//! only waits for that page to be copied.
+//!
+//! The asynchronous mode of `SVSM_FULL_BACKUP` takes a lazy backup but
+//! keeps copying the pending pages a few at a time whenever the SVSM gains
+//! control from the guest. `SVSM_BACKUP_FINALIZE` copies the pages still
+//! pending, so the guest only pauses for the pages it did not get to. In
+//! its deferred mode it returns right away and copies them as a deferred
+//! request (see `protocols::deferred`), which the guest polls until done.
+
+use super::access::reset_access_stats;
+use super::budget::{admit_backup, estimate_backup_cost, SnapshotCharge};
+use super::devices::snapshot_devices;
+use super::dirty::{registered_page, release_registered_pages};
+use super::errors::{backup_exists, no_backup};
+use super::internal::snapshot_internal_state;
+use super::metadata::record_metadata;
+use super::pagetables::record_paging_roots;
+use super::rings::quiesce_and_save_rings;
+use super::stats::{record_backup_stats, shared_pages};
+use super::tracking::registered_bytes;
+use super::validation::start_validation_tracking;
+use super::vcpus::snapshot_vcpus;
+use super::verbosity::detail;
+use super::vtpm::snapshot_vtpm;
+use super::watchdog::cow_disabled;
+use super::{
+    backup_registered_page, discard_backup_pages, enable_copy_on_write, BACKUP_CREATED,
+    PAGES_TO_BACKUP, SVSM_BACKUP_FINALIZE, ZERO_PAGES,
+};
+use crate::address::PhysAddr;
+use crate::cpu::tsc::tsc_now;
+use crate::health::{set_backup_state, BackupState};
+use crate::locking::SpinLock;
+use crate::mm::pin::pin_pages;
+use crate::mm::set::PageSet;
+use crate::protocols::barrier::restore_in_progress;
+use crate::protocols::deferred::{defer_request, DeferredWork};
+use crate::protocols::errors::{SvsmReqError, SvsmResultCode};
+use crate::protocols::{RequestParams, SVSM_CUSTOM_PROTOCOL};
+use crate::task::preemption_point;
+use crate::types::{PageSize, PAGE_SIZE};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Number of pending pages copied in the background each time the SVSM
+/// gains control from the guest.
+const BACKGROUND_BATCH: usize = 16;
+/// Number of pending pages copied by each step of a deferred
+/// `SVSM_BACKUP_FINALIZE`.
+const FINALIZE_BATCH: usize = 256;
+
+/// Flag of `SVSM_BACKUP_FINALIZE` in `rcx`: copy the pending pages as a
+/// deferred request.
+const FINALIZE_DEFERRED: u64 = 1 << 0;
+
+/// Registered pages of the lazy backup which were not copied yet.
+static PENDING: PageSet = PageSet::new();
+/// Serializes copying pending pages, so a page racing between two vCPUs is
+/// copied once. Comes before `BACKUP_PAGES` in the lock order.
+static CAPTURE: SpinLock<()> = SpinLock::new(());
+/// Whether the pending pages are copied in the background.
+static BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+/// Takes a lazy backup of the registered pages and the vCPU state. Like a
+/// full backup it sets `rdx` to 0, and a vCPU resuming from the backup
+/// after a restore sees 1. The backup reserves budget for all registered
+/// pages, but pages are only copied on their first write. Fails with
+/// `SVSM_ERR_BACKUP_EXISTS` if a backup exists.
+pub fn lazy_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    params.rdx = 0;
+    if *(BACKUP_CREATED.lock()) {
+        log::info!("Backup already exists. No new backup will be created.");
+        return Err(backup_exists());
+    }
+
+    let registered = registered_bytes()?;
+    admit_backup(estimate_backup_cost(registered))?;
+
+    quiesce_and_save_rings()?;
+
+    detail!("Starting lazy backup...");
+    set_backup_state(BackupState::BackingUp);
+    start_validation_tracking();
+    let start = tsc_now();
+    let roots = record_paging_roots();
+    mark_pending();
+    let result = snapshot_vtpm()
+        .and_then(|_| snapshot_devices())
+        .map(|_| snapshot_internal_state())
+        .and_then(|_| snapshot_vcpus(roots))
+        .and_then(|_| enable_copy_on_write());
+    if let Err(err) = result {
+        discard_backup_pages();
+        set_backup_state(BackupState::Idle);
+        return Err(err);
+    }
+
+    record_backup_stats(start);
+    record_metadata((registered / PAGE_SIZE) as u64);
+    *(BACKUP_CREATED.lock()) = true;
+    reset_access_stats();
+    log::info!(
+        "Lazy backup taken, {} registered pages pending",
+        PENDING.size()
+    );
+    Ok(())
+}
+
+/// Takes a backup in the asynchronous mode of `SVSM_FULL_BACKUP`: a lazy
+/// backup whose pending pages are also copied in the background. On success
+/// `rcx` is 0 and `r8` holds the number of 4K pages pending.
+pub fn async_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    lazy_backup(params)?;
+    params.rcx = 0;
+    params.r8 = pending_pages();
+    BACKGROUND.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the number of 4K pages pending.
+fn pending_pages() -> u64 {
+    PENDING
+        .iter_addresses()
+        .map(|(_, size)| (usize::from(size) / PAGE_SIZE) as u64)
+        .sum()
+}
+
+/// Copies a batch of pending pages of an asynchronous backup. Called
+/// whenever the SVSM gains control from the guest, so it gives way to
+/// restores and to other vCPUs copying pages. A failed copy stops the
+/// background copies, the page stays pending for `SVSM_BACKUP_FINALIZE`.
+pub fn advance_background_backup() {
+    if !BACKGROUND.load(Ordering::Relaxed) || restore_in_progress() {
+        return;
+    }
+    let Some(_guard) = CAPTURE.try_lock() else {
+        return;
+    };
+    for (paddr, size) in PENDING.first(BACKGROUND_BATCH) {
+        if let Err(e) = capture(paddr, size) {
+            log::warn!("Background backup of {:#x} failed: {:?}", paddr, e);
+            BACKGROUND.store(false, Ordering::Relaxed);
+            return;
+        }
+    }
+    if PENDING.is_empty() {
+        log::info!("Background backup complete");
+        BACKGROUND.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Completes a lazy or asynchronous backup by copying all pending pages.
+/// Copy-on-write stays enabled, so partial restores remain possible. On
+/// return `rcx` holds the number of 4K pages copied by the call, also on
+/// failure, and on success `rdx` the number of registered 4K pages skipped
+/// because they were not private guest memory. Fails with
+/// `SVSM_ERR_NO_BACKUP` if there is no backup and with
+/// `SVSM_ERR_BACKUP_OVER_BUDGET` if a copy does not fit into the budget, in
+/// which case the call can be repeated after freeing memory. With
+/// [`FINALIZE_DEFERRED`] in `rcx` the call fails with INCOMPLETE and `rcx`
+/// holds the token to poll for the same results instead.
+pub fn finalize_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let flags = params.rcx;
+    if flags & !FINALIZE_DEFERRED != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    params.rcx = 0;
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    let pending = pending_pages();
+    if flags & FINALIZE_DEFERRED != 0 && pending != 0 {
+        let work = Box::new(DeferredFinalize { pending });
+        let err = defer_request(SVSM_CUSTOM_PROTOCOL, SVSM_BACKUP_FINALIZE, params, work);
+        // Once the work is queued, the background copies would race it for
+        // CAPTURE. If it was not, e.g. with BUSY, they carry on.
+        if matches!(err, SvsmReqError::RequestError(SvsmResultCode::INCOMPLETE)) {
+            BACKGROUND.store(false, Ordering::Relaxed);
+        }
+        return Err(err);
+    }
+    let result = settle_lazy_backup();
+    params.rcx = pending - pending_pages();
+    result?;
+    finalized(params);
+    Ok(())
+}
+
+/// Completes `SVSM_BACKUP_FINALIZE` once all pending pages are copied.
+fn finalized(params: &mut RequestParams) {
+    BACKGROUND.store(false, Ordering::Relaxed);
+    params.rdx = shared_pages();
+    log::info!("Finalized backup, copied {} pages", params.rcx);
+}
+
+/// A deferred `SVSM_BACKUP_FINALIZE`.
+#[derive(Debug)]
+struct DeferredFinalize {
+    /// Number of 4K pages pending when the call was made.
+    pending: u64,
+}
+
+impl DeferredWork for DeferredFinalize {
+    fn step(&mut self, params: &mut RequestParams) -> Poll<Result<(), SvsmReqError>> {
+        if !*BACKUP_CREATED.lock() {
+            return Poll::Ready(Err(no_backup()));
+        }
+        let result = {
+            let _guard = CAPTURE.lock();
+            PENDING
+                .first(FINALIZE_BATCH)
+                .into_iter()
+                .try_for_each(|(paddr, size)| capture(paddr, size))
+        };
+        params.rcx = self.pending.saturating_sub(pending_pages());
+        if let Err(err) = result {
+            return Poll::Ready(Err(err));
+        }
+        if !PENDING.is_empty() {
+            return Poll::Pending;
+        }
+        finalized(params);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Marks all registered pages pending. Pages become pending before they are
+/// protected, so no write can get through without a copy.
+fn mark_pending() {
+    for size in [PageSize::Regular, PageSize::Huge] {
+        for (start, end) in PAGES_TO_BACKUP.iter_ranges(size) {
+            PENDING.insert_range(start, end, size);
+        }
+    }
+}
+
+/// Copies the registered page at `paddr` of `size` into the backup if it
+/// is pending. `CAPTURE` must be held.
+fn capture(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmReqError> {
+    if !PENDING.contains_addr(paddr, size) {
+        return Ok(());
+    }
+    let mut charge = SnapshotCharge::new();
+    backup_registered_page(paddr, size, &mut None, &mut charge)?;
+    PENDING.remove_addr(paddr, size);
+    Ok(())
+}
+
+/// Copies the registered page containing the guest page at `paddr` into
+/// the backup if it is pending, so it can be written or read from the
+/// backup. Fails with `SVSM_ERR_BACKUP_OVER_BUDGET` if the copy does not fit
+/// into the budget, in which case the page stays pending and protected.
+pub fn capture_pending(paddr: PhysAddr) -> Result<(), SvsmReqError> {
+    if PENDING.is_empty() {
+        return Ok(());
+    }
+    let Some((page, size)) = registered_page(paddr) else {
+        return Ok(());
+    };
+    // The guest keeps running on other vCPUs, which must not remap the page
+    // while it is copied.
+    let _pins = pin_pages(page, size)?;
+    let _guard = CAPTURE.lock();
+    capture(page, size)
+}
+
+/// Copies all pending pages into the backup, which completes a lazy
+/// backup.
+pub fn settle_lazy_backup() -> Result<(), SvsmReqError> {
+    if PENDING.is_empty() {
+        return Ok(());
+    }
+    let _guard = CAPTURE.lock();
+    log::info!(
+        "Copying {} pending pages of the lazy backup",
+        PENDING.size()
+    );
+    for (paddr, size) in PENDING.iter_addresses() {
+        capture(paddr, size)?;
+        preemption_point();
+    }
+    Ok(())
+}
+
+/// Copies the registered pages for a full backup while the guest keeps
+/// running. The pages are write-protected before the first copy and made
+/// writable again once all are copied, so the backup holds their contents
+/// as of the start of the copy. Returns the bytes stored and the bytes
+/// skipped as zero. On failure the protection is lifted as well.
+pub fn backup_protected_pages() -> Result<(u64, u64), SvsmReqError> {
+    mark_pending();
+    let result = enable_copy_on_write().and_then(|_| {
+        for (paddr, size) in PENDING.iter_addresses() {
+            let guard = CAPTURE.lock();
+            capture(paddr, size)?;
+            drop(guard);
+            preemption_point();
+        }
+        Ok(())
+    });
+    let released = release_registered_pages();
+    cow_disabled();
+    result?;
+    released?;
+
+    let registered: usize = PAGES_TO_BACKUP
+        .iter_addresses()
+        .map(|(_, size)| usize::from(size))
+        .sum();
+    let skipped = (ZERO_PAGES.lock_read().len() * PAGE_SIZE) as u64;
+    let shared = shared_pages() * PAGE_SIZE as u64;
+    Ok((
+        (registered as u64).saturating_sub(skipped + shared),
+        skipped,
+    ))
+}
+
+/// Forgets the pending pages after the backup was discarded.
+pub fn discard_pending() {
+    PENDING.clear();
+    BACKGROUND.store(false, Ordering::Relaxed);
+}
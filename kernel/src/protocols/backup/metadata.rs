@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Metadata of snapshots.
+//!
+//! Orchestration layers juggling several snapshots of a guest need to know
+//! which one they are about to restore. Every backup carries a metadata
+//! record with the time it was taken, its number of pages and the identity
+//! of the guest it belongs to: the SNP launch measurement and the guest
+//! policy from a VMPL0 attestation report. Neither changes while the guest
+//! runs, so the report is only requested with the first backup. The record
+//! of the current backup or of a named snapshot is read with
+//! `SVSM_QUERY_SNAPSHOT_METADATA`.
+//!
+//! The timestamp is the SVSM uptime in nanoseconds. It is not wall-clock
+//! time, but restores do not roll it back, so it orders the snapshots of a
+//! guest. The record of an imported snapshot describes the import.
+//!
+//! The record is 80 bytes of little-endian fields:
+//!
+//! | Offset | Size | Field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0x00   | 4    | Version of the record, currently 1           |
+//! | 0x04   | 4    | Flags, see [`METADATA_MEASURED`]             |
+//! | 0x08   | 8    | Creation time in nanoseconds of SVSM uptime  |
+//! | 0x10   | 8    | Number of 4K pages                           |
+//! | 0x18   | 8    | Guest policy                                 |
+//! | 0x20   | 48   | Launch measurement                           |
+
+use super::named::snapshot_metadata;
+use crate::cpu::tsc::uptime_ns;
+use crate::greq::pld_report::{SnpReportResponse, MEASUREMENT_SIZE};
+use crate::greq::services::get_regular_report;
+use crate::locking::SpinLock;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use core::mem::size_of;
+
+extern crate alloc;
+use alloc::vec;
+
+/// Version of the metadata record.
+const METADATA_VERSION: u32 = 1;
+/// Size of the metadata record in the guest buffer.
+pub const METADATA_SIZE: usize = 80;
+/// Record flag: the policy and measurement fields are valid. They are
+/// zero if the attestation report could not be obtained.
+pub const METADATA_MEASURED: u32 = 1 << 0;
+
+/// Launch measurement and policy of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LaunchIdentity {
+    pub measurement: [u8; MEASUREMENT_SIZE],
+    pub policy: u64,
+}
+
+/// Metadata of a backup.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotMetadata {
+    /// Creation time in nanoseconds of SVSM uptime.
+    created_ns: u64,
+    /// Number of 4K pages.
+    pages: u64,
+    identity: Option<LaunchIdentity>,
+}
+
+impl SnapshotMetadata {
+    pub fn to_bytes(self) -> [u8; METADATA_SIZE] {
+        let mut buf = [0u8; METADATA_SIZE];
+        let flags = match self.identity {
+            Some(_) => METADATA_MEASURED,
+            None => 0,
+        };
+        buf[0x00..0x04].copy_from_slice(&METADATA_VERSION.to_le_bytes());
+        buf[0x04..0x08].copy_from_slice(&flags.to_le_bytes());
+        buf[0x08..0x10].copy_from_slice(&self.created_ns.to_le_bytes());
+        buf[0x10..0x18].copy_from_slice(&self.pages.to_le_bytes());
+        if let Some(identity) = self.identity {
+            buf[0x18..0x20].copy_from_slice(&identity.policy.to_le_bytes());
+            buf[0x20..0x20 + MEASUREMENT_SIZE].copy_from_slice(&identity.measurement);
+        }
+        buf
+    }
+
+    /// Parses a record written by [`Self::to_bytes`].
+    pub fn from_bytes(buf: &[u8; METADATA_SIZE]) -> Option<Self> {
+        let field = |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let version = u32::from_le_bytes(buf[0x00..0x04].try_into().unwrap());
+        let flags = u32::from_le_bytes(buf[0x04..0x08].try_into().unwrap());
+        if version != METADATA_VERSION {
+            return None;
+        }
+        let identity = (flags & METADATA_MEASURED != 0).then(|| LaunchIdentity {
+            measurement: buf[0x20..0x20 + MEASUREMENT_SIZE].try_into().unwrap(),
+            policy: field(0x18),
+        });
+        Some(Self {
+            created_ns: field(0x08),
+            pages: field(0x10),
+            identity,
+        })
+    }
+
+    /// Returns the number of 4K pages of the backup.
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+}
+
+/// Launch identity from the first attestation report.
+static LAUNCH_IDENTITY: SpinLock<Option<LaunchIdentity>> = SpinLock::new(None);
+/// Metadata of the current backup. Comes last in the lock order.
+static METADATA: SpinLock<Option<SnapshotMetadata>> = SpinLock::new(None);
+
+/// Returns the launch identity of the guest, requesting an attestation
+/// report the first time.
+pub fn launch_identity() -> Option<LaunchIdentity> {
+    if let Some(identity) = *LAUNCH_IDENTITY.lock() {
+        return Some(identity);
+    }
+    let mut message = vec![0u8; size_of::<SnpReportResponse>()];
+    let identity = get_regular_report(&mut message).and_then(|_| {
+        let response = SnpReportResponse::try_from_as_ref(&message)?;
+        response.validate()?;
+        Ok(LaunchIdentity {
+            measurement: response.report().measurement(),
+            policy: response.report().policy(),
+        })
+    });
+    match identity {
+        Ok(identity) => {
+            *LAUNCH_IDENTITY.lock() = Some(identity);
+            Some(identity)
+        }
+        Err(err) => {
+            log::warn!("No launch measurement for snapshot metadata: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Records the metadata of the backup of `pages` 4K pages just taken.
+pub fn record_metadata(pages: u64) {
+    let metadata = SnapshotMetadata {
+        created_ns: uptime_ns(),
+        pages,
+        identity: launch_identity(),
+    };
+    *METADATA.lock() = Some(metadata);
+}
+
+/// Drops the metadata of a discarded backup.
+pub fn discard_metadata() {
+    *METADATA.lock() = None;
+}
+
+/// Returns the metadata of the current backup.
+pub fn current_metadata() -> Option<SnapshotMetadata> {
+    *METADATA.lock()
+}
+
+/// Takes the metadata of the current backup.
+pub fn take_metadata() -> Option<SnapshotMetadata> {
+    METADATA.lock().take()
+}
+
+/// Makes `metadata` the metadata of the current backup.
+pub fn put_metadata(metadata: Option<SnapshotMetadata>) {
+    *METADATA.lock() = metadata;
+}
+
+/// Writes the metadata record of snapshot `r8` into the guest buffer at
+/// `rcx` of size `rdx`. An ID of `u64::MAX` selects the current backup. On
+/// success `rdx` holds the size of the record. Fails with
+/// `SVSM_ERR_NO_BACKUP` if there is no current backup, and with
+/// INVALID_PARAMETER if there is no such snapshot or the buffer is too
+/// small.
+pub fn query_metadata(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+    if buffer.size() < METADATA_SIZE {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let metadata = snapshot_metadata(params.r8)?;
+    buffer
+        .write(0, &metadata.to_bytes())
+        .map_err(SvsmReqError::from_mapping)?;
+    params.rdx = METADATA_SIZE as u64;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_bytes() {
+        let metadata = SnapshotMetadata {
+            created_ns: 0x1122,
+            pages: 0x33,
+            identity: Some(LaunchIdentity {
+                measurement: [0xaa; MEASUREMENT_SIZE],
+                policy: 0x30000,
+            }),
+        };
+        let bytes = metadata.to_bytes();
+        assert_eq!(bytes[0x00..0x04], 1u32.to_le_bytes());
+        assert_eq!(bytes[0x04..0x08], METADATA_MEASURED.to_le_bytes());
+        assert_eq!(bytes[0x08..0x10], 0x1122u64.to_le_bytes());
+        assert_eq!(bytes[0x10..0x18], 0x33u64.to_le_bytes());
+        assert_eq!(bytes[0x18..0x20], 0x30000u64.to_le_bytes());
+        assert!(bytes[0x20..].iter().all(|&b| b == 0xaa));
+
+        let unmeasured = SnapshotMetadata {
+            identity: None,
+            ..metadata
+        }
+        .to_bytes();
+        assert_eq!(unmeasured[0x04..0x08], 0u32.to_le_bytes());
+        assert!(unmeasured[0x18..].iter().all(|&b| b == 0));
+
+        let parsed = SnapshotMetadata::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert!(SnapshotMetadata::from_bytes(&[0; METADATA_SIZE]).is_none());
+    }
+}
@@ -0,0 +1,438 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Migration of the SVSM state of a guest to another host.
+//!
+//! Guest memory is moved by the migration agent, but some state the
+//! destination SVSM needs lives in SVSM memory: the restore generation
+//! and the metadata of the current backup, the vTPM state and which vCPU
+//! runs on which VMSA. `SVSM_EXPORT_SVSM_STATE` writes it into a blob
+//! which `SVSM_IMPORT_SVSM_STATE` loads on the destination.
+//!
+//! The payload is encrypted with AES-256-GCM under a key the guest cannot
+//! learn: a VMPL0 key the PSP derives from the VM root key (VMRK), with
+//! the guest policy and launch measurement mixed in. The migration agent
+//! moves the VMRK along with the guest, so the destination SVSM derives
+//! the same key, while the guest kernel can neither read the vTPM state
+//! from a blob nor forge one. Without a VMRK both calls fail with
+//! INVALID_REQUEST.
+//!
+//! The blob is also bound to the migration agent set with
+//! `SVSM_SET_MIGRATION_AGENT`, a 32-byte identity like the digest of its
+//! launch measurement, and to a single-use challenge of the destination:
+//! setting the agent returns a fresh random challenge, which the export on
+//! the source takes and the import on the destination consumes. The header,
+//! which holds both, is the associated data, so any change is detected and
+//! a blob is only imported once, by the SVSM which issued its challenge.
+//! Older blobs carry older challenges and are rejected, and an SVSM imports
+//! at most one blob, so the vTPM cannot be rolled back by replaying one.
+//!
+//! The import also checks that the destination guest has the same launch
+//! measurement and policy, and that its vCPUs run on the same VMSAs, which
+//! the agent creates before the import. Nothing is changed unless every
+//! check passes.
+//!
+//! The blob starts with a 96-byte header of little-endian fields:
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0x00   | 8    | Magic, `SVSMMIGR`                      |
+//! | 0x08   | 4    | Version of the blob, currently 2       |
+//! | 0x0c   | 4    | Size of the encrypted payload          |
+//! | 0x10   | 12   | AES-GCM nonce                          |
+//! | 0x1c   | 4    | Reserved, zero                         |
+//! | 0x20   | 32   | Identity of the migration agent        |
+//! | 0x40   | 32   | Challenge of the destination SVSM      |
+//!
+//! followed by the encrypted payload and its 16-byte authentication tag.
+
+use super::checksum::SVSM_ERR_BACKUP_CORRUPTED;
+use super::metadata::{
+    current_metadata, launch_identity, put_metadata, SnapshotMetadata, METADATA_SIZE,
+};
+use super::reseed::{raise_restore_generation, restore_generation};
+use super::vtpm::{load_migration_vtpm, migration_vtpm};
+use super::BACKUP_CREATED;
+use crate::address::PhysAddr;
+use crate::cpu::percpu::PERCPU_AREAS;
+use crate::crypto::aead::{Aes256Gcm, Aes256GcmTrait, AUTHTAG_SIZE, IV_SIZE, KEY_SIZE};
+use crate::crypto::digest::{Sha256, Sha256Trait};
+use crate::crypto::rng::fill_random;
+use crate::greq::pld_key::{
+    SnpDerivedKeyRequest, FIELD_GUEST_POLICY, FIELD_MEASUREMENT, ROOT_KEY_VMRK,
+};
+use crate::greq::pld_report::MEASUREMENT_SIZE;
+use crate::greq::services::get_derived_key;
+use crate::locking::SpinLock;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MIGRATION_MAGIC: [u8; 8] = *b"SVSMMIGR";
+const MIGRATION_VERSION: u32 = 2;
+const HEADER_SIZE: usize = 0x60;
+/// Size of the agent identity.
+const IDENTITY_SIZE: usize = 32;
+/// Size of the import challenge.
+const CHALLENGE_SIZE: usize = 32;
+/// Label of the blob key derivation.
+const KEY_LABEL: &[u8] = b"COCONUT-SVSM migration";
+
+/// Payload flag: the launch identity fields are valid.
+const STATE_MEASURED: u32 = 1 << 0;
+/// Payload flag: the backup metadata record is valid.
+const STATE_BACKUP: u32 = 1 << 1;
+/// Size of the fixed part of the payload, followed by the vCPU entries and
+/// the vTPM state.
+const STATE_FIXED_SIZE: usize = 0xa0;
+/// Size of a vCPU entry: APIC ID, four reserved bytes and VMSA address.
+const VCPU_ENTRY_SIZE: usize = 16;
+
+/// Migration agent the state is bound to.
+#[derive(Clone, Copy, Debug)]
+struct MigrationAgent {
+    identity: [u8; IDENTITY_SIZE],
+    /// Challenge a blob must carry to be imported, until one is.
+    challenge: Option<[u8; CHALLENGE_SIZE]>,
+}
+
+impl MigrationAgent {
+    /// Returns the key of blobs bound to the agent.
+    fn blob_key(&self) -> Result<[u8; KEY_SIZE], SvsmReqError> {
+        let request =
+            SnpDerivedKeyRequest::new(ROOT_KEY_VMRK, FIELD_GUEST_POLICY | FIELD_MEASUREMENT);
+        let mut sealing_key = get_derived_key(&request)?;
+        let mut hash = Sha256::new();
+        hash.update(KEY_LABEL);
+        hash.update(&sealing_key);
+        hash.update(&self.identity);
+        sealing_key.fill(0);
+        Ok(hash.finalize())
+    }
+}
+
+static AGENT: SpinLock<Option<MigrationAgent>> = SpinLock::new(None);
+/// Whether this SVSM imported a blob already.
+static IMPORTED: AtomicBool = AtomicBool::new(false);
+
+/// SVSM state moved with a migration.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MigrationState {
+    generation: u64,
+    /// Launch policy and measurement of the guest.
+    identity: Option<(u64, [u8; MEASUREMENT_SIZE])>,
+    /// Metadata record of the current backup.
+    metadata: Option<[u8; METADATA_SIZE]>,
+    /// APIC ID and VMSA address of every vCPU.
+    vcpus: Vec<(u32, u64)>,
+    /// NV memory of the vTPM, empty without one.
+    vtpm: Vec<u8>,
+}
+
+impl MigrationState {
+    /// Collects the state of this SVSM.
+    fn collect() -> Result<Self, SvsmReqError> {
+        let metadata = current_metadata()
+            .filter(|_| *BACKUP_CREATED.lock())
+            .map(SnapshotMetadata::to_bytes);
+        let vcpus = PERCPU_AREAS
+            .iter()
+            .filter_map(|info| {
+                let cpu = info.as_cpu_ref();
+                Some((cpu.apic_id(), u64::from(cpu.guest_vmsa_phys()?)))
+            })
+            .collect();
+        Ok(Self {
+            generation: restore_generation(),
+            identity: launch_identity().map(|id| (id.policy, id.measurement)),
+            metadata,
+            vcpus,
+            vtpm: migration_vtpm()?,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; STATE_FIXED_SIZE];
+        let mut flags = 0;
+        if let Some((policy, measurement)) = self.identity {
+            flags |= STATE_MEASURED;
+            buf[0x10..0x18].copy_from_slice(&policy.to_le_bytes());
+            buf[0x18..0x18 + MEASUREMENT_SIZE].copy_from_slice(&measurement);
+        }
+        if let Some(metadata) = self.metadata {
+            flags |= STATE_BACKUP;
+            buf[0x48..0x48 + METADATA_SIZE].copy_from_slice(&metadata);
+        }
+        buf[0x00..0x04].copy_from_slice(&flags.to_le_bytes());
+        buf[0x04..0x08].copy_from_slice(&(self.vcpus.len() as u32).to_le_bytes());
+        buf[0x08..0x10].copy_from_slice(&self.generation.to_le_bytes());
+        buf[0x98..0x9c].copy_from_slice(&(self.vtpm.len() as u32).to_le_bytes());
+        for &(apic_id, vmsa) in self.vcpus.iter() {
+            buf.extend_from_slice(&apic_id.to_le_bytes());
+            buf.extend_from_slice(&[0; 4]);
+            buf.extend_from_slice(&vmsa.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.vtpm);
+        buf
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let fixed = buf.get(..STATE_FIXED_SIZE)?;
+        let word =
+            |offset: usize| u32::from_le_bytes(fixed[offset..offset + 4].try_into().unwrap());
+        let flags = word(0x00);
+        let vcpu_count = word(0x04) as usize;
+        let vtpm_size = word(0x98) as usize;
+        let vcpus_end = vcpu_count
+            .checked_mul(VCPU_ENTRY_SIZE)?
+            .checked_add(STATE_FIXED_SIZE)?;
+        if vcpus_end.checked_add(vtpm_size)? != buf.len() {
+            return None;
+        }
+
+        let identity = (flags & STATE_MEASURED != 0).then(|| {
+            (
+                u64::from_le_bytes(fixed[0x10..0x18].try_into().unwrap()),
+                fixed[0x18..0x18 + MEASUREMENT_SIZE].try_into().unwrap(),
+            )
+        });
+        let metadata = (flags & STATE_BACKUP != 0)
+            .then(|| fixed[0x48..0x48 + METADATA_SIZE].try_into().unwrap());
+        let vcpus = buf[STATE_FIXED_SIZE..vcpus_end]
+            .chunks_exact(VCPU_ENTRY_SIZE)
+            .map(|entry| {
+                (
+                    u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+                    u64::from_le_bytes(entry[8..16].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Some(Self {
+            generation: u64::from_le_bytes(fixed[0x08..0x10].try_into().unwrap()),
+            identity,
+            metadata,
+            vcpus,
+            vtpm: buf[vcpus_end..].to_vec(),
+        })
+    }
+}
+
+fn header(
+    payload_size: usize,
+    iv: &[u8; IV_SIZE],
+    identity: &[u8; IDENTITY_SIZE],
+    challenge: &[u8; CHALLENGE_SIZE],
+) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[0x00..0x08].copy_from_slice(&MIGRATION_MAGIC);
+    header[0x08..0x0c].copy_from_slice(&MIGRATION_VERSION.to_le_bytes());
+    header[0x0c..0x10].copy_from_slice(&(payload_size as u32).to_le_bytes());
+    header[0x10..0x1c].copy_from_slice(iv);
+    header[0x20..0x40].copy_from_slice(identity);
+    header[0x40..0x60].copy_from_slice(challenge);
+    header
+}
+
+fn agent() -> Result<MigrationAgent, SvsmReqError> {
+    AGENT.lock().ok_or_else(|| {
+        log::warn!("No migration agent set");
+        SvsmReqError::invalid_request()
+    })
+}
+
+fn rejected(reason: &str) -> SvsmReqError {
+    log::error!("Migration state rejected: {}", reason);
+    SvsmReqError::protocol(SVSM_ERR_BACKUP_CORRUPTED)
+}
+
+/// Sets the migration agent from the identity in the first 32 bytes of
+/// the 64 bytes at `rcx`, and writes a fresh import challenge into the
+/// other 32 bytes. The challenge replaces any earlier one. An address of
+/// zero clears the agent.
+pub fn set_migration_agent(params: &RequestParams) -> Result<(), SvsmReqError> {
+    if params.rcx == 0 {
+        *AGENT.lock() = None;
+        log::info!("Cleared migration agent");
+        return Ok(());
+    }
+    let buffer = GuestBuffer::new(PhysAddr::from(params.rcx), IDENTITY_SIZE + CHALLENGE_SIZE)?;
+    let identity = buffer.read_obj(0).map_err(SvsmReqError::from_mapping)?;
+    let mut challenge = [0u8; CHALLENGE_SIZE];
+    fill_random(&mut challenge).map_err(SvsmReqError::from_mapping)?;
+    buffer
+        .write_obj(IDENTITY_SIZE as u64, &challenge)
+        .map_err(SvsmReqError::from_mapping)?;
+    *AGENT.lock() = Some(MigrationAgent {
+        identity,
+        challenge: Some(challenge),
+    });
+    log::info!("Set migration agent");
+    Ok(())
+}
+
+/// Writes the SVSM state bound to the migration agent and the 32-byte
+/// challenge of the destination at `r8` into the guest buffer at `rcx` of
+/// size `rdx`. On success `rcx` holds the size of the blob. If the buffer
+/// is too small, `rcx` holds the required size and INVALID_PARAMETER is
+/// returned. Fails with INVALID_REQUEST if no agent is set.
+pub fn export_svsm_state(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let agent = agent()?;
+    let start = PhysAddr::from(params.rcx);
+    let challenge: [u8; CHALLENGE_SIZE] =
+        GuestBuffer::read_only(PhysAddr::from(params.r8), CHALLENGE_SIZE)?
+            .read_obj(0)
+            .map_err(SvsmReqError::from_mapping)?;
+    let key = agent.blob_key()?;
+    let mut payload = MigrationState::collect()?.to_bytes();
+    let size = HEADER_SIZE + payload.len() + AUTHTAG_SIZE;
+    params.rcx = size as u64;
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    if len < size {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let buffer = GuestBuffer::new(start, len)?;
+
+    let mut iv = [0u8; IV_SIZE];
+    fill_random(&mut iv).map_err(SvsmReqError::from_mapping)?;
+    let header = header(payload.len(), &iv, &agent.identity, &challenge);
+    let mut blob = vec![0u8; size];
+    blob[..HEADER_SIZE].copy_from_slice(&header);
+    let result = Aes256Gcm::encrypt(&iv, &key, &header, &payload, &mut blob[HEADER_SIZE..]);
+    // Do not leave the vTPM state behind in freed SVSM memory.
+    payload.fill(0);
+    result?;
+    buffer.write(0, &blob).map_err(SvsmReqError::from_mapping)?;
+    log::info!("Exported SVSM state for migration, {} bytes", size);
+    Ok(())
+}
+
+/// Checks that `state` can be loaded into this SVSM.
+fn check_state(state: &MigrationState) -> Result<(), SvsmReqError> {
+    if let Some((policy, measurement)) = state.identity {
+        let same = launch_identity()
+            .is_some_and(|id| id.policy == policy && id.measurement == measurement);
+        if !same {
+            return Err(rejected("launch measurement or policy differs"));
+        }
+    }
+    for &(apic_id, vmsa) in state.vcpus.iter() {
+        let vmsa_here = PERCPU_AREAS
+            .get(apic_id)
+            .and_then(|cpu| cpu.guest_vmsa_phys());
+        if vmsa_here != Some(PhysAddr::from(vmsa)) {
+            return Err(rejected("vCPUs differ"));
+        }
+    }
+    Ok(())
+}
+
+/// Loads the SVSM state from the blob in the guest buffer at `rcx` of size
+/// `rdx`, written by `SVSM_EXPORT_SVSM_STATE` on another host. Fails with
+/// INVALID_REQUEST if no agent is set or a blob was imported already, and
+/// with `SVSM_ERR_BACKUP_CORRUPTED` if the blob is not bound to the agent
+/// and the current challenge, fails authentication or does not match this
+/// guest.
+pub fn import_svsm_state(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let agent = agent()?;
+    if IMPORTED.load(Ordering::Acquire) {
+        log::warn!("SVSM state was imported already");
+        return Err(SvsmReqError::invalid_request());
+    }
+    let challenge = agent
+        .challenge
+        .ok_or_else(|| rejected("no pending challenge"))?;
+    let buffer = GuestBuffer::from_params(params)?;
+    let len = buffer.size();
+    if len < HEADER_SIZE + AUTHTAG_SIZE {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let head: [u8; HEADER_SIZE] = buffer.read_obj(0).map_err(SvsmReqError::from_mapping)?;
+    let payload_size = u32::from_le_bytes(head[0x0c..0x10].try_into().unwrap()) as usize;
+    let iv: [u8; IV_SIZE] = head[0x10..0x1c].try_into().unwrap();
+    if head != header(payload_size, &iv, &agent.identity, &challenge) {
+        return Err(rejected(
+            "header does not match the migration agent or challenge",
+        ));
+    }
+    let size = payload_size
+        .checked_add(HEADER_SIZE + AUTHTAG_SIZE)
+        .filter(|&size| size <= len)
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+
+    let mut sealed = vec![0u8; size - HEADER_SIZE];
+    buffer
+        .read(HEADER_SIZE as u64, &mut sealed)
+        .map_err(SvsmReqError::from_mapping)?;
+    let mut payload = vec![0u8; payload_size];
+    Aes256Gcm::decrypt(&iv, &agent.blob_key()?, &head, &sealed, &mut payload)
+        .map_err(|_| rejected("authentication failed"))?;
+    // The challenge is used up by the first authentic blob, even if it
+    // does not match this guest.
+    if let Some(agent) = AGENT.lock().as_mut() {
+        agent.challenge = None;
+    }
+    let state = MigrationState::from_bytes(&payload);
+    payload.fill(0);
+    let state = state.ok_or_else(|| rejected("malformed payload"))?;
+    check_state(&state)?;
+    if IMPORTED.swap(true, Ordering::AcqRel) {
+        return Err(SvsmReqError::invalid_request());
+    }
+
+    if !state.vtpm.is_empty() {
+        load_migration_vtpm(&state.vtpm)?;
+    }
+    raise_restore_generation(state.generation);
+    // The metadata describes the backup of the source, which the agent
+    // imports as a snapshot container before the SVSM state.
+    if let Some(record) = state
+        .metadata
+        .as_ref()
+        .and_then(SnapshotMetadata::from_bytes)
+    {
+        let matches = *BACKUP_CREATED.lock()
+            && current_metadata().is_some_and(|current| current.pages() == record.pages());
+        if matches {
+            put_metadata(Some(record));
+        }
+    }
+    log::info!(
+        "Imported SVSM state, restore generation {}",
+        restore_generation()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_bytes() {
+        let state = MigrationState {
+            generation: 7,
+            identity: Some((0x30000, [0xaa; MEASUREMENT_SIZE])),
+            metadata: Some([0x55; METADATA_SIZE]),
+            vcpus: vec![(0, 0x1000), (1, 0x5000)],
+            vtpm: vec![1, 2, 3],
+        };
+        let bytes = state.to_bytes();
+        assert_eq!(bytes.len(), STATE_FIXED_SIZE + 2 * VCPU_ENTRY_SIZE + 3);
+        assert_eq!(MigrationState::from_bytes(&bytes), Some(state));
+
+        let empty = MigrationState::default();
+        assert_eq!(
+            MigrationState::from_bytes(&empty.to_bytes()),
+            Some(MigrationState::default())
+        );
+        // Truncated or padded payloads are rejected.
+        assert!(MigrationState::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert!(MigrationState::from_bytes(&padded).is_none());
+    }
+}
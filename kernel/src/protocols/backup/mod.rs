@@ -0,0 +1,1293 @@
+use crate::address::{Address, PhysAddr};
+use crate::cpu::tsc::{ticks_to_ns, tsc_now};
+use crate::debug::fault::{inject_fault, FaultPoint};
+use crate::error::SvsmError;
+use crate::fw_protect::fw_page_protected;
+use crate::health::{set_backup_state, BackupState};
+use crate::protocols::barrier::RestoreBarrier;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::registry::BuiltinProtocol;
+use crate::protocols::{RequestParams, SVSM_CUSTOM_PROTOCOL};
+use crate::mm::alloc::AllocError;
+use crate::mm::set::PageSet;
+use crate::sev::ghcb::{ghcb_retry_stats, GhcbRetryStats};
+use crate::sev::rmp::{rmp_set_guest_access_paddr, GuestAccess};
+use crate::types::{PageSize, PAGE_SHIFT, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::mm::copy::{is_zeroed, CopyDest, CopyFlags, CopySource, PageCopier};
+use crate::mm::guestmem::{fill_phys_range, write_phys_page, write_phys_range};
+use crate::mm::{allocate_file_page_ref, writable_phys_addr, PageBox};
+use crate::locking::{RWLock, SpinLock};
+use crate::task::preemption_point;
+
+mod access;
+mod arena;
+mod attest;
+mod budget;
+mod caps;
+mod checkpoint;
+mod checksum;
+mod compress;
+mod crypt;
+mod delta;
+mod devices;
+mod diff;
+mod dirty;
+mod errors;
+mod export;
+mod index;
+mod inspect;
+mod internal;
+mod journal;
+mod lazy;
+mod layout;
+mod metadata;
+mod migrate;
+mod named;
+mod pacing;
+mod pagetables;
+mod parallel;
+mod paranoid;
+mod partial;
+mod policy;
+mod precheck;
+mod remap;
+mod report;
+mod reseed;
+mod rings;
+mod shootdown;
+mod spill;
+mod stats;
+mod store;
+mod tracking;
+mod validation;
+mod vcpus;
+mod verbosity;
+mod vtpm;
+mod watchdog;
+
+use access::{query_access_stats, record_write, reset_access_stats};
+use arena::SnapshotArena;
+use attest::attest_generation;
+use caps::query_caps;
+use checkpoint::set_checkpoint_interval;
+use checksum::{check_page_checksum, corrupted, page_checksum, verify_backup, PageChecksums};
+use compress::{PackedStore, PACKED_SLOT};
+use crypt::{open, seal, Seal};
+use delta::{discard_delta_layers, incremental_backup, restore_delta_layers};
+use devices::{discard_devices, restore_devices, snapshot_devices};
+use diff::diff_backup;
+use dirty::{
+    pages_4k, rearm_dirty_pages, release_registered_pages, reset_dirty_pages, track_write,
+};
+use errors::{backup_exists, no_backup, rmp_failed};
+use export::{
+    discard_snapshot, export_snapshot, export_snapshot_chunk, import_snapshot, set_export_key,
+    verify_snapshot,
+};
+use index::PfnIndex;
+use inspect::read_snapshot_page;
+use internal::{discard_internal_state, restore_internal_state, snapshot_internal_state};
+use journal::{discard_journal, RestoreJournal, RestoreStep};
+use lazy::{
+    async_backup, backup_protected_pages, capture_pending, discard_pending, finalize_backup,
+    lazy_backup,
+};
+use layout::query_memory_layout;
+use metadata::{discard_metadata, query_metadata, record_metadata};
+use migrate::{export_svsm_state, import_svsm_state, set_migration_agent};
+use named::{
+    create_snapshot, delete_snapshot, forget_current_snapshot, fork_snapshot, list_snapshots,
+    restore_snapshot,
+};
+use pagetables::{check_page_tables, record_paging_roots};
+use parallel::{backup_registered_pages, join_backup};
+use partial::{partial_restore, restore_in_place};
+use precheck::restore_check;
+use remap::restore_remapped;
+use paranoid::{
+    check_canaries, check_page_digest, check_rmp_state, discard_seal, seal_backup,
+    set_paranoid_mode,
+};
+use report::{query_restore_report, PageOutcome, RangeLog, RegionStats};
+use reseed::{register_reseed_buffer, reseed_guest};
+use rings::{discard_saved_rings, quiesce_and_save_rings, register_shared_ring, restore_rings};
+use shootdown::flush_restored_translations;
+use spill::{set_spill_area, spill_capacity, spill_registered_page, SpillStore, SPILLED_SLOT};
+use stats::{
+    discard_backup_stats, record_backup_stats, record_cow_fault, record_shared_pages, shared_pages,
+};
+use store::SharedPages;
+use tracking::{
+    check_backup_address, exclude_backup_range, excluded, guest_private, include_backup_range,
+    pin_backup_pages, register_backup_range, registered_bytes, unpin_backup_pages,
+    unregister_backup_range,
+};
+use validation::{
+    apply_validation_changes, rescind_new_page, restore_validation, start_validation_tracking,
+};
+use vcpus::{discard_saved_vcpus, restore_vcpus, snapshot_vcpus};
+use verbosity::{detail, page_trace, set_verbosity};
+use vtpm::{discard_vtpm, restore_vtpm, snapshot_vtpm};
+use watchdog::{cow_active, cow_disabled, cow_enabled, heartbeat};
+pub use budget::set_snapshot_budget;
+pub use checkpoint::take_periodic_checkpoint;
+pub use compress::set_backup_compression;
+pub use devices::{register_device_hooks, DeviceHooks};
+pub use internal::{register_tagged_state, SnapshotState, TaggedState};
+pub use pacing::set_backup_bandwidth;
+pub use paranoid::set_paranoid_checks;
+pub use policy::set_snapshot_policy;
+pub use reseed::restore_generation;
+pub use tracking::track_pvalidate;
+pub use lazy::advance_background_backup;
+pub use watchdog::check_cow_watchdog;
+
+use budget::{
+    admit_backup, backup_memory_stats, copy_error, estimate_backup_cost, record_backup,
+    release_all, release_shared, snapshot_memory, SnapshotCharge,
+};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::sync::atomic::AtomicUsize;
+
+
+pub const BACKUP_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const BACKUP_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub static BACKUP_PROTOCOL_HANDLER: BuiltinProtocol = BuiltinProtocol::new(
+    SVSM_CUSTOM_PROTOCOL,
+    "backup",
+    BACKUP_PROTOCOL_VERSION_MIN,
+    BACKUP_PROTOCOL_VERSION_MAX,
+    backup_protocol_request,
+);
+
+const SVSM_FULL_BACKUP: u32 = 0;
+const SVSM_RESTORE: u32 = 1;
+const SVSM_ENABLE_COPY_ON_WRITE: u32 = 2;
+const SVSM_PARTIAL_RESTORE: u32 = 3;
+const SVSM_EXPORT_SNAPSHOT: u32 = 4;
+const SVSM_IMPORT_SNAPSHOT: u32 = 5;
+const SVSM_EXPORT_SNAPSHOT_CHUNK: u32 = 6;
+const SVSM_HEARTBEAT: u32 = 7;
+/// Maximum number of zero pages not forming a complete 2M page that are
+/// zeroed through one mapping.
+const ZERO_BATCH_PAGES: usize = 64;
+/// Maximum number of contiguous pages with data that are restored through
+/// one mapping.
+const RESTORE_BATCH_PAGES: usize = 16;
+
+const SVSM_QUERY_MEMORY_LAYOUT: u32 = 8;
+const SVSM_VERIFY_SNAPSHOT: u32 = 9;
+const SVSM_REGISTER_SHARED_RING: u32 = 10;
+const SVSM_REGISTER_RESEED_BUFFER: u32 = 11;
+const SVSM_QUERY_RESTORE_REPORT: u32 = 12;
+const SVSM_READ_SNAPSHOT_PAGE: u32 = 13;
+const SVSM_RESOLVE_COW_FAULT: u32 = 14;
+const SVSM_QUERY_ACCESS_STATS: u32 = 15;
+const SVSM_RESTORE_REMAPPED: u32 = 16;
+const SVSM_SET_PARANOID_MODE: u32 = 17;
+const SVSM_CREATE_SNAPSHOT: u32 = 18;
+const SVSM_RESTORE_SNAPSHOT: u32 = 19;
+const SVSM_LIST_SNAPSHOTS: u32 = 20;
+const SVSM_DELETE_SNAPSHOT: u32 = 21;
+const SVSM_INCREMENTAL_BACKUP: u32 = 22;
+const SVSM_VERIFY_BACKUP: u32 = 23;
+const SVSM_RESTORE_IN_PLACE: u32 = 24;
+const SVSM_REGISTER_BACKUP_RANGE: u32 = 25;
+const SVSM_UNREGISTER_BACKUP_RANGE: u32 = 26;
+const SVSM_SET_EXPORT_KEY: u32 = 27;
+const SVSM_LAZY_BACKUP: u32 = 28;
+const SVSM_JOIN_BACKUP: u32 = 29;
+const SVSM_DISCARD_BACKUP: u32 = 30;
+const SVSM_ATTEST_GENERATION: u32 = 31;
+const SVSM_BACKUP_FINALIZE: u32 = 32;
+const SVSM_EXCLUDE_BACKUP_RANGE: u32 = 33;
+const SVSM_INCLUDE_BACKUP_RANGE: u32 = 34;
+const SVSM_DIFF_BACKUP: u32 = 35;
+const SVSM_SET_SPILL_AREA: u32 = 36;
+const SVSM_RESUME_RESTORE: u32 = 37;
+const SVSM_BACKUP_QUERY_CAPS: u32 = 38;
+const SVSM_PIN_BACKUP_PAGES: u32 = 39;
+const SVSM_QUERY_SNAPSHOT_METADATA: u32 = 40;
+const SVSM_SET_BACKUP_VERBOSITY: u32 = 41;
+const SVSM_BACKUP_STATS: u32 = 42;
+const SVSM_FORK_SNAPSHOT: u32 = 43;
+pub const SVSM_RESTORE_CHECK: u32 = 44;
+const SVSM_SET_CHECKPOINT_INTERVAL: u32 = 45;
+const SVSM_SET_MIGRATION_AGENT: u32 = 46;
+const SVSM_EXPORT_SVSM_STATE: u32 = 47;
+const SVSM_IMPORT_SVSM_STATE: u32 = 48;
+/// Highest call number, reported by `SVSM_BACKUP_QUERY_CAPS`.
+const SVSM_BACKUP_LAST_CALL: u32 = SVSM_IMPORT_SVSM_STATE;
+
+/// Consistency modes of `SVSM_FULL_BACKUP` in `rcx`. In the parked mode the
+/// guest keeps its other vCPUs out of guest code during the backup. In the
+/// protected mode they keep running and the registered pages are
+/// write-protected before the first copy, which gives a crash-consistent
+/// image of memory. The asynchronous mode returns once the pages are
+/// protected and copies them in the background (see the `lazy` module).
+const BACKUP_MODE_PARKED: u64 = 0;
+const BACKUP_MODE_PROTECTED: u64 = 1;
+const BACKUP_MODE_ASYNC: u64 = 2;
+
+/// Flag of `SVSM_RESTORE` in `rdx`: write-protect the restored pages again
+/// for copy-on-write, or enable copy-on-write if it was not enabled, so the
+/// writes of the guest after the restore are tracked for the next
+/// checkpoint.
+const RESTORE_REARM_COW: u64 = 1 << 0;
+
+/// A backed-up guest page. The copy is stored in slot `slot` of the
+/// snapshot arena, or compressed in entry `slot & !PACKED_SLOT` of the
+/// packed store if [`PACKED_SLOT`] is set, or in entry `slot & !SPILLED_SLOT`
+/// of the spill store if [`SPILLED_SLOT`] is set. Pages with identical
+/// contents share an arena slot. Either way the copy is encrypted.
+struct MemPage4K {
+    phys_addr: PhysAddr,
+    slot: usize,
+}
+
+/// The backed-up guest pages together with the arena, packed store and
+/// spill store holding their contents.
+struct BackupPages {
+    index: PfnIndex,
+    arena: SnapshotArena,
+    packed: PackedStore,
+    spilled: SpillStore,
+    /// Seals of the encrypted arena slots, by slot.
+    seals: Vec<Seal>,
+    checksums: PageChecksums,
+    /// Arena slots by the checksum of their contents, to find duplicates.
+    /// Slots are only freed all at once, so sharing needs no refcount.
+    dedup: BTreeMap<u64, usize>,
+    /// Number of pages stored in a slot of another page.
+    shared: usize,
+    /// Bytes of snapshot memory charged to the pages since they are shared
+    /// with forks, see the `store` module.
+    charge: AtomicUsize,
+}
+
+impl BackupPages {
+    const fn new() -> Self {
+        Self {
+            index: PfnIndex::new(),
+            arena: SnapshotArena::new(),
+            packed: PackedStore::new(),
+            spilled: SpillStore::new(),
+            seals: Vec::new(),
+            checksums: PageChecksums::new(),
+            dedup: BTreeMap::new(),
+            shared: 0,
+            charge: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the backed-up pages in ascending address order.
+    fn pages(&self) -> impl Iterator<Item = MemPage4K> + '_ {
+        self.index.iter().map(|(pfn, slot)| MemPage4K {
+            phys_addr: PhysAddr::from(pfn << PAGE_SHIFT),
+            slot,
+        })
+    }
+
+    /// Returns the number of backed-up pages.
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the contents stored in `slot`, decrypted and if needed
+    /// decompressed into `scratch`, or `None` if they cannot be read or
+    /// fail authentication.
+    fn slot_data<'a>(
+        &'a self,
+        slot: usize,
+        scratch: &'a mut [u8; PAGE_SIZE],
+    ) -> Option<&'a [u8; PAGE_SIZE]> {
+        let readable = if slot & PACKED_SLOT != 0 {
+            self.packed.read(slot & !PACKED_SLOT, scratch);
+            true
+        } else if slot & SPILLED_SLOT != 0 {
+            self.spilled.read(slot & !SPILLED_SLOT, scratch)
+        } else {
+            open(self.arena.page(slot), &self.seals[slot], scratch)
+        };
+        readable.then_some(scratch)
+    }
+
+    /// Returns the backed-up contents of `page`, decrypted into `scratch`,
+    /// or `None` if they cannot be read.
+    fn data<'a>(
+        &'a self,
+        page: &MemPage4K,
+        scratch: &'a mut [u8; PAGE_SIZE],
+    ) -> Option<&'a [u8; PAGE_SIZE]> {
+        self.slot_data(page.slot, scratch)
+    }
+
+    /// Returns the backed-up contents of `page` like [`Self::data`], after
+    /// verifying them against their checksum.
+    fn checked_data<'a>(
+        &'a self,
+        page: &MemPage4K,
+        scratch: &'a mut [u8; PAGE_SIZE],
+    ) -> Result<&'a [u8; PAGE_SIZE], SvsmReqError> {
+        let data = self
+            .slot_data(page.slot, scratch)
+            .ok_or_else(|| corrupted(page.phys_addr))?;
+        check_page_checksum(&self.checksums, page.phys_addr, page.slot, data)?;
+        Ok(data)
+    }
+
+    /// Returns the backed-up contents of the page at `paddr`, if any, in
+    /// constant time, decrypted into `scratch`. Fails if they do not match
+    /// their checksum.
+    fn checked_lookup<'a>(
+        &'a self,
+        paddr: PhysAddr,
+        scratch: &'a mut [u8; PAGE_SIZE],
+    ) -> Result<Option<&'a [u8; PAGE_SIZE]>, SvsmReqError> {
+        let Some(slot) = self.index.get(paddr.pfn()) else {
+            return Ok(None);
+        };
+        let data = self
+            .slot_data(slot, scratch)
+            .ok_or_else(|| corrupted(paddr))?;
+        check_page_checksum(&self.checksums, paddr, slot, data)?;
+        Ok(Some(data))
+    }
+
+    /// Returns whether the page at `paddr` is backed up.
+    fn contains(&self, paddr: PhysAddr) -> bool {
+        self.index.get(paddr.pfn()).is_some()
+    }
+
+    /// Returns the SVSM memory holding page contents in bytes.
+    fn memory(&self) -> usize {
+        self.arena.slots() * PAGE_SIZE + self.packed.memory()
+    }
+
+    /// Returns an older arena slot with the same contents as the newest,
+    /// not yet encrypted slot `slot`, whose checksum is `sum`.
+    fn find_duplicate(&self, slot: usize, sum: u64) -> Option<usize> {
+        let other = *self.dedup.get(&sum)?;
+        let mut data = vec![0u8; PAGE_SIZE];
+        open(self.arena.page(other), &self.seals[other], &mut data);
+        (data[..] == self.arena.page(slot)[..]).then_some(other)
+    }
+
+    /// Moves the contents of the newest arena slot `slot` to the packed
+    /// store if compression is enabled and pays off, or encrypts them in
+    /// place otherwise. Returns where the contents are stored.
+    fn pack(&mut self, slot: usize) -> Result<usize, SvsmError> {
+        match self.packed.push(self.arena.page(slot))? {
+            Some(entry) => {
+                self.arena.free_last_slot()?;
+                Ok(entry | PACKED_SLOT)
+            }
+            None => {
+                self.seals
+                    .try_reserve(1)
+                    .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+                let seal = seal(self.arena.page_mut(slot))?;
+                self.seals.push(seal);
+                Ok(slot)
+            }
+        }
+    }
+
+    /// Returns the storage of the newest page, which was not indexed.
+    fn unpack_last(&mut self, slot: usize) -> Result<(), SvsmError> {
+        if slot & PACKED_SLOT != 0 {
+            self.packed.pop()
+        } else {
+            self.seals.pop();
+            self.arena.free_last_slot()
+        }
+    }
+
+    /// Backs up the page at `paddr`. `fill` writes the contents into a new
+    /// arena slot and returns whether the page is to be kept. The slot is
+    /// returned to the arena otherwise.
+    fn push_with<E, F>(&mut self, paddr: PhysAddr, fill: F) -> Result<bool, E>
+    where
+        E: From<SvsmError>,
+        F: FnOnce(&mut [u8; PAGE_SIZE]) -> Result<bool, E>,
+    {
+        let slot = self.arena.alloc_slot()?;
+        match fill(self.arena.page_mut(slot)) {
+            Ok(true) => {
+                let sum = page_checksum(self.arena.page(slot));
+                if let Some(other) = self.find_duplicate(slot, sum) {
+                    self.arena.free_last_slot()?;
+                    self.index.insert(paddr.pfn(), other)?;
+                    self.shared += 1;
+                    return Ok(true);
+                }
+                let slot = match self.pack(slot) {
+                    Ok(slot) => slot,
+                    Err(err) => {
+                        self.arena.free_last_slot()?;
+                        return Err(err.into());
+                    }
+                };
+                if let Err(err) = self.checksums.push(slot, sum) {
+                    self.unpack_last(slot)?;
+                    return Err(err.into());
+                }
+                match self.index.insert(paddr.pfn(), slot) {
+                    Ok(_) => {
+                        if slot & PACKED_SLOT == 0 {
+                            self.dedup.insert(sum, slot);
+                        }
+                        Ok(true)
+                    }
+                    Err(err) => {
+                        self.checksums.pop(slot);
+                        self.unpack_last(slot)?;
+                        Err(err.into())
+                    }
+                }
+            }
+            result => {
+                self.arena.free_last_slot()?;
+                result
+            }
+        }
+    }
+
+    /// Backs up the page at `paddr` with the contents `data` in the spill
+    /// store. Spilled pages are not deduplicated.
+    fn push_spilled(&mut self, paddr: PhysAddr, data: &[u8; PAGE_SIZE]) -> Result<(), SvsmError> {
+        let slot = self.spilled.push(data)? | SPILLED_SLOT;
+        if let Err(err) = self.checksums.push(slot, page_checksum(data)) {
+            self.spilled.pop();
+            return Err(err);
+        }
+        if let Err(err) = self.index.insert(paddr.pfn(), slot) {
+            self.checksums.pop(slot);
+            self.spilled.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.index.clear();
+        self.arena.clear();
+        self.packed.clear();
+        self.spilled.clear();
+        self.seals = Vec::new();
+        self.checksums.clear();
+        self.dedup.clear();
+        self.shared = 0;
+        release_shared(core::mem::take(self.charge.get_mut()));
+    }
+}
+
+pub static PAGES_TO_BACKUP: PageSet = PageSet::new();
+pub static PAGES_TO_CLEAR: PageSet = PageSet::new();
+/// 4K pages excluded from backups and restores.
+pub static PAGES_TO_SKIP: PageSet = PageSet::new();
+
+pub static BACKUP_CREATED: SpinLock<bool> = SpinLock::new(false); 
+
+/// The backup is read-locked by restores and inspection calls and only
+/// write-locked while pages are added or the backup is dropped.
+static BACKUP_PAGES: RWLock<SharedPages> = RWLock::new(SharedPages::new());
+static ZERO_PAGES: RWLock<Vec<PhysAddr>> = RWLock::new(Vec::new());
+
+
+pub fn backup_protocol_request(request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    match request {
+        SVSM_FULL_BACKUP => create_full_backup(params),
+        SVSM_RESTORE => restore_pages_from_backup(params),
+        SVSM_ENABLE_COPY_ON_WRITE => enable_copy_on_write(),
+        SVSM_PARTIAL_RESTORE => partial_restore(params),
+        SVSM_EXPORT_SNAPSHOT => export_snapshot(params),
+        SVSM_IMPORT_SNAPSHOT => import_snapshot(params),
+        SVSM_EXPORT_SNAPSHOT_CHUNK => export_snapshot_chunk(params),
+        SVSM_HEARTBEAT => heartbeat(params),
+        SVSM_QUERY_MEMORY_LAYOUT => query_memory_layout(params),
+        SVSM_VERIFY_SNAPSHOT => verify_snapshot(params),
+        SVSM_REGISTER_SHARED_RING => register_shared_ring(params),
+        SVSM_REGISTER_RESEED_BUFFER => register_reseed_buffer(params),
+        SVSM_QUERY_RESTORE_REPORT => query_restore_report(params),
+        SVSM_READ_SNAPSHOT_PAGE => read_snapshot_page(params),
+        SVSM_RESOLVE_COW_FAULT => resolve_cow_fault(params),
+        SVSM_QUERY_ACCESS_STATS => query_access_stats(params),
+        SVSM_RESTORE_REMAPPED => restore_remapped(params),
+        SVSM_SET_PARANOID_MODE => set_paranoid_mode(params),
+        SVSM_CREATE_SNAPSHOT => create_snapshot(params),
+        SVSM_RESTORE_SNAPSHOT => restore_snapshot(params),
+        SVSM_LIST_SNAPSHOTS => list_snapshots(params),
+        SVSM_DELETE_SNAPSHOT => delete_snapshot(params),
+        SVSM_INCREMENTAL_BACKUP => incremental_backup(params),
+        SVSM_VERIFY_BACKUP => verify_backup(params),
+        SVSM_RESTORE_IN_PLACE => restore_in_place(params),
+        SVSM_REGISTER_BACKUP_RANGE => register_backup_range(params),
+        SVSM_UNREGISTER_BACKUP_RANGE => unregister_backup_range(params),
+        SVSM_SET_EXPORT_KEY => set_export_key(params),
+        SVSM_LAZY_BACKUP => lazy_backup(params),
+        SVSM_JOIN_BACKUP => join_backup(params),
+        SVSM_DISCARD_BACKUP => discard_backup(params),
+        SVSM_ATTEST_GENERATION => attest_generation(params),
+        SVSM_BACKUP_FINALIZE => finalize_backup(params),
+        SVSM_EXCLUDE_BACKUP_RANGE => exclude_backup_range(params),
+        SVSM_INCLUDE_BACKUP_RANGE => include_backup_range(params),
+        SVSM_DIFF_BACKUP => diff_backup(params),
+        SVSM_SET_SPILL_AREA => set_spill_area(params),
+        SVSM_RESUME_RESTORE => resume_restore(params),
+        SVSM_BACKUP_QUERY_CAPS => query_caps(params),
+        SVSM_PIN_BACKUP_PAGES => pin_backup_pages(params),
+        SVSM_QUERY_SNAPSHOT_METADATA => query_metadata(params),
+        SVSM_SET_BACKUP_VERBOSITY => set_verbosity(params),
+        SVSM_BACKUP_STATS => backup_memory_stats(params),
+        SVSM_FORK_SNAPSHOT => fork_snapshot(params),
+        SVSM_RESTORE_CHECK => restore_check(params),
+        SVSM_SET_CHECKPOINT_INTERVAL => set_checkpoint_interval(params),
+        SVSM_SET_MIGRATION_AGENT => set_migration_agent(params),
+        SVSM_EXPORT_SVSM_STATE => export_svsm_state(params),
+        SVSM_IMPORT_SVSM_STATE => import_svsm_state(params),
+        _ => Err(SvsmReqError::unsupported_call()),
+    }
+}
+
+/// Takes a full backup of the registered pages and the vCPU state in the
+/// consistency mode `rcx`. Sets `rdx` to 0; a vCPU resuming from the backup
+/// after a restore sees 1. `r8` holds the number of 4K pages saved, also if
+/// copying them failed. On success `rcx` holds the number of registered 4K
+/// pages skipped because they were not private guest memory or excluded. In the
+/// asynchronous mode no pages are saved yet, `rcx` is 0 and `r8` holds the
+/// number of 4K pages pending until `SVSM_BACKUP_FINALIZE`. Fails with
+/// INVALID_PARAMETER for unknown modes and with `SVSM_ERR_BACKUP_EXISTS` if
+/// a backup exists.
+fn create_full_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    full_backup(params, params.rcx)
+}
+
+/// Takes a full backup in consistency `mode` like [`create_full_backup`].
+fn full_backup(params: &mut RequestParams, mode: u64) -> Result<(), SvsmReqError> {
+    let copy_pages = match mode {
+        BACKUP_MODE_PARKED => backup_registered_pages,
+        BACKUP_MODE_PROTECTED => backup_protected_pages,
+        BACKUP_MODE_ASYNC => return async_backup(params),
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    };
+    params.rdx = 0;
+    params.r8 = 0;
+    if *(BACKUP_CREATED.lock()) {
+        log::info!("Backup already exists. No new backup will be created.");
+        return Err(backup_exists());
+    }
+
+    admit_backup(estimate_backup_cost(registered_bytes()?))?;
+
+    quiesce_and_save_rings()?;
+
+    detail!("Starting to backup pages...");
+    set_backup_state(BackupState::BackingUp);
+    start_validation_tracking();
+    let start = tsc_now();
+    let ghcb_stats = ghcb_retry_stats();
+    let roots = record_paging_roots();
+    let result = copy_pages().and_then(|sizes| {
+        snapshot_vtpm()?;
+        snapshot_devices()?;
+        snapshot_internal_state();
+        snapshot_vcpus(roots)?;
+        Ok(sizes)
+    });
+    let (total_size, skipped) = match result {
+        Ok(sizes) => sizes,
+        Err(err) => {
+            params.r8 = saved_pages();
+            log::info!("Backup failed after {} pages", params.r8);
+            discard_backup_pages();
+            set_backup_state(BackupState::Idle);
+            return Err(err);
+        }
+    };
+    log::info!(
+        "Backed up: {} Byte, skipped: {} Byte in {} us",
+        total_size,
+        skipped,
+        ticks_to_ns(tsc_now().saturating_sub(start)) / 1000
+    );
+    params.rcx = shared_pages();
+    if params.rcx != 0 {
+        log::warn!("Skipped {} non-private pages", params.rcx);
+    }
+    params.r8 = (total_size + skipped) / PAGE_SIZE as u64;
+    record_backup(skipped as usize / PAGE_SIZE, (total_size + skipped) as usize / PAGE_SIZE);
+    log::info!("Snapshot memory in use: {} Byte", snapshot_memory());
+    log_ghcb_retries(ghcb_stats);
+    let sealed = {
+        let mut backup = BACKUP_PAGES.lock_write();
+        detail!(
+            "Page index: {} pages, {} Byte",
+            backup.len(),
+            backup.index.memory()
+        );
+        if backup.shared != 0 {
+            detail!("Deduplicated {} pages", backup.shared);
+        }
+        if backup.packed.pages() != 0 {
+            detail!(
+                "Compressed {} pages: {} Byte raw, {} Byte stored",
+                backup.packed.pages(),
+                backup.packed.pages() * PAGE_SIZE,
+                backup.packed.bytes()
+            );
+        }
+        backup.get_mut().and_then(seal_backup)
+    };
+    if let Err(err) = sealed {
+        discard_backup_pages();
+        set_backup_state(BackupState::Idle);
+        return Err(SvsmReqError::from_mapping(err));
+    }
+
+    record_backup_stats(start);
+    record_metadata(params.r8);
+    *(BACKUP_CREATED.lock()) = true;
+    reset_access_stats();
+    set_backup_state(BackupState::Ready);
+    log::info!("Successfully backed up pages.");
+    Ok(())
+}
+
+/// Backs up the registered page at `paddr` of `size`, charging the memory
+/// to `charge`. `staging` is the staging buffer of the calling processor.
+/// A page which does not fit into the budget any more goes to the spill
+/// area, if there is one. Returns the bytes stored and the bytes skipped as
+/// zero.
+fn backup_registered_page(
+    paddr: PhysAddr,
+    size: PageSize,
+    staging: &mut Option<HugeBuffer>,
+    charge: &mut SnapshotCharge,
+) -> Result<(u64, u64), SvsmReqError> {
+    // Charge the whole page up front and return what turned out to be
+    // zero pages or was saved by compression afterwards.
+    if let Err(err) = charge.charge(usize::from(size)) {
+        if spill_capacity() < usize::from(size) {
+            return Err(err);
+        }
+        return spill_registered_page(paddr, size);
+    }
+    let (backed_up, skipped, stored) = backup_page(paddr, size, staging).map_err(copy_error)?;
+    charge
+        .refund(usize::from(size).saturating_sub(stored))
+        .map_err(SvsmReqError::from)?;
+    Ok((backed_up, skipped))
+}
+
+/// Returns the number of 4K pages saved in the backup so far, with data or
+/// as zero pages.
+fn saved_pages() -> u64 {
+    let backup = BACKUP_PAGES.lock_read();
+    (backup.len() + ZERO_PAGES.lock_read().len()) as u64
+}
+
+/// Frees all pages held by the backup and returns their memory to the
+/// snapshot budget.
+fn discard_backup_pages() {
+    BACKUP_PAGES.lock_write().clear();
+    discard_delta_layers();
+    ZERO_PAGES.lock_write().clear();
+    discard_pending();
+    reset_dirty_pages();
+    forget_current_snapshot();
+    discard_saved_rings();
+    discard_vtpm();
+    discard_devices();
+    discard_internal_state();
+    discard_saved_vcpus();
+    discard_seal();
+    discard_backup_stats();
+    discard_journal();
+    discard_metadata();
+    unpin_backup_pages();
+    apply_validation_changes();
+    release_all();
+}
+
+/// Discards the current backup and frees its memory. If copy-on-write is
+/// enabled, the protection of the registered pages is lifted first. Named
+/// snapshots which are not current are kept. Fails with `SVSM_ERR_NO_BACKUP`
+/// if there is no backup. On success `rcx` holds the number of bytes freed.
+fn discard_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    if cow_active() {
+        release_registered_pages()?;
+        cow_disabled();
+    }
+    let memory = snapshot_memory();
+    discard_snapshot();
+    let freed = memory.saturating_sub(snapshot_memory());
+    log::info!("Discarded backup, freed {} Byte", freed);
+    params.rcx = freed as u64;
+    Ok(())
+}
+
+/// Backs up the page at `paddr` of `size`. Returns the bytes stored, the
+/// bytes skipped as zero and the growth of the backup memory.
+fn backup_page(
+    paddr: PhysAddr,
+    size: PageSize,
+    staging: &mut Option<HugeBuffer>,
+) -> Result<(u64, u64, usize), SvsmError> {
+    if !guest_private(paddr, size)? || pages_4k(paddr, size).any(excluded) {
+        return backup_partly_private(paddr, size);
+    }
+    match size {
+        PageSize::Regular => {
+            let (success, memory) = match staging {
+                Some(buffer) => backup_4k_staged(paddr, buffer)?,
+                None => backup_4k_page(paddr)?,
+            };
+            if success {
+                return Ok((PAGE_SIZE as u64, 0, memory))
+            } else {
+                return Ok((0, PAGE_SIZE as u64, memory))
+            }
+        }
+        PageSize::Huge => {
+            if staging.is_none() {
+                *staging = alloc_huge_buffer();
+            }
+            let (backup_size, memory) = match staging {
+                Some(buffer) => backup_2m_page(paddr, buffer)?,
+                None => {
+                    let mut backup_size = 0;
+                    let mut memory = 0;
+                    for i in 0..(PAGE_SIZE_2M / PAGE_SIZE) {
+                        let (stored, grown) = backup_4k_page(paddr + i * PAGE_SIZE)?;
+                        if stored {
+                            backup_size += PAGE_SIZE as u64;
+                        }
+                        memory += grown;
+                    }
+                    (backup_size, memory)
+                }
+            };
+            return Ok((backup_size, PAGE_SIZE_2M as u64 - backup_size, memory));
+        }
+    }
+}
+
+/// Backs up the registered page at `paddr` of `size` which is not private
+/// guest memory as a whole, e.g. because the guest converted part of it to
+/// shared, or which is partly excluded. Shared memory is writable by the
+/// host, so only the 4K pages which are still private and not excluded are
+/// copied and the others are counted as shared.
+fn backup_partly_private(paddr: PhysAddr, size: PageSize) -> Result<(u64, u64, usize), SvsmError> {
+    let mut stored = 0;
+    let mut skipped = 0;
+    let mut memory = 0;
+    let mut shared = 0;
+    for page in pages_4k(paddr, size) {
+        if excluded(page) {
+            page_trace!("Skipping excluded page {:#x}", page);
+            shared += 1;
+            continue;
+        }
+        if size == PageSize::Regular || !guest_private(page, PageSize::Regular)? {
+            page_trace!("Skipping non-private page {:#x}", page);
+            shared += 1;
+            continue;
+        }
+        let (success, grown) = backup_4k_page(page)?;
+        if success {
+            stored += PAGE_SIZE as u64;
+        } else {
+            skipped += PAGE_SIZE as u64;
+        }
+        memory += grown;
+    }
+    record_shared_pages(shared);
+    Ok((stored, skipped, memory))
+}
+
+/// Staging buffer for copying a huge page through a single 2M mapping.
+type HugeBuffer = PageBox<[u8; PAGE_SIZE_2M]>;
+
+/// Allocates a staging buffer for huge pages. Returns `None` if there is
+/// no contiguous 2M block, in which case huge pages are copied in 4K
+/// pieces.
+fn alloc_huge_buffer() -> Option<HugeBuffer> {
+    let buffer = PageBox::<[u8; PAGE_SIZE_2M]>::try_new_zeroed().ok()?;
+    // SAFETY: the buffer was zeroed, which is a valid `u8` array.
+    Some(unsafe { buffer.assume_init() })
+}
+
+/// Copies the huge page at `paddr` into `buffer` with a single mapping and
+/// backs up its non-zero 4K pages from there. Returns the number of bytes
+/// stored and the growth of the backup memory.
+fn backup_2m_page(paddr: PhysAddr, buffer: &mut HugeBuffer) -> Result<(u64, usize), SvsmError> {
+    inject_fault(FaultPoint::GuestRead)?;
+    PageCopier::new(CopyFlags::empty()).copy(
+        CopySource::Guest(paddr),
+        CopyDest::Buffer(&mut buffer[..]),
+        PageSize::Huge,
+    )?;
+
+    let mut pages = BACKUP_PAGES.lock_write();
+    let backup = pages.get_mut()?;
+    let mut zero_pages = ZERO_PAGES.lock_write();
+    let memory = backup.memory();
+    let mut stored = 0;
+    for (i, chunk) in buffer.chunks_exact(PAGE_SIZE).enumerate() {
+        let page = paddr + i * PAGE_SIZE;
+        if is_zeroed(chunk) {
+            zero_pages.push(page);
+            continue;
+        }
+        backup.push_with(page, |data| {
+            data.copy_from_slice(chunk);
+            Ok::<_, SvsmError>(true)
+        })?;
+        stored += PAGE_SIZE as u64;
+    }
+    Ok((stored, backup.memory() - memory))
+}
+  
+/// Backs up the 4K page at `paddr`. Returns whether it was stored and the
+/// growth of the backup memory.
+fn backup_4k_page(paddr: PhysAddr) -> Result<(bool, usize), SvsmError> {
+    let mut pages = BACKUP_PAGES.lock_write();
+    let backup = pages.get_mut()?;
+    let memory = backup.memory();
+    let stored = copy_guest_page(backup, paddr)?;
+    let grown = backup.memory() - memory;
+    drop(pages);
+    if !stored {
+        ZERO_PAGES.lock_write().push(paddr);
+    }
+    Ok((stored, grown))
+}
+
+/// Backs up the 4K page at `paddr` like [`backup_4k_page`], but copies it
+/// into `buffer` first, so the backup lock is only held to store the copy.
+fn backup_4k_staged(paddr: PhysAddr, buffer: &mut HugeBuffer) -> Result<(bool, usize), SvsmError> {
+    inject_fault(FaultPoint::GuestRead)?;
+    let chunk = &mut buffer[..PAGE_SIZE];
+    let outcome = PageCopier::new(CopyFlags::DETECT_ZERO).copy(
+        CopySource::Guest(paddr),
+        CopyDest::Buffer(&mut chunk[..]),
+        PageSize::Regular,
+    )?;
+    if outcome.zero {
+        ZERO_PAGES.lock_write().push(paddr);
+        return Ok((false, 0));
+    }
+    let mut pages = BACKUP_PAGES.lock_write();
+    let backup = pages.get_mut()?;
+    let memory = backup.memory();
+    backup.push_with(paddr, |data| {
+        data.copy_from_slice(chunk);
+        Ok::<_, SvsmError>(true)
+    })?;
+    Ok((true, backup.memory() - memory))
+}
+
+/// Copies the guest page at `paddr` into `backup` unless it is zero.
+/// Returns whether the page was stored.
+fn copy_guest_page(backup: &mut BackupPages, paddr: PhysAddr) -> Result<bool, SvsmError> {
+    backup.push_with(paddr, |data| {
+        inject_fault(FaultPoint::GuestRead)?;
+        let outcome = PageCopier::new(CopyFlags::DETECT_ZERO).copy(
+            CopySource::Guest(paddr),
+            CopyDest::Buffer(&mut data[..]),
+            PageSize::Regular,
+        )?;
+        Ok::<_, SvsmError>(!outcome.zero)
+    })
+}
+
+/// Logs the GHCB calls retried since `before` was taken, if any.
+fn log_ghcb_retries(before: GhcbRetryStats) {
+    let now = ghcb_retry_stats();
+    let retries = now.retries - before.retries;
+    if retries != 0 {
+        log::info!(
+            "GHCB calls retried: {} times, {} gave up",
+            retries,
+            now.exhausted - before.exhausted
+        );
+    }
+}
+
+/// Restores guest memory from the backup, with the flags in `rdx`, see
+/// [`RESTORE_REARM_COW`]. A failed restore is journaled, see
+/// [`RestoreJournal::interrupt`] for what is reported, and can be retried
+/// from the start or resumed with `SVSM_RESUME_RESTORE`.
+fn restore_pages_from_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    restore_with_journal(params, false)
+}
+
+/// Rolls the interrupted restore of the current backup forward from where
+/// it stopped, with the flags of `SVSM_RESTORE` in `rdx`. Fails with
+/// INVALID_REQUEST if no restore was interrupted.
+fn resume_restore(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    restore_with_journal(params, true)
+}
+
+fn restore_with_journal(params: &mut RequestParams, resume: bool) -> Result<(), SvsmReqError> {
+    if params.rdx & !RESTORE_REARM_COW != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let rearm = params.rdx & RESTORE_REARM_COW != 0;
+    let _barrier = RestoreBarrier::raise()?;
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    let mut journal = match (resume, RestoreJournal::take_interrupted()) {
+        (true, Some(journal)) => journal,
+        (true, None) => return Err(SvsmReqError::invalid_request()),
+        (false, _) => RestoreJournal::new(),
+    };
+    detail!("Starting to restore pages from backup");
+    set_backup_state(BackupState::Restoring);
+    if !resume {
+        check_page_tables();
+    }
+    let ghcb_stats = ghcb_retry_stats();
+
+    // Report the ranges restored so far even if the restore fails.
+    let mut report = RangeLog::new();
+    let result = journal
+        .run(RestoreStep::Validation, |_| restore_validation())
+        .and_then(|_| restore_backup_pages(&mut report, &mut journal))
+        .and_then(|_| journal.run(RestoreStep::NewPages, |_| clear_new_pages()))
+        .and_then(|_| journal.run(RestoreStep::Rearm, |_| rearm_copy_on_write(rearm)))
+        .and_then(|_| {
+            journal.run(RestoreStep::State, |_| {
+                flush_restored_translations()
+                    .and_then(|_| restore_rings())
+                    .and_then(|_| restore_vtpm())
+                    .and_then(|_| restore_devices())
+                    .map(|_| restore_internal_state())
+                    .and_then(|_| restore_vcpus(params))
+                    .and_then(|_| reseed_guest())
+            })
+        });
+    report.finish(result.is_ok());
+    log_ghcb_retries(ghcb_stats);
+    if let Err(err) = result {
+        set_backup_state(BackupState::Failed);
+        journal.interrupt(params);
+        return Err(err);
+    }
+    set_backup_state(if cow_active() {
+        BackupState::CopyOnWrite
+    } else {
+        BackupState::Ready
+    });
+
+    log::info!("Successfully restored pages from backup");
+    Ok(())
+}
+
+/// Write-protects the pages rewritten by a restore again if `rearm` is
+/// set. Without copy-on-write, it is enabled for all registered pages.
+fn rearm_copy_on_write(rearm: bool) -> Result<(), SvsmReqError> {
+    if !rearm {
+        return Ok(());
+    }
+    if !cow_active() {
+        return enable_copy_on_write();
+    }
+    let rearmed = rearm_dirty_pages()?;
+    log::info!("Re-armed copy-on-write for {} pages", rearmed);
+    Ok(())
+}
+
+/// Restores the backed-up pages, skipping the steps and pages `journal`
+/// records as done. The page lists are copied up front and the backup is
+/// only locked while pages are decrypted, so the lock is not held across
+/// the guest memory writes.
+fn restore_backup_pages(
+    report: &mut RangeLog,
+    journal: &mut RestoreJournal,
+) -> Result<(), SvsmReqError> {
+    let backed_up: Vec<MemPage4K> = {
+        let backup = BACKUP_PAGES.lock_read();
+        check_canaries(&backup.arena)?;
+        backup.pages().collect()
+    };
+    let mut pages = ZERO_PAGES.lock_read().clone();
+    pages.sort_unstable();
+
+    // The regions of the report are the ranges of contiguous pages in the
+    // backup, regardless of whether they hold data or zeroes.
+    let mut all: Vec<PhysAddr> = backed_up.iter().map(|page| page.phys_addr).collect();
+    all.extend_from_slice(&pages);
+    all.sort_unstable();
+    report.set_regions(RegionStats::coalesce(&all));
+
+    let mut scratch = allocate_file_page_ref()?;
+    journal.run(RestoreStep::Pages, |journal| {
+        restore_data_pages(&backed_up, scratch.as_mut(), report, journal)
+    })?;
+    journal.run(RestoreStep::ZeroPages, |journal| {
+        restore_zero_pages(&pages, report, journal)
+    })?;
+
+    // Delta layers are applied on top, their pages are not part of the
+    // report regions.
+    journal.run(RestoreStep::DeltaLayers, |_| {
+        restore_delta_layers(scratch.as_mut())
+    })
+}
+
+/// Restores the pages with data, from the first one `journal` does not
+/// record as done. The backup yields its pages in ascending address order,
+/// so every restore, and every resumed one, writes them in the same order.
+/// Runs of contiguous pages are decrypted into a window buffer and written
+/// through a single mapping, so the TLB is flushed once per window instead of once
+/// per page. If the per-CPU mapping window is exhausted, the window size is
+/// halved down to single pages. Fails with `SVSM_ERR_BACKUP_BAD_ADDRESS` at
+/// a page which can never be backed up.
+fn restore_data_pages(
+    backed_up: &[MemPage4K],
+    scratch: &mut [u8; PAGE_SIZE],
+    report: &mut RangeLog,
+    journal: &mut RestoreJournal,
+) -> Result<(), SvsmReqError> {
+    detail!("Restoring non-empty pages...");
+    let mut window = vec![[0u8; PAGE_SIZE]; RESTORE_BATCH_PAGES];
+    let mut batch = RESTORE_BATCH_PAGES;
+    let mut pending = backed_up.get(journal.done()..).unwrap_or_default();
+    while let Some(first) = pending.first() {
+        let start = first.phys_addr;
+        journal.attempt(start);
+        check_backup_address(start, PageSize::Regular)?;
+        if !restorable(start) {
+            report.record(start, PageOutcome::Skipped);
+            journal.advance(1);
+            pending = &pending[1..];
+            continue;
+        }
+        let count = pending
+            .iter()
+            .take(batch)
+            .enumerate()
+            .take_while(|&(i, page)| {
+                page.phys_addr == start + i * PAGE_SIZE
+                    && check_backup_address(page.phys_addr, PageSize::Regular).is_ok()
+                    && restorable(page.phys_addr)
+            })
+            .count();
+        let run = &pending[..count];
+
+        {
+            let backup = BACKUP_PAGES.lock_read();
+            for (page_src, data) in run.iter().zip(window.iter_mut()) {
+                match backup.checked_data(page_src, scratch) {
+                    Ok(page) => data.copy_from_slice(page),
+                    Err(err) => {
+                        report.record(page_src.phys_addr, PageOutcome::Corrupted);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        for (page_src, data) in run.iter().zip(window.iter()) {
+            check_page_digest(page_src.phys_addr, data)?;
+        }
+        let pages: Vec<&[u8; PAGE_SIZE]> = window[..count].iter().collect();
+        match write_phys_range(start, &pages) {
+            Err(SvsmError::Mem) if count > 1 => {
+                batch = count / 2;
+                continue;
+            }
+            result => result.map_err(SvsmReqError::from_mapping)?,
+        }
+        for page_src in run {
+            check_rmp_state(page_src.phys_addr)?;
+            report.record(page_src.phys_addr, PageOutcome::Restored);
+        }
+        journal.advance(count);
+        pending = &pending[count..];
+    }
+    Ok(())
+}
+
+/// Zeroes the sorted zero `pages`, from the first one `journal` does not
+/// record as done. Fails like [`restore_data_pages`].
+fn restore_zero_pages(
+    pages: &[PhysAddr],
+    report: &mut RangeLog,
+    journal: &mut RestoreJournal,
+) -> Result<(), SvsmReqError> {
+    detail!("Restoring empty pages...");
+    let mut run: Option<(PhysAddr, usize)> = None;
+    for &paddr in pages.iter().skip(journal.done()) {
+        check_backup_address(paddr, PageSize::Regular)?;
+        if !restorable(paddr) {
+            if let Some((start, count)) = run.take() {
+                zero_run(start, count, report, journal)?;
+            }
+            report.record(paddr, PageOutcome::Skipped);
+            journal.advance(1);
+            continue;
+        }
+        // Runs do not cross 2M boundaries, so complete 2M pages can be
+        // zeroed through a single mapping.
+        let extends_run = |&(start, count): &(PhysAddr, usize)| {
+            start + count * PAGE_SIZE == paddr && !paddr.is_aligned(PAGE_SIZE_2M)
+        };
+        match run.as_mut() {
+            Some(run) if extends_run(&*run) => run.1 += 1,
+            _ => {
+                if let Some((start, count)) = run.take() {
+                    zero_run(start, count, report, journal)?;
+                }
+                run = Some((paddr, 1));
+            }
+        }
+    }
+    if let Some((start, count)) = run {
+        zero_run(start, count, report, journal)?;
+    }
+    Ok(())
+}
+
+/// Zeroes the pages the guest validated after the backup was taken, which
+/// hold data the restored guest must not see, and stops tracking them. Their
+/// validation is rescinded afterwards, as the restored guest has not
+/// accepted them yet.
+fn clear_new_pages() -> Result<(), SvsmReqError> {
+    detail!("Zeroing and rescinding new pages...");
+    let (mut cleared, mut skipped) = (0usize, 0usize);
+    for (paddr, size) in PAGES_TO_CLEAR.iter_addresses() {
+        if writable_phys_addr(paddr)
+            && !fw_page_protected(paddr)
+            && !pages_4k(paddr, size).any(excluded)
+        {
+            fill_phys_range(paddr, usize::from(size), 0).map_err(SvsmReqError::from_mapping)?;
+            for i in 0..usize::from(size) / PAGE_SIZE {
+                check_rmp_state(paddr + i * PAGE_SIZE)?;
+            }
+            rescind_new_page(paddr, size)?;
+            cleared += usize::from(size);
+        } else {
+            skipped += usize::from(size);
+        }
+        PAGES_TO_CLEAR.remove_addr(paddr, size);
+        preemption_point();
+    }
+    log::info!(
+        "Zeroed new pages: {} Byte, skipped {} Byte",
+        cleared,
+        skipped
+    );
+    Ok(())
+}
+
+/// Zeroes `count` contiguous pages starting at `start`, which do not cross
+/// a 2M boundary. A complete 2M page is zeroed through a single 2M mapping,
+/// other runs in batches of [`ZERO_BATCH_PAGES`]. If the per-CPU mapping
+/// window is exhausted, the batch size is halved down to single pages.
+/// Completed pages are recorded in `journal`.
+fn zero_run(
+    start: PhysAddr,
+    count: usize,
+    report: &mut RangeLog,
+    journal: &mut RestoreJournal,
+) -> Result<(), SvsmReqError> {
+    let mut batch = if count * PAGE_SIZE == PAGE_SIZE_2M {
+        count
+    } else {
+        ZERO_BATCH_PAGES
+    };
+    let mut done = 0;
+    while done < count {
+        let pages = min(batch, count - done);
+        let paddr = start + done * PAGE_SIZE;
+        journal.attempt(paddr);
+        match fill_phys_range(paddr, pages * PAGE_SIZE, 0) {
+            Err(SvsmError::Mem) if pages > 1 => {
+                batch = pages / 2;
+                continue;
+            }
+            result => result.map_err(SvsmReqError::from_mapping)?,
+        }
+        for i in 0..pages {
+            check_rmp_state(paddr + i * PAGE_SIZE)?;
+            report.record(paddr + i * PAGE_SIZE, PageOutcome::Zeroed);
+        }
+        journal.advance(pages);
+        done += pages;
+    }
+    Ok(())
+}
+
+/// Returns whether a restore writes the page at `paddr`. Pages which are
+/// not writable, protected firmware pages and excluded pages are skipped.
+fn restorable(paddr: PhysAddr) -> bool {
+    writable_phys_addr(paddr) && !fw_page_protected(paddr) && !excluded(paddr)
+}
+
+fn restore_page(paddr_dest: PhysAddr, data: &[u8; PAGE_SIZE]) -> Result<PageOutcome, SvsmError> {
+    if !restorable(paddr_dest) {
+        return Ok(PageOutcome::Skipped);
+    }
+    write_phys_page(paddr_dest, data)?;
+    Ok(PageOutcome::Restored)
+}
+
+fn zero_page(paddr_dest: PhysAddr) -> Result<PageOutcome, SvsmError> {
+    if !restorable(paddr_dest) {
+        return Ok(PageOutcome::Skipped);
+    }
+    fill_phys_range(paddr_dest, PAGE_SIZE, 0)?;
+    Ok(PageOutcome::Zeroed)
+}
+
+fn enable_copy_on_write() -> Result<(), SvsmReqError> {
+    detail!("Starting to enable copy-on-write...");
+    for (phys_addr, size) in PAGES_TO_BACKUP.iter_addresses() {
+        set_read_only(phys_addr, size).map_err(rmp_failed)?;
+        preemption_point();
+    }
+    reset_dirty_pages();
+    cow_enabled();
+    set_backup_state(BackupState::CopyOnWrite);
+    log::info!("Successfully enabled copy-on-write for validated pages");
+    Ok(())
+}
+
+/// Records the registered page containing the guest page at `rcx` as dirty
+/// and lifts its copy-on-write protection after the guest driver took a
+/// write fault on it. The whole registered page becomes writable, i.e. 2M
+/// for huge pages. If the page is still pending in a lazy backup, it is
+/// copied into the backup first. Fails with INVALID_REQUEST if
+/// copy-on-write is not enabled and with INVALID_PARAMETER if the page is
+/// not registered for backups.
+fn resolve_cow_fault(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let paddr = PhysAddr::from(params.rcx).page_align();
+    if !cow_active() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    capture_pending(paddr)?;
+    track_write(paddr)?;
+    record_cow_fault();
+    record_write(paddr);
+    page_trace!("Resolved copy-on-write fault at {:#x}", paddr);
+    Ok(())
+}
+
+fn set_read_only(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmError> {
+    inject_fault(FaultPoint::Rmp)?;
+    rmp_set_guest_access_paddr(paddr, size, GuestAccess::ReadOnly)?;
+    page_trace!("Set read-only for page {:#x}, size {:?}", paddr, size);
+    Ok(())
+}
@@ -0,0 +1,377 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Named snapshots.
+//!
+//! Besides the single unnamed backup, the guest can keep several snapshots
+//! under IDs of its choice, e.g. one per function image of a serverless
+//! runtime. The current snapshot, named or not, is the backup all other
+//! calls operate on. The other named snapshots are parked: their pages,
+//! delta layers, zero pages, saved ring contents, vCPU, vTPM and tagged
+//! state and metadata are moved out of the way and their memory stays
+//! charged to the global snapshot budget. Restoring a parked snapshot parks
+//! the current one and makes it current.
+//!
+//! A snapshot can be forked into a new parked snapshot, which shares its
+//! pages and delta layers (see the `store` module) and gets copies of the
+//! rest of its state. The fork and its base then go separate ways: each
+//! saves the pages it writes later in delta layers of its own.
+//!
+//! The page digests of paranoid mode and the backup statistics are only
+//! kept for the current snapshot. The dirty-page set is kept across
+//! switches: a dirty page stays writable, so it may differ from whichever
+//! snapshot is current.
+
+use super::budget::{park_memory, release_parked, share_memory, share_parked, unpark_memory};
+use super::delta::{put_delta_layers, take_delta_layers, DeltaLayers};
+use super::devices::{put_saved_devices, take_saved_devices, SavedDevices};
+use super::errors::no_backup;
+use super::export::{discard_snapshot, export_in_progress};
+use super::internal::{put_internal_state, take_internal_state, SavedInternalState};
+use super::journal::discard_journal;
+use super::lazy::settle_lazy_backup;
+use super::metadata::{current_metadata, put_metadata, take_metadata, SnapshotMetadata};
+use super::paranoid::discard_seal;
+use super::rings::{put_saved_rings, take_saved_rings, SavedRings};
+use super::stats::discard_backup_stats;
+use super::store::SharedPages;
+use super::tracking::unpin_backup_pages;
+use super::validation::apply_validation_changes;
+use super::vcpus::{put_saved_vcpus, take_saved_vcpus, SavedVcpus};
+use super::vtpm::{put_vtpm, take_vtpm, SavedVtpm};
+use super::watchdog::cow_active;
+use super::{
+    full_backup, restore_pages_from_backup, BACKUP_CREATED, BACKUP_MODE_PARKED, BACKUP_PAGES,
+    ZERO_PAGES,
+};
+use crate::address::PhysAddr;
+use crate::health::{set_backup_state, BackupState};
+use crate::locking::SpinLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Maximum number of named snapshots, including the current one.
+const MAX_SNAPSHOTS: usize = 64;
+/// ID of the current backup if it has no name. Not a valid snapshot ID.
+const UNNAMED: u64 = u64::MAX;
+
+/// Size of a list entry in the guest buffer: snapshot ID and flags as
+/// `u64`.
+const SNAPSHOT_ENTRY_SIZE: usize = 16;
+/// List entry flag: the snapshot is the current one.
+const SNAPSHOT_CURRENT: u64 = 1 << 0;
+
+/// State of a named snapshot which is not the current one.
+struct ParkedSnapshot {
+    pages: SharedPages,
+    layers: DeltaLayers,
+    zero_pages: Vec<PhysAddr>,
+    rings: SavedRings,
+    vcpus: SavedVcpus,
+    vtpm: SavedVtpm,
+    devices: SavedDevices,
+    internal: SavedInternalState,
+    metadata: Option<SnapshotMetadata>,
+    /// Bytes charged to the snapshot budget.
+    memory: usize,
+}
+
+impl ParkedSnapshot {
+    /// Takes the state of the current backup, which is charged `memory`
+    /// bytes.
+    fn take_current(memory: usize) -> Self {
+        Self {
+            pages: core::mem::take(&mut *BACKUP_PAGES.lock_write()),
+            layers: take_delta_layers(),
+            zero_pages: core::mem::take(&mut *ZERO_PAGES.lock_write()),
+            rings: take_saved_rings(),
+            vcpus: take_saved_vcpus(),
+            vtpm: take_vtpm(),
+            devices: take_saved_devices(),
+            internal: take_internal_state(),
+            metadata: take_metadata(),
+            memory,
+        }
+    }
+
+    /// Makes the state the state of the current backup and returns the
+    /// bytes it is charged.
+    fn put_current(self) -> usize {
+        *BACKUP_PAGES.lock_write() = self.pages;
+        put_delta_layers(self.layers);
+        *ZERO_PAGES.lock_write() = self.zero_pages;
+        put_saved_rings(self.rings);
+        put_saved_vcpus(self.vcpus);
+        put_vtpm(self.vtpm);
+        put_saved_devices(self.devices);
+        put_internal_state(self.internal);
+        put_metadata(self.metadata);
+        self.memory
+    }
+
+    /// Returns a fork of the snapshot which shares its pages and delta
+    /// layers. `charge` bytes of the snapshot memory move to the shared
+    /// pages. The fork itself is charged nothing.
+    fn fork(&mut self, charge: usize) -> Self {
+        Self {
+            pages: self.pages.share(charge),
+            layers: self.layers.clone(),
+            zero_pages: self.zero_pages.clone(),
+            rings: self.rings.clone(),
+            vcpus: self.vcpus.clone(),
+            vtpm: self.vtpm.clone(),
+            devices: self.devices.clone(),
+            internal: self.internal.clone(),
+            metadata: self.metadata,
+            memory: 0,
+        }
+    }
+}
+
+/// Parked snapshots by ID. Held for the duration of every named snapshot
+/// call, so it comes first in the lock order.
+static SNAPSHOTS: SpinLock<BTreeMap<u64, ParkedSnapshot>> = SpinLock::new(BTreeMap::new());
+/// ID of the current backup, [`UNNAMED`] if it has no name or there is no
+/// backup.
+static CURRENT_SNAPSHOT: AtomicU64 = AtomicU64::new(UNNAMED);
+
+/// Forgets the name of the current backup after it was discarded.
+pub fn forget_current_snapshot() {
+    CURRENT_SNAPSHOT.store(UNNAMED, Ordering::Relaxed);
+}
+
+fn snapshot_id(params: &RequestParams) -> Result<u64, SvsmReqError> {
+    match params.rcx {
+        UNNAMED => Err(SvsmReqError::invalid_parameter()),
+        id => Ok(id),
+    }
+}
+
+/// Returns the ID of the current backup, if there is one and it is named.
+fn current_snapshot() -> Option<u64> {
+    match CURRENT_SNAPSHOT.load(Ordering::Relaxed) {
+        UNNAMED => None,
+        id => (*BACKUP_CREATED.lock()).then_some(id),
+    }
+}
+
+/// Parks the current backup, if any. Fails with INVALID_REQUEST if it has
+/// no name, since it could not be found again.
+fn park_current(snapshots: &mut BTreeMap<u64, ParkedSnapshot>) -> Result<(), SvsmReqError> {
+    if export_in_progress() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    // Another snapshot takes over the copy-on-write protection.
+    settle_lazy_backup()?;
+    let mut created = BACKUP_CREATED.lock();
+    if !*created {
+        return Ok(());
+    }
+    let id = match CURRENT_SNAPSHOT.load(Ordering::Relaxed) {
+        UNNAMED => return Err(SvsmReqError::invalid_request()),
+        id => id,
+    };
+    let parked = ParkedSnapshot::take_current(park_memory());
+    discard_seal();
+    discard_backup_stats();
+    discard_journal();
+    unpin_backup_pages();
+    apply_validation_changes();
+    snapshots.insert(id, parked);
+    *created = false;
+    forget_current_snapshot();
+    set_backup_state(BackupState::Idle);
+    log::info!("Parked snapshot {:#x}", id);
+    Ok(())
+}
+
+/// Makes the parked snapshot `id` the current backup. There must be no
+/// current backup.
+fn unpark(id: u64, parked: ParkedSnapshot) {
+    let mut created = BACKUP_CREATED.lock();
+    unpark_memory(parked.put_current());
+    *created = true;
+    CURRENT_SNAPSHOT.store(id, Ordering::Relaxed);
+    set_backup_state(if cow_active() {
+        BackupState::CopyOnWrite
+    } else {
+        BackupState::Ready
+    });
+}
+
+/// Parks the current backup and takes a full backup as snapshot `rcx`.
+/// Fails with INVALID_PARAMETER if the ID is in use and with
+/// INVALID_REQUEST if the current backup has no name or there are too many
+/// snapshots.
+pub fn create_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let id = snapshot_id(params)?;
+    let mut snapshots = SNAPSHOTS.lock();
+    let current = current_snapshot();
+    if current == Some(id) || snapshots.contains_key(&id) {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if snapshots.len() + usize::from(current.is_some()) >= MAX_SNAPSHOTS {
+        return Err(SvsmReqError::invalid_request());
+    }
+    park_current(&mut snapshots)?;
+    full_backup(params, BACKUP_MODE_PARKED)?;
+    CURRENT_SNAPSHOT.store(id, Ordering::Relaxed);
+    log::info!("Created snapshot {:#x}", id);
+    Ok(())
+}
+
+/// Returns a fork of the current backup. Fails with `SVSM_ERR_NO_BACKUP`
+/// if there is none.
+fn fork_current() -> Result<ParkedSnapshot, SvsmReqError> {
+    // Pages still being captured must not end up in shared pages.
+    settle_lazy_backup()?;
+    let created = BACKUP_CREATED.lock();
+    if !*created {
+        return Err(no_backup());
+    }
+    let mut current = ParkedSnapshot::take_current(0);
+    let fork = current.fork(share_memory());
+    current.put_current();
+    Ok(fork)
+}
+
+/// Forks snapshot `rcx`, or the current backup if `rcx` is `u64::MAX`,
+/// into the new parked snapshot `rdx`. The fork shares the pages of its
+/// base, so it takes next to no memory, and is restored like any other
+/// snapshot. On success `rcx` holds the number of shared pages. Fails with
+/// `SVSM_ERR_NO_BACKUP` if there is no current backup, with
+/// INVALID_PARAMETER if there is no such snapshot or the new ID is in use,
+/// and with INVALID_REQUEST if there are too many snapshots.
+pub fn fork_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let base = params.rcx;
+    let id = match params.rdx {
+        UNNAMED => return Err(SvsmReqError::invalid_parameter()),
+        id => id,
+    };
+    let mut snapshots = SNAPSHOTS.lock();
+    let current = current_snapshot();
+    if current == Some(id) || snapshots.contains_key(&id) {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if snapshots.len() + usize::from(current.is_some()) >= MAX_SNAPSHOTS {
+        return Err(SvsmReqError::invalid_request());
+    }
+    let fork = if base == UNNAMED || current == Some(base) {
+        fork_current()?
+    } else {
+        let parked = snapshots
+            .get_mut(&base)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        share_parked(parked.memory);
+        let charge = core::mem::take(&mut parked.memory);
+        parked.fork(charge)
+    };
+    params.rcx = fork.pages.len() as u64;
+    snapshots.insert(id, fork);
+    log::info!("Forked snapshot {:#x} from {:#x}", id, base);
+    Ok(())
+}
+
+/// Restores guest memory from snapshot `rcx`, making it the current
+/// backup, with the flags of `SVSM_RESTORE` in `rdx`. Fails with
+/// INVALID_PARAMETER if there is no such snapshot.
+pub fn restore_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let id = snapshot_id(params)?;
+    let mut snapshots = SNAPSHOTS.lock();
+    if current_snapshot() != Some(id) {
+        if !snapshots.contains_key(&id) {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        park_current(&mut snapshots)?;
+        let parked = snapshots
+            .remove(&id)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        unpark(id, parked);
+        log::info!("Switched to snapshot {:#x}", id);
+    }
+    restore_pages_from_backup(params)
+}
+
+/// Deletes snapshot `rcx` and returns its memory. Fails with
+/// INVALID_PARAMETER if there is no such snapshot.
+pub fn delete_snapshot(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let id = snapshot_id(params)?;
+    let mut snapshots = SNAPSHOTS.lock();
+    if current_snapshot() == Some(id) {
+        discard_snapshot();
+    } else {
+        let parked = snapshots
+            .remove(&id)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        release_parked(parked.memory);
+    }
+    log::info!("Deleted snapshot {:#x}", id);
+    Ok(())
+}
+
+/// Returns the metadata of snapshot `id`, or of the current backup if `id`
+/// is `u64::MAX`. Fails with `SVSM_ERR_NO_BACKUP` if there is no current
+/// backup and with INVALID_PARAMETER if there is no such snapshot.
+pub fn snapshot_metadata(id: u64) -> Result<SnapshotMetadata, SvsmReqError> {
+    let snapshots = SNAPSHOTS.lock();
+    if id == UNNAMED || current_snapshot() == Some(id) {
+        if !*BACKUP_CREATED.lock() {
+            return Err(no_backup());
+        }
+        return current_metadata().ok_or_else(no_backup);
+    }
+    snapshots
+        .get(&id)
+        .and_then(|parked| parked.metadata)
+        .ok_or_else(SvsmReqError::invalid_parameter)
+}
+
+fn entry_bytes(id: u64, flags: u64) -> [u8; SNAPSHOT_ENTRY_SIZE] {
+    let mut buf = [0u8; SNAPSHOT_ENTRY_SIZE];
+    buf[0..8].copy_from_slice(&id.to_le_bytes());
+    buf[8..16].copy_from_slice(&flags.to_le_bytes());
+    buf
+}
+
+/// Writes the named snapshots into the guest buffer at `rcx` of size `rdx`,
+/// in ascending ID order. On success `rcx` holds the number of snapshots.
+/// If the buffer is too small, `rcx` holds the number of snapshots and
+/// INVALID_PARAMETER is returned.
+pub fn list_snapshots(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+
+    let snapshots = SNAPSHOTS.lock();
+    let current = current_snapshot();
+    let mut entries: Vec<(u64, u64)> = snapshots.keys().map(|&id| (id, 0)).collect();
+    if let Some(id) = current {
+        entries.push((id, SNAPSHOT_CURRENT));
+        entries.sort_unstable();
+    }
+    params.rcx = entries.len() as u64;
+    if entries.len() * SNAPSHOT_ENTRY_SIZE > buffer.size() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    for (i, &(id, flags)) in entries.iter().enumerate() {
+        buffer
+            .write((i * SNAPSHOT_ENTRY_SIZE) as u64, &entry_bytes(id, flags))
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_bytes() {
+        let bytes = entry_bytes(0x1234, SNAPSHOT_CURRENT);
+        assert_eq!(bytes[0..8], 0x1234u64.to_le_bytes());
+        assert_eq!(bytes[8..16], 1u64.to_le_bytes());
+    }
+}
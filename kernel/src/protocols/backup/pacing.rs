@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Memory bandwidth pacing of backup copies.
+//!
+//! Copying the registered guest pages saturates the memory bus and slows
+//! down the guest vCPUs running in parallel. The IGVM parameters can cap
+//! the bandwidth of backup copies at a percentage of the copy throughput
+//! measured at boot. Copies then draw tokens, one per byte, from a bucket
+//! which is refilled at the capped rate, and wait when it runs empty.
+
+use crate::cpu::msr::rdtsc;
+use crate::cpu::tsc::{tsc_khz, tsc_now};
+use crate::error::SvsmError;
+use crate::mm::alloc::{allocate_page, free_page};
+use crate::task::preemption_point;
+use crate::types::PAGE_SIZE;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of page copies timed to measure the copy throughput.
+const CALIBRATION_COPIES: u64 = 256;
+/// Amount of unused bandwidth which can be saved up for a burst.
+const BURST_MS: u64 = 1;
+
+/// Bandwidth ceiling in bytes per millisecond, zero for no limit.
+static RATE: AtomicU64 = AtomicU64::new(0);
+
+/// Measures the throughput of page copies in bytes per millisecond.
+fn measure_copy_throughput() -> Result<u64, SvsmError> {
+    let src = allocate_page()?;
+    let dst = match allocate_page() {
+        Ok(dst) => dst,
+        Err(e) => {
+            free_page(src);
+            return Err(e);
+        }
+    };
+
+    let start = rdtsc();
+    for _ in 0..CALIBRATION_COPIES {
+        // SAFETY: both pages were allocated above and do not overlap.
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr::<u8>(), PAGE_SIZE);
+        }
+    }
+    let ticks = rdtsc().saturating_sub(start).max(1);
+
+    free_page(dst);
+    free_page(src);
+
+    let bytes = u128::from(CALIBRATION_COPIES) * PAGE_SIZE as u128;
+    let rate = bytes * u128::from(tsc_khz()) / u128::from(ticks);
+    Ok(u64::try_from(rate).unwrap_or(u64::MAX))
+}
+
+/// Caps the bandwidth of backup copies at `percent` of the measured copy
+/// throughput. Zero, or a failed measurement, removes the cap.
+pub fn set_backup_bandwidth(percent: u32) {
+    if percent == 0 {
+        RATE.store(0, Ordering::Relaxed);
+        return;
+    }
+    let throughput = match measure_copy_throughput() {
+        Ok(throughput) => throughput,
+        Err(e) => {
+            log::warn!("Failed to measure copy throughput: {:?}", e);
+            RATE.store(0, Ordering::Relaxed);
+            return;
+        }
+    };
+    let rate = (throughput / 100 * u64::from(percent.min(100))).max(1);
+    RATE.store(rate, Ordering::Relaxed);
+    log::info!(
+        "Backup bandwidth: {}% of {} bytes/ms, {} bytes/ms",
+        percent.min(100),
+        throughput,
+        rate
+    );
+}
+
+/// Returns whether backup copies are subject to a bandwidth ceiling.
+pub fn pacing_enabled() -> bool {
+    RATE.load(Ordering::Relaxed) != 0
+}
+
+/// Token bucket pacing one series of copies.
+#[derive(Debug)]
+pub struct Pacer {
+    /// Refill rate in bytes per millisecond, zero for no limit.
+    rate: u64,
+    tokens: u64,
+    /// TSC value of the last refill.
+    last: u64,
+    ticks_per_ms: u64,
+}
+
+impl Pacer {
+    /// Creates a pacer for the current bandwidth ceiling, starting with a
+    /// full bucket.
+    pub fn new() -> Self {
+        let rate = RATE.load(Ordering::Relaxed);
+        Self {
+            rate,
+            tokens: rate * BURST_MS,
+            last: tsc_now(),
+            ticks_per_ms: tsc_khz().max(1),
+        }
+    }
+
+    fn refill(&mut self, capacity: u64) {
+        let now = tsc_now();
+        let elapsed = now.saturating_sub(self.last);
+        let new = u128::from(elapsed) * u128::from(self.rate) / u128::from(self.ticks_per_ms);
+        if new > 0 {
+            let new = u64::try_from(new).unwrap_or(u64::MAX);
+            self.tokens = self.tokens.saturating_add(new).min(capacity);
+            self.last = now;
+        }
+    }
+
+    /// Waits until `bytes` may be copied without exceeding the bandwidth
+    /// ceiling. Must not be called with locks held.
+    pub fn consume(&mut self, bytes: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        let bytes = bytes as u64;
+        // A copy larger than the burst waits for a full bucket.
+        let capacity = (self.rate * BURST_MS).max(bytes);
+        loop {
+            self.refill(capacity);
+            if self.tokens >= bytes {
+                self.tokens -= bytes;
+                return;
+            }
+            spin_loop();
+            preemption_point();
+        }
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
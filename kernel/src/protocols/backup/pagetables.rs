@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Consistency of the guest page tables with the snapshot.
+//!
+//! Guest memory is only usable through the guest page tables. If the
+//! registered ranges leave out a page table, a restore rewinds the memory
+//! it maps but not the table itself. If a translation points outside the
+//! ranges, the guest finds memory of another point in time behind it after
+//! the restore. Both go unnoticed until the guest crashes.
+//!
+//! With [`PARANOID_PAGE_TABLES`] enabled, a backup records the page-table
+//! root (CR3) of every vCPU before it copies any page, since in the
+//! protected and lazy modes the vCPUs keep running and may switch address
+//! spaces while pages are copied. Roots outside the registered ranges are
+//! reported right away. The roots are saved with the vCPU state. Before a
+//! full restore writes guest memory, the hierarchy below them is walked as
+//! it is in the base backup, and every page table which the registered
+//! ranges no longer cover and every translation pointing outside of them
+//! is reported. The check only warns, the restore goes ahead.
+//!
+//! Only 4- and 5-level long mode paging is walked.
+
+use super::dirty::{pages_4k, registered_page};
+use super::paranoid::{enabled, PARANOID_PAGE_TABLES};
+use super::tracking::excluded;
+use super::vcpus::{live_paging_roots, saved_paging_roots};
+use super::{BackupPages, BACKUP_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::cpu::control_regs::{CR0Flags, CR4Flags};
+use crate::cpu::efer::EFERFlags;
+use crate::mm::pagetable::{PTEntry, PTEntryFlags};
+use crate::mm::{allocate_file_page_ref, PageRef};
+use crate::protocols::errors::SvsmReqError;
+use crate::types::PAGE_SIZE;
+use cpuarch::vmsa::VMSA;
+
+extern crate alloc;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+/// Number of entries in a page table.
+const ENTRIES: usize = PAGE_SIZE / 8;
+/// Maximum number of findings logged one by one.
+const MAX_REPORTED: usize = 8;
+
+/// The page-table root of a vCPU.
+#[derive(Clone, Copy, Debug)]
+pub struct PagingRoot {
+    apic_id: u32,
+    table: PhysAddr,
+    levels: usize,
+}
+
+impl PagingRoot {
+    /// Returns the page-table root of the vCPU `apic_id` with the state in
+    /// `vmsa`, if it runs with 4- or 5-level paging.
+    pub fn from_vmsa(apic_id: u32, vmsa: &VMSA) -> Option<Self> {
+        let (cr0, cr3, cr4, efer) = (vmsa.cr0, vmsa.cr3, vmsa.cr4, vmsa.efer);
+        if cr0 & CR0Flags::PG.bits() == 0 || efer & EFERFlags::LMA.bits() == 0 {
+            return None;
+        }
+        let levels = if cr4 & CR4Flags::LA57.bits() != 0 {
+            5
+        } else {
+            4
+        };
+        Some(Self {
+            apic_id,
+            table: PTEntry::from_raw(cr3).address(),
+            levels,
+        })
+    }
+}
+
+/// Returns whether the guest memory at `paddr` of `len` bytes is registered
+/// and not excluded.
+fn covered(paddr: PhysAddr, len: usize) -> bool {
+    let end = paddr + len;
+    let mut addr = paddr.page_align();
+    while addr < end {
+        match registered_page(addr) {
+            Some((page, size)) if !pages_4k(page, size).any(excluded) => {
+                addr = page + usize::from(size);
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Records the page-table roots of all vCPUs for a backup about to copy
+/// pages, if [`PARANOID_PAGE_TABLES`] is enabled, and reports roots outside
+/// the registered ranges.
+pub fn record_paging_roots() -> Vec<PagingRoot> {
+    if !enabled(PARANOID_PAGE_TABLES) {
+        return Vec::new();
+    }
+    let roots = live_paging_roots();
+    for root in roots.iter().filter(|root| !covered(root.table, PAGE_SIZE)) {
+        log::warn!(
+            "Page-table root {:#018x} of vCPU {} is outside the registered ranges",
+            root.table,
+            root.apic_id
+        );
+    }
+    roots
+}
+
+/// Walk of the guest page tables in the backup.
+struct Walk<'a> {
+    backup: &'a BackupPages,
+    scratch: PageRef,
+    visited: BTreeSet<PhysAddr>,
+    /// Page tables outside the registered ranges.
+    tables_outside: usize,
+    /// Translations pointing outside the registered ranges.
+    leaves_outside: usize,
+}
+
+impl Walk<'_> {
+    /// Returns the present entries of the table at `paddr` as it is in the
+    /// backup. A registered table without data was zero.
+    fn entries(&mut self, paddr: PhysAddr) -> Result<Vec<PTEntry>, SvsmReqError> {
+        let Some(data) = self.backup.checked_lookup(paddr, self.scratch.as_mut())? else {
+            return Ok(Vec::new());
+        };
+        Ok(data
+            .chunks_exact(8)
+            .map(|raw| PTEntry::from_raw(u64::from_le_bytes(raw.try_into().unwrap())))
+            .filter(PTEntry::present)
+            .collect())
+    }
+
+    /// Walks the table at `paddr` of `level`, 1 being the last level.
+    fn walk(&mut self, paddr: PhysAddr, level: usize) -> Result<(), SvsmReqError> {
+        if !self.visited.insert(paddr) {
+            return Ok(());
+        }
+        if !covered(paddr, PAGE_SIZE) {
+            if self.tables_outside < MAX_REPORTED {
+                log::warn!(
+                    "Page table {:#018x} (level {}) is outside the registered ranges",
+                    paddr,
+                    level
+                );
+            }
+            self.tables_outside += 1;
+            return Ok(());
+        }
+        for entry in self.entries(paddr)? {
+            let leaf = level == 1 || entry.flags().contains(PTEntryFlags::HUGE);
+            if !leaf {
+                self.walk(entry.address(), level - 1)?;
+                continue;
+            }
+            // Level 1 maps 4K, level 2 2M and level 3 1G pages.
+            let len = PAGE_SIZE * ENTRIES.pow(level as u32 - 1);
+            let target = PhysAddr::from(entry.address().bits() & !(len - 1));
+            if level <= 3 && !covered(target, len) {
+                if self.leaves_outside < MAX_REPORTED {
+                    log::warn!(
+                        "Translation in table {:#018x} points outside the registered ranges: {:#018x}, {:#x} bytes",
+                        paddr,
+                        target,
+                        len
+                    );
+                }
+                self.leaves_outside += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walks the guest page tables below the roots saved with the backup, as
+/// they are in the base backup, if [`PARANOID_PAGE_TABLES`] is enabled.
+/// Reports page tables and translations outside the registered ranges.
+pub fn check_page_tables() {
+    if !enabled(PARANOID_PAGE_TABLES) {
+        return;
+    }
+    let roots = saved_paging_roots();
+    if roots.is_empty() {
+        return;
+    }
+    let scratch = match allocate_file_page_ref() {
+        Ok(scratch) => scratch,
+        Err(err) => {
+            log::warn!("Page-table check skipped: {:?}", err);
+            return;
+        }
+    };
+    let backup = BACKUP_PAGES.lock_read();
+    let mut walk = Walk {
+        backup: &backup,
+        scratch,
+        visited: BTreeSet::new(),
+        tables_outside: 0,
+        leaves_outside: 0,
+    };
+    for root in roots.iter() {
+        if let Err(err) = walk.walk(root.table, root.levels) {
+            log::warn!("Page-table check stopped: {:?}", err);
+            return;
+        }
+    }
+    if walk.tables_outside != 0 || walk.leaves_outside != 0 {
+        log::warn!(
+            "Restore leaves {} page tables and {} translations outside the snapshot",
+            walk.tables_outside,
+            walk.leaves_outside
+        );
+    } else {
+        log::info!(
+            "Checked {} guest page tables of {} roots",
+            walk.visited.len(),
+            roots.len()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Copying a full backup on several processors.
+//!
+//! An SVSM processor only runs while its vCPU calls into the SVSM, so the
+//! SVSM cannot put the other processors to work on its own. A full backup
+//! instead publishes the registered pages as a job which the other
+//! processors join: the guest driver sends an IPI to its other vCPUs,
+//! whose handlers call `SVSM_JOIN_BACKUP`. Every worker claims batches of
+//! pages and copies them through its own per-CPU mappings into its own
+//! staging buffer, so the backup lock is only held to store the copies.
+//! A join returns once no pages are left to claim, the backup once all
+//! workers are done. A join while no backup is being taken returns at
+//! once.
+//!
+//! The bandwidth ceiling of backup copies applies to the backup as a
+//! whole, so no processor can join a paced backup.
+
+use super::budget::SnapshotCharge;
+use super::pacing::{pacing_enabled, Pacer};
+use super::{alloc_huge_buffer, backup_registered_page, PAGES_TO_BACKUP};
+use crate::address::PhysAddr;
+use crate::locking::SpinLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::task::preemption_point;
+use crate::types::PageSize;
+use core::cmp::min;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Number of registered pages a worker claims at a time.
+const BATCH_PAGES: usize = 16;
+
+/// The registered pages of a full backup and the progress of copying them.
+#[derive(Debug)]
+struct BackupJob {
+    pages: Vec<(PhysAddr, PageSize)>,
+    /// Index of the next page to claim.
+    next: AtomicUsize,
+    /// Bytes stored so far.
+    backed_up: AtomicU64,
+    /// Bytes skipped as zero so far.
+    skipped: AtomicU64,
+    /// Number of processors which joined.
+    helpers: AtomicUsize,
+    /// First error of any worker. No further pages are claimed once set.
+    error: SpinLock<Option<SvsmReqError>>,
+}
+
+/// The job of the full backup in progress, while it can be joined.
+static JOB: SpinLock<Option<Arc<BackupJob>>> = SpinLock::new(None);
+
+impl BackupJob {
+    fn new(pages: Vec<(PhysAddr, PageSize)>) -> Self {
+        Self {
+            pages,
+            next: AtomicUsize::new(0),
+            backed_up: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            helpers: AtomicUsize::new(0),
+            error: SpinLock::new(None),
+        }
+    }
+
+    /// Claims the next batch of pages. Returns `None` once all pages are
+    /// claimed or a worker failed.
+    fn claim(&self) -> Option<&[(PhysAddr, PageSize)]> {
+        if self.error.lock().is_some() {
+            return None;
+        }
+        let start = self.next.fetch_add(BATCH_PAGES, Ordering::Relaxed);
+        if start >= self.pages.len() {
+            return None;
+        }
+        Some(&self.pages[start..min(start + BATCH_PAGES, self.pages.len())])
+    }
+
+    /// Copies claimed pages until none are left. Errors are recorded in
+    /// the job. Returns the number of pages copied.
+    fn work(&self) -> usize {
+        let mut charge = SnapshotCharge::new();
+        let mut pacer = Pacer::new();
+        let mut staging = alloc_huge_buffer();
+        let mut copied = 0;
+        while let Some(batch) = self.claim() {
+            for &(paddr, size) in batch {
+                pacer.consume(usize::from(size));
+                match backup_registered_page(paddr, size, &mut staging, &mut charge) {
+                    Ok((backed_up, skipped)) => {
+                        self.backed_up.fetch_add(backed_up, Ordering::Relaxed);
+                        self.skipped.fetch_add(skipped, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        self.error.lock().get_or_insert(err);
+                        return copied;
+                    }
+                }
+                copied += 1;
+                preemption_point();
+            }
+        }
+        copied
+    }
+}
+
+/// Backs up the registered pages together with the processors which join.
+/// Returns the bytes stored and the bytes skipped as zero.
+pub fn backup_registered_pages() -> Result<(u64, u64), SvsmReqError> {
+    let job = Arc::new(BackupJob::new(PAGES_TO_BACKUP.iter_addresses().collect()));
+    if !pacing_enabled() {
+        *JOB.lock() = Some(job.clone());
+    }
+    job.work();
+
+    // Stop further joins and wait for the workers still copying.
+    JOB.lock().take();
+    while Arc::strong_count(&job) > 1 {
+        spin_loop();
+        preemption_point();
+    }
+
+    let helpers = job.helpers.load(Ordering::Relaxed);
+    if helpers != 0 {
+        log::info!("{} processors helped with the backup", helpers);
+    }
+    if let Some(err) = *job.error.lock() {
+        return Err(err);
+    }
+    Ok((
+        job.backed_up.load(Ordering::Relaxed),
+        job.skipped.load(Ordering::Relaxed),
+    ))
+}
+
+/// Helps copying the pages of the full backup in progress, if any. On
+/// return `rcx` holds the number of registered pages copied by this
+/// processor. Errors are reported by the backup call.
+pub fn join_backup(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let job = JOB.lock().clone();
+    params.rcx = match job {
+        Some(job) => {
+            job.helpers.fetch_add(1, Ordering::Relaxed);
+            job.work() as u64
+        }
+        None => 0,
+    };
+    Ok(())
+}
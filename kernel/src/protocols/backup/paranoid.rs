@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Optional safety checks of backups and restores ("paranoid mode").
+//!
+//! Staging environments can enable expensive checks which production skips:
+//!
+//! * [`PARANOID_PAGE_HASHES`]: a SHA-256 digest of every backed-up page is
+//!   recorded with the backup and verified before the page is restored, so
+//!   corruption of snapshot memory is detected instead of restored,
+//! * [`PARANOID_RMP_CHECKS`]: the RMP entry of every restored page is
+//!   queried to make sure the guest can still access it,
+//! * [`PARANOID_CANARIES`]: the unused slots of the snapshot arena are filled
+//!   with a canary pattern when a backup is taken, and a restore fails if
+//!   the pattern was overwritten by a stray write into snapshot memory,
+//! * [`PARANOID_PAGE_TABLES`]: the page-table roots of the vCPUs are
+//!   recorded before a backup copies any page, and a restore checks that
+//!   the page tables below them stay within the registered ranges (see the
+//!   `pagetables` module).
+//!
+//! The checks are always compiled in. Whether one is enabled is a single
+//! relaxed atomic load, so the fast path pays next to nothing for them. The
+//! initial set comes from the IGVM parameters and the guest can change it
+//! at run time.
+
+use super::arena::SnapshotArena;
+use super::BackupPages;
+use crate::address::PhysAddr;
+use crate::crypto::digest::{Sha256, Sha256Trait, SHA256_SIZE};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::{allocate_file_page_ref, PerCPUPageMappingGuard};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::sev::rmp::{rmp_query, GuestAccess, RmpError};
+use crate::types::PAGE_SIZE;
+use bootlib::igvm_params::{
+    IGVM_PARANOID_CANARIES, IGVM_PARANOID_PAGE_HASHES, IGVM_PARANOID_PAGE_TABLES,
+    IGVM_PARANOID_RMP_CHECKS,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+
+/// Verify a digest of every page before restoring it.
+pub const PARANOID_PAGE_HASHES: u32 = IGVM_PARANOID_PAGE_HASHES;
+/// Check the RMP entry of every restored page.
+pub const PARANOID_RMP_CHECKS: u32 = IGVM_PARANOID_RMP_CHECKS;
+/// Guard unused snapshot memory with canaries.
+pub const PARANOID_CANARIES: u32 = IGVM_PARANOID_CANARIES;
+/// Check that the guest page tables stay within the registered ranges.
+pub const PARANOID_PAGE_TABLES: u32 = IGVM_PARANOID_PAGE_TABLES;
+const PARANOID_ALL: u32 =
+    PARANOID_PAGE_HASHES | PARANOID_RMP_CHECKS | PARANOID_CANARIES | PARANOID_PAGE_TABLES;
+
+/// Error returned to the guest when a paranoid check fails.
+pub const SVSM_ERR_PARANOID_CHECK_FAILED: u64 = 0x101;
+
+/// Pattern written into unused arena slots.
+const CANARY: u8 = 0xa5;
+
+static PARANOID_CHECKS: AtomicU32 = AtomicU32::new(0);
+/// Page digests recorded with the backup, if page hashes were enabled.
+static PAGE_DIGESTS: SpinLock<BTreeMap<PhysAddr, [u8; SHA256_SIZE]>> =
+    SpinLock::new(BTreeMap::new());
+/// Whether the spare arena slots hold canaries.
+static CANARIES_SET: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether `check` is enabled.
+#[inline]
+pub fn enabled(check: u32) -> bool {
+    PARANOID_CHECKS.load(Ordering::Relaxed) & check != 0
+}
+
+fn check_failed() -> SvsmReqError {
+    SvsmReqError::protocol(SVSM_ERR_PARANOID_CHECK_FAILED)
+}
+
+/// Sets the enabled checks from the `IGVM_PARANOID_*` flags of the IGVM
+/// parameters. Unknown flags are ignored.
+pub fn set_paranoid_checks(flags: u32) {
+    let flags = flags & PARANOID_ALL;
+    if flags != 0 {
+        log::info!("Paranoid backup checks enabled: {:#x}", flags);
+    }
+    PARANOID_CHECKS.store(flags, Ordering::Relaxed);
+}
+
+/// Replaces the enabled checks with the flags in `rcx` and returns the
+/// previous ones in `rcx`. Checks which need state recorded with the backup
+/// only take effect with the next backup.
+pub fn set_paranoid_mode(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let flags = u32::try_from(params.rcx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    if flags & !PARANOID_ALL != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let previous = PARANOID_CHECKS.swap(flags, Ordering::Relaxed);
+    if previous != flags {
+        log::info!("Paranoid backup checks: {:#x} -> {:#x}", previous, flags);
+    }
+    params.rcx = u64::from(previous);
+    Ok(())
+}
+
+fn digest(data: &[u8; PAGE_SIZE]) -> [u8; SHA256_SIZE] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finalize()
+}
+
+/// Records what the enabled checks need to verify the new backup later.
+pub(super) fn seal_backup(backup: &mut BackupPages) -> Result<(), SvsmError> {
+    if enabled(PARANOID_PAGE_HASHES) {
+        let mut scratch = allocate_file_page_ref()?;
+        let digests = backup
+            .pages()
+            .map(|page| {
+                let data = backup
+                    .data(&page, scratch.as_mut())
+                    .ok_or(SvsmError::InvalidAddress)?;
+                Ok((page.phys_addr, digest(data)))
+            })
+            .collect::<Result<_, SvsmError>>()?;
+        *PAGE_DIGESTS.lock() = digests;
+    }
+    if enabled(PARANOID_CANARIES) {
+        backup.arena.fill_spare(CANARY);
+        CANARIES_SET.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Drops the state recorded with a discarded backup.
+pub fn discard_seal() {
+    PAGE_DIGESTS.lock().clear();
+    CANARIES_SET.store(false, Ordering::Relaxed);
+}
+
+/// Checks the canaries in the unused slots of `arena`, if they were set.
+pub fn check_canaries(arena: &SnapshotArena) -> Result<(), SvsmReqError> {
+    if !enabled(PARANOID_CANARIES) || !CANARIES_SET.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if !arena.spare_holds(CANARY) {
+        log::error!("Snapshot memory canary overwritten");
+        return Err(check_failed());
+    }
+    Ok(())
+}
+
+/// Verifies the backed-up contents of the page at `paddr` against the
+/// digest recorded with the backup. Pages without a digest, e.g. from a
+/// backup taken before the check was enabled, pass.
+pub fn check_page_digest(paddr: PhysAddr, data: &[u8; PAGE_SIZE]) -> Result<(), SvsmReqError> {
+    if !enabled(PARANOID_PAGE_HASHES) {
+        return Ok(());
+    }
+    let expected = PAGE_DIGESTS.lock().get(&paddr).copied();
+    match expected {
+        Some(expected) if expected != digest(data) => {
+            log::error!("Backup of page {:#018x} is corrupted", paddr);
+            Err(check_failed())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the guest can access the restored page at `paddr`. Passes
+/// if the platform cannot query the RMP.
+pub fn check_rmp_state(paddr: PhysAddr) -> Result<(), SvsmReqError> {
+    if !enabled(PARANOID_RMP_CHECKS) {
+        return Ok(());
+    }
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    match rmp_query(guard.virt_addr()) {
+        Ok(state) if state.guest_access == GuestAccess::None => {
+            log::error!(
+                "Restored page {:#018x} is not accessible to the guest",
+                paddr
+            );
+            Err(check_failed())
+        }
+        Ok(_) | Err(RmpError::Unsupported) => Ok(()),
+        Err(err) => {
+            log::error!(
+                "RMP query of restored page {:#018x} failed: {:?}",
+                paddr,
+                err
+            );
+            Err(check_failed())
+        }
+    }
+}
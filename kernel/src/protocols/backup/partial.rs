@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Partial restore of the pages written since the backup.
+//!
+//! While copy-on-write is enabled, every first write to a registered page
+//! is resolved through the SVSM, which records the page as dirty (see the
+//! `dirty` module). All other registered pages still hold their backed-up
+//! contents, so a restore only has to rewind the dirty ones and its
+//! duration scales with the memory the guest dirtied instead of the size of
+//! the snapshot. Rewound pages are write-protected again, so the next
+//! partial restore sees the writes made after this one. Pages saved by
+//! incremental backups are rewound to their newest saved version.
+//!
+//! An in-place restore rewinds the dirty pages the same way but then lifts
+//! the protection of all registered pages and ends copy-on-write, which
+//! gives the result of a full restore without copying the clean pages.
+
+use super::delta::DELTA_LAYERS;
+use super::devices::restore_devices;
+use super::dirty::{
+    dirty_page_count, dirty_pages, pages_4k, protect_clean, release_registered_pages,
+};
+use super::errors::no_backup;
+use super::internal::restore_internal_state;
+use super::lazy::settle_lazy_backup;
+use super::paranoid::{check_canaries, check_page_digest, check_rmp_state};
+use super::report::{PageOutcome, RangeLog, RegionStats};
+use super::reseed::reseed_guest;
+use super::rings::restore_rings;
+use super::shootdown::flush_restored_translations;
+use super::validation::restore_validation;
+use super::vtpm::restore_vtpm;
+use super::watchdog::{cow_active, cow_disabled};
+use super::{
+    clear_new_pages, log_ghcb_retries, restore_page, zero_page, BACKUP_CREATED, BACKUP_PAGES,
+    ZERO_PAGES,
+};
+use crate::address::PhysAddr;
+use crate::health::{set_backup_state, BackupState};
+use crate::mm::allocate_file_page_ref;
+use crate::protocols::barrier::RestoreBarrier;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::sev::ghcb::ghcb_retry_stats;
+use crate::types::PageSize;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// What happens to the registered pages after the dirty ones were rewound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RewindMode {
+    /// Protect the rewound pages again and keep copy-on-write enabled.
+    Partial,
+    /// Lift the protection of all registered pages and end copy-on-write.
+    InPlace,
+}
+
+/// Rewinds the dirty pages and, in [`RewindMode::Partial`], protects them
+/// again. Returns the number of 4K pages rewound.
+fn restore_dirty_pages(report: &mut RangeLog, mode: RewindMode) -> Result<u64, SvsmReqError> {
+    let dirty: Vec<(PhysAddr, PageSize)> = dirty_pages().collect();
+    let backup = BACKUP_PAGES.lock_read();
+    check_canaries(&backup.arena)?;
+    let layers = DELTA_LAYERS.lock();
+    let mut scratch = allocate_file_page_ref()?;
+    let mut zero = ZERO_PAGES.lock_read().clone();
+    zero.sort_unstable();
+
+    let all: Vec<PhysAddr> = dirty
+        .iter()
+        .flat_map(|&(paddr, size)| pages_4k(paddr, size))
+        .collect();
+    report.set_regions(RegionStats::coalesce(&all));
+
+    for &(page, size) in dirty.iter() {
+        for paddr in pages_4k(page, size) {
+            // The newest delta layer holding the page has its latest
+            // version. Only base pages are covered by page digests.
+            let outcome = if let Some(layer) = layers.newest_first().find(|l| l.contains(paddr)) {
+                layer.restore(paddr, scratch.as_mut())?
+            } else {
+                match backup.checked_lookup(paddr, scratch.as_mut()) {
+                    Ok(Some(data)) => {
+                        check_page_digest(paddr, data)?;
+                        restore_page(paddr, data).map_err(SvsmReqError::from_mapping)?
+                    }
+                    Ok(None) if zero.binary_search(&paddr).is_ok() => {
+                        zero_page(paddr).map_err(SvsmReqError::from_mapping)?
+                    }
+                    Ok(None) => PageOutcome::Skipped,
+                    Err(err) => {
+                        report.record(paddr, PageOutcome::Corrupted);
+                        return Err(err);
+                    }
+                }
+            };
+            if outcome != PageOutcome::Skipped {
+                check_rmp_state(paddr)?;
+            }
+            report.record(paddr, outcome);
+        }
+        if mode == RewindMode::Partial {
+            protect_clean(page, size)?;
+        }
+    }
+    Ok(all.len() as u64)
+}
+
+/// Rewinds the dirty pages and restores the state restored with every
+/// restore. Returns the number of 4K pages rewound and, in
+/// [`RewindMode::InPlace`], the number of 4K pages released without a copy.
+fn rewind(mode: RewindMode) -> Result<(u64, u64), SvsmReqError> {
+    let _barrier = RestoreBarrier::raise()?;
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    if !cow_active() {
+        return Err(SvsmReqError::invalid_request());
+    }
+    log::info!(
+        "Starting {} restore of {} dirty pages",
+        if mode == RewindMode::InPlace {
+            "in-place"
+        } else {
+            "partial"
+        },
+        dirty_page_count()
+    );
+    set_backup_state(BackupState::Restoring);
+    let ghcb_stats = ghcb_retry_stats();
+
+    let mut report = RangeLog::new();
+    let result = restore_validation()
+        .and_then(|_| restore_dirty_pages(&mut report, mode))
+        .and_then(|pages| {
+            let released = match mode {
+                RewindMode::Partial => 0,
+                RewindMode::InPlace => {
+                    settle_lazy_backup()?;
+                    let released = release_registered_pages()?;
+                    cow_disabled();
+                    released
+                }
+            };
+            clear_new_pages()?;
+            flush_restored_translations()?;
+            restore_rings()?;
+            restore_vtpm()?;
+            restore_devices()?;
+            restore_internal_state();
+            reseed_guest()?;
+            Ok((pages, released))
+        });
+    report.finish(result.is_ok());
+    log_ghcb_retries(ghcb_stats);
+    match result {
+        Ok((pages, released)) => {
+            set_backup_state(match mode {
+                RewindMode::Partial => BackupState::CopyOnWrite,
+                RewindMode::InPlace => BackupState::Ready,
+            });
+            log::info!(
+                "Successfully restored {} dirty pages, {} pages released",
+                pages,
+                released
+            );
+            Ok((pages, released))
+        }
+        Err(err) => {
+            set_backup_state(BackupState::Failed);
+            Err(err)
+        }
+    }
+}
+
+/// Restores only the registered pages the guest wrote since copy-on-write
+/// was enabled, plus the state restored with every restore. Fails with
+/// `SVSM_ERR_NO_BACKUP` if there is no backup and with INVALID_REQUEST if
+/// copy-on-write is not enabled, since writes are not tracked then. On
+/// success `rcx` holds the number of 4K pages rewound.
+pub fn partial_restore(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let (pages, _) = rewind(RewindMode::Partial)?;
+    params.rcx = pages;
+    Ok(())
+}
+
+/// Restores guest memory without copying the pages which were not written:
+/// the dirty pages are rewound as on a partial restore, and all other
+/// registered pages still hold their backed-up contents, so only their
+/// write protection is lifted. Copy-on-write ends, as after a full restore.
+/// Fails like [`partial_restore`]. On success `rcx` holds the number of 4K
+/// pages rewound and `rdx` the number of 4K pages released without a copy.
+pub fn restore_in_place(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let (pages, released) = rewind(RewindMode::InPlace)?;
+    params.rcx = pages;
+    params.rdx = released;
+    Ok(())
+}
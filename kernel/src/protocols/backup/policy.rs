@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Policy deciding which external snapshots may be imported.
+//!
+//! The approved snapshot measurements are taken from the measured IGVM
+//! parameter block, so they are covered by the launch measurement and a
+//! relying party can verify through attestation which images the SVSM is
+//! willing to resurrect. Without any approved measurement, no snapshot can
+//! be imported.
+
+use crate::crypto::digest::SHA256_SIZE;
+use crate::locking::RWLock;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+static APPROVED_SNAPSHOTS: RWLock<Vec<[u8; SHA256_SIZE]>> = RWLock::new(Vec::new());
+
+/// Installs the measurements of the snapshots which may be imported.
+pub fn set_snapshot_policy(digests: &[[u8; SHA256_SIZE]]) {
+    log::info!(
+        "{} snapshot measurement(s) approved for import",
+        digests.len()
+    );
+    *APPROVED_SNAPSHOTS.lock_write() = digests.to_vec();
+}
+
+/// Returns whether a snapshot with the given measurement may be imported.
+pub fn snapshot_approved(measurement: &[u8; SHA256_SIZE]) -> bool {
+    APPROVED_SNAPSHOTS
+        .lock_read()
+        .iter()
+        .any(|digest| digest == measurement)
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Dry run of a restore.
+//!
+//! A restore which fails partway leaves guest memory half old and half new
+//! until it is resumed, and the guest stays paused all the while. With
+//! `SVSM_RESTORE_CHECK` an orchestrator finds out beforehand whether a
+//! restore of the current backup can succeed. The check goes through every
+//! page a full restore writes, those of the base backup and of the delta
+//! layers, and checks its destination like the restore does: it must be
+//! guest RAM the SVSM can map, and private unless the guest rescinded it
+//! since the backup, in which case the restore validates it again. Pages
+//! the restore skips are counted. Guest memory and the backup are left
+//! alone.
+//!
+//! The check also reports a backup or restore in flight, which a restore
+//! would have to wait for. It may run while a restore holds the request
+//! barrier. The verdict only holds as long as the guest does not change
+//! its memory or validation state.
+
+use super::delta::DELTA_LAYERS;
+use super::errors::no_backup;
+use super::tracking::{check_backup_address, guest_private};
+use super::validation::rescinded;
+use super::{restorable, BACKUP_CREATED, BACKUP_PAGES, ZERO_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::health::{backup_state, BackupState};
+use crate::mm::set::RangeSet;
+use crate::protocols::barrier::restore_in_progress;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::task::preemption_point;
+use crate::types::{PageSize, PAGE_SIZE};
+
+/// Verdicts of `SVSM_RESTORE_CHECK`.
+pub mod restore_verdict {
+    /// A restore can go ahead.
+    pub const FEASIBLE: u64 = 0;
+    /// A backup is being taken or a restore is in progress.
+    pub const BUSY: u64 = 1;
+    /// A page is beyond the guest physical address width, owned by the
+    /// SVSM or not guest RAM. A restore fails with
+    /// `SVSM_ERR_BACKUP_BAD_ADDRESS`.
+    pub const BAD_ADDRESS: u64 = 2;
+    /// A page cannot be mapped.
+    pub const UNMAPPED: u64 = 3;
+    /// A page is no longer private guest memory.
+    pub const NOT_PRIVATE: u64 = 4;
+}
+
+/// What a restore does with a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Destination {
+    Written,
+    Skipped,
+    /// The restore cannot write the page, with the verdict.
+    Refused(u64),
+}
+
+/// Returns the 4K pages a full restore writes.
+fn restored_pages() -> RangeSet {
+    let pages = RangeSet::new();
+    let add = |paddr: PhysAddr| pages.insert_range(paddr, paddr + PAGE_SIZE);
+    let backup = BACKUP_PAGES.lock_read();
+    let layers = DELTA_LAYERS.lock();
+    backup.pages().for_each(|page| add(page.phys_addr));
+    ZERO_PAGES.lock_read().iter().copied().for_each(add);
+    for layer in layers.newest_first() {
+        layer.pages().pages().for_each(|page| add(page.phys_addr));
+        layer.zero_pages().iter().copied().for_each(add);
+    }
+    pages
+}
+
+/// Checks the destination of the restored 4K page at `paddr`.
+fn check_destination(paddr: PhysAddr) -> Destination {
+    if check_backup_address(paddr, PageSize::Regular).is_err() {
+        return Destination::Refused(restore_verdict::BAD_ADDRESS);
+    }
+    if !restorable(paddr) {
+        return Destination::Skipped;
+    }
+    if rescinded(paddr) {
+        return Destination::Written;
+    }
+    match guest_private(paddr, PageSize::Regular) {
+        Ok(true) => Destination::Written,
+        Ok(false) => Destination::Refused(restore_verdict::NOT_PRIVATE),
+        Err(SvsmError::InvalidAddress) => Destination::Refused(restore_verdict::BAD_ADDRESS),
+        Err(_) => Destination::Refused(restore_verdict::UNMAPPED),
+    }
+}
+
+/// Checks whether a full restore of the current backup can succeed,
+/// without writing guest memory. On return `rcx` holds the verdict, see
+/// [`restore_verdict`]. If the restore can go ahead, `rdx` holds the number
+/// of pages it writes and `r8` the number of pages it skips. If a page
+/// cannot be restored, `rdx` holds its address and `r8` the number of pages
+/// checked before it. Fails with `SVSM_ERR_NO_BACKUP` if there is no backup.
+pub fn restore_check(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    params.rcx = restore_verdict::FEASIBLE;
+    params.rdx = 0;
+    params.r8 = 0;
+    if restore_in_progress() || backup_state() == BackupState::BackingUp {
+        params.rcx = restore_verdict::BUSY;
+        return Ok(());
+    }
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+
+    let (mut written, mut skipped) = (0u64, 0u64);
+    for (start, end) in restored_pages().iter_ranges() {
+        for paddr in (start.bits()..end.bits()).step_by(PAGE_SIZE) {
+            let paddr = PhysAddr::from(paddr);
+            match check_destination(paddr) {
+                Destination::Written => written += 1,
+                Destination::Skipped => skipped += 1,
+                Destination::Refused(verdict) => {
+                    log::info!("Restore check: page {:#018x} cannot be restored", paddr);
+                    params.rcx = verdict;
+                    params.rdx = u64::from(paddr);
+                    params.r8 = written + skipped;
+                    return Ok(());
+                }
+            }
+            preemption_point();
+        }
+    }
+    log::info!(
+        "Restore check: {} pages to restore, {} to skip",
+        written,
+        skipped
+    );
+    params.rdx = written;
+    params.r8 = skipped;
+    Ok(())
+}
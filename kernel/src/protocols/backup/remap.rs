@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Restore of backed-up pages to other guest addresses.
+//!
+//! A full restore rewinds the whole VM. A guest runtime which only wants to
+//! reuse part of a checkpoint, e.g. restore a heap image into an arena it
+//! allocated since, instead passes a remapping table. Each entry maps a
+//! range of backed-up pages to a destination range of the same size. Only
+//! the destination ranges are written; everything else, including the
+//! original addresses of the pages, is left alone.
+
+use super::errors::no_backup;
+use super::inspect::destination_allowed;
+use super::lazy::capture_pending;
+use super::paranoid::check_page_digest;
+use super::shootdown::flush_restored_translations;
+use super::tracking::excluded;
+use super::{BACKUP_CREATED, BACKUP_PAGES, ZERO_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::mm::allocate_file_page_ref;
+use crate::mm::guestmem::{fill_phys_range, write_phys_page};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::PAGE_SIZE;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Size of a remapping table entry: source address, destination address
+/// and page count as `u64`.
+const REMAP_ENTRY_SIZE: usize = 24;
+/// Maximum number of entries, the number which fits into a page.
+const REMAP_ENTRIES_MAX: usize = PAGE_SIZE / REMAP_ENTRY_SIZE;
+
+/// Maps `pages` backed-up pages starting at `src` to guest pages starting
+/// at `dst`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RemapEntry {
+    src: PhysAddr,
+    dst: PhysAddr,
+    pages: usize,
+}
+
+impl RemapEntry {
+    fn from_bytes(bytes: &[u8; REMAP_ENTRY_SIZE]) -> Result<Self, SvsmReqError> {
+        let field = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let src = PhysAddr::from(field(0));
+        let dst = PhysAddr::from(field(1));
+        let pages = usize::try_from(field(2)).map_err(|_| SvsmReqError::invalid_parameter())?;
+        let entry = Self { src, dst, pages };
+        if !src.is_page_aligned() || !dst.is_page_aligned() || pages == 0 {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        entry
+            .src_end()
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        entry
+            .dst_end()
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        Ok(entry)
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.pages.checked_mul(PAGE_SIZE)
+    }
+
+    fn src_end(&self) -> Option<PhysAddr> {
+        self.src.checked_add(self.len()?)
+    }
+
+    fn dst_end(&self) -> Option<PhysAddr> {
+        self.dst.checked_add(self.len()?)
+    }
+
+    /// Returns the `(source, destination)` address pairs of all pages.
+    fn pages(&self) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + '_ {
+        (0..self.pages).map(|i| (self.src + i * PAGE_SIZE, self.dst + i * PAGE_SIZE))
+    }
+}
+
+/// A validated remapping table, sorted by destination address.
+#[derive(Debug)]
+struct RemapTable {
+    entries: Vec<RemapEntry>,
+}
+
+impl RemapTable {
+    /// Reads the table from `buffer`. Destination ranges must not overlap,
+    /// so no page is written twice.
+    fn read(buffer: &GuestBuffer) -> Result<Self, SvsmReqError> {
+        let len = buffer.size();
+        if len % REMAP_ENTRY_SIZE != 0 || len / REMAP_ENTRY_SIZE > REMAP_ENTRIES_MAX {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        let mut entries = Vec::with_capacity(len / REMAP_ENTRY_SIZE);
+        let mut bytes = [0u8; REMAP_ENTRY_SIZE];
+        for offset in (0..len).step_by(REMAP_ENTRY_SIZE) {
+            buffer
+                .read(offset as u64, &mut bytes)
+                .map_err(SvsmReqError::from_mapping)?;
+            entries.push(RemapEntry::from_bytes(&bytes)?);
+        }
+        entries.sort_unstable_by_key(|entry| entry.dst);
+        // Checked in RemapEntry::from_bytes()
+        if entries
+            .windows(2)
+            .any(|pair| pair[0].dst_end().unwrap() > pair[1].dst)
+        {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        Ok(Self { entries })
+    }
+
+    fn pages(&self) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + '_ {
+        self.entries.iter().flat_map(RemapEntry::pages)
+    }
+}
+
+/// Restores backed-up pages to the destinations given by the page-aligned
+/// remapping table at `rcx` of size `rdx`, see [`REMAP_ENTRY_SIZE`]. All
+/// destination pages must be guest memory which may receive snapshot contents,
+/// otherwise nothing is written and INVALID_ADDRESS is returned. Source
+/// pages which are not part of the backup leave their destination
+/// untouched. On success `rcx`, `rdx` and `r8` hold the number of pages
+/// restored, zeroed and skipped.
+pub fn restore_remapped(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+    let table = RemapTable::read(&buffer)?;
+    if !table.pages().all(|(_, dst)| destination_allowed(dst)) {
+        return Err(SvsmReqError::invalid_address());
+    }
+    for (src, _) in table.pages() {
+        capture_pending(src)?;
+    }
+
+    let created = BACKUP_CREATED.lock();
+    if !*created {
+        return Err(no_backup());
+    }
+    let backup = BACKUP_PAGES.lock_read();
+    let mut zero = ZERO_PAGES.lock_read().clone();
+    zero.sort_unstable();
+
+    let mut scratch = allocate_file_page_ref()?;
+    let (mut restored, mut zeroed, mut skipped) = (0u64, 0u64, 0u64);
+    for (src, dst) in table.pages() {
+        if excluded(dst) {
+            skipped += 1;
+        } else if let Some(data) = backup.checked_lookup(src, scratch.as_mut())? {
+            check_page_digest(src, data)?;
+            write_phys_page(dst, data).map_err(SvsmReqError::from_mapping)?;
+            restored += 1;
+        } else if zero.binary_search(&src).is_ok() {
+            fill_phys_range(dst, PAGE_SIZE, 0).map_err(SvsmReqError::from_mapping)?;
+            zeroed += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    flush_restored_translations()?;
+    log::info!(
+        "Restored {} entries to new addresses: {} pages restored, {} zeroed, {} skipped",
+        table.entries.len(),
+        restored,
+        zeroed,
+        skipped
+    );
+
+    params.rcx = restored;
+    params.rdx = zeroed;
+    params.r8 = skipped;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry_bytes(src: u64, dst: u64, pages: u64) -> [u8; REMAP_ENTRY_SIZE] {
+        let mut bytes = [0u8; REMAP_ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&src.to_le_bytes());
+        bytes[8..16].copy_from_slice(&dst.to_le_bytes());
+        bytes[16..24].copy_from_slice(&pages.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_remap_entry() {
+        let entry = RemapEntry::from_bytes(&entry_bytes(0x10000, 0x80000, 2)).unwrap();
+        let pages: Vec<_> = entry.pages().collect();
+        assert_eq!(
+            pages,
+            [
+                (PhysAddr::from(0x10000u64), PhysAddr::from(0x80000u64)),
+                (PhysAddr::from(0x11000u64), PhysAddr::from(0x81000u64)),
+            ]
+        );
+
+        assert!(RemapEntry::from_bytes(&entry_bytes(0x10800, 0x80000, 1)).is_err());
+        assert!(RemapEntry::from_bytes(&entry_bytes(0x10000, 0x80000, 0)).is_err());
+        assert!(RemapEntry::from_bytes(&entry_bytes(0x10000, u64::MAX & !0xfff, 2)).is_err());
+    }
+}
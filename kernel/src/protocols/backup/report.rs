@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Summarized logging of per-page backup operations.
+//!
+//! Restoring a snapshot touches every backed-up page. Instead of one log
+//! line per page, [`RangeLog`] coalesces contiguous pages with the same
+//! outcome into one line per range and reports totals at the end. Only the
+//! totals are logged by default, the ranges and pages depend on the
+//! verbosity (see the `verbosity` module).
+//!
+//! The log also accounts outcomes and time per region of the backup, i.e.
+//! per range of contiguous backed-up pages. The report of the last restore
+//! can be read by the guest, so restore behavior can be tracked without
+//! parsing the console.
+
+use super::verbosity::{detail, page_trace};
+use crate::address::PhysAddr;
+use crate::cpu::tsc::{ticks_to_ns, tsc_khz, tsc_now};
+use crate::locking::SpinLock;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::PAGE_SIZE;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// Size of a region entry in the guest buffer: start and end address,
+/// followed by the restored, zeroed and skipped page counts and the time
+/// spent in microseconds, all as `u64`.
+const REGION_ENTRY_SIZE: usize = 48;
+
+/// What happened to a single page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageOutcome {
+    /// The page was restored from its backup copy.
+    Restored,
+    /// The page was restored as a zero page.
+    Zeroed,
+    /// The page was left alone because it is not writable guest memory or
+    /// belongs to protected firmware.
+    Skipped,
+    /// The backup copy of the page is corrupted, so the page was left
+    /// alone and the restore failed.
+    Corrupted,
+}
+
+impl PageOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Restored => "Restored",
+            Self::Zeroed => "Zeroed",
+            Self::Skipped => "Skipped",
+            Self::Corrupted => "Corrupted",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Run {
+    start: PhysAddr,
+    pages: usize,
+    outcome: PageOutcome,
+}
+
+impl Run {
+    fn end(&self) -> PhysAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+}
+
+/// Outcomes of a restore within one region of the backup.
+#[derive(Clone, Copy, Debug)]
+pub struct RegionStats {
+    start: PhysAddr,
+    end: PhysAddr,
+    restored: u64,
+    zeroed: u64,
+    skipped: u64,
+    /// TSC ticks spent on the pages of the region.
+    ticks: u64,
+}
+
+impl RegionStats {
+    /// Coalesces sorted page addresses into regions of contiguous pages.
+    pub fn coalesce(pages: &[PhysAddr]) -> Vec<Self> {
+        let mut regions: Vec<Self> = Vec::new();
+        for &paddr in pages {
+            match regions.last_mut() {
+                Some(region) if region.end == paddr => region.end = paddr + PAGE_SIZE,
+                _ => regions.push(Self {
+                    start: paddr,
+                    end: paddr + PAGE_SIZE,
+                    restored: 0,
+                    zeroed: 0,
+                    skipped: 0,
+                    ticks: 0,
+                }),
+            }
+        }
+        regions
+    }
+
+    fn to_bytes(self, khz: u64) -> [u8; REGION_ENTRY_SIZE] {
+        let micros = self.ticks.saturating_mul(1000) / khz.max(1);
+        let fields = [
+            u64::from(self.start),
+            u64::from(self.end),
+            self.restored,
+            self.zeroed,
+            self.skipped,
+            micros,
+        ];
+        let mut buf = [0u8; REGION_ENTRY_SIZE];
+        for (chunk, field) in buf.chunks_exact_mut(8).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// Per-region report of the last restore.
+#[derive(Debug)]
+pub struct RestoreReport {
+    regions: Vec<RegionStats>,
+    /// TSC ticks spent on the whole restore.
+    ticks: u64,
+    completed: bool,
+}
+
+static LAST_RESTORE: SpinLock<Option<RestoreReport>> = SpinLock::new(None);
+
+/// Coalesces page outcomes into ranges and logs one line per range.
+#[derive(Debug)]
+pub struct RangeLog {
+    run: Option<Run>,
+    restored: usize,
+    zeroed: usize,
+    skipped: usize,
+    corrupted: usize,
+    regions: Vec<RegionStats>,
+    start_tsc: u64,
+    last_tsc: u64,
+}
+
+impl RangeLog {
+    pub fn new() -> Self {
+        let now = tsc_now();
+        Self {
+            run: None,
+            restored: 0,
+            zeroed: 0,
+            skipped: 0,
+            corrupted: 0,
+            regions: Vec::new(),
+            start_tsc: now,
+            last_tsc: now,
+        }
+    }
+
+    /// Sets the regions outcomes are accounted to.
+    pub fn set_regions(&mut self, regions: Vec<RegionStats>) {
+        self.regions = regions;
+    }
+
+    /// Accounts `outcome` and the time since the previous record to the
+    /// region containing `paddr`.
+    fn account(&mut self, paddr: PhysAddr, outcome: PageOutcome) {
+        let now = tsc_now();
+        let ticks = now.saturating_sub(self.last_tsc);
+        self.last_tsc = now;
+        let index = self.regions.partition_point(|r| r.end <= paddr);
+        let Some(region) = self.regions.get_mut(index).filter(|r| r.start <= paddr) else {
+            return;
+        };
+        region.ticks += ticks;
+        match outcome {
+            PageOutcome::Restored => region.restored += 1,
+            PageOutcome::Zeroed => region.zeroed += 1,
+            PageOutcome::Skipped | PageOutcome::Corrupted => region.skipped += 1,
+        }
+    }
+
+    /// Records the outcome of the 4K page at `paddr`.
+    pub fn record(&mut self, paddr: PhysAddr, outcome: PageOutcome) {
+        page_trace!("{} page {:#x}", outcome.name(), paddr);
+        match outcome {
+            PageOutcome::Restored => self.restored += 1,
+            PageOutcome::Zeroed => self.zeroed += 1,
+            PageOutcome::Skipped => self.skipped += 1,
+            PageOutcome::Corrupted => self.corrupted += 1,
+        }
+        self.account(paddr, outcome);
+
+        match self.run.as_mut() {
+            Some(run) if run.outcome == outcome && run.end() == paddr => run.pages += 1,
+            _ => {
+                self.flush();
+                self.run = Some(Run {
+                    start: paddr,
+                    pages: 1,
+                    outcome,
+                });
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(run) = self.run.take() {
+            detail!(
+                "{} {:#018x}-{:#018x} ({} pages)",
+                run.outcome.name(),
+                run.start,
+                run.end(),
+                run.pages
+            );
+        }
+    }
+
+    /// Logs the last range and the totals, and keeps the per-region
+    /// report for [`query_restore_report()`].
+    pub fn finish(mut self, completed: bool) {
+        self.flush();
+        let ticks = tsc_now().saturating_sub(self.start_tsc);
+        log::info!(
+            "Pages restored: {}, zeroed: {}, skipped: {}, corrupted: {}, {} Byte written in {} us",
+            self.restored,
+            self.zeroed,
+            self.skipped,
+            self.corrupted,
+            (self.restored + self.zeroed) * PAGE_SIZE,
+            ticks_to_ns(ticks) / 1000
+        );
+        *LAST_RESTORE.lock() = Some(RestoreReport {
+            regions: self.regions,
+            ticks,
+            completed,
+        });
+    }
+}
+
+impl Default for RangeLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the per-region report of the last restore into the guest buffer
+/// at `rcx` of size `rdx`. On success `rcx` holds the number of regions,
+/// `rdx` the duration of the restore in microseconds and `r8` one if the
+/// restore completed or zero if it failed. If the buffer is too small,
+/// `rcx` holds the number of regions and INVALID_PARAMETER is returned.
+/// Fails with INVALID_REQUEST if no restore has happened yet.
+pub fn query_restore_report(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let buffer = GuestBuffer::from_params(params)?;
+
+    let last = LAST_RESTORE.lock();
+    let report = last.as_ref().ok_or_else(SvsmReqError::invalid_request)?;
+    params.rcx = report.regions.len() as u64;
+    if report.regions.len() * REGION_ENTRY_SIZE > buffer.size() {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    let khz = tsc_khz();
+    for (i, region) in report.regions.iter().enumerate() {
+        buffer
+            .write((i * REGION_ENTRY_SIZE) as u64, &region.to_bytes(khz))
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+    params.rdx = report.ticks.saturating_mul(1000) / khz.max(1);
+    params.r8 = u64::from(report.completed);
+    Ok(())
+}
@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Fresh entropy for restored guests.
+//!
+//! Every restore of the same backup resumes the guest with the same RNG
+//! state. The guest therefore registers a reseed buffer, which the SVSM
+//! fills right after each restore, similar to a VM generation ID. The
+//! buffer starts with a 64-bit restore generation, followed by fresh random
+//! bytes. Guest PRNGs reseed from the buffer whenever the generation
+//! changes.
+
+use crate::address::PhysAddr;
+use crate::crypto::rng::fill_random;
+use crate::locking::SpinLock;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+extern crate alloc;
+use alloc::vec;
+
+/// Size of the restore generation at the start of the buffer.
+const GENERATION_SIZE: usize = 8;
+/// Minimum amount of entropy in the buffer.
+const MIN_ENTROPY_SIZE: usize = 32;
+
+static RESEED_BUFFER: SpinLock<Option<GuestBuffer>> = SpinLock::new(None);
+/// Number of restores so far.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the reseed buffer at `rcx` of size `rdx`, which must hold the
+/// generation and at least 32 bytes of entropy and fit into a page. A size
+/// of zero unregisters the buffer. On return `rcx` holds the current
+/// restore generation.
+pub fn register_reseed_buffer(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let buffer = if len == 0 {
+        None
+    } else if !(GENERATION_SIZE + MIN_ENTROPY_SIZE..=PAGE_SIZE).contains(&len) {
+        return Err(SvsmReqError::invalid_parameter());
+    } else {
+        Some(GuestBuffer::new(PhysAddr::from(params.rcx), len)?)
+    };
+
+    *RESEED_BUFFER.lock() = buffer;
+    params.rcx = restore_generation();
+    Ok(())
+}
+
+/// Returns the number of restores so far.
+pub fn restore_generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+/// Raises the restore generation to `generation`, e.g. the one of the host
+/// the guest migrated from, so it never goes backwards.
+pub fn raise_restore_generation(generation: u64) {
+    GENERATION.fetch_max(generation, Ordering::SeqCst);
+}
+
+/// Writes a new restore generation and fresh entropy into the registered
+/// reseed buffer. Called after the guest memory has been restored, so the
+/// restored contents of the buffer are overwritten.
+pub fn reseed_guest() -> Result<(), SvsmReqError> {
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    let Some(buffer) = *RESEED_BUFFER.lock() else {
+        return Ok(());
+    };
+
+    let mut bytes = vec![0u8; buffer.size()];
+    bytes[..GENERATION_SIZE].copy_from_slice(&generation.to_le_bytes());
+    fill_random(&mut bytes[GENERATION_SIZE..]).map_err(SvsmReqError::from_mapping)?;
+    let result = buffer.write(0, &bytes);
+    // Do not leave the entropy behind in freed SVSM memory.
+    bytes.fill(0);
+    result.map_err(SvsmReqError::from_mapping)?;
+    log::info!("Reseeded guest entropy, restore generation {}", generation);
+    Ok(())
+}
@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Device rings in shared guest memory.
+//!
+//! Virtio devices communicate with the guest through rings in shared
+//! memory, which is not part of a backup. A snapshot taken while the device
+//! still has descriptors in flight resumes with rings that no longer match
+//! the guest driver state. Guest drivers therefore register their rings
+//! together with the location of the driver and device indices. Before a
+//! backup is created, the SVSM waits until every ring is quiescent, i.e.
+//! the device has consumed everything the driver submitted, and saves the
+//! ring contents so a restore can put them back.
+
+use crate::address::{Address, PhysAddr};
+use crate::cpu::flush_address;
+use crate::cpu::percpu::this_cpu;
+use crate::cpu::tsc::{tsc_khz, tsc_now};
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::task::preemption_point;
+use crate::types::PAGE_SIZE;
+use core::hint::spin_loop;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Maximum number of registered rings.
+const MAX_RINGS: usize = 64;
+/// Maximum size of a ring region.
+const MAX_RING_SIZE: usize = 16 * PAGE_SIZE;
+/// Time to wait for in-flight descriptors before a backup is refused.
+const QUIESCE_TIMEOUT_MS: u64 = 100;
+
+/// A ring in shared guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SharedRing {
+    start: PhysAddr,
+    len: usize,
+    /// Offset of the 16-bit index the driver advances when submitting.
+    driver_index: usize,
+    /// Offset of the 16-bit index the device advances when completing.
+    device_index: usize,
+}
+
+impl SharedRing {
+    /// Maps the ring region as shared memory and calls `f` with it.
+    fn with_mapping<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, SvsmError> {
+        let end = (self.start + self.len).page_align_up();
+        let guard = PerCPUPageMappingGuard::create(self.start, end, 0)?;
+        let mut vaddr = guard.virt_addr();
+        while vaddr < guard.virt_addr() + (end - self.start) {
+            this_cpu().get_pgtable().set_shared_4k(vaddr)?;
+            flush_address(vaddr);
+            vaddr = vaddr + PAGE_SIZE;
+        }
+        // SAFETY: the guard maps `len` bytes starting at its virtual
+        // address for the lifetime of the slice.
+        let data = unsafe {
+            core::slice::from_raw_parts_mut(guard.virt_addr().as_mut_ptr::<u8>(), self.len)
+        };
+        Ok(f(data))
+    }
+
+    fn quiescent(&self) -> Result<bool, SvsmError> {
+        self.with_mapping(|data| {
+            let index = |off: usize| u16::from_le_bytes([data[off], data[off + 1]]);
+            index(self.driver_index) == index(self.device_index)
+        })
+    }
+}
+
+/// Contents of a ring saved with the backup.
+#[derive(Clone, Debug)]
+struct SavedRing {
+    ring: SharedRing,
+    data: Vec<u8>,
+}
+
+/// Ring contents saved with a backup which is not the current one.
+#[derive(Clone, Debug, Default)]
+pub struct SavedRings(Vec<SavedRing>);
+
+static SHARED_RINGS: SpinLock<Vec<SharedRing>> = SpinLock::new(Vec::new());
+static SAVED_RINGS: SpinLock<Vec<SavedRing>> = SpinLock::new(Vec::new());
+
+/// Registers the ring at `rcx` of size `rdx` in shared guest memory. The
+/// low half of `r8` holds the offset of the driver index, the high half
+/// the offset of the device index. A size of zero unregisters the ring at
+/// `rcx`.
+pub fn register_shared_ring(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let start = PhysAddr::from(params.rcx);
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    let mut rings = SHARED_RINGS.lock();
+    if len == 0 {
+        let count = rings.len();
+        rings.retain(|ring| ring.start != start);
+        return if rings.len() < count {
+            Ok(())
+        } else {
+            Err(SvsmReqError::invalid_parameter())
+        };
+    }
+
+    let driver_index = (params.r8 & 0xffff_ffff) as usize;
+    let device_index = (params.r8 >> 32) as usize;
+    if len > MAX_RING_SIZE
+        || driver_index.saturating_add(2) > len
+        || device_index.saturating_add(2) > len
+    {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    // Validates alignment and the address range.
+    GuestBuffer::new_shared(start, len)?;
+    if rings.iter().any(|ring| ring.start == start) {
+        return Err(SvsmReqError::invalid_request());
+    }
+    if rings.len() >= MAX_RINGS {
+        return Err(SvsmReqError::busy());
+    }
+
+    rings.push(SharedRing {
+        start,
+        len,
+        driver_index,
+        device_index,
+    });
+    log::info!("Registered shared ring {:#x}, {} bytes", start, len);
+    Ok(())
+}
+
+/// Waits until no registered ring has descriptors in flight and saves the
+/// ring contents. Returns BUSY if a ring does not quiesce in time, so the
+/// guest can retry the backup later.
+pub fn quiesce_and_save_rings() -> Result<(), SvsmReqError> {
+    let rings = SHARED_RINGS.lock().clone();
+    let deadline = tsc_now().saturating_add(QUIESCE_TIMEOUT_MS * tsc_khz());
+    for ring in rings.iter() {
+        while !ring.quiescent().map_err(SvsmReqError::from_mapping)? {
+            if tsc_now() >= deadline {
+                log::info!("Shared ring {:#x} did not quiesce", ring.start);
+                return Err(SvsmReqError::busy());
+            }
+            spin_loop();
+            preemption_point();
+        }
+    }
+
+    let mut saved = Vec::with_capacity(rings.len());
+    for ring in rings {
+        let mut data = vec![0u8; ring.len];
+        ring.with_mapping(|src| data.copy_from_slice(src))
+            .map_err(SvsmReqError::from_mapping)?;
+        saved.push(SavedRing { ring, data });
+    }
+    *SAVED_RINGS.lock() = saved;
+    Ok(())
+}
+
+/// Writes the ring contents saved with the backup back to shared memory.
+pub fn restore_rings() -> Result<(), SvsmReqError> {
+    let saved = SAVED_RINGS.lock();
+    for entry in saved.iter() {
+        entry
+            .ring
+            .with_mapping(|dst| dst.copy_from_slice(&entry.data))
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+    if !saved.is_empty() {
+        log::info!("Restored {} shared rings", saved.len());
+    }
+    Ok(())
+}
+
+/// Drops the ring contents saved with the backup.
+pub fn discard_saved_rings() {
+    SAVED_RINGS.lock().clear();
+}
+
+/// Takes the ring contents saved with the current backup.
+pub fn take_saved_rings() -> SavedRings {
+    SavedRings(core::mem::take(&mut *SAVED_RINGS.lock()))
+}
+
+/// Makes `saved` the ring contents saved with the current backup.
+pub fn put_saved_rings(saved: SavedRings) {
+    *SAVED_RINGS.lock() = saved.0;
+}
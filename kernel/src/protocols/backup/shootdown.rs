@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! TLB shootdown after a restore.
+//!
+//! A restore rewrites guest memory, page tables included, while the other
+//! vCPUs may still hold translations cached before the restore. Unless they
+//! are dropped, the guest could keep reaching pre-restore data through them.
+//! The SVSM runs in the ASID of its guest, so a broadcast INVLPGB for the
+//! ASID invalidates the translations of all VMPLs on all processors, and
+//! TLBSYNC only completes once every processor has acknowledged it. This is
+//! the shootdown: it needs no interrupt round trip through vCPUs that are
+//! running the guest. The same flush drops translations whose RMP
+//! permissions were changed by copy-on-write protection, since processors
+//! cache the result of the RMP check in the TLB and there is no separate RMP
+//! cache for the guest to invalidate.
+//!
+//! If INVLPGB is not available or a processor was started but never came
+//! online, the flush cannot be guaranteed to reach every processor and the
+//! restore fails with [`SVSM_ERR_BACKUP_FLUSH_FAILED`].
+
+use crate::cpu::cpuid::{cpuid_table_has_feature, CpuidFeature};
+use crate::cpu::flush_tlb_global_sync;
+use crate::cpu::percpu::PERCPU_AREAS;
+use crate::protocols::errors::SvsmReqError;
+
+/// Error returned to the guest when the TLB flush after a restore cannot be
+/// delivered to all processors.
+pub const SVSM_ERR_BACKUP_FLUSH_FAILED: u64 = 0x102;
+
+/// Flushes the translations of all processors after guest memory was
+/// restored.
+pub fn flush_restored_translations() -> Result<(), SvsmReqError> {
+    if !cpuid_table_has_feature(CpuidFeature::INVLPGB) {
+        log::error!("Cannot flush TLBs after restore: INVLPGB not supported");
+        return Err(SvsmReqError::protocol(SVSM_ERR_BACKUP_FLUSH_FAILED));
+    }
+    let offline = PERCPU_AREAS
+        .iter()
+        .filter(|info| !info.as_cpu_ref().is_online())
+        .count();
+    if offline != 0 {
+        log::error!(
+            "Cannot flush TLBs after restore: {} processors not online",
+            offline
+        );
+        return Err(SvsmReqError::protocol(SVSM_ERR_BACKUP_FLUSH_FAILED));
+    }
+    flush_tlb_global_sync();
+    Ok(())
+}
@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Spilling of backed-up pages to host storage.
+//!
+//! A backup which does not fit into its memory budget fails. The guest can
+//! instead hand the SVSM a spill area with `SVSM_SET_SPILL_AREA`: shared
+//! memory provided by the host, which is free to keep it in slower storage.
+//! Pages which no longer fit into the budget are written there, which makes
+//! the budget a tradeoff against restore speed rather than a hard limit.
+//!
+//! The host can read and change the area at will, so every spilled page is
+//! encrypted and authenticated with the key of the pages at rest (see the
+//! `crypt` module) before it leaves the SVSM. Its seal and checksum stay in
+//! SVSM memory, so a page the host changed, or swapped with another, fails
+//! authentication when it is read back for a restore and is reported as
+//! corrupted.
+
+use super::budget::copy_error;
+use super::crypt::{open, seal, Seal};
+use super::dirty::pages_4k;
+use super::errors::backup_exists;
+use super::stats::record_shared_pages;
+use super::tracking::{excluded, guest_private};
+use super::{BACKUP_CREATED, BACKUP_PAGES, ZERO_PAGES};
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::fw_protect::fw_range_protected;
+use crate::locking::SpinLock;
+use crate::mm::alloc::AllocError;
+use crate::mm::allocate_file_page_ref;
+use crate::mm::copy::{CopyDest, CopyFlags, CopySource, PageCopier};
+use crate::protocols::buffer::GuestBuffer;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::scratch::{map_shared_page, scratch_region};
+use crate::types::{PageSize, PAGE_SIZE};
+use crate::utils::MemoryRegion;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Slot numbers of the page index with this bit set refer to entries of
+/// the spill store instead of arena slots.
+pub const SPILLED_SLOT: usize = 1 << 30;
+
+/// The spill area and its free pages.
+#[derive(Debug)]
+struct SpillArea {
+    region: MemoryRegion<PhysAddr>,
+    /// Indices of the pages not holding a spilled page.
+    free: Vec<usize>,
+}
+
+impl SpillArea {
+    fn pages(&self) -> usize {
+        self.region.len() / PAGE_SIZE
+    }
+
+    fn page(&self, index: usize) -> PhysAddr {
+        self.region.start() + index * PAGE_SIZE
+    }
+}
+
+/// Comes after `BACKUP_PAGES` and the parked snapshots in the lock order.
+static SPILL_AREA: SpinLock<Option<SpillArea>> = SpinLock::new(None);
+
+/// Returns the bytes which can still be spilled.
+pub fn spill_capacity() -> usize {
+    SPILL_AREA
+        .lock()
+        .as_ref()
+        .map_or(0, |area| area.free.len() * PAGE_SIZE)
+}
+
+/// Takes a free page of the spill area.
+fn take_page() -> Result<usize, SvsmError> {
+    SPILL_AREA
+        .lock()
+        .as_mut()
+        .and_then(|area| area.free.pop())
+        .ok_or(SvsmError::Alloc(AllocError::OutOfMemory))
+}
+
+/// Returns the page `index` to the spill area.
+fn free_page(index: usize) {
+    if let Some(area) = SPILL_AREA.lock().as_mut() {
+        area.free.push(index);
+    }
+}
+
+/// Returns the address of the page `index` of the spill area.
+fn area_page(index: usize) -> Result<PhysAddr, SvsmError> {
+    SPILL_AREA
+        .lock()
+        .as_ref()
+        .map(|area| area.page(index))
+        .ok_or(SvsmError::InvalidAddress)
+}
+
+/// Backed-up pages spilled to the spill area.
+#[derive(Debug, Default)]
+pub struct SpillStore {
+    /// Page of the spill area and seal of every entry.
+    entries: Vec<(usize, Seal)>,
+}
+
+impl SpillStore {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Encrypts a copy of `data` into a free page of the spill area and
+    /// returns its entry.
+    pub fn push(&mut self, data: &[u8; PAGE_SIZE]) -> Result<usize, SvsmError> {
+        self.entries
+            .try_reserve(1)
+            .map_err(|_| SvsmError::Alloc(AllocError::OutOfMemory))?;
+        let mut sealed = data.to_vec();
+        let seal = seal(&mut sealed)?;
+        let index = take_page()?;
+        let written = area_page(index).and_then(|paddr| {
+            let guard = map_shared_page(paddr)?;
+            // SAFETY: the guard maps a whole page of the spill area, which
+            // is not SVSM memory.
+            unsafe {
+                guard
+                    .virt_addr()
+                    .as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(sealed.as_ptr(), PAGE_SIZE);
+            }
+            Ok(())
+        });
+        if let Err(err) = written {
+            free_page(index);
+            return Err(err);
+        }
+        self.entries.push((index, seal));
+        Ok(self.entries.len() - 1)
+    }
+
+    /// Drops the newest entry.
+    pub fn pop(&mut self) {
+        if let Some((index, _)) = self.entries.pop() {
+            free_page(index);
+        }
+    }
+
+    /// Reads entry `entry` back and decrypts it into `out`. Returns false,
+    /// with `out` zeroed, if the contents cannot be read or fail
+    /// authentication, e.g. because the host changed them.
+    pub fn read(&self, entry: usize, out: &mut [u8; PAGE_SIZE]) -> bool {
+        let (index, seal) = &self.entries[entry];
+        let mut sealed = vec![0u8; PAGE_SIZE];
+        let read = area_page(*index).and_then(|paddr| {
+            let guard = map_shared_page(paddr)?;
+            // SAFETY: the guard maps a whole page of the spill area.
+            unsafe {
+                guard
+                    .virt_addr()
+                    .as_ptr::<u8>()
+                    .copy_to_nonoverlapping(sealed.as_mut_ptr(), PAGE_SIZE);
+            }
+            Ok(())
+        });
+        if let Err(err) = read {
+            log::error!("Failed to read spilled page: {:?}", err);
+            out.fill(0);
+            return false;
+        }
+        open(&sealed, seal, out)
+    }
+
+    /// Returns the number of spilled pages.
+    pub fn pages(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        while !self.entries.is_empty() {
+            self.pop();
+        }
+    }
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Backs up the registered page at `paddr` of `size` into the spill area,
+/// since it does not fit into the budget. Like a page kept in SVSM memory,
+/// zero pages are only recorded and pages which are not private or are
+/// excluded are skipped. Returns the bytes stored and the bytes skipped as
+/// zero.
+pub fn spill_registered_page(paddr: PhysAddr, size: PageSize) -> Result<(u64, u64), SvsmReqError> {
+    let mut scratch = allocate_file_page_ref()?;
+    let (mut stored, mut skipped, mut shared) = (0, 0, 0);
+    for page in pages_4k(paddr, size) {
+        if excluded(page) || !guest_private(page, PageSize::Regular).map_err(copy_error)? {
+            shared += 1;
+            continue;
+        }
+        let outcome = PageCopier::new(CopyFlags::DETECT_ZERO)
+            .copy(
+                CopySource::Guest(page),
+                CopyDest::Buffer(&mut scratch.as_mut()[..]),
+                PageSize::Regular,
+            )
+            .map_err(copy_error)?;
+        if outcome.zero {
+            ZERO_PAGES.lock_write().push(page);
+            skipped += PAGE_SIZE as u64;
+            continue;
+        }
+        BACKUP_PAGES
+            .lock_write()
+            .get_mut()
+            .and_then(|backup| backup.push_spilled(page, scratch.as_ref()))
+            .map_err(copy_error)?;
+        stored += PAGE_SIZE as u64;
+    }
+    record_shared_pages(shared);
+    Ok((stored, skipped))
+}
+
+/// Sets the spill area to the shared memory at `rcx` of `rdx` bytes, which
+/// must be page aligned. A size of 0 removes the spill area. Fails with
+/// INVALID_PARAMETER for a misaligned area, with INVALID_ADDRESS if it is
+/// not shared guest memory or overlaps protected firmware or the scratch
+/// region, and with `SVSM_ERR_BACKUP_EXISTS` while the current backup or a
+/// named snapshot holds spilled pages.
+pub fn set_spill_area(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let start = PhysAddr::from(params.rcx);
+    let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+    if !start.is_page_aligned() || len % PAGE_SIZE != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    let area = match len {
+        0 => None,
+        _ => {
+            let end = start
+                .checked_add(len)
+                .ok_or_else(SvsmReqError::invalid_parameter)?;
+            let region = MemoryRegion::from_addresses(start, end);
+            // Checks that every page is shared memory of the guest.
+            GuestBuffer::new_shared(start, len)?;
+            if fw_range_protected(region)
+                || scratch_region().is_some_and(|scratch| scratch.overlap(&region))
+            {
+                return Err(SvsmReqError::invalid_address());
+            }
+            Some(region)
+        }
+    };
+
+    let _created = BACKUP_CREATED.lock();
+    let mut current = SPILL_AREA.lock();
+    if current
+        .as_ref()
+        .is_some_and(|area| area.free.len() != area.pages())
+    {
+        return Err(backup_exists());
+    }
+    *current = area.map(|region| SpillArea {
+        region,
+        free: (0..region.len() / PAGE_SIZE).rev().collect(),
+    });
+    log::info!("Spill area set to {:#018x}, {:#x} bytes", start, len);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Statistics recorded with a backup.
+//!
+//! Exported snapshots carry a statistics section, so offline tooling can
+//! analyze fleets of snapshots without querying live instances. Most values
+//! follow from the backup pages; the ones which do not, how long the backup
+//! took and how many copy-on-write faults led up to it, are kept here.
+
+use super::BackupPages;
+use crate::cpu::tsc::{ticks_to_ns, tsc_now};
+use crate::types::PAGE_SIZE;
+use core::sync::atomic::{AtomicU64, Ordering};
+use snapshot::SnapshotStats;
+
+/// Copy-on-write faults resolved since the last backup was taken.
+static COW_FAULTS: AtomicU64 = AtomicU64::new(0);
+/// Copy-on-write faults resolved before the current backup was taken.
+static BACKUP_COW_FAULTS: AtomicU64 = AtomicU64::new(0);
+/// Duration of taking the current backup in nanoseconds.
+static BACKUP_CREATION_NS: AtomicU64 = AtomicU64::new(0);
+/// Registered 4K pages the current backup skipped because they were no
+/// longer private guest memory or were excluded.
+static SHARED_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a resolved copy-on-write fault.
+pub fn record_cow_fault() {
+    COW_FAULTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the statistics of the backup just taken, which started at TSC
+/// value `start`, and starts counting faults towards the next backup.
+pub fn record_backup_stats(start: u64) {
+    let ns = ticks_to_ns(tsc_now().saturating_sub(start));
+    BACKUP_CREATION_NS.store(ns, Ordering::Relaxed);
+    BACKUP_COW_FAULTS.store(COW_FAULTS.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Counts `pages` registered 4K pages skipped because they were not private.
+pub fn record_shared_pages(pages: u64) {
+    SHARED_PAGES.fetch_add(pages, Ordering::Relaxed);
+}
+
+/// Returns the number of registered 4K pages the current backup skipped
+/// because they were not private.
+pub fn shared_pages() -> u64 {
+    SHARED_PAGES.load(Ordering::Relaxed)
+}
+
+/// Forgets the statistics of a discarded backup.
+pub fn discard_backup_stats() {
+    BACKUP_CREATION_NS.store(0, Ordering::Relaxed);
+    BACKUP_COW_FAULTS.store(0, Ordering::Relaxed);
+    SHARED_PAGES.store(0, Ordering::Relaxed);
+}
+
+/// Returns the statistics of the backup held in `backup` together with
+/// `zero_pages` zero pages, whose payload takes `stored_bytes` bytes in the
+/// container.
+pub(super) fn backup_stats(
+    backup: &BackupPages,
+    zero_pages: usize,
+    stored_bytes: u64,
+) -> SnapshotStats {
+    let stored_pages = backup.len() as u64;
+    let payload_bytes = stored_pages * PAGE_SIZE as u64;
+    SnapshotStats {
+        creation_ns: BACKUP_CREATION_NS.load(Ordering::Relaxed),
+        cow_faults: BACKUP_COW_FAULTS.load(Ordering::Relaxed),
+        logical_pages: stored_pages + zero_pages as u64,
+        stored_pages,
+        payload_bytes,
+        stored_bytes,
+    }
+}
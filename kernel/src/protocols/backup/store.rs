@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Refcounted storage of backed-up pages.
+//!
+//! A serverless runtime restores the same base snapshot over and over, or
+//! runs several logical guests from it in turn. Instead of copying the base
+//! for each of them, a snapshot is forked (see the `named` module): the
+//! fork refers to the pages and delta layers of its base and only copies
+//! the pages it saves later, in delta layers of its own. Backed-up pages
+//! are therefore held through a refcounted [`SharedPages`] handle and are
+//! read-only while more than one snapshot holds them. They are freed with
+//! the last handle.
+//!
+//! The memory the base was charged when it was forked moves from the base
+//! to the shared pages, so deleting the base does not return memory which
+//! its forks still use. It is returned with the pages.
+
+use super::budget::release_shared;
+use super::BackupPages;
+use crate::error::SvsmError;
+use crate::invariant::{invariant_violated, InvariantCode};
+use core::ops::Deref;
+use core::sync::atomic::Ordering;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+/// Stands in for the pages of a snapshot which has none.
+static NO_PAGES: BackupPages = BackupPages::new();
+
+/// Backed-up pages held by a snapshot, possibly together with its forks.
+#[derive(Clone, Default)]
+pub(super) struct SharedPages(Option<Arc<BackupPages>>);
+
+impl SharedPages {
+    pub(super) const fn new() -> Self {
+        Self(None)
+    }
+
+    /// Returns the pages for adding to them. Fails if they are shared with
+    /// another snapshot.
+    pub(super) fn get_mut(&mut self) -> Result<&mut BackupPages, SvsmError> {
+        let pages = self.0.get_or_insert_with(|| Arc::new(BackupPages::new()));
+        Arc::get_mut(pages).ok_or_else(|| {
+            invariant_violated(
+                InvariantCode::SharedPagesWritten,
+                "Arc::get_mut(pages).is_some()",
+                file!(),
+                line!(),
+            )
+        })
+    }
+
+    /// Returns another handle to the pages, for a fork of the snapshot,
+    /// and adds `charge` bytes of snapshot memory to their charge.
+    pub(super) fn share(&mut self, charge: usize) -> Self {
+        let pages = self.0.get_or_insert_with(|| Arc::new(BackupPages::new()));
+        pages.charge.fetch_add(charge, Ordering::Relaxed);
+        Self(Some(pages.clone()))
+    }
+
+    /// Drops the handle. The pages are freed unless a fork still holds
+    /// them.
+    pub(super) fn clear(&mut self) {
+        match self.0.as_mut().and_then(Arc::get_mut) {
+            Some(pages) => pages.clear(),
+            None => self.0 = None,
+        }
+    }
+}
+
+impl Deref for SharedPages {
+    type Target = BackupPages;
+
+    fn deref(&self) -> &BackupPages {
+        self.0.as_deref().unwrap_or(&NO_PAGES)
+    }
+}
+
+impl Drop for BackupPages {
+    fn drop(&mut self) {
+        release_shared(*self.charge.get_mut());
+    }
+}
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Tracking of the guest pages covered by a backup.
+//!
+//! Every page the guest validates through the core protocol is added to
+//! [`PAGES_TO_BACKUP`], and every page it rescinds is removed again, so a
+//! full backup copies exactly the private memory of the guest.
+//!
+//! The guest can also declare the ranges it wants checkpointed itself with
+//! `SVSM_REGISTER_BACKUP_RANGE` and `SVSM_UNREGISTER_BACKUP_RANGE`. Both
+//! take the start address in `rcx`, the number of pages in `rdx` and the
+//! page size in `r8` (0 for 4K, 1 for 2M, as in the core PVALIDATE call, or
+//! 2 for 1G). A 1G page is handled as the 2M pages it consists of, for
+//! copies as well as for RMP operations.
+//! Only private memory the guest can access may be registered. Pages beyond
+//! the guest physical address width of the platform, owned by the SVSM or
+//! outside guest RAM, like MMIO ranges, are refused with
+//! `SVSM_ERR_BACKUP_BAD_ADDRESS`, and backups refuse to start while any are
+//! registered.
+//!
+//! While a backup exists, the guest can pin the registered pages with
+//! `SVSM_PIN_BACKUP_PAGES`. Core protocol requests which would change their
+//! backing, i.e. PVALIDATE in either direction and using one as a VMSA, then
+//! fail with SVSM_ERR_BUSY instead of being tracked for the restore (see the
+//! `validation` module). The pin is dropped with the backup.
+//!
+//! Ranges which must never be rolled back, such as DMA bounce buffers
+//! shared with the host, are excluded with `SVSM_EXCLUDE_BACKUP_RANGE` and
+//! included again with `SVSM_INCLUDE_BACKUP_RANGE`, which take the same
+//! arguments. Excluded pages are neither copied by backups nor overwritten
+//! by restores, whether they are registered or not, and can be excluded
+//! while a backup exists.
+
+use super::dirty::pages_4k;
+use super::errors::{backup_exists, bad_address, no_backup, not_private};
+use super::validation::{track_validation, validation_tracked};
+use super::{BACKUP_CREATED, PAGES_TO_BACKUP, PAGES_TO_SKIP};
+use crate::address::{Address, PhysAddr};
+use crate::checked_invariant;
+use crate::error::SvsmError;
+use crate::fw_protect::fw_page_protected;
+use crate::mm::pagetable::max_phys_addr;
+use crate::mm::pin::{pin_page_set, unpin_page_set};
+use crate::mm::{
+    svsm_phys_addr, valid_phys_address, writable_phys_addr, PerCPUPageMappingGuard, SIZE_1G,
+};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::sev::rmp::{rmp_query, GuestAccess, RmpError};
+use crate::sev::utils::PvalidateOp;
+use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
+
+fn update_pages_to_backup_invalid(paddr: PhysAddr, size: PageSize) {
+    log::info!("Attemt to remove page from backup: {:#x}, size: {:?}", paddr, size);
+    
+    match size {
+        PageSize::Regular => {
+            if PAGES_TO_BACKUP.contains_addr(paddr, size){
+                PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Regular);
+                log::info!("Removed page from backup: {:#x}, size: {:?}", paddr, size);
+
+            } else if PAGES_TO_BACKUP.contains_addr(paddr.page_align_2m(), PageSize::Huge){
+                
+                // Split huge page into regular pages and add them to set
+                // Then remove the huge page and the regular page specified by paddr from the set
+                log::info!("Splitting page from backup: {:#x}, size: {:?}", paddr.page_align_2m(), size);
+                let base_addr = paddr.page_align_2m();
+                PAGES_TO_BACKUP.insert_range(base_addr, base_addr + PAGE_SIZE_2M, PageSize::Regular);
+                checked_invariant!(
+                    PAGES_TO_BACKUP.remove_addr(base_addr, PageSize::Huge),
+                    SplitMissingHugePage
+                );
+                PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Regular);
+                log::info!("Removed page from backup: {:#x}", paddr);
+            }
+        },
+        PageSize::Huge => {
+            // Scenario:    1. insert address 0x58000000 with size Huge, 
+            //              2. insert 0x58000000 with size Regular, 
+            //              3. insert 0x58001000 with size Regular, 
+            //                ....
+            //              4. remove 0x58000000 with size Huge 
+            //              --> pages 0x58000000, 0x58001000 ... with size Regular have to be removed too
+            PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Huge);
+            PAGES_TO_BACKUP.remove_range(paddr, paddr + PAGE_SIZE_2M, PageSize::Regular);
+            log::info!("Removed page from backup {:#x}, size: {:?}", paddr.page_align_2m(), PageSize::Huge);
+        }
+    }
+
+}
+
+/// Keeps the set of pages to back up in sync with the validation state of
+/// guest memory. While a backup is taken or exists, the change is recorded
+/// for the next restore instead.
+pub fn track_pvalidate(paddr: PhysAddr, size: PageSize, valid: PvalidateOp) -> Result<(), SvsmReqError> {
+    if validation_tracked() {
+        track_validation(paddr, size, valid);
+    } else {
+        apply_pvalidate(paddr, size, valid);
+    }
+    Ok(())
+}
+
+/// Adds the page at `paddr` of `size` to the pages to back up, or removes
+/// it, according to `valid`.
+pub fn apply_pvalidate(paddr: PhysAddr, size: PageSize, valid: PvalidateOp) {
+    match valid {
+        PvalidateOp::Valid => PAGES_TO_BACKUP.insert_addr(paddr, size),
+        PvalidateOp::Invalid => update_pages_to_backup_invalid(paddr, size),
+    }
+}
+
+/// Decodes the range of a registration request. A range of 1G pages is
+/// returned as its 2M pages. Fails with INVALID_PARAMETER if the page size
+/// is unknown, the range is empty or misaligned, or it wraps around.
+fn backup_range(params: &RequestParams) -> Result<(PhysAddr, u64, PageSize), SvsmReqError> {
+    let (page_size, size) = match params.r8 {
+        0 => (PAGE_SIZE, PageSize::Regular),
+        1 => (PAGE_SIZE_2M, PageSize::Huge),
+        2 => (SIZE_1G, PageSize::Huge),
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    };
+    let start = PhysAddr::from(params.rcx);
+    if params.rdx == 0 || !start.is_aligned(page_size) {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    params
+        .rdx
+        .checked_mul(page_size as u64)
+        .and_then(|len| params.rcx.checked_add(len))
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+    let count = params.rdx * (page_size / usize::from(size)) as u64;
+    Ok((start, count, size))
+}
+
+/// Returns the addresses of the `count` pages of `size` starting at `start`.
+fn range_pages(start: PhysAddr, count: u64, size: PageSize) -> impl Iterator<Item = PhysAddr> {
+    (0..count as usize).map(move |i| start + i * usize::from(size))
+}
+
+/// Returns the end of the `count` pages of `size` starting at `start`.
+fn range_end(start: PhysAddr, count: u64, size: PageSize) -> PhysAddr {
+    start + count as usize * usize::from(size)
+}
+
+/// Whether a 4K page is private guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Privacy {
+    /// Not guest RAM, protected firmware or not accessible to the guest.
+    NotPrivate,
+    /// Private, in an RMP entry of the given size.
+    Private(PageSize),
+    /// Guest RAM whose RMP entry cannot be queried without RMPQUERY.
+    Unchecked,
+}
+
+/// Returns whether the 4K page at `paddr` is private guest memory: writable
+/// guest RAM outside protected firmware which the guest can access in the
+/// RMP.
+fn privacy(paddr: PhysAddr) -> Result<Privacy, SvsmError> {
+    if !writable_phys_addr(paddr) || fw_page_protected(paddr) {
+        return Ok(Privacy::NotPrivate);
+    }
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    match rmp_query(guard.virt_addr()) {
+        Ok(state) if state.guest_access != GuestAccess::None => Ok(Privacy::Private(state.size)),
+        Err(RmpError::Unsupported) => Ok(Privacy::Unchecked),
+        Ok(_) | Err(_) => Ok(Privacy::NotPrivate),
+    }
+}
+
+/// Returns whether the whole registered page at `paddr` of `size` is still
+/// private guest memory. A huge page whose RMP entry was split is checked
+/// 4K page by 4K page. Without RMPQUERY the RMP check is skipped.
+pub fn guest_private(paddr: PhysAddr, size: PageSize) -> Result<bool, SvsmError> {
+    match privacy(paddr)? {
+        Privacy::NotPrivate => Ok(false),
+        Privacy::Private(PageSize::Regular) if size == PageSize::Huge => {
+            for paddr in pages_4k(paddr, size).skip(1) {
+                if privacy(paddr)? == Privacy::NotPrivate {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Privacy::Private(_) | Privacy::Unchecked => Ok(true),
+    }
+}
+
+/// Checks that the page at `paddr` of `size` can be backed up at all: it
+/// must be within the guest physical address width, not owned by the SVSM
+/// and guest RAM.
+pub fn check_backup_address(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmReqError> {
+    let in_range = paddr
+        .checked_add(usize::from(size))
+        .is_some_and(|end| end <= max_phys_addr());
+    if !in_range || pages_4k(paddr, size).any(|p| svsm_phys_addr(p) || !valid_phys_address(p)) {
+        log::warn!("Refusing to back up page {:#018x}, size: {:?}", paddr, size);
+        return Err(bad_address());
+    }
+    Ok(())
+}
+
+/// Returns the bytes of registered guest memory, after checking that every
+/// registered page can be backed up.
+pub fn registered_bytes() -> Result<usize, SvsmReqError> {
+    let mut bytes = 0;
+    for (paddr, size) in PAGES_TO_BACKUP.iter_addresses() {
+        check_backup_address(paddr, size)?;
+        bytes += usize::from(size);
+    }
+    Ok(bytes)
+}
+
+/// Checks that the 4K page at `paddr` is private guest memory.
+fn check_guest_private(paddr: PhysAddr) -> Result<(), SvsmReqError> {
+    if privacy(paddr)? == Privacy::NotPrivate {
+        log::warn!("Refusing to register non-private page {:#018x}", paddr);
+        return Err(not_private());
+    }
+    Ok(())
+}
+
+/// Adds a guest range to the pages to back up. Fails with
+/// `SVSM_ERR_BACKUP_EXISTS` while a backup exists, with
+/// `SVSM_ERR_BACKUP_BAD_ADDRESS` if any page of the range can never be
+/// backed up and with `SVSM_ERR_BACKUP_NOT_PRIVATE` if any page is not
+/// private guest memory. Nothing is registered in these cases.
+pub fn register_backup_range(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let (start, count, size) = backup_range(params)?;
+    if *BACKUP_CREATED.lock() {
+        return Err(backup_exists());
+    }
+    for paddr in range_pages(start, count, size) {
+        check_backup_address(paddr, size)?;
+        for paddr in pages_4k(paddr, size) {
+            check_guest_private(paddr)?;
+        }
+    }
+    PAGES_TO_BACKUP.insert_range(start, range_end(start, count, size), size);
+    log::info!(
+        "Registered backup range {:#018x}: {} pages, size: {:?}",
+        start,
+        count,
+        size
+    );
+    Ok(())
+}
+
+/// Removes a guest range from the pages to back up. Huge pages partially
+/// covered by a range of 4K pages are split. Fails with
+/// `SVSM_ERR_BACKUP_EXISTS` while a backup exists.
+pub fn unregister_backup_range(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let (start, count, size) = backup_range(params)?;
+    if *BACKUP_CREATED.lock() {
+        return Err(backup_exists());
+    }
+    for paddr in range_pages(start, count, size) {
+        update_pages_to_backup_invalid(paddr, size);
+    }
+    Ok(())
+}
+
+/// Pins the registered pages until the current backup is dropped if `rcx`
+/// is 1, or drops the pin if it is 0. Fails with `SVSM_ERR_NO_BACKUP` if
+/// there is no backup.
+pub fn pin_backup_pages(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let pin = match params.rcx {
+        0 => false,
+        1 => true,
+        _ => return Err(SvsmReqError::invalid_parameter()),
+    };
+    if !*BACKUP_CREATED.lock() {
+        return Err(no_backup());
+    }
+    if pin {
+        pin_page_set(&PAGES_TO_BACKUP);
+        log::info!("Pinned the registered pages");
+    } else {
+        unpin_page_set(&PAGES_TO_BACKUP);
+        log::info!("Unpinned the registered pages");
+    }
+    Ok(())
+}
+
+/// Drops the pin of the registered pages, as the backup is dropped.
+pub fn unpin_backup_pages() {
+    unpin_page_set(&PAGES_TO_BACKUP);
+}
+
+/// Excludes a guest range from backups and restores. Huge pages are
+/// excluded as their 4K pages, so parts of them can be included again.
+pub fn exclude_backup_range(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let (start, count, size) = backup_range(params)?;
+    PAGES_TO_SKIP.insert_range(start, range_end(start, count, size), PageSize::Regular);
+    log::info!(
+        "Excluded backup range {:#018x}: {} pages, size: {:?}",
+        start,
+        count,
+        size
+    );
+    Ok(())
+}
+
+/// Includes an excluded guest range in backups and restores again.
+pub fn include_backup_range(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let (start, count, size) = backup_range(params)?;
+    PAGES_TO_SKIP.remove_range(start, range_end(start, count, size), PageSize::Regular);
+    Ok(())
+}
+
+/// Returns whether the 4K page containing `paddr` is excluded from backups
+/// and restores.
+pub fn excluded(paddr: PhysAddr) -> bool {
+    !PAGES_TO_SKIP.is_empty() && PAGES_TO_SKIP.contains_addr(paddr.page_align(), PageSize::Regular)
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Validation state of guest memory across restores.
+//!
+//! Restoring page contents alone does not take back validation changes the
+//! guest made after the backup. A page rescinded since, e.g. to convert it
+//! to shared, cannot hold its backed-up contents, and a page validated
+//! since is still validated although the restored guest considers it
+//! unaccepted, so accepting it again fails. From the moment a backup starts
+//! copying pages until it is discarded, guest PVALIDATE calls through the
+//! core protocol are therefore recorded here instead of changing the pages
+//! to back up. Pages validated while the copy runs are missing from the
+//! backup, so they are cleared by a restore as well. A restore first makes the
+//! rescinded pages private and validated again, so their contents can be
+//! restored, and rescinds the validation of the new pages after zeroing
+//! them (see `clear_new_pages`), so the RMP matches the backup again.
+
+use super::dirty::{mark_dirty, pages_4k, registered_page};
+use super::errors::rmp_failed;
+use super::tracking::apply_pvalidate;
+use super::watchdog::cow_active;
+use super::{set_read_only, BACKUP_CREATED, PAGES_TO_CLEAR};
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::mm::set::PageSet;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::platform::{PageStateChangeOp, SVSM_PLATFORM};
+use crate::protocols::errors::SvsmReqError;
+use crate::sev::utils::{
+    pvalidate, rmp_grant_guest_access, rmp_revoke_guest_access, PvalidateOp, SevSnpError,
+};
+use crate::task::preemption_point;
+use crate::types::PageSize;
+use crate::utils::MemoryRegion;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Pages of the backup the guest rescinded since the backup was taken.
+static RESCINDED: PageSet = PageSet::new();
+
+/// Whether a backup started copying pages. Cleared once the changes are
+/// applied.
+static BACKUP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Records validation changes from now on, for a backup about to fix the
+/// pages it copies. Changes recorded for a backup which fails are applied
+/// when it is discarded.
+pub fn start_validation_tracking() {
+    BACKUP_STARTED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether validation changes are recorded rather than applied,
+/// either because a backup is being taken or because one exists.
+pub fn validation_tracked() -> bool {
+    BACKUP_STARTED.load(Ordering::SeqCst) || *BACKUP_CREATED.lock()
+}
+
+/// Returns whether the page at `paddr` of `size` overlaps the backup.
+fn in_backup(paddr: PhysAddr, size: PageSize) -> bool {
+    pages_4k(paddr, size).any(|page| registered_page(page).is_some())
+}
+
+/// Removes the page at `paddr` of `size`, and for huge pages its 4K pages,
+/// from `set`.
+fn remove_page(set: &PageSet, paddr: PhysAddr, size: PageSize) {
+    set.remove_addr(paddr, size);
+    if size == PageSize::Huge {
+        set.remove_range(paddr, paddr + usize::from(size), PageSize::Regular);
+    }
+}
+
+/// Records that the guest changed the validation of the page at `paddr` of
+/// `size` while a backup exists.
+pub fn track_validation(paddr: PhysAddr, size: PageSize, valid: PvalidateOp) {
+    match valid {
+        PvalidateOp::Invalid if in_backup(paddr, size) => RESCINDED.insert_addr(paddr, size),
+        PvalidateOp::Invalid => remove_page(&PAGES_TO_CLEAR, paddr, size),
+        PvalidateOp::Valid if RESCINDED.contains_addr(paddr, size) => {
+            remove_page(&RESCINDED, paddr, size)
+        }
+        PvalidateOp::Valid if in_backup(paddr, size) => {
+            log::warn!(
+                "Validated page {:#x} of the backup not rescinded as a whole, size: {:?}",
+                paddr,
+                size
+            );
+        }
+        PvalidateOp::Valid => PAGES_TO_CLEAR.insert_addr(paddr, size),
+    }
+}
+
+/// Returns whether the 4K page at `paddr` belongs to a page of the backup
+/// the guest rescinded since. A restore validates it again before writing
+/// it.
+pub fn rescinded(paddr: PhysAddr) -> bool {
+    RESCINDED.contains_addr(paddr, PageSize::Regular)
+        || RESCINDED.contains_addr(paddr.page_align_2m(), PageSize::Huge)
+}
+
+/// Runs PVALIDATE on the page mapped at `guard`, ignoring pages which are
+/// already in the requested state.
+fn pvalidate_page(
+    guard: &PerCPUPageMappingGuard,
+    size: PageSize,
+    valid: PvalidateOp,
+) -> Result<(), SvsmError> {
+    pvalidate(guard.virt_addr(), size, valid).or_else(|err| match err {
+        SvsmError::SevSnp(SevSnpError::FAIL_UNCHANGED(_)) => Ok(()),
+        _ => Err(err),
+    })
+}
+
+/// Makes the rescinded page at `paddr` of `size` private and validated and
+/// gives the guest access again, read-only under copy-on-write.
+fn revalidate(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmError> {
+    let len = usize::from(size);
+    SVSM_PLATFORM.as_dyn_ref().page_state_change(
+        MemoryRegion::new(paddr, len),
+        size,
+        PageStateChangeOp::Private,
+    )?;
+    let guard = PerCPUPageMappingGuard::create_with_page_size(paddr, paddr + len, size)?;
+    pvalidate_page(&guard, size, PvalidateOp::Valid)?;
+    rmp_grant_guest_access(guard.virt_addr(), size)?;
+    if cow_active() {
+        set_read_only(paddr, size)?;
+    }
+    Ok(())
+}
+
+/// Makes the pages of the backup which the guest rescinded since private
+/// and validated again. Their contents are undefined until restored, so
+/// they are marked dirty for partial restores.
+pub fn restore_validation() -> Result<(), SvsmReqError> {
+    let mut restored = 0;
+    for (paddr, size) in RESCINDED.iter_addresses() {
+        revalidate(paddr, size).map_err(rmp_failed)?;
+        if let Some((page, size)) = registered_page(paddr) {
+            mark_dirty(page, size);
+        }
+        RESCINDED.remove_addr(paddr, size);
+        restored += 1;
+        preemption_point();
+    }
+    if restored != 0 {
+        log::info!("Validated {} rescinded pages again", restored);
+    }
+    Ok(())
+}
+
+/// Rescinds the validation of the page at `paddr` of `size`, which the
+/// guest validated after the backup was taken.
+pub fn rescind_new_page(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmReqError> {
+    let len = usize::from(size);
+    let guard = PerCPUPageMappingGuard::create_with_page_size(paddr, paddr + len, size)
+        .map_err(SvsmReqError::from_mapping)?;
+    rmp_revoke_guest_access(guard.virt_addr(), size).map_err(rmp_failed)?;
+    pvalidate_page(&guard, size, PvalidateOp::Invalid).map_err(rmp_failed)
+}
+
+/// Applies the recorded validation changes to the pages to back up, once
+/// the current backup was discarded or parked. Rescinded pages are no
+/// longer backed up and new pages are.
+pub fn apply_validation_changes() {
+    BACKUP_STARTED.store(false, Ordering::SeqCst);
+    for (paddr, size) in RESCINDED.iter_addresses() {
+        apply_pvalidate(paddr, size, PvalidateOp::Invalid);
+    }
+    RESCINDED.clear();
+    for (paddr, size) in PAGES_TO_CLEAR.iter_addresses() {
+        apply_pvalidate(paddr, size, PvalidateOp::Valid);
+    }
+    PAGES_TO_CLEAR.clear();
+}
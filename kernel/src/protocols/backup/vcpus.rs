@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! vCPU register state saved with a backup.
+//!
+//! Restoring memory alone leaves every vCPU where it was, which is not
+//! enough to fork a VM. The VMSA of every vCPU (general purpose, control
+//! and segment registers and MSR state) is therefore saved with a full
+//! backup and written back by a full restore, so the guest resumes at a
+//! consistent execution point.
+//!
+//! The vCPU taking the backup is in the middle of the backup call. After a
+//! restore it returns from that call a second time, like `fork()`: the call
+//! succeeds with `rdx` set to 0 when the backup is taken and to 1 when the
+//! vCPU resumes from it. If another vCPU restores, that vCPU continues
+//! after its restore call and the vCPU which took the backup is made
+//! runnable at the resume point.
+//!
+//! The other vCPUs are saved as of their last exit, so their state is only
+//! consistent if the guest keeps them parked outside of guest code while
+//! the backup is taken and restored. A VMSA is not runnable while it is
+//! rewritten. vCPUs created after the backup, or whose VMSA moved, are left
+//! alone.
+//!
+//! The page-table roots recorded before the pages were copied (see the
+//! `pagetables` module) are saved together with the VMSAs.
+
+use super::pagetables::PagingRoot;
+use crate::address::PhysAddr;
+use crate::cpu::efer::EFERFlags;
+use crate::cpu::percpu::{this_cpu, PERCPU_AREAS};
+use crate::cpu::vmsa::vmsa_mut_ref_from_vaddr;
+use crate::error::SvsmError;
+use crate::locking::SpinLock;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::protocols::errors::{SvsmReqError, SvsmResultCode};
+use crate::protocols::RequestParams;
+use crate::sev::vmsa::VMSAControl;
+use crate::types::PAGE_SIZE;
+use cpuarch::vmsa::VMSA;
+
+extern crate alloc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// `rdx` of a backup call which returns after a restore.
+const RESUMED: u64 = 1;
+
+/// The VMSA of a vCPU saved with the backup.
+#[derive(Clone, Debug)]
+struct SavedVmsa {
+    apic_id: u32,
+    /// Address of the VMSA page when it was saved.
+    paddr: PhysAddr,
+    /// Contents of the VMSA page, with EFER.SVME clear.
+    data: Vec<u8>,
+    /// Whether EFER.SVME was set, i.e. the vCPU was runnable.
+    runnable: bool,
+}
+
+/// vCPU state saved with a backup which is not the current one.
+#[derive(Clone, Debug, Default)]
+pub struct SavedVcpus {
+    /// APIC ID of the vCPU which took the backup.
+    caller: u32,
+    vmsas: Vec<SavedVmsa>,
+    /// Page-table roots of the vCPUs before the pages were copied.
+    roots: Vec<PagingRoot>,
+}
+
+static SAVED_VCPUS: SpinLock<SavedVcpus> = SpinLock::new(SavedVcpus {
+    caller: 0,
+    vmsas: Vec::new(),
+    roots: Vec::new(),
+});
+
+/// Returns the bytes of `vmsa`.
+fn vmsa_bytes(vmsa: &mut VMSA) -> &mut [u8] {
+    // SAFETY: the VMSA is a packed structure of exactly one page.
+    unsafe { core::slice::from_raw_parts_mut((vmsa as *mut VMSA).cast::<u8>(), PAGE_SIZE) }
+}
+
+/// Returns the VMSA saved in `data`.
+fn as_vmsa(data: &mut [u8]) -> &mut VMSA {
+    assert_eq!(data.len(), PAGE_SIZE);
+    // SAFETY: the VMSA is a packed structure of exactly one page, so it
+    // has no alignment requirement, and `data` was copied from a VMSA.
+    unsafe { &mut *data.as_mut_ptr().cast::<VMSA>() }
+}
+
+/// Maps the VMSA page at `paddr` and calls `f` with it.
+fn with_vmsa<R>(paddr: PhysAddr, f: impl FnOnce(&mut VMSA) -> R) -> Result<R, SvsmError> {
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    Ok(f(vmsa_mut_ref_from_vaddr(guard.virt_addr())))
+}
+
+/// Returns the current page-table roots of the vCPUs which run with 4- or
+/// 5-level paging.
+pub fn live_paging_roots() -> Vec<PagingRoot> {
+    let mut roots = Vec::new();
+    for info in PERCPU_AREAS.iter() {
+        let cpu = info.as_cpu_ref();
+        let Some(paddr) = cpu.guest_vmsa_phys() else {
+            continue;
+        };
+        match with_vmsa(paddr, |vmsa| PagingRoot::from_vmsa(cpu.apic_id(), vmsa)) {
+            Ok(root) => roots.extend(root),
+            Err(err) => log::warn!("Cannot read VMSA of vCPU {}: {:?}", cpu.apic_id(), err),
+        }
+    }
+    roots
+}
+
+/// Returns the page-table roots saved with the current backup.
+pub fn saved_paging_roots() -> Vec<PagingRoot> {
+    SAVED_VCPUS.lock().roots.clone()
+}
+
+/// Saves the VMSA of every vCPU with a new backup taken by the calling
+/// vCPU, together with the page-table `roots` recorded before the pages
+/// were copied.
+pub fn snapshot_vcpus(roots: Vec<PagingRoot>) -> Result<(), SvsmReqError> {
+    let mut vmsas = Vec::new();
+    for info in PERCPU_AREAS.iter() {
+        let cpu = info.as_cpu_ref();
+        let Some(paddr) = cpu.guest_vmsa_phys() else {
+            continue;
+        };
+        let mut data = vec![0u8; PAGE_SIZE];
+        let runnable = with_vmsa(paddr, |vmsa| {
+            data.copy_from_slice(vmsa_bytes(vmsa));
+            vmsa.efer & EFERFlags::SVME.bits() != 0
+        })
+        .map_err(SvsmReqError::from_mapping)?;
+        as_vmsa(&mut data).disable();
+        vmsas.push(SavedVmsa {
+            apic_id: cpu.apic_id(),
+            paddr,
+            data,
+            runnable,
+        });
+    }
+    *SAVED_VCPUS.lock() = SavedVcpus {
+        caller: this_cpu().get_apic_id(),
+        vmsas,
+        roots,
+    };
+    Ok(())
+}
+
+/// Writes the saved VMSAs back after a full restore. If the calling vCPU
+/// took the backup, `params` are set up so it returns from the backup
+/// call; otherwise its own registers are left alone.
+pub fn restore_vcpus(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let saved = SAVED_VCPUS.lock();
+    let this = this_cpu().get_apic_id();
+    let mut restored = 0;
+    for entry in saved.vmsas.iter() {
+        let resumes = entry.apic_id == saved.caller;
+        if entry.apic_id == this && !resumes {
+            continue;
+        }
+        let current = PERCPU_AREAS
+            .get(entry.apic_id)
+            .and_then(|cpu| cpu.guest_vmsa_phys());
+        if current != Some(entry.paddr) {
+            log::warn!(
+                "VMSA of vCPU {} changed since the backup, not restored",
+                entry.apic_id
+            );
+            continue;
+        }
+        let regs = with_vmsa(entry.paddr, |vmsa| {
+            vmsa.disable();
+            vmsa_bytes(vmsa).copy_from_slice(&entry.data);
+            if resumes {
+                vmsa.rax = SvsmResultCode::SUCCESS.into();
+                vmsa.rdx = RESUMED;
+            }
+            // The request loop of the calling vCPU makes its VMSA runnable
+            // again once the results are written back.
+            if entry.apic_id != this && (entry.runnable || resumes) {
+                vmsa.enable();
+            }
+            (vmsa.rcx, vmsa.r8)
+        })
+        .map_err(SvsmReqError::from_mapping)?;
+        if entry.apic_id == this {
+            params.rcx = regs.0;
+            params.rdx = RESUMED;
+            params.r8 = regs.1;
+        }
+        restored += 1;
+    }
+    log::info!("Restored the registers of {} vCPUs", restored);
+    Ok(())
+}
+
+/// Drops the vCPU state saved with the backup.
+pub fn discard_saved_vcpus() {
+    let mut saved = SAVED_VCPUS.lock();
+    saved.vmsas.clear();
+    saved.roots.clear();
+}
+
+/// Takes the vCPU state saved with the current backup.
+pub fn take_saved_vcpus() -> SavedVcpus {
+    core::mem::take(&mut *SAVED_VCPUS.lock())
+}
+
+/// Makes `saved` the vCPU state saved with the current backup.
+pub fn put_saved_vcpus(saved: SavedVcpus) {
+    *SAVED_VCPUS.lock() = saved;
+}
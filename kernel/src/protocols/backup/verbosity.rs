@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Verbosity of the backup log.
+//!
+//! Every line logged on a serial console costs time, and backups and
+//! restores of large guests touch a lot of pages. By default the backup
+//! code only logs a summary of each operation: page counts, byte totals
+//! and its duration. More detail can be switched on at runtime with
+//! `SVSM_SET_BACKUP_VERBOSITY`, up to a line for every page touched. The
+//! `backup-trace` feature makes the per-page lines the default.
+
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the backup code logs. Each level includes the ones below.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// One summary per operation.
+    Summary = 0,
+    /// The steps of an operation and the ranges of pages restored.
+    Ranges = 1,
+    /// Every page touched.
+    Pages = 2,
+}
+
+impl TryFrom<u64> for Verbosity {
+    type Error = SvsmReqError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Summary),
+            1 => Ok(Self::Ranges),
+            2 => Ok(Self::Pages),
+            _ => Err(SvsmReqError::invalid_parameter()),
+        }
+    }
+}
+
+const DEFAULT_VERBOSITY: Verbosity = if cfg!(feature = "backup-trace") {
+    Verbosity::Pages
+} else {
+    Verbosity::Summary
+};
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(DEFAULT_VERBOSITY as u8);
+
+/// Returns whether lines of `level` are logged.
+pub fn verbose(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Sets the verbosity of the backup log to `rcx`, see [`Verbosity`]. On
+/// success `rcx` holds the previous verbosity. Fails with
+/// INVALID_PARAMETER for an unknown level.
+pub fn set_verbosity(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let level = Verbosity::try_from(params.rcx)?;
+    let previous = VERBOSITY.swap(level as u8, Ordering::Relaxed);
+    log::info!("Backup log verbosity: {:?}", level);
+    params.rcx = u64::from(previous);
+    Ok(())
+}
+
+/// Logs the progress of a backup operation, at [`Verbosity::Ranges`].
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if $crate::protocols::backup::verbosity::verbose(
+            $crate::protocols::backup::verbosity::Verbosity::Ranges,
+        ) {
+            log::info!($($arg)*);
+        }
+    };
+}
+
+/// Logs per-page details of a backup operation, at [`Verbosity::Pages`].
+macro_rules! page_trace {
+    ($($arg:tt)*) => {
+        if $crate::protocols::backup::verbosity::verbose(
+            $crate::protocols::backup::verbosity::Verbosity::Pages,
+        ) {
+            log::info!($($arg)*);
+        }
+    };
+}
+
+pub(super) use {detail, page_trace};
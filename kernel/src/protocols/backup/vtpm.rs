@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Hooks keeping the vTPM coherent with backups.
+//!
+//! The vTPM state is handled by [`crate::vtpm::restore`] according to the
+//! configured restore policy. Without the `mstpm` feature there is no vTPM
+//! and the hooks do nothing.
+
+use crate::protocols::errors::SvsmReqError;
+use snapshot::vtpm_policy;
+
+#[cfg(all(feature = "mstpm", not(test)))]
+use crate::vtpm::restore::{
+    vtpm_backup_policy, vtpm_discard_snapshot, vtpm_load_migration_state, vtpm_migration_state,
+    vtpm_put_snapshot, vtpm_restore, vtpm_snapshot, vtpm_take_snapshot, VtpmRestorePolicy,
+};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// vTPM state saved with a backup which is not the current one.
+#[derive(Clone, Debug, Default)]
+pub struct SavedVtpm {
+    #[cfg(all(feature = "mstpm", not(test)))]
+    nv: Option<Vec<u8>>,
+}
+
+/// Saves the vTPM state with a new backup if the policy requires it.
+pub fn snapshot_vtpm() -> Result<(), SvsmReqError> {
+    #[cfg(all(feature = "mstpm", not(test)))]
+    vtpm_snapshot()?;
+    Ok(())
+}
+
+/// Drops the vTPM state saved with a discarded backup.
+pub fn discard_vtpm() {
+    #[cfg(all(feature = "mstpm", not(test)))]
+    vtpm_discard_snapshot();
+}
+
+/// Takes the vTPM state saved with the current backup.
+pub fn take_vtpm() -> SavedVtpm {
+    SavedVtpm {
+        #[cfg(all(feature = "mstpm", not(test)))]
+        nv: vtpm_take_snapshot(),
+    }
+}
+
+/// Makes `saved` the vTPM state saved with the current backup.
+pub fn put_vtpm(saved: SavedVtpm) {
+    #[cfg(all(feature = "mstpm", not(test)))]
+    vtpm_put_snapshot(saved.nv);
+    #[cfg(not(all(feature = "mstpm", not(test))))]
+    let _ = saved;
+}
+
+/// Brings the vTPM in line with restored guest memory.
+pub fn restore_vtpm() -> Result<(), SvsmReqError> {
+    #[cfg(all(feature = "mstpm", not(test)))]
+    vtpm_restore()?;
+    Ok(())
+}
+
+/// Returns the vTPM state to be moved to another host, empty if the vTPM
+/// is not powered on.
+#[cfg(all(feature = "mstpm", not(test)))]
+pub fn migration_vtpm() -> Result<Vec<u8>, SvsmReqError> {
+    Ok(vtpm_migration_state()?.unwrap_or_default())
+}
+
+/// Returns the vTPM state to be moved to another host, always empty.
+#[cfg(not(all(feature = "mstpm", not(test))))]
+pub fn migration_vtpm() -> Result<Vec<u8>, SvsmReqError> {
+    Ok(Vec::new())
+}
+
+/// Replaces the vTPM state with `nv` from [`migration_vtpm`] on another
+/// host.
+#[cfg(all(feature = "mstpm", not(test)))]
+pub fn load_migration_vtpm(nv: &[u8]) -> Result<(), SvsmReqError> {
+    vtpm_load_migration_state(nv)
+}
+
+/// Fails with INVALID_REQUEST, as there is no vTPM to load `nv` into.
+#[cfg(not(all(feature = "mstpm", not(test))))]
+pub fn load_migration_vtpm(_nv: &[u8]) -> Result<(), SvsmReqError> {
+    Err(SvsmReqError::invalid_request())
+}
+
+/// Returns whether there is a vTPM whose state is kept with backups.
+pub fn vtpm_present() -> bool {
+    cfg!(all(feature = "mstpm", not(test)))
+}
+
+/// Returns the [`vtpm_policy`] recorded in exported snapshots, i.e. the
+/// one a restore of the current backup applies.
+#[cfg(all(feature = "mstpm", not(test)))]
+pub fn manifest_vtpm_policy() -> u32 {
+    match vtpm_backup_policy() {
+        VtpmRestorePolicy::Reset => vtpm_policy::RESET,
+        VtpmRestorePolicy::Snapshot => vtpm_policy::SNAPSHOT,
+    }
+}
+
+/// Returns the [`vtpm_policy`] recorded in exported snapshots.
+#[cfg(not(all(feature = "mstpm", not(test))))]
+pub fn manifest_vtpm_policy() -> u32 {
+    vtpm_policy::NONE
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Liveness watchdog for the guest driver which enabled copy-on-write.
+//!
+//! While copy-on-write is enabled, the registered guest pages are read-only
+//! for the guest. If the guest driver which is expected to handle the write
+//! faults dies, the guest livelocks on the first write to such a page. The
+//! driver therefore arms a watchdog with a heartbeat call and has to repeat
+//! the call before the timeout expires. Once it expires, the SVSM lifts the
+//! copy-on-write protection on its own and, if requested, discards the
+//! backup as well.
+
+use super::export::discard_snapshot;
+use super::lazy::settle_lazy_backup;
+use super::PAGES_TO_BACKUP;
+use crate::cpu::tsc::{tsc_khz, tsc_now};
+use crate::health::{set_backup_state, BackupState};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::sev::rmp::{rmp_set_guest_access_paddr, GuestAccess};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Heartbeat flag: discard the backup when the watchdog expires.
+const HEARTBEAT_DISCARD_ON_EXPIRY: u64 = 1 << 0;
+const HEARTBEAT_FLAGS: u64 = HEARTBEAT_DISCARD_ON_EXPIRY;
+
+/// Whether copy-on-write protection is currently enabled.
+static COW_ENABLED: AtomicBool = AtomicBool::new(false);
+/// TSC value after which the watchdog expires, zero while disarmed.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// Timeout of the last heartbeat in TSC ticks.
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+static DISCARD_ON_EXPIRY: AtomicBool = AtomicBool::new(false);
+
+/// Number of times the watchdog lifted stale copy-on-write protection.
+static COW_CLEANUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Heartbeat of the guest driver. `rcx` holds the timeout in milliseconds
+/// after which copy-on-write protection is lifted if no further heartbeat
+/// arrives, zero disarms the watchdog. `rdx` holds the heartbeat flags. On
+/// return `rcx` holds the number of stale copy-on-write cleanups so far.
+pub fn heartbeat(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let timeout_ms = params.rcx;
+    let flags = params.rdx;
+    if flags & !HEARTBEAT_FLAGS != 0 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+
+    if timeout_ms == 0 {
+        DEADLINE.store(0, Ordering::SeqCst);
+        TIMEOUT.store(0, Ordering::SeqCst);
+    } else {
+        let timeout = timeout_ms
+            .checked_mul(tsc_khz())
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        DISCARD_ON_EXPIRY.store(flags & HEARTBEAT_DISCARD_ON_EXPIRY != 0, Ordering::SeqCst);
+        TIMEOUT.store(timeout, Ordering::SeqCst);
+        DEADLINE.store(tsc_now().saturating_add(timeout), Ordering::SeqCst);
+    }
+
+    params.rcx = cow_cleanups();
+    Ok(())
+}
+
+/// Records that copy-on-write protection has been enabled and restarts the
+/// timeout of an armed watchdog.
+pub fn cow_enabled() {
+    let timeout = TIMEOUT.load(Ordering::SeqCst);
+    if timeout != 0 {
+        DEADLINE.store(tsc_now().saturating_add(timeout), Ordering::SeqCst);
+    }
+    COW_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Records that copy-on-write protection has been lifted and disarms the
+/// watchdog.
+pub fn cow_disabled() {
+    COW_ENABLED.store(false, Ordering::SeqCst);
+    DEADLINE.store(0, Ordering::SeqCst);
+}
+
+/// Returns whether copy-on-write protection is enabled.
+pub fn cow_active() -> bool {
+    COW_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Lifts the copy-on-write protection if the watchdog expired. Called
+/// whenever the SVSM gains control from the guest.
+pub fn check_cow_watchdog() {
+    let deadline = DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || !COW_ENABLED.load(Ordering::SeqCst) || tsc_now() < deadline {
+        return;
+    }
+    // Only one vCPU performs the cleanup.
+    if COW_ENABLED
+        .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return;
+    }
+    DEADLINE.store(0, Ordering::SeqCst);
+    TIMEOUT.store(0, Ordering::SeqCst);
+
+    let count = COW_CLEANUPS.fetch_add(1, Ordering::SeqCst) + 1;
+    log::warn!(
+        "Guest driver heartbeat expired, lifting copy-on-write protection (cleanup #{})",
+        count
+    );
+    // Pages still pending in a lazy backup are only unchanged while they
+    // are protected.
+    let mut discard = DISCARD_ON_EXPIRY.load(Ordering::SeqCst);
+    if !discard {
+        if let Err(e) = settle_lazy_backup() {
+            log::error!("Failed to complete lazy backup: {:?}", e);
+            discard = true;
+        }
+    }
+    for (phys_addr, size) in PAGES_TO_BACKUP.iter_addresses() {
+        if let Err(e) = rmp_set_guest_access_paddr(phys_addr, size, GuestAccess::ReadWrite) {
+            log::error!(
+                "Failed to lift copy-on-write protection of {:#x}: {:?}",
+                phys_addr,
+                e
+            );
+        }
+    }
+    set_backup_state(BackupState::Ready);
+
+    if discard {
+        log::warn!("Discarding backup of unresponsive guest driver");
+        discard_snapshot();
+    }
+}
+
+/// Returns the number of times stale copy-on-write protection was lifted.
+fn cow_cleanups() -> u64 {
+    COW_CLEANUPS.load(Ordering::SeqCst)
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Barrier which keeps protocol requests from running concurrently with a
+//! restore.
+//!
+//! Every protocol request other than a status query is accounted while it
+//! is being processed. A restore raises the barrier and then yields to the
+//! scheduler until all other accounted requests have completed. While the
+//! barrier is up, new requests other than status queries are rejected with
+//! `BUSY`, so no vCPU can mutate guest memory or SVSM state in the middle of
+//! a restore. Status queries only read state, so they neither wait for the
+//! barrier nor hold up a restore.
+
+#[cfg(feature = "backup")]
+use crate::protocols::backup::SVSM_RESTORE_CHECK;
+use crate::protocols::core::SVSM_REQ_CORE_QUERY_PROTOCOL;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::SVSM_CORE_PROTOCOL;
+#[cfg(feature = "backup")]
+use crate::protocols::SVSM_CUSTOM_PROTOCOL;
+use crate::task::schedule;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static BARRIER_RAISED: AtomicBool = AtomicBool::new(false);
+/// Number of requests in flight, not counting status queries.
+static REQUESTS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether the request only queries state and may run while the
+/// barrier is raised.
+fn is_status_query(protocol: u32, request: u32) -> bool {
+    #[cfg(feature = "backup")]
+    if (protocol, request) == (SVSM_CUSTOM_PROTOCOL, SVSM_RESTORE_CHECK) {
+        return true;
+    }
+    matches!(
+        (protocol, request),
+        (SVSM_CORE_PROTOCOL, SVSM_REQ_CORE_QUERY_PROTOCOL)
+    )
+}
+
+/// Accounts a protocol request while it is being processed.
+#[derive(Debug)]
+#[must_use = "the request is no longer accounted once the guard is dropped"]
+pub struct RequestGuard {
+    /// Whether the request is counted in `REQUESTS_IN_FLIGHT`.
+    counted: bool,
+}
+
+impl RequestGuard {
+    /// Accounts a new request, or fails with `BUSY` if a restore is in
+    /// progress and the request is not a status query.
+    pub fn enter(protocol: u32, request: u32) -> Result<Self, SvsmReqError> {
+        if is_status_query(protocol, request) {
+            return Ok(Self { counted: false });
+        }
+        REQUESTS_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        if BARRIER_RAISED.load(Ordering::SeqCst) {
+            REQUESTS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            return Err(SvsmReqError::busy());
+        }
+        Ok(Self { counted: true })
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if self.counted {
+            REQUESTS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Keeps other protocol requests out while a restore is in progress. The
+/// barrier is lowered when the guard is dropped.
+#[derive(Debug)]
+#[must_use = "the barrier is lowered once the guard is dropped"]
+pub struct RestoreBarrier {
+    _private: (),
+}
+
+impl RestoreBarrier {
+    /// Raises the barrier and waits until the calling request is the only
+    /// one in flight. Status queries are not waited for. Must be called
+    /// while processing a request accounted by a [`RequestGuard`], which is
+    /// not a status query. Fails with `BUSY` if the barrier is already
+    /// raised.
+    pub fn raise() -> Result<Self, SvsmReqError> {
+        BARRIER_RAISED
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| SvsmReqError::busy())?;
+
+        while REQUESTS_IN_FLIGHT.load(Ordering::SeqCst) > 1 {
+            schedule();
+        }
+
+        Ok(Self { _private: () })
+    }
+}
+
+impl Drop for RestoreBarrier {
+    fn drop(&mut self) {
+        BARRIER_RAISED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Returns whether a restore currently holds the barrier.
+pub fn restore_in_progress() -> bool {
+    BARRIER_RAISED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Guest buffers passed to protocol calls.
+//!
+//! A [`GuestBuffer`] is a range of guest physical memory named by a call,
+//! usually by its address in `rcx` and its size in `rdx`. The range is
+//! checked once when the buffer is created: it must be guest memory which
+//! is neither a VMSA nor owned by the SVSM, and every page must be private
+//! memory the guest can access, or shared memory for buffers in shared
+//! memory. Buffers the SVSM writes must not cover protected firmware. Their
+//! contents are then accessed with bounds-checked copies, one temporarily
+//! mapped page at a time, so handlers never map guest memory or hold
+//! references to it themselves.
+
+use crate::address::{Address, PhysAddr};
+use crate::error::SvsmError;
+use crate::fw_protect::fw_page_protected;
+use crate::mm::{svsm_phys_addr, valid_phys_address, PerCPUPageMappingGuard};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+use crate::scratch::map_shared_page;
+use crate::sev::rmp::{rmp_query, GuestAccess, RmpError};
+use crate::types::PAGE_SIZE;
+
+use core::cmp::min;
+use core::mem::{size_of, zeroed};
+use core::ptr::addr_of_mut;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+
+/// Types which can be copied from and to guest memory byte by byte.
+///
+/// # Safety
+///
+/// Every bit pattern of the size of the type must be a valid value of it
+/// and the type must not contain padding bytes.
+pub unsafe trait GuestData: Copy {}
+
+// SAFETY: integers have no padding and no invalid bit patterns.
+unsafe impl GuestData for u8 {}
+// SAFETY: integers have no padding and no invalid bit patterns.
+unsafe impl GuestData for u16 {}
+// SAFETY: integers have no padding and no invalid bit patterns.
+unsafe impl GuestData for u32 {}
+// SAFETY: integers have no padding and no invalid bit patterns.
+unsafe impl GuestData for u64 {}
+// SAFETY: arrays have no padding between their elements, which are
+// GuestData themselves.
+unsafe impl<T: GuestData, const N: usize> GuestData for [T; N] {}
+
+/// The memory a [`GuestBuffer`] is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Private memory, which the SVSM only reads.
+    ReadOnly,
+    /// Private memory.
+    ReadWrite,
+    /// Shared memory.
+    Shared,
+}
+
+/// Checks that the page at `paddr` can back a buffer of the given access.
+fn check_page(paddr: PhysAddr, access: Access) -> Result<(), SvsmReqError> {
+    if !valid_phys_address(paddr) || svsm_phys_addr(paddr) {
+        return Err(SvsmReqError::invalid_address());
+    }
+    if access == Access::ReadWrite && fw_page_protected(paddr) {
+        log::warn!("Guest buffer at {:#x} is protected firmware", paddr);
+        return Err(SvsmReqError::invalid_address());
+    }
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    let private = match rmp_query(guard.virt_addr()) {
+        Ok(state) => state.guest_access != GuestAccess::None,
+        Err(RmpError::Unsupported) => return Ok(()),
+        Err(_) => false,
+    };
+    if private != (access != Access::Shared) {
+        log::warn!(
+            "Guest buffer at {:#x} is not {} memory",
+            paddr,
+            if private { "shared" } else { "private" }
+        );
+        return Err(SvsmReqError::invalid_address());
+    }
+    Ok(())
+}
+
+/// A validated range of guest memory.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestBuffer {
+    start: PhysAddr,
+    len: usize,
+    access: Access,
+}
+
+impl GuestBuffer {
+    /// Returns the page-aligned buffer of `len` bytes at `start`. Fails
+    /// with INVALID_PARAMETER if `start` is not page-aligned or `len` is
+    /// zero, and with INVALID_ADDRESS if the range is not private guest
+    /// memory the guest can access or covers protected firmware.
+    pub fn new(start: PhysAddr, len: usize) -> Result<Self, SvsmReqError> {
+        if !start.is_page_aligned() {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        Self::checked(start, len, Access::ReadWrite)
+    }
+
+    /// Returns the buffer of `len` bytes at `start`, which need not be
+    /// aligned. Fails like [`GuestBuffer::new`].
+    pub fn unaligned(start: PhysAddr, len: usize) -> Result<Self, SvsmReqError> {
+        Self::checked(start, len, Access::ReadWrite)
+    }
+
+    /// Returns the buffer of `len` bytes at `start`, which need not be
+    /// aligned, for reading only. Unlike [`GuestBuffer::unaligned`] it may
+    /// cover protected firmware, and writes to it fail.
+    pub fn read_only(start: PhysAddr, len: usize) -> Result<Self, SvsmReqError> {
+        Self::checked(start, len, Access::ReadOnly)
+    }
+
+    /// Returns the page-aligned buffer of `len` bytes of shared memory at
+    /// `start`, which the guest shares with the host. Fails like
+    /// [`GuestBuffer::new`], but with INVALID_ADDRESS if the range is
+    /// private memory.
+    pub fn new_shared(start: PhysAddr, len: usize) -> Result<Self, SvsmReqError> {
+        if !start.is_page_aligned() {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        Self::checked(start, len, Access::Shared)
+    }
+
+    fn checked(start: PhysAddr, len: usize, access: Access) -> Result<Self, SvsmReqError> {
+        if start.is_null() || len == 0 {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        let end = start
+            .checked_add(len)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        let mut paddr = start.page_align();
+        while paddr < end {
+            check_page(paddr, access)?;
+            paddr = paddr + PAGE_SIZE;
+        }
+        Ok(Self { start, len, access })
+    }
+
+    /// Returns the page-aligned buffer at `rcx` of the size in `rdx`.
+    pub fn from_params(params: &RequestParams) -> Result<Self, SvsmReqError> {
+        let len = usize::try_from(params.rdx).map_err(|_| SvsmReqError::invalid_parameter())?;
+        Self::new(PhysAddr::from(params.rcx), len)
+    }
+
+    /// Returns the buffer of `len` bytes of shared memory at `start`, which
+    /// the SVSM set up itself, e.g. a window of the scratch region.
+    pub(crate) fn shared(start: PhysAddr, len: usize) -> Self {
+        Self {
+            start,
+            len,
+            access: Access::Shared,
+        }
+    }
+
+    /// Calls `f` with every mapped chunk of `[offset, offset + len)`,
+    /// together with the offset of the chunk relative to `offset`.
+    fn for_each_chunk<F>(&self, offset: u64, len: usize, mut f: F) -> Result<(), SvsmError>
+    where
+        F: FnMut(*mut u8, usize, usize),
+    {
+        let offset = usize::try_from(offset).map_err(|_| SvsmError::InvalidAddress)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => {}
+            _ => return Err(SvsmError::InvalidAddress),
+        }
+
+        let mut done = 0;
+        while done < len {
+            let paddr = self.start + offset + done;
+            let page_off = paddr.page_offset();
+            let chunk = min(PAGE_SIZE - page_off, len - done);
+            let guard = if self.access == Access::Shared {
+                map_shared_page(paddr.page_align())?
+            } else {
+                PerCPUPageMappingGuard::create_4k(paddr.page_align())?
+            };
+            let ptr = (guard.virt_addr() + page_off).as_mut_ptr::<u8>();
+            f(ptr, done, chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the buffer in bytes.
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Copies `data` into the buffer at `offset`. Fails for read-only
+    /// buffers.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), SvsmError> {
+        if self.access == Access::ReadOnly {
+            return Err(SvsmError::InvalidAddress);
+        }
+        self.for_each_chunk(offset, data.len(), |ptr, pos, chunk| {
+            // SAFETY: `ptr` points to `chunk` bytes of mapped guest memory.
+            unsafe { ptr.copy_from_nonoverlapping(data[pos..].as_ptr(), chunk) };
+        })
+    }
+
+    /// Fills `data` from the buffer at `offset`.
+    pub fn read(&self, offset: u64, data: &mut [u8]) -> Result<(), SvsmError> {
+        let len = data.len();
+        self.for_each_chunk(offset, len, |ptr, pos, chunk| {
+            // SAFETY: `ptr` points to `chunk` bytes of mapped guest memory.
+            unsafe { ptr.copy_to_nonoverlapping(data[pos..].as_mut_ptr(), chunk) };
+        })
+    }
+
+    /// Reads a `T` from the buffer at `offset`, which need not be aligned.
+    pub fn read_obj<T: GuestData>(&self, offset: u64) -> Result<T, SvsmError> {
+        // SAFETY: T is GuestData, so the all-zero bit pattern is a valid
+        // value of it.
+        let mut obj: T = unsafe { zeroed() };
+        // SAFETY: the slice covers the memory of `obj`, and any bytes
+        // written to it form a valid value as T is GuestData.
+        let bytes = unsafe { from_raw_parts_mut(addr_of_mut!(obj).cast::<u8>(), size_of::<T>()) };
+        self.read(offset, bytes)?;
+        Ok(obj)
+    }
+
+    /// Writes `obj` into the buffer at `offset`, which need not be aligned.
+    pub fn write_obj<T: GuestData>(&self, offset: u64, obj: &T) -> Result<(), SvsmError> {
+        // SAFETY: T is GuestData, so all bytes of `obj` are initialized.
+        let bytes = unsafe { from_raw_parts((obj as *const T).cast::<u8>(), size_of::<T>()) };
+        self.write(offset, bytes)
+    }
+}
@@ -9,22 +9,28 @@ use crate::cpu::flush_tlb_global_sync;
 use crate::cpu::percpu::{this_cpu, this_cpu_shared, PERCPU_AREAS, PERCPU_VMSAS};
 use crate::cpu::vmsa::{vmsa_mut_ref_from_vaddr, vmsa_ref_from_vaddr};
 use crate::error::SvsmError;
+use crate::fw_protect::fw_range_protected;
 use crate::locking::RWLock;
-use crate::mm::virtualrange::{VIRT_ALIGN_2M, VIRT_ALIGN_4K};
+use crate::mm::memory::{guest_memory_layout, GuestMemoryKind};
+use crate::mm::pin::lock_page_not_pinned;
 use crate::mm::PerCPUPageMappingGuard;
 use crate::mm::{valid_phys_address, writable_phys_addr, GuestPtr};
-use crate::protocols::apic::{APIC_PROTOCOL, APIC_PROTOCOL_VERSION_MAX, APIC_PROTOCOL_VERSION_MIN};
+#[cfg(feature = "backup")]
+use crate::protocols::backup::track_pvalidate;
+use crate::protocols::buffer::{GuestBuffer, GuestData};
+use crate::protocols::deferred::poll_request;
 use crate::protocols::errors::SvsmReqError;
-use crate::protocols::RequestParams;
-use crate::protocols::backup::{PAGES_TO_BACKUP, BACKUP_CREATED};
+use crate::protocols::registry::{query_protocol, BuiltinProtocol};
+use crate::protocols::{RequestParams, SVSM_CORE_PROTOCOL};
 use crate::requests::SvsmCaa;
 use crate::sev::utils::{
     pvalidate, rmp_clear_guest_vmsa, rmp_grant_guest_access, rmp_revoke_guest_access,
     rmp_set_guest_vmsa, PvalidateOp, RMPFlags, SevSnpError,
 };
-use crate::sev::vmsa::VMSAControl;
-use crate::types::{PageSize, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::zero_mem_region;
+use crate::sev::vmsa::{VMSAControl, VMPL_MAX};
+use crate::types::{PageSize, GUEST_VMPL, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::{zero_mem_region, MemoryRegion};
+use core::mem::size_of;
 use cpuarch::vmsa::VMSA;
 
 const SVSM_REQ_CORE_REMAP_CA: u32 = 0;
@@ -33,13 +39,23 @@ const SVSM_REQ_CORE_CREATE_VCPU: u32 = 2;
 const SVSM_REQ_CORE_DELETE_VCPU: u32 = 3;
 const SVSM_REQ_CORE_DEPOSIT_MEM: u32 = 4;
 const SVSM_REQ_CORE_WITHDRAW_MEM: u32 = 5;
-const SVSM_REQ_CORE_QUERY_PROTOCOL: u32 = 6;
+pub const SVSM_REQ_CORE_QUERY_PROTOCOL: u32 = 6;
 const SVSM_REQ_CORE_CONFIGURE_VTOM: u32 = 7;
+// Extension, outside the range of calls defined by the SVSM spec
+const SVSM_REQ_CORE_QUERY_MEMORY_MAP: u32 = 0x8000_0000;
+const SVSM_REQ_CORE_POLL_REQUEST: u32 = 0x8000_0001;
 
-const CORE_PROTOCOL: u32 = 1;
 const CORE_PROTOCOL_VERSION_MIN: u32 = 1;
 const CORE_PROTOCOL_VERSION_MAX: u32 = 1;
 
+pub static CORE_PROTOCOL_HANDLER: BuiltinProtocol = BuiltinProtocol::new(
+    SVSM_CORE_PROTOCOL,
+    "core",
+    CORE_PROTOCOL_VERSION_MIN,
+    CORE_PROTOCOL_VERSION_MAX,
+    core_protocol_request,
+);
+
 // This lock prevents races around PVALIDATE and CREATE_VCPU
 //
 // Without the lock there is a possible attack where the error path of
@@ -111,6 +127,9 @@ fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
         .get(apic_id)
         .ok_or_else(SvsmReqError::invalid_parameter)?;
 
+    // A pinned page must not turn into a VMSA.
+    let remap = lock_page_not_pinned(paddr, PageSize::Regular)?;
+
     // Got valid gPAs and APIC ID, register VMSA immediately to avoid races
     PERCPU_VMSAS.register(paddr, apic_id, true)?;
 
@@ -147,6 +166,7 @@ fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
     })?;
 
     drop(lock);
+    drop(remap);
 
     assert!(PERCPU_VMSAS.set_used(paddr) == Some(apic_id));
     target_cpu.update_guest_vmsa_caa(paddr, pcaa);
@@ -192,45 +212,12 @@ fn core_withdraw_mem(_params: &RequestParams) -> Result<(), SvsmReqError> {
     Err(SvsmReqError::unsupported_call())
 }
 
-fn protocol_supported(version: u32, version_min: u32, version_max: u32) -> u64 {
-    if version >= version_min && version <= version_max {
-        let ret_low: u64 = version_min.into();
-        let ret_high: u64 = version_max.into();
-
-        ret_low | (ret_high << 32)
-    } else {
-        0
-    }
-}
-
 fn core_query_protocol(params: &mut RequestParams) -> Result<(), SvsmReqError> {
     let rcx: u64 = params.rcx;
     let protocol: u32 = (rcx >> 32).try_into().unwrap();
     let version: u32 = (rcx & 0xffff_ffffu64).try_into().unwrap();
 
-    let ret_val = match protocol {
-        CORE_PROTOCOL => protocol_supported(
-            version,
-            CORE_PROTOCOL_VERSION_MIN,
-            CORE_PROTOCOL_VERSION_MAX,
-        ),
-        APIC_PROTOCOL => {
-            // The APIC protocol is only supported if the calling CPU supports
-            // alternate injection.
-            if this_cpu().use_apic_emulation() {
-                protocol_supported(
-                    version,
-                    APIC_PROTOCOL_VERSION_MIN,
-                    APIC_PROTOCOL_VERSION_MAX,
-                )
-            } else {
-                0
-            }
-        }
-        _ => 0,
-    };
-
-    params.rcx = ret_val;
+    params.rcx = query_protocol(protocol, version);
 
     Ok(())
 }
@@ -247,64 +234,10 @@ fn core_configure_vtom(params: &mut RequestParams) -> Result<(), SvsmReqError> {
     }
 }
 
-fn update_pages_to_backup_invalid(paddr: PhysAddr, size: PageSize) -> Result<(), SvsmReqError> {
-    log::info!("Attemt to remove page from backup: {:#x}, size: {:?}", paddr, size);
-    
-    match size {
-        PageSize::Regular => {
-            if PAGES_TO_BACKUP.contains_addr(paddr, size){
-                PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Regular);
-                log::info!("Removed page from backup: {:#x}, size: {:?}", paddr, size);
-
-            } else if PAGES_TO_BACKUP.contains_addr(paddr.page_align_2m(), PageSize::Huge){
-                
-                // Split huge page into regular pages and add them to set
-                // Then remove the huge page and the regular page specified by paddr from the set
-                log::info!("Splitting page from backup: {:#x}, size: {:?}", paddr.page_align_2m(), size);
-                let base_addr = paddr.page_align_2m();
-                for i in 0..(PAGE_SIZE_2M / PAGE_SIZE){
-                    PAGES_TO_BACKUP.insert_addr(base_addr + (i * PAGE_SIZE), PageSize::Regular);
-                }
-                PAGES_TO_BACKUP.remove_addr(base_addr, PageSize::Huge);
-                PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Regular);
-                log::info!("Removed page from backup: {:#x}", paddr);
-            }
-        },
-        PageSize::Huge => {
-            // Scenario:    1. insert address 0x58000000 with size Huge, 
-            //              2. insert 0x58000000 with size Regular, 
-            //              3. insert 0x58001000 with size Regular, 
-            //                ....
-            //              4. remove 0x58000000 with size Huge 
-            //              --> pages 0x58000000, 0x58001000 ... with size Regular have to be removed too
-            PAGES_TO_BACKUP.remove_addr(paddr, PageSize::Huge);
-            for i in 0..(PAGE_SIZE_2M / PAGE_SIZE){
-                PAGES_TO_BACKUP.remove_addr(paddr + (i * PAGE_SIZE), PageSize::Regular);
-            }
-            log::info!("Removed page from backup {:#x}, size: {:?}", paddr.page_align_2m(), PageSize::Huge);
-        }
-    }
-    Ok(())
-
-}
-
-fn update_pages_to_backup(paddr: PhysAddr, size: PageSize, valid: PvalidateOp) -> Result<(), SvsmReqError> {
-    if *(BACKUP_CREATED.lock()) {
-        // TODO implement
-        return Err(SvsmReqError::unsupported_call());
-    } else {
-        match valid {
-            PvalidateOp::Valid => PAGES_TO_BACKUP.insert_addr(paddr, size),
-            PvalidateOp::Invalid => update_pages_to_backup_invalid(paddr, size)?,
-        };
-    }
-    Ok(())
-}
-
 fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError> {
-    let (page_size_bytes, valign, size) = match entry & 3 {
-        0 => (PAGE_SIZE, VIRT_ALIGN_4K, PageSize::Regular),
-        1 => (PAGE_SIZE_2M, VIRT_ALIGN_2M, PageSize::Huge),
+    let (page_size_bytes, size) = match entry & 3 {
+        0 => (PAGE_SIZE, PageSize::Regular),
+        1 => (PAGE_SIZE_2M, PageSize::Huge),
         _ => return Err(SvsmReqError::invalid_parameter()),
     };
 
@@ -325,7 +258,20 @@ fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError>
         return Err(SvsmReqError::invalid_address());
     }
 
-    let guard = PerCPUPageMappingGuard::create(paddr, paddr + page_size_bytes, valign)?;
+    // Pages which are being copied by the SVSM must not change underneath,
+    // so no pin may be taken until the page is remapped.
+    let remap = lock_page_not_pinned(paddr, size)?;
+
+    // Protected firmware must stay validated and read-only.
+    if valid == PvalidateOp::Invalid
+        && fw_range_protected(MemoryRegion::new(paddr, page_size_bytes))
+    {
+        log::warn!("Refusing to invalidate protected firmware page {:#x}", paddr);
+        return Err(SvsmReqError::invalid_address());
+    }
+
+    let guard = PerCPUPageMappingGuard::create_with_page_size(paddr, paddr + page_size_bytes, size)
+        .map_err(SvsmReqError::from_mapping)?;
     let vaddr = guard.virt_addr();
 
     // Take lock to prevent races with CREATE_VCPU calls
@@ -375,7 +321,9 @@ fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError>
         }
         rmp_grant_guest_access(vaddr, size)?;
     }
-    update_pages_to_backup(paddr, size, valid)?;
+    drop(remap);
+    #[cfg(feature = "backup")]
+    track_pvalidate(paddr, size, valid)?;
     Ok(())
 }
 
@@ -474,6 +422,84 @@ fn core_remap_ca(params: &RequestParams) -> Result<(), SvsmReqError> {
     Ok(())
 }
 
+/// Entry of the memory map returned by SVSM_REQ_CORE_QUERY_MEMORY_MAP
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct MemoryMapEntry {
+    /// First address of the range
+    start: u64,
+    /// End address of the range, exclusive
+    end: u64,
+    /// Kind of the range, see [`GuestMemoryKind`]
+    kind: u32,
+    /// RMP permission mask of each VMPL to the range, one byte per VMPL
+    /// starting with VMPL0 in the lowest byte
+    access: u32,
+}
+
+// SAFETY: MemoryMapEntry is comprised entirely of integer types, laid out
+// without padding.
+unsafe impl GuestData for MemoryMapEntry {}
+
+/// Returns the RMP permission masks of the VMPLs to a range of `kind`, one
+/// byte per VMPL.
+fn vmpl_access(kind: GuestMemoryKind) -> u32 {
+    let perms = |flags: RMPFlags| (flags.bits() >> 8) as u32;
+    let guest = match kind {
+        // Shared memory is not subject to VMPL permissions.
+        GuestMemoryKind::Shared => {
+            return (0..VMPL_MAX).fold(0, |access, vmpl| {
+                access | (perms(RMPFlags::RWX) << (8 * vmpl))
+            })
+        }
+        GuestMemoryKind::Ram => RMPFlags::RWX,
+        GuestMemoryKind::Firmware => RMPFlags::RX,
+        GuestMemoryKind::Reserved | GuestMemoryKind::Svsm => RMPFlags::NONE,
+    };
+    // VMPL0 runs the SVSM and can access everything. The VMPLs between the
+    // SVSM and the guest are not used and have no access.
+    (GUEST_VMPL..VMPL_MAX).fold(perms(RMPFlags::RWX), |access, vmpl| {
+        access | (perms(guest) << (8 * vmpl))
+    })
+}
+
+/// Writes the SVSM's view of the guest physical address space into the
+/// guest buffer at `rcx` of size `rdx`, as an array of [`MemoryMapEntry`].
+/// The buffer must be 8-byte aligned private guest memory and may span
+/// several pages. On return `rcx` holds the number of entries. If the buffer is too small,
+/// nothing is written and INVALID_PARAMETER is returned.
+fn core_query_memory_map(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let layout = guest_memory_layout();
+    let size = layout.len() * size_of::<MemoryMapEntry>();
+
+    params.rcx = layout.len() as u64;
+    if params.rdx < size as u64 {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if !gpa.is_aligned(8) {
+        return Err(SvsmReqError::invalid_parameter());
+    }
+    if layout.is_empty() {
+        return Ok(());
+    }
+
+    let buffer = GuestBuffer::unaligned(gpa, size)?;
+    for (i, range) in layout.iter().enumerate() {
+        let entry = MemoryMapEntry {
+            start: u64::from(range.region.start()),
+            end: u64::from(range.region.end()),
+            kind: range.kind as u32,
+            access: vmpl_access(range.kind),
+        };
+        buffer
+            .write_obj((i * size_of::<MemoryMapEntry>()) as u64, &entry)
+            .map_err(SvsmReqError::from_mapping)?;
+    }
+
+    Ok(())
+}
+
 pub fn core_protocol_request(request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
     match request {
         SVSM_REQ_CORE_REMAP_CA => core_remap_ca(params),
@@ -484,6 +510,8 @@ pub fn core_protocol_request(request: u32, params: &mut RequestParams) -> Result
         SVSM_REQ_CORE_WITHDRAW_MEM => core_withdraw_mem(params),
         SVSM_REQ_CORE_QUERY_PROTOCOL => core_query_protocol(params),
         SVSM_REQ_CORE_CONFIGURE_VTOM => core_configure_vtom(params),
+        SVSM_REQ_CORE_QUERY_MEMORY_MAP => core_query_memory_map(params),
+        SVSM_REQ_CORE_POLL_REQUEST => poll_request(params),
         _ => Err(SvsmReqError::unsupported_call()),
     }
 }
@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Deferred completion of protocol requests.
+//!
+//! Some requests, like copying the pending pages of a large lazy backup,
+//! take too long to finish before the guest runs again. Their handler can
+//! instead queue the remaining work with [`defer_request`]. The request
+//! then fails with INCOMPLETE and `rcx` holds a token naming the queued
+//! work. The work is advanced a step at a time whenever a CPU enters the
+//! SVSM, and whenever the guest polls the token with
+//! `SVSM_REQ_CORE_POLL_REQUEST`. Once done, the poll returns the result and
+//! the registers of the request as if it had completed right away, and the
+//! token is released.
+//!
+//! A step is accounted like a request by the restore barrier, so queued
+//! work never runs concurrently with a restore. Polls advance the work, so
+//! unlike status queries they are refused with BUSY during a restore.
+//!
+//! Only `SVSM_BACKUP_FINALIZE` defers its work so far, which the backup
+//! protocol advertises with its `DEFERRED_FINALIZE` capability. Full
+//! backups and restores still complete within the call, as they hold back
+//! all other vCPUs until they are done.
+
+use crate::locking::SpinLock;
+use crate::protocols::barrier::{restore_in_progress, RequestGuard};
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+
+/// Maximum number of deferred requests, running or completed but not
+/// polled yet.
+const MAX_DEFERRED: usize = 16;
+
+/// The remaining work of a deferred request.
+pub trait DeferredWork: Send {
+    /// Advances the work by one short step. Returns `Poll::Ready` with the
+    /// result of the request once it is complete, with its results in the
+    /// registers of `params`.
+    fn step(&mut self, params: &mut RequestParams) -> Poll<Result<(), SvsmReqError>>;
+}
+
+struct Deferred {
+    protocol: u32,
+    request: u32,
+    /// Registers returned to the guest on completion.
+    params: RequestParams,
+    /// The remaining work, taken out while a CPU runs a step of it.
+    work: Option<Box<dyn DeferredWork>>,
+    /// The result, once the work is complete.
+    result: Option<Result<(), SvsmReqError>>,
+}
+
+static DEFERRED: SpinLock<BTreeMap<u64, Deferred>> = SpinLock::new(BTreeMap::new());
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+/// Queues `work` to complete the call `request` of `protocol`, which is
+/// being processed with `params`. Always returns an error: INCOMPLETE with
+/// the token of the work in `rcx`, or BUSY if too many requests are
+/// deferred already.
+pub fn defer_request(
+    protocol: u32,
+    request: u32,
+    params: &mut RequestParams,
+    work: Box<dyn DeferredWork>,
+) -> SvsmReqError {
+    let mut deferred = DEFERRED.lock();
+    if deferred.len() >= MAX_DEFERRED {
+        return SvsmReqError::busy();
+    }
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    deferred.insert(
+        token,
+        Deferred {
+            protocol,
+            request,
+            params: *params,
+            work: Some(work),
+            result: None,
+        },
+    );
+    log::info!(
+        "Deferred protocol {} request {} as {:#x}",
+        protocol,
+        request,
+        token
+    );
+    params.rcx = token;
+    SvsmReqError::incomplete()
+}
+
+/// Runs a step of the deferred request `token` if it is neither complete
+/// nor advanced by another CPU.
+fn advance(token: u64) {
+    if restore_in_progress() {
+        return;
+    }
+    let (protocol, request, mut params, mut work) = {
+        let mut deferred = DEFERRED.lock();
+        let Some(entry) = deferred.get_mut(&token) else {
+            return;
+        };
+        let Some(work) = entry.work.take() else {
+            return;
+        };
+        (entry.protocol, entry.request, entry.params, work)
+    };
+
+    let progress = match RequestGuard::enter(protocol, request) {
+        Ok(_guard) => work.step(&mut params),
+        Err(_) => Poll::Pending,
+    };
+
+    let mut deferred = DEFERRED.lock();
+    let entry = deferred
+        .get_mut(&token)
+        .expect("deferred request released while advanced");
+    entry.params = params;
+    match progress {
+        Poll::Pending => entry.work = Some(work),
+        Poll::Ready(result) => {
+            log::info!("Deferred request {:#x} completed: {:?}", token, result);
+            entry.result = Some(result);
+        }
+    }
+}
+
+/// Advances the oldest deferred request which is still running. Called
+/// whenever the SVSM gains control from the guest.
+pub fn advance_deferred_requests() {
+    let token = DEFERRED
+        .lock()
+        .iter()
+        .find(|(_, entry)| entry.work.is_some())
+        .map(|(token, _)| *token);
+    if let Some(token) = token {
+        advance(token);
+    }
+}
+
+/// Polls the deferred request with the token in `rcx`, after advancing it
+/// by a step. Once it is complete, returns its result and registers and
+/// releases the token. Otherwise fails with INCOMPLETE, with the token
+/// still in `rcx`. Fails with INVALID_PARAMETER for unknown tokens.
+pub fn poll_request(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let token = params.rcx;
+    advance(token);
+
+    let mut deferred = DEFERRED.lock();
+    let entry = deferred
+        .get(&token)
+        .ok_or_else(SvsmReqError::invalid_parameter)?;
+    let Some(result) = entry.result else {
+        return Err(SvsmReqError::incomplete());
+    };
+    params.rcx = entry.params.rcx;
+    params.rdx = entry.params.rdx;
+    params.r8 = entry.params.r8;
+    deferred.remove(&token);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::errors::SvsmResultCode;
+
+    struct Countdown(u64);
+
+    impl DeferredWork for Countdown {
+        fn step(&mut self, params: &mut RequestParams) -> Poll<Result<(), SvsmReqError>> {
+            self.0 -= 1;
+            params.rdx += 1;
+            if self.0 == 0 {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    fn incomplete(result: Result<(), SvsmReqError>) -> bool {
+        matches!(
+            result,
+            Err(SvsmReqError::RequestError(SvsmResultCode::INCOMPLETE))
+        )
+    }
+
+    #[test]
+    fn test_defer_and_poll() {
+        let mut params = RequestParams::default();
+        let err = defer_request(0x8000_0000, 0, &mut params, Box::new(Countdown(3)));
+        assert!(incomplete(Err(err)));
+        let token = params.rcx;
+
+        assert!(incomplete(poll_request(&mut params)));
+        assert_eq!(params.rcx, token);
+        assert!(incomplete(poll_request(&mut params)));
+        poll_request(&mut params).unwrap();
+        assert_eq!(params.rdx, 3);
+
+        params.rcx = token;
+        assert!(poll_request(&mut params).is_err());
+    }
+}
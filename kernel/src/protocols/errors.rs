@@ -40,6 +40,9 @@ impl From<SvsmResultCode> for u64 {
 }
 
 const SVSM_ERR_APIC_CANNOT_REGISTER: u64 = 0;
+/// Protocol error codes from this base on report a violated invariant,
+/// with the invariant code added.
+const SVSM_ERR_INVARIANT_BASE: u64 = 0xf0000;
 
 #[derive(Debug, Clone, Copy)]
 pub enum SvsmReqError {
@@ -68,6 +71,16 @@ impl SvsmReqError {
     pub fn protocol(code: u64) -> Self {
         Self::RequestError(SvsmResultCode::PROTOCOL_BASE(code))
     }
+
+    /// Converts an error raised while mapping a guest-supplied range.
+    /// Misaligned or empty ranges are reported to the guest as invalid
+    /// parameters instead of being treated as invalid addresses.
+    pub fn from_mapping(err: SvsmError) -> Self {
+        match err {
+            SvsmError::InvalidAddress => Self::invalid_parameter(),
+            _ => Self::from(err),
+        }
+    }
 }
 
 impl From<SvsmError> for SvsmReqError {
@@ -77,7 +90,12 @@ impl From<SvsmError> for SvsmReqError {
             // SEV-SNP errors obtained from PVALIDATE or RMPADJUST are returned
             // to the guest as protocol-specific errors.
             SvsmError::SevSnp(e) => Self::protocol(e.ret()),
+            SvsmError::Rmp(e) => Self::protocol(e.code()),
             SvsmError::InvalidAddress => Self::invalid_address(),
+            SvsmError::PagePinned => Self::busy(),
+            SvsmError::Invariant(code) => {
+                Self::protocol(SVSM_ERR_INVARIANT_BASE + u64::from(code.code()))
+            }
             SvsmError::Apic(e) => match e {
                 ApicError::Disabled => Self::unsupported_protocol(),
                 ApicError::Emulation => Self::invalid_parameter(),
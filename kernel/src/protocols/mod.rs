@@ -5,8 +5,14 @@
 // Author: Dov Murik <dovmurik@linux.ibm.com>
 
 pub mod apic;
+pub mod attest;
+pub mod barrier;
+pub mod buffer;
 pub mod core;
+pub mod deferred;
 pub mod errors;
+pub mod registry;
+#[cfg(feature = "backup")]
 pub mod backup;
 #[cfg(all(feature = "mstpm", not(test)))]
 pub mod vtpm;
@@ -15,6 +21,7 @@ use cpuarch::vmsa::{GuestVMExit, VMSA};
 
 // SVSM protocols
 pub const SVSM_CORE_PROTOCOL: u32 = 0;
+pub const SVSM_ATTEST_PROTOCOL: u32 = 1;
 pub const SVSM_VTPM_PROTOCOL: u32 = 2;
 pub const SVSM_APIC_PROTOCOL: u32 = 3;
 pub const SVSM_CUSTOM_PROTOCOL: u32 = 4;
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Registry of the protocols the SVSM serves.
+//!
+//! Every protocol is served by a [`ProtocolHandler`] registered once with
+//! [`register_protocol`], usually during initialization before the guest
+//! is launched. The request loop dispatches each request to the handler of
+//! its protocol, and `SVSM_CORE_QUERY_PROTOCOL` reports the versions of the
+//! registered handlers, so a protocol maintained outside this tree only
+//! needs to register itself. The protocols of this tree are registered with
+//! [`register_builtin_protocols`].
+
+use crate::locking::RWLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::protocols::RequestParams;
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A protocol served by the SVSM, see [`register_protocol`].
+pub trait ProtocolHandler: Sync {
+    /// Number of the protocol.
+    fn protocol(&self) -> u32;
+    /// Name of the protocol, for log messages.
+    fn name(&self) -> &'static str;
+    /// Lowest and highest version of the protocol supported.
+    fn versions(&self) -> (u32, u32);
+    /// Whether the protocol can be used by the calling CPU.
+    fn available(&self) -> bool {
+        true
+    }
+    /// Handles the call `request` of the protocol.
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError>;
+}
+
+fn always_available() -> bool {
+    true
+}
+
+/// A protocol handled by a plain function.
+pub struct BuiltinProtocol {
+    protocol: u32,
+    name: &'static str,
+    versions: (u32, u32),
+    available: fn() -> bool,
+    handler: fn(u32, &mut RequestParams) -> Result<(), SvsmReqError>,
+}
+
+impl BuiltinProtocol {
+    pub const fn new(
+        protocol: u32,
+        name: &'static str,
+        version_min: u32,
+        version_max: u32,
+        handler: fn(u32, &mut RequestParams) -> Result<(), SvsmReqError>,
+    ) -> Self {
+        Self {
+            protocol,
+            name,
+            versions: (version_min, version_max),
+            available: always_available,
+            handler,
+        }
+    }
+
+    /// Makes the protocol available only to CPUs for which `available`
+    /// returns true.
+    pub const fn with_check(self, available: fn() -> bool) -> Self {
+        Self { available, ..self }
+    }
+}
+
+impl fmt::Debug for BuiltinProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BuiltinProtocol")
+            .field("protocol", &self.protocol)
+            .field("name", &self.name)
+            .field("versions", &self.versions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for BuiltinProtocol {
+    fn protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn versions(&self) -> (u32, u32) {
+        self.versions
+    }
+
+    fn available(&self) -> bool {
+        (self.available)()
+    }
+
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        (self.handler)(request, params)
+    }
+}
+
+static PROTOCOLS: RWLock<Vec<&'static dyn ProtocolHandler>> = RWLock::new(Vec::new());
+
+/// Registers the handler of a protocol. Fails with INVALID_REQUEST if a
+/// handler for the protocol is registered already.
+pub fn register_protocol(handler: &'static dyn ProtocolHandler) -> Result<(), SvsmReqError> {
+    let mut protocols = PROTOCOLS.lock_write();
+    if protocols
+        .iter()
+        .any(|registered| registered.protocol() == handler.protocol())
+    {
+        log::error!(
+            "Protocol {} ({}) is registered already",
+            handler.protocol(),
+            handler.name()
+        );
+        return Err(SvsmReqError::invalid_request());
+    }
+    protocols.push(handler);
+    let (min, max) = handler.versions();
+    log::info!(
+        "Registered protocol {} ({}), versions {}-{}",
+        handler.protocol(),
+        handler.name(),
+        min,
+        max
+    );
+    Ok(())
+}
+
+/// Registers the protocols of this tree.
+pub fn register_builtin_protocols() -> Result<(), SvsmReqError> {
+    register_protocol(&crate::protocols::core::CORE_PROTOCOL_HANDLER)?;
+    register_protocol(&crate::protocols::attest::ATTEST_PROTOCOL_HANDLER)?;
+    register_protocol(&crate::protocols::apic::APIC_PROTOCOL_HANDLER)?;
+    #[cfg(all(feature = "mstpm", not(test)))]
+    register_protocol(&crate::protocols::vtpm::VTPM_PROTOCOL_HANDLER)?;
+    #[cfg(feature = "backup")]
+    register_protocol(&crate::protocols::backup::BACKUP_PROTOCOL_HANDLER)?;
+    Ok(())
+}
+
+/// Returns the handler of `protocol` if it is registered and available to
+/// the calling CPU.
+fn lookup(protocol: u32) -> Option<&'static dyn ProtocolHandler> {
+    PROTOCOLS
+        .lock_read()
+        .iter()
+        .copied()
+        .find(|handler| handler.protocol() == protocol)
+        .filter(|handler| handler.available())
+}
+
+/// Dispatches the call `request` of `protocol` to its handler. Fails with
+/// UNSUPPORTED_PROTOCOL if no handler is registered.
+pub fn dispatch_request(
+    protocol: u32,
+    request: u32,
+    params: &mut RequestParams,
+) -> Result<(), SvsmReqError> {
+    // The registry is not locked while the request is handled, which may
+    // take long.
+    let handler = lookup(protocol).ok_or_else(SvsmReqError::unsupported_protocol)?;
+    handler.handle(request, params)
+}
+
+/// Returns the answer of `SVSM_CORE_QUERY_PROTOCOL` for `version` of
+/// `protocol`: the lowest supported version in the lower and the highest
+/// in the upper 32 bits if `version` is supported, zero otherwise.
+pub fn query_protocol(protocol: u32, version: u32) -> u64 {
+    let Some(handler) = lookup(protocol) else {
+        return 0;
+    };
+    let (version_min, version_max) = handler.versions();
+    if version >= version_min && version <= version_max {
+        let ret_low: u64 = version_min.into();
+        let ret_high: u64 = version_max.into();
+
+        ret_low | (ret_high << 32)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PROTOCOL: u32 = 0x8000_0000;
+
+    fn test_request(request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        match request {
+            0 => {
+                params.rcx += 1;
+                Ok(())
+            }
+            _ => Err(SvsmReqError::unsupported_call()),
+        }
+    }
+
+    static TEST_HANDLER: BuiltinProtocol =
+        BuiltinProtocol::new(TEST_PROTOCOL, "test", 2, 3, test_request);
+
+    #[test]
+    fn test_register_and_dispatch() {
+        assert_eq!(query_protocol(TEST_PROTOCOL, 2), 0);
+        register_protocol(&TEST_HANDLER).unwrap();
+        assert!(register_protocol(&TEST_HANDLER).is_err());
+
+        assert_eq!(query_protocol(TEST_PROTOCOL, 1), 0);
+        assert_eq!(query_protocol(TEST_PROTOCOL, 3), 2 | (3 << 32));
+
+        let mut params = RequestParams::default();
+        dispatch_request(TEST_PROTOCOL, 0, &mut params).unwrap();
+        assert_eq!(params.rcx, 1);
+        assert!(dispatch_request(TEST_PROTOCOL, 1, &mut params).is_err());
+        assert!(dispatch_request(TEST_PROTOCOL + 1, 0, &mut params).is_err());
+    }
+}
@@ -15,7 +15,9 @@ use alloc::vec::Vec;
 use crate::{
     address::{Address, PhysAddr},
     mm::{valid_phys_address, GuestPtr, PerCPUPageMappingGuard},
-    protocols::{errors::SvsmReqError, RequestParams},
+    protocols::{
+        errors::SvsmReqError, registry::BuiltinProtocol, RequestParams, SVSM_VTPM_PROTOCOL,
+    },
     types::PAGE_SIZE,
     vtpm::{vtpm_get_locked, MsTpmSimulatorInterface, VtpmProtocolInterface},
 };
@@ -66,6 +68,20 @@ fn is_vtpm_platform_command_supported(cmd: TpmPlatformCommand) -> bool {
 
 const SEND_COMMAND_REQ_INBUF_SIZE: usize = PAGE_SIZE - 9;
 
+/// Size of the header of a TPM 2.0 command (tag, commandSize, commandCode)
+const TPM_COMMAND_HEADER_SIZE: usize = 10;
+
+pub const VTPM_PROTOCOL_VERSION_MIN: u32 = 1;
+pub const VTPM_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub static VTPM_PROTOCOL_HANDLER: BuiltinProtocol = BuiltinProtocol::new(
+    SVSM_VTPM_PROTOCOL,
+    "vtpm",
+    VTPM_PROTOCOL_VERSION_MIN,
+    VTPM_PROTOCOL_VERSION_MAX,
+    vtpm_protocol_request,
+);
+
 // vTPM protocol services (SVSM spec, table 14)
 const SVSM_VTPM_QUERY: u32 = 0;
 const SVSM_VTPM_COMMAND: u32 = 1;
@@ -113,6 +129,15 @@ impl TpmSendCommandRequest {
             .inbuf
             .get(..length)
             .ok_or_else(SvsmReqError::invalid_parameter)?;
+        // The commandSize field of the TPM command header must match the
+        // size of the input buffer, so the TPM never reads past the command.
+        let header = tpm_cmd
+            .get(..TPM_COMMAND_HEADER_SIZE)
+            .ok_or_else(SvsmReqError::invalid_parameter)?;
+        let command_size = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+        if command_size as usize != length {
+            return Err(SvsmReqError::invalid_parameter());
+        }
         let mut buffer: Vec<u8> = Vec::with_capacity(SEND_COMMAND_RESP_OUTBUF_SIZE);
         buffer.extend_from_slice(tpm_cmd);
 
@@ -201,7 +226,7 @@ fn tpm_send_command_request(buffer: &mut [u8]) -> Result<u32, SvsmReqError> {
         request.send()?
     };
     let response = TpmSendCommandResponse::try_from_as_mut_ref(buffer)?;
-    let _ = response.set_outbuf(outbuf.as_slice());
+    response.set_outbuf(outbuf.as_slice())?;
 
     Ok(outbuf.len() as u32)
 }
@@ -212,14 +237,21 @@ fn vtpm_command_request(params: &RequestParams) -> Result<(), SvsmReqError> {
     if paddr.is_null() {
         return Err(SvsmReqError::invalid_parameter());
     }
-    if !valid_phys_address(paddr) {
-        return Err(SvsmReqError::invalid_address());
-    }
 
     // The vTPM buffer size is one page, but it not required to be page aligned.
     let start = paddr.page_align();
     let offset = paddr.page_offset();
-    let end = (paddr + PAGE_SIZE).page_align_up();
+    let end = paddr
+        .checked_add(PAGE_SIZE)
+        .ok_or_else(SvsmReqError::invalid_parameter)?
+        .page_align_up();
+
+    // An unaligned buffer spans two pages, both must be guest memory.
+    for page in (start.bits()..end.bits()).step_by(PAGE_SIZE) {
+        if !valid_phys_address(PhysAddr::from(page)) {
+            return Err(SvsmReqError::invalid_address());
+        }
+    }
 
     let guard = PerCPUPageMappingGuard::create(start, end, 0)?;
     let vaddr = guard.virt_addr() + offset;
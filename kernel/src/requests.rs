@@ -7,16 +7,19 @@
 use crate::cpu::flush_tlb_global_sync;
 use crate::cpu::percpu::{process_requests, this_cpu, wait_for_requests};
 use crate::error::SvsmError;
+use crate::health::health_tick;
 use crate::mm::GuestPtr;
-use crate::protocols::apic::apic_protocol_request;
-use crate::protocols::core::core_protocol_request;
-use crate::protocols::backup::backup_protocol_request;
+use crate::protocols::barrier::RequestGuard;
+#[cfg(feature = "backup")]
+use crate::protocols::backup::{
+    advance_background_backup, check_cow_watchdog, take_periodic_checkpoint,
+};
+use crate::protocols::deferred::advance_deferred_requests;
 use crate::protocols::errors::{SvsmReqError, SvsmResultCode};
 use crate::sev::ghcb::switch_to_vmpl;
 
-#[cfg(all(feature = "mstpm", not(test)))]
-use crate::protocols::{vtpm::vtpm_protocol_request, SVSM_VTPM_PROTOCOL};
-use crate::protocols::{RequestParams, SVSM_APIC_PROTOCOL, SVSM_CORE_PROTOCOL, SVSM_CUSTOM_PROTOCOL};
+use crate::protocols::registry::dispatch_request;
+use crate::protocols::RequestParams;
 use crate::sev::vmsa::VMSAControl;
 use crate::types::GUEST_VMPL;
 use crate::utils::halt;
@@ -107,14 +110,9 @@ fn request_loop_once(
         return Ok(false);
     }
 
-    match protocol {
-        SVSM_CORE_PROTOCOL => core_protocol_request(request, params).map(|_| true),
-        #[cfg(all(feature = "mstpm", not(test)))]
-        SVSM_VTPM_PROTOCOL => vtpm_protocol_request(request, params).map(|_| true),
-        SVSM_APIC_PROTOCOL => apic_protocol_request(request, params).map(|_| true),
-        SVSM_CUSTOM_PROTOCOL => backup_protocol_request(request, params).map(|_| true),
-        _ => Err(SvsmReqError::unsupported_protocol()),
-    }
+    let _guard = RequestGuard::enter(protocol, request)?;
+
+    dispatch_request(protocol, request, params).map(|_| true)
 }
 
 fn check_requests() -> Result<bool, SvsmReqError> {
@@ -201,6 +199,19 @@ pub fn request_loop() {
             ((rax >> 32) as u32, (rax & 0xffff_ffff) as u32)
         };
 
+        // Lift stale copy-on-write protection before the guest can run into
+        // it again.
+        #[cfg(feature = "backup")]
+        check_cow_watchdog();
+        // Make progress on an asynchronous backup while the guest waits.
+        #[cfg(feature = "backup")]
+        advance_background_backup();
+        // Take a checkpoint if one is due.
+        #[cfg(feature = "backup")]
+        take_periodic_checkpoint();
+        // Make progress on a deferred request while the guest waits.
+        advance_deferred_requests();
+
         match check_requests() {
             Ok(pending) => {
                 if pending {
@@ -224,6 +235,8 @@ pub fn request_loop() {
                 break;
             }
         }
+
+        health_tick();
     }
 }
 
@@ -235,6 +248,7 @@ pub extern "C" fn request_processing_main() {
 
     loop {
         wait_for_requests();
+        advance_deferred_requests();
 
         // Obtain a reference to the VMSA just long enough to extract the
         // request parameters.
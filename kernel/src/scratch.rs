@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Shared scratch region for host cooperation.
+//!
+//! Handing data to the host requires shared memory, and converting pages
+//! between private and shared at run time depends on the hypervisor, which
+//! may not support it or may fail, e.g. when the SVSM is about to die. The
+//! IGVM parameters can therefore name a fixed scratch region, which the SVSM
+//! converts to shared through the GHCB once at boot and never converts
+//! back. Its address is part of the IGVM parameters, so the host knows
+//! where to look. The region is laid out as follows:
+//!
+//! * page [`SCRATCH_HEALTH_PAGE`]: the health record, see
+//!   [`crate::health`],
+//! * page [`SCRATCH_CRASH_PAGE`]: the crash record written on the first
+//!   panic,
+//! * the remaining pages, starting at [`SCRATCH_EXPORT_PAGE`]: the window
+//!   of streamed snapshot exports.
+
+use crate::address::{Address, PhysAddr};
+use crate::cpu::flush_address;
+use crate::cpu::percpu::this_cpu;
+use crate::error::SvsmError;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::platform::{PageStateChangeOp, SVSM_PLATFORM};
+use crate::types::{PageSize, PAGE_SIZE};
+use crate::utils::immut_after_init::ImmutAfterInitCell;
+use crate::utils::MemoryRegion;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Page of the scratch region holding the health record.
+pub const SCRATCH_HEALTH_PAGE: usize = 0;
+/// Page of the scratch region holding the crash record.
+pub const SCRATCH_CRASH_PAGE: usize = 1;
+/// First page of the snapshot export window.
+pub const SCRATCH_EXPORT_PAGE: usize = 2;
+/// Smallest usable scratch region: one page for each user.
+const SCRATCH_MIN_PAGES: usize = 3;
+
+/// "SVCR" in little endian.
+const CRASH_MAGIC: u32 = 0x5243_5653;
+const CRASH_VERSION: u32 = 1;
+/// Size of the crash record header: magic, version, APIC ID and message
+/// length as `u32`.
+const CRASH_HEADER_SIZE: usize = 16;
+/// Maximum length of the panic message in the crash record.
+const CRASH_MESSAGE_MAX: usize = 1024;
+
+static SCRATCH_REGION: ImmutAfterInitCell<Option<MemoryRegion<PhysAddr>>> =
+    ImmutAfterInitCell::new(None);
+static CRASH_RECORDED: AtomicBool = AtomicBool::new(false);
+
+/// Converts `region` to shared memory and uses it as the scratch region.
+/// The region must be page aligned, hold at least three pages and lie
+/// outside of SVSM memory. Must be called during single-threaded boot.
+pub fn init_scratch_region(
+    region: MemoryRegion<PhysAddr>,
+    kernel_region: &MemoryRegion<PhysAddr>,
+) -> Result<(), SvsmError> {
+    if !region.start().is_page_aligned()
+        || region.len() % PAGE_SIZE != 0
+        || region.len() < SCRATCH_MIN_PAGES * PAGE_SIZE
+        || region.overlap(kernel_region)
+    {
+        log::warn!(
+            "Ignoring invalid scratch region {:#018x}-{:#018x}",
+            region.start(),
+            region.end()
+        );
+        return Err(SvsmError::InvalidAddress);
+    }
+    SVSM_PLATFORM.as_dyn_ref().page_state_change(
+        region,
+        PageSize::Regular,
+        PageStateChangeOp::Shared,
+    )?;
+    SCRATCH_REGION
+        .reinit(&Some(region))
+        .map_err(|_| SvsmError::Mem)?;
+    log::info!(
+        "Shared scratch region at {:#018x}-{:#018x}",
+        region.start(),
+        region.end()
+    );
+    Ok(())
+}
+
+/// Returns the scratch region, if there is one.
+pub fn scratch_region() -> Option<MemoryRegion<PhysAddr>> {
+    *SCRATCH_REGION
+}
+
+/// Returns the guest physical address of page `index` of the scratch
+/// region, if there is one.
+pub fn scratch_page(index: usize) -> Option<PhysAddr> {
+    let region = (*SCRATCH_REGION)?;
+    let paddr = region.start().checked_add(index * PAGE_SIZE)?;
+    (paddr < region.end()).then_some(paddr)
+}
+
+/// Returns the window of streamed snapshot exports, if there is a scratch
+/// region.
+pub fn scratch_export_window() -> Option<MemoryRegion<PhysAddr>> {
+    let start = scratch_page(SCRATCH_EXPORT_PAGE)?;
+    let end = (*SCRATCH_REGION)?.end();
+    Some(MemoryRegion::from_addresses(start, end))
+}
+
+/// Maps the page at `paddr`, which must already be shared memory, e.g. a
+/// page of the scratch region, for access by the SVSM.
+pub fn map_shared_page(paddr: PhysAddr) -> Result<PerCPUPageMappingGuard, SvsmError> {
+    let guard = PerCPUPageMappingGuard::create_4k(paddr)?;
+    this_cpu().get_pgtable().set_shared_4k(guard.virt_addr())?;
+    flush_address(guard.virt_addr());
+    Ok(guard)
+}
+
+/// Formats into a fixed buffer and cuts off what does not fit.
+struct MessageBuffer {
+    buf: [u8; CRASH_MESSAGE_MAX],
+    len: usize,
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Writes the crash record for a panic on the CPU with `apic_id` into the
+/// scratch region. Only the first panic is recorded. Called from the panic
+/// handler, so it takes no locks.
+pub fn record_crash(apic_id: u32, message: fmt::Arguments<'_>) {
+    let Some(paddr) = scratch_page(SCRATCH_CRASH_PAGE) else {
+        return;
+    };
+    if CRASH_RECORDED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let mut text = MessageBuffer {
+        buf: [0; CRASH_MESSAGE_MAX],
+        len: 0,
+    };
+    let _ = text.write_fmt(message);
+
+    let Ok(guard) = map_shared_page(paddr) else {
+        return;
+    };
+    let mut header = [0u8; CRASH_HEADER_SIZE];
+    header[0..4].copy_from_slice(&CRASH_MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&CRASH_VERSION.to_le_bytes());
+    header[8..12].copy_from_slice(&apic_id.to_le_bytes());
+    header[12..16].copy_from_slice(&(text.len as u32).to_le_bytes());
+    let ptr = guard.virt_addr().as_mut_ptr::<u8>();
+    // SAFETY: the guard maps the crash page, which holds the header and up
+    // to CRASH_MESSAGE_MAX bytes of message.
+    unsafe {
+        ptr.add(CRASH_HEADER_SIZE)
+            .copy_from_nonoverlapping(text.buf.as_ptr(), text.len);
+        ptr.copy_from_nonoverlapping(header.as_ptr(), CRASH_HEADER_SIZE);
+    }
+}
@@ -24,9 +24,11 @@ use crate::utils::MemoryRegion;
 use crate::mm::PageBox;
 use core::arch::global_asm;
 use core::cell::Cell;
+use core::hint::spin_loop;
 use core::mem::{self, offset_of};
 use core::ops::{Deref, DerefMut};
 use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use super::msr_protocol::{invalidate_page_msr, register_ghcb_gpa_msr, validate_page_msr};
 use super::{pvalidate, PvalidateOp};
@@ -52,6 +54,21 @@ const PSC_FLAG_HUGE: u64 = 1 << PSC_FLAG_HUGE_SHIFT;
 
 const GHCB_BUFFER_SIZE: usize = 0x7f0;
 
+/// `SW_EXITINFO2` of a guest request which the hypervisor could not forward
+/// to the PSP because it is busy.
+const GUEST_REQ_ERR_BUSY: u64 = 2 << 32;
+const GUEST_REQ_ERR_MASK: u64 = 0xffff_ffff_0000_0000;
+
+/// Number of times a GHCB call failing with a retryable error is issued
+/// before the error is returned.
+const GHCB_RETRY_ATTEMPTS: u32 = 8;
+/// Spin loop iterations before the first retry. The pause doubles with
+/// every further retry.
+const GHCB_RETRY_BACKOFF_SPINS: u32 = 256;
+
+static GHCB_RETRIES: AtomicU64 = AtomicU64::new(0);
+static GHCB_RETRIES_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
 macro_rules! ghcb_getter {
     ($name:ident, $field:ident,$t:ty) => {
         #[allow(unused)]
@@ -81,6 +98,85 @@ pub enum GhcbError {
     VmgexitInvalid,
     // A response from the hypervisor included an error code
     VmgexitError(u64, u64),
+    // The hypervisor returned before processing all page state changes
+    PscIncomplete,
+}
+
+/// How a failed GHCB call is to be handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GhcbErrorClass {
+    /// The hypervisor did not complete the call for a transient reason,
+    /// issuing it again may succeed.
+    Retryable,
+    /// The call failed and will keep failing.
+    Fatal,
+}
+
+impl GhcbError {
+    fn class(&self, exit_code: GHCBExitCode) -> GhcbErrorClass {
+        match (exit_code, self) {
+            (GHCBExitCode::SNP_PSC, Self::PscIncomplete) => GhcbErrorClass::Retryable,
+            (
+                GHCBExitCode::GUEST_REQUEST | GHCBExitCode::GUEST_EXT_REQUEST,
+                Self::VmgexitError(_, info2),
+            ) if info2 & GUEST_REQ_ERR_MASK == GUEST_REQ_ERR_BUSY => GhcbErrorClass::Retryable,
+            _ => GhcbErrorClass::Fatal,
+        }
+    }
+}
+
+/// Counters of retried GHCB calls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GhcbRetryStats {
+    /// Number of retries taken.
+    pub retries: u64,
+    /// Number of calls which still failed with a retryable error after the
+    /// last attempt.
+    pub exhausted: u64,
+}
+
+/// Returns the retry counters of all GHCB calls since boot.
+pub fn ghcb_retry_stats() -> GhcbRetryStats {
+    GhcbRetryStats {
+        retries: GHCB_RETRIES.load(Ordering::Relaxed),
+        exhausted: GHCB_RETRIES_EXHAUSTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Performs the GHCB call `call` with exit code `exit_code`. While it fails
+/// with a [`GhcbErrorClass::Retryable`] error, the call is issued again after
+/// an exponentially growing pause, up to [`GHCB_RETRY_ATTEMPTS`] times in
+/// total.
+fn with_retry<T, F>(exit_code: GHCBExitCode, mut call: F) -> Result<T, GhcbError>
+where
+    F: FnMut() -> Result<T, GhcbError>,
+{
+    let mut backoff = GHCB_RETRY_BACKOFF_SPINS;
+    for _ in 1..GHCB_RETRY_ATTEMPTS {
+        match call() {
+            Err(e) if e.class(exit_code) == GhcbErrorClass::Retryable => {
+                GHCB_RETRIES.fetch_add(1, Ordering::Relaxed);
+                for _ in 0..backoff {
+                    spin_loop();
+                }
+                backoff = backoff.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    let result = call();
+    if let Err(e) = &result {
+        if e.class(exit_code) == GhcbErrorClass::Retryable {
+            GHCB_RETRIES_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "GHCB call {:?} still failing after {} attempts: {:?}",
+                exit_code,
+                GHCB_RETRY_ATTEMPTS,
+                e
+            );
+        }
+    }
+    result
 }
 
 impl From<GhcbError> for SvsmError {
@@ -457,6 +553,40 @@ impl GHCB {
         Ok(())
     }
 
+    fn read_buffer<T>(&self, offset: usize) -> Result<T, GhcbError>
+    where
+        T: Copy,
+    {
+        offset
+            .checked_add(mem::size_of::<T>())
+            .filter(|end| *end <= GHCB_BUFFER_SIZE)
+            .ok_or(GhcbError::InvalidOffset)?;
+
+        // SAFETY: we have verified that the offset is within bounds and does
+        // not overflow. The buffer is read without alignment requirements.
+        unsafe {
+            let src = self.buffer.as_ptr().cast::<u8>().add(offset);
+            Ok(src.cast::<T>().read_unaligned())
+        }
+    }
+
+    /// Checks the header of a page state change request after the
+    /// hypervisor returned. It may return before processing all entries, in
+    /// which case the request is to be issued again, but must not go
+    /// backwards or change the request.
+    fn psc_check_progress(&self, end_entry: u16, cur_entry: &mut u16) -> Result<(), GhcbError> {
+        let header: PageStateChangeHeader = self.read_buffer(0)?;
+        let (cur, end) = (header.cur_entry, header.end_entry);
+        if end != end_entry || cur < *cur_entry {
+            return Err(GhcbError::VmgexitInvalid);
+        }
+        *cur_entry = cur;
+        if cur <= end {
+            return Err(GhcbError::PscIncomplete);
+        }
+        Ok(())
+    }
+
     pub fn psc_entry(
         &self,
         paddr: PhysAddr,
@@ -521,9 +651,14 @@ impl GHCB {
 
                 let buffer_va = VirtAddr::from(self.buffer.as_ptr());
                 let buffer_pa = u64::from(virt_to_phys(buffer_va));
-                self.set_sw_scratch_valid(buffer_pa);
 
-                if let Err(mut e) = self.vmgexit(GHCBExitCode::SNP_PSC, 0, 0) {
+                let mut cur_entry = 0;
+                let result = with_retry(GHCBExitCode::SNP_PSC, || {
+                    self.set_sw_scratch_valid(buffer_pa);
+                    self.vmgexit(GHCBExitCode::SNP_PSC, 0, 0)?;
+                    self.psc_check_progress(header.end_entry, &mut cur_entry)
+                });
+                if let Err(mut e) = result {
                     if let Err(err) = self.get_exit_info_2_valid() {
                         e = err;
                     }
@@ -584,17 +719,22 @@ impl GHCB {
     }
 
     pub fn guest_request(&self, req_page: VirtAddr, resp_page: VirtAddr) -> Result<(), SvsmError> {
-        self.clear();
-
         let info1: u64 = u64::from(virt_to_phys(req_page));
         let info2: u64 = u64::from(virt_to_phys(resp_page));
 
-        self.vmgexit(GHCBExitCode::GUEST_REQUEST, info1, info2)?;
+        with_retry(GHCBExitCode::GUEST_REQUEST, || {
+            self.clear();
+            self.vmgexit(GHCBExitCode::GUEST_REQUEST, info1, info2)?;
 
-        let sw_exit_info_2 = self.get_exit_info_2_valid()?;
-        if sw_exit_info_2 != 0 {
-            return Err(GhcbError::VmgexitError(self.sw_exit_info_1.get(), sw_exit_info_2).into());
-        }
+            let sw_exit_info_2 = self.get_exit_info_2_valid()?;
+            if sw_exit_info_2 != 0 {
+                return Err(GhcbError::VmgexitError(
+                    self.sw_exit_info_1.get(),
+                    sw_exit_info_2,
+                ));
+            }
+            Ok(())
+        })?;
 
         Ok(())
     }
@@ -606,25 +746,27 @@ impl GHCB {
         data_pages: VirtAddr,
         data_size: u64,
     ) -> Result<(), SvsmError> {
-        self.clear();
-
         let info1: u64 = u64::from(virt_to_phys(req_page));
         let info2: u64 = u64::from(virt_to_phys(resp_page));
         let rax: u64 = u64::from(virt_to_phys(data_pages));
 
-        self.set_rax_valid(rax);
-        self.set_rbx_valid(data_size);
+        with_retry(GHCBExitCode::GUEST_EXT_REQUEST, || {
+            self.clear();
+            self.set_rax_valid(rax);
+            self.set_rbx_valid(data_size);
 
-        self.vmgexit(GHCBExitCode::GUEST_EXT_REQUEST, info1, info2)?;
+            self.vmgexit(GHCBExitCode::GUEST_EXT_REQUEST, info1, info2)?;
 
-        let sw_exit_info_2 = self.get_exit_info_2_valid()?;
+            let sw_exit_info_2 = self.get_exit_info_2_valid()?;
 
-        // On error, RBX and exit_info_2 are returned for proper error handling.
-        // For an extended request, if the buffer provided is too small, the hypervisor
-        // will return in RBX the number of contiguous pages required
-        if sw_exit_info_2 != 0 {
-            return Err(GhcbError::VmgexitError(self.rbx.get(), sw_exit_info_2).into());
-        }
+            // On error, RBX and exit_info_2 are returned for proper error handling.
+            // For an extended request, if the buffer provided is too small, the hypervisor
+            // will return in RBX the number of contiguous pages required
+            if sw_exit_info_2 != 0 {
+                return Err(GhcbError::VmgexitError(self.rbx.get(), sw_exit_info_2));
+            }
+            Ok(())
+        })?;
 
         Ok(())
     }
@@ -813,4 +955,26 @@ mod tests {
         assert_eq!(offset_of!(GHCB, usage), 0xffc);
         assert_eq!(mem::size_of::<GHCB>(), 0x1000);
     }
+
+    #[test]
+    fn test_error_class() {
+        let busy = GhcbError::VmgexitError(0, GUEST_REQ_ERR_BUSY);
+        assert_eq!(
+            busy.class(GHCBExitCode::GUEST_REQUEST),
+            GhcbErrorClass::Retryable
+        );
+        assert_eq!(busy.class(GHCBExitCode::SNP_PSC), GhcbErrorClass::Fatal);
+        assert_eq!(
+            GhcbError::VmgexitError(0, 1 << 32).class(GHCBExitCode::GUEST_EXT_REQUEST),
+            GhcbErrorClass::Fatal
+        );
+        assert_eq!(
+            GhcbError::PscIncomplete.class(GHCBExitCode::SNP_PSC),
+            GhcbErrorClass::Retryable
+        );
+        assert_eq!(
+            GhcbError::VmgexitInvalid.class(GHCBExitCode::SNP_PSC),
+            GhcbErrorClass::Fatal
+        );
+    }
 }
@@ -7,6 +7,7 @@
 pub mod ghcb;
 pub mod hv_doorbell;
 pub mod msr_protocol;
+pub mod rmp;
 pub mod secrets_page;
 pub mod status;
 pub mod vmsa;
@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Typed RMP operations.
+//!
+//! The raw [`rmp_adjust`] reports hardware result codes as [`SevSnpError`].
+//! The operations in this module change and query the access of the guest
+//! VMPLs to a page and decode the result codes into [`RmpError`], so callers
+//! can react to permission and page size failures specifically.
+
+use crate::address::{Address, PhysAddr, VirtAddr};
+use crate::cpu::cpuid::{cpuid_table_has_feature, CpuidFeature, CpuidReg};
+use crate::error::SvsmError;
+use crate::mm::PerCPUPageMappingGuard;
+use crate::sev::utils::{rmp_adjust, RMPFlags, SevSnpError};
+use crate::types::{PageSize, GUEST_VMPL, PAGE_SIZE, PAGE_SIZE_2M};
+use core::arch::asm;
+
+/// Decoded failures of RMP instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RmpError {
+    /// The input operands were invalid (FAIL_INPUT), or the instruction
+    /// faulted.
+    Input,
+    /// The SVSM is not allowed to change the page (FAIL_PERMISSION).
+    Permission,
+    /// The page size of the operation does not match the page size of the
+    /// RMP entry (FAIL_SIZEMISMATCH).
+    SizeMismatch,
+    /// The instruction is not supported by the platform.
+    Unsupported,
+}
+
+impl RmpError {
+    /// Returns the hardware result code of the failure.
+    pub fn code(&self) -> u64 {
+        match self {
+            Self::Input | Self::Unsupported => 1,
+            Self::Permission => 2,
+            Self::SizeMismatch => 6,
+        }
+    }
+}
+
+impl From<SevSnpError> for RmpError {
+    fn from(err: SevSnpError) -> Self {
+        match err {
+            SevSnpError::FAIL_PERMISSION(_) => Self::Permission,
+            SevSnpError::FAIL_SIZEMISMATCH(_) => Self::SizeMismatch,
+            SevSnpError::FAIL_INPUT(_) | SevSnpError::FAIL_UNCHANGED(_) => Self::Input,
+        }
+    }
+}
+
+impl From<RmpError> for SvsmError {
+    fn from(err: RmpError) -> Self {
+        Self::Rmp(err)
+    }
+}
+
+/// Decodes an error of [`rmp_adjust`] into an [`RmpError`].
+fn decode(err: SvsmError) -> RmpError {
+    match err {
+        SvsmError::SevSnp(e) => RmpError::from(e),
+        _ => RmpError::Input,
+    }
+}
+
+/// Access of the guest VMPLs to a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestAccess {
+    None,
+    ReadOnly,
+    ReadWrite,
+}
+
+impl GuestAccess {
+    fn flags(self) -> RMPFlags {
+        match self {
+            Self::None => RMPFlags::NONE,
+            Self::ReadOnly => RMPFlags::RX,
+            Self::ReadWrite => RMPFlags::RWX,
+        }
+    }
+
+    fn from_perms(perms: u8) -> Self {
+        let read = RMPFlags::READ.bits() >> 8;
+        let write = RMPFlags::WRITE.bits() >> 8;
+        if u64::from(perms) & write != 0 {
+            Self::ReadWrite
+        } else if u64::from(perms) & read != 0 {
+            Self::ReadOnly
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Sets the access of all guest VMPLs to the page mapped at `vaddr`.
+pub fn rmp_set_guest_access(
+    vaddr: VirtAddr,
+    size: PageSize,
+    access: GuestAccess,
+) -> Result<(), RmpError> {
+    for vmpl in RMPFlags::GUEST_VMPL.bits()..=RMPFlags::VMPL3.bits() {
+        let vmpl = RMPFlags::from_bits_truncate(vmpl);
+        rmp_adjust(vaddr, vmpl | access.flags(), size).map_err(decode)?;
+    }
+    Ok(())
+}
+
+/// Like [`rmp_set_guest_access`], but retries a 2M page that was smashed to
+/// 4K entries in the RMP as 512 individual 4K pages. `vaddr` must map the
+/// whole page.
+pub fn rmp_set_guest_access_splinter(
+    vaddr: VirtAddr,
+    size: PageSize,
+    access: GuestAccess,
+) -> Result<(), RmpError> {
+    match rmp_set_guest_access(vaddr, size, access) {
+        Err(RmpError::SizeMismatch) if size == PageSize::Huge => {
+            log::debug!("Splintering RMP update of 2M page at {:#x}", vaddr);
+            for offset in (0..PAGE_SIZE_2M).step_by(PAGE_SIZE) {
+                rmp_set_guest_access(vaddr + offset, PageSize::Regular, access)?;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Sets the access of all guest VMPLs to the guest page at `paddr`, mapping
+/// it temporarily and splintering 2M pages if necessary.
+pub fn rmp_set_guest_access_paddr(
+    paddr: PhysAddr,
+    size: PageSize,
+    access: GuestAccess,
+) -> Result<(), SvsmError> {
+    let guard = match size {
+        PageSize::Huge => PerCPUPageMappingGuard::create_2m(paddr)?,
+        PageSize::Regular => PerCPUPageMappingGuard::create_4k(paddr)?,
+    };
+    rmp_set_guest_access_splinter(guard.virt_addr(), size, access)?;
+    Ok(())
+}
+
+/// The state of an RMP entry as reported by RMPQUERY.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RmpState {
+    /// Page size of the RMP entry.
+    pub size: PageSize,
+    /// Access of the guest VMPL to the page.
+    pub guest_access: GuestAccess,
+}
+
+/// RMPQUERY support is reported in CPUID Fn8000_001F EAX[6].
+const RMPQUERY: CpuidFeature = CpuidFeature::new(0x8000_001f, 0, CpuidReg::Eax, 6);
+
+/// Queries the RMP entry of the page mapped at `vaddr`.
+pub fn rmp_query(vaddr: VirtAddr) -> Result<RmpState, RmpError> {
+    if !cpuid_table_has_feature(RMPQUERY) {
+        return Err(RmpError::Unsupported);
+    }
+
+    let mut rax: u64 = vaddr.page_align().bits() as u64;
+    let rdx: u64;
+    let rcx: u64;
+    let mut ex: u64 = 0;
+
+    // SAFETY: RMPQUERY only reads the RMP entry of the given address.
+    unsafe {
+        asm!("1: .byte 0xf3, 0x0f, 0x01, 0xfd
+                 jmp 3f
+              2: movq $1, %r8
+              3:
+              .pushsection \"__exception_table\",\"a\"
+              .balign 16
+              .quad (1b)
+              .quad (2b)
+              .popsection",
+                inout("rax") rax,
+                lateout("rdx") rdx,
+                lateout("rcx") rcx,
+                inout("r8") ex,
+                options(att_syntax));
+    }
+
+    if ex != 0 {
+        return Err(RmpError::Input);
+    }
+    match rax {
+        0 => {}
+        1 => return Err(RmpError::Input),
+        2 => return Err(RmpError::Permission),
+        _ => {
+            log::error!("RMPQUERY: Unexpected return value: {:#x}", rax);
+            return Err(RmpError::Input);
+        }
+    }
+
+    // RDX holds the permission masks of VMPL1-3 in consecutive bytes.
+    let shift = 8 * (GUEST_VMPL - 1);
+    Ok(RmpState {
+        size: if rcx & 1 != 0 {
+            PageSize::Huge
+        } else {
+            PageSize::Regular
+        },
+        guest_access: GuestAccess::from_perms((rdx >> shift) as u8),
+    })
+}
@@ -20,7 +20,7 @@ use svsm::address::{PhysAddr, VirtAddr};
 use svsm::config::SvsmConfig;
 use svsm::console::install_console_logger;
 use svsm::cpu::control_regs::{cr0_init, cr4_init};
-use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table};
+use svsm::cpu::cpuid::{dump_cpuid_table, register_cpuid_table, validate_cpuid_table};
 use svsm::cpu::efer::efer_init;
 use svsm::cpu::gdt;
 use svsm::cpu::idt::svsm::{early_idt_init, idt_init};
@@ -33,16 +33,25 @@ use svsm::debug::stacktrace::print_stack;
 use svsm::error::SvsmError;
 use svsm::fs::{initialize_fs, populate_ram_fs};
 use svsm::fw_cfg::FwCfg;
+use svsm::fw_protect::protect_fw_regions;
 use svsm::greq::driver::guest_request_driver_init;
+use svsm::health::{health_panic, set_boot_phase, set_health_page, BootPhase};
 use svsm::igvm_params::IgvmParams;
 use svsm::kernel_region::new_kernel_region;
-use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
+use svsm::mm::alloc::{memory_info, print_memory_info, print_memory_usage, root_mem_init, stats};
 use svsm::mm::memory::{init_memory_map, write_guest_memory_map};
 use svsm::mm::pagetable::paging_init;
-use svsm::mm::virtualrange::virt_log_usage;
+use svsm::mm::virtualrange::{set_virt_window_4k_pages, virt_log_usage};
 use svsm::mm::{init_kernel_mapping_info, PerCPUPageMappingGuard};
 use svsm::platform::{SvsmPlatformCell, SVSM_PLATFORM};
+#[cfg(feature = "backup")]
+use svsm::protocols::backup::{
+    set_backup_bandwidth, set_backup_compression, set_paranoid_checks, set_snapshot_budget,
+    set_snapshot_policy,
+};
+use svsm::protocols::registry::register_builtin_protocols;
 use svsm::requests::{request_loop, request_processing_main, update_mappings};
+use svsm::scratch::{init_scratch_region, record_crash, scratch_page, SCRATCH_HEALTH_PAGE};
 use svsm::sev::utils::{rmp_adjust, RMPFlags};
 use svsm::sev::{secrets_page, secrets_page_mut};
 use svsm::svsm_paging::{init_page_table, invalidate_early_boot_memory};
@@ -51,7 +60,7 @@ use svsm::task::{create_kernel_task, schedule_init};
 use svsm::types::{PageSize, GUEST_VMPL, PAGE_SIZE};
 use svsm::utils::{halt, immut_after_init::ImmutAfterInitCell, zero_mem_region};
 #[cfg(all(feature = "mstpm", not(test)))]
-use svsm::vtpm::vtpm_init;
+use svsm::vtpm::{restore::set_vtpm_restore_policy, vtpm_init};
 
 use svsm::mm::validate::{init_valid_bitmap_ptr, migrate_valid_bitmap};
 
@@ -261,6 +270,7 @@ fn init_cpuid_table(addr: VirtAddr) {
         addr.aligned_mut::<SnpCpuidTable>()
             .expect("Misaligned SNP CPUID table address")
     };
+    validate_cpuid_table(table).expect("Invalid SNP CPUID table");
 
     for func in table.func.iter_mut().take(table.count as usize) {
         if func.eax_in == 0x8000001f {
@@ -349,6 +359,9 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: usize) {
         .setup_on_cpu(platform)
         .expect("Failed to run percpu.setup_on_cpu()");
     bsp_percpu.load();
+    // The BSP counts as online like the APs it starts, e.g. for TLB
+    // shootdowns which have to reach every processor.
+    bsp_percpu.shared().set_online();
 
     // Idle task must be allocated after PerCPU data is mapped
     bsp_percpu
@@ -407,11 +420,30 @@ pub extern "C" fn svsm_main() {
         }
         SvsmConfig::IgvmConfig(igvm_params)
     } else {
-        SvsmConfig::FirmwareConfig(FwCfg::new(SVSM_PLATFORM.as_dyn_ref().get_io_port()))
+        let mut fw_cfg = FwCfg::new(SVSM_PLATFORM.as_dyn_ref().get_io_port());
+        if let Err(e) = fw_cfg.enable_dma() {
+            log::info!("fw_cfg DMA not available, using port I/O: {:?}", e);
+        }
+        SvsmConfig::FirmwareConfig(fw_cfg)
     };
 
     init_memory_map(&config, &LAUNCH_INFO).expect("Failed to init guest memory map");
 
+    if let Some(pages) = config.percpu_map_window_pages() {
+        set_virt_window_4k_pages(pages);
+    }
+
+    let kernel_region = new_kernel_region(&LAUNCH_INFO);
+    if let Some(region) = config.scratch_region() {
+        if let Err(e) = init_scratch_region(region, &kernel_region) {
+            log::warn!("Failed to set up the shared scratch region: {:?}", e);
+        }
+    }
+
+    if let Some(paddr) = scratch_page(SCRATCH_HEALTH_PAGE).or(config.health_page()) {
+        set_health_page(paddr, &kernel_region);
+    }
+
     initialize_fs();
 
     populate_ram_fs(LAUNCH_INFO.kernel_fs_start, LAUNCH_INFO.kernel_fs_end)
@@ -431,8 +463,10 @@ pub extern "C" fn svsm_main() {
 
     log::info!("{} CPU(s) present", nr_cpus);
 
+    set_boot_phase(BootPhase::StartingCpus);
     start_secondary_cpus(platform, &cpus, launch_info.vtom);
 
+    set_boot_phase(BootPhase::ValidatingMemory);
     let fw_metadata = config.get_fw_metadata();
     if let Some(ref fw_meta) = fw_metadata {
         print_fw_meta(fw_meta);
@@ -440,6 +474,20 @@ pub extern "C" fn svsm_main() {
         validate_fw_memory(&config, fw_meta, &LAUNCH_INFO).expect("Failed to validate memory");
         copy_tables_to_fw(fw_meta).expect("Failed to copy firmware tables");
         validate_fw(&config, &LAUNCH_INFO).expect("Failed to validate flash memory");
+        protect_fw_regions(&config.get_fw_image_regions(&kernel_region))
+            .expect("Failed to write-protect firmware");
+    }
+
+    set_boot_phase(BootPhase::LaunchingGuest);
+
+    #[cfg(feature = "backup")]
+    {
+        set_snapshot_policy(config.snapshot_digests());
+        let (snapshot_budget, snapshot_global_budget) = config.snapshot_budget();
+        set_snapshot_budget(snapshot_budget, snapshot_global_budget);
+        set_backup_bandwidth(config.backup_bandwidth_percent());
+        set_paranoid_checks(config.paranoid_checks());
+        set_backup_compression(config.backup_compression());
     }
 
     guest_request_driver_init();
@@ -449,7 +497,12 @@ pub extern "C" fn svsm_main() {
     }
 
     #[cfg(all(feature = "mstpm", not(test)))]
-    vtpm_init().expect("vTPM failed to initialize");
+    {
+        set_vtpm_restore_policy(config.vtpm_restore_policy());
+        vtpm_init().expect("vTPM failed to initialize");
+    }
+
+    register_builtin_protocols().expect("Failed to register SVSM protocols");
 
     virt_log_usage();
 
@@ -468,6 +521,7 @@ pub extern "C" fn svsm_main() {
         log::info!("Failed to launch /init");
     }
 
+    set_boot_phase(BootPhase::Running);
     request_loop();
 
     panic!("Road ends here!");
@@ -480,7 +534,13 @@ fn panic(info: &PanicInfo<'_>) -> ! {
     secrets_page_mut().clear_vmpck(2);
     secrets_page_mut().clear_vmpck(3);
 
+    health_panic();
+    record_crash(this_cpu().get_apic_id(), format_args!("{}", info));
+
     log::error!("Panic: CPU[{}] {}", this_cpu().get_apic_id(), info);
+    if let Some(info) = stats() {
+        print_memory_usage(&info);
+    }
 
     print_stack(3);
 
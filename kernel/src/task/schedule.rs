@@ -18,7 +18,9 @@
 //! * [`TERMINATED`] The task is about to be destroyed and owned by the [`RunQueue`].
 //!
 //! The scheduler is cooperative. A task runs until it voluntarily calls the
-//! [`schedule()`] function.
+//! [`schedule()`] function. Long-running tasks call [`preemption_point()`]
+//! at safe points, which yields the CPU once the task has used up its time
+//! slice and other tasks are waiting to run.
 //!
 //! Only when a task is in [`RUNNING`] or [`TERMINATED`] state it is assigned to a
 //! specific CPU. Tasks in the [`BLOCKED`] state have no CPU assigned and will run
@@ -33,15 +35,34 @@ extern crate alloc;
 use super::INITIAL_TASK_ID;
 use super::{Task, TaskListAdapter, TaskPointer, TaskRunListAdapter};
 use crate::address::Address;
+use crate::cpu::msr::rdtsc;
 use crate::cpu::percpu::this_cpu;
+use crate::cpu::tsc::tsc_khz;
 use crate::error::SvsmError;
 use crate::locking::SpinLock;
 use alloc::sync::Arc;
 use core::arch::{asm, global_asm};
 use core::cell::OnceCell;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
 use intrusive_collections::LinkedList;
 
+/// Time a task may run before [`preemption_point()`] yields the CPU.
+const TIME_SLICE_MS: u64 = 10;
+
+/// Length of a time slice in TSC ticks, computed on first use.
+static TIME_SLICE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn time_slice_ticks() -> u64 {
+    let ticks = TIME_SLICE_TICKS.load(Ordering::Relaxed);
+    if ticks != 0 {
+        return ticks;
+    }
+    let ticks = TIME_SLICE_MS * tsc_khz();
+    TIME_SLICE_TICKS.store(ticks, Ordering::Relaxed);
+    ticks
+}
+
 /// A RunQueue implementation that uses an RBTree to efficiently sort the priority
 /// of tasks within the queue.
 #[derive(Debug, Default)]
@@ -57,6 +78,9 @@ pub struct RunQueue {
 
     /// Temporary storage for tasks which are about to be terminated
     terminated_task: Option<TaskPointer>,
+
+    /// TSC value when the current task started its time slice
+    slice_start: u64,
 }
 
 impl RunQueue {
@@ -69,6 +93,7 @@ impl RunQueue {
             current_task: None,
             idle_task: OnceCell::new(),
             terminated_task: None,
+            slice_start: 0,
         }
     }
 
@@ -111,6 +136,7 @@ impl RunQueue {
     pub fn schedule_init(&mut self) -> TaskPointer {
         let task = self.get_next_task();
         self.current_task = Some(task.clone());
+        self.slice_start = rdtsc();
         task
     }
 
@@ -137,6 +163,7 @@ impl RunQueue {
         // Get next task and update current_task state
         let next = self.get_next_task();
         self.current_task = Some(next.clone());
+        self.slice_start = rdtsc();
 
         // Check if task switch is needed
         if current != next {
@@ -146,6 +173,12 @@ impl RunQueue {
         }
     }
 
+    /// Returns whether the current task has used up its time slice while
+    /// other tasks are waiting to run.
+    pub fn need_resched(&self) -> bool {
+        !self.run_list.is_empty() && rdtsc().wrapping_sub(self.slice_start) >= time_slice_ticks()
+    }
+
     pub fn current_task_id(&self) -> u32 {
         self.current_task
             .as_ref()
@@ -352,6 +385,18 @@ pub fn schedule() {
     let _ = this_cpu().runqueue().borrow_mut().terminated_task.take();
 }
 
+/// Yields the CPU if the current task has used up its time slice and other
+/// tasks are runnable on this CPU. Long-running work calls this between
+/// units of work so that it cannot starve request processing on the same
+/// CPU. It must not be called while holding a lock that another task on
+/// this CPU may take.
+pub fn preemption_point() {
+    let resched = this_cpu().runqueue().borrow().need_resched();
+    if resched {
+        schedule();
+    }
+}
+
 pub fn schedule_task(task: TaskPointer) {
     task.set_task_running();
     this_cpu().runqueue().borrow_mut().handle_task(task);
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Fast checksums for integrity checks where a cryptographic digest is not
+//! needed.
+//!
+//! [`Crc32c`] uses the SSE4.2 `crc32` instruction when the CPU supports it
+//! and a table-driven implementation otherwise. [`Adler32`] is implemented
+//! in software only.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Reflected CRC32C (Castagnoli) polynomial.
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const HW_UNKNOWN: u8 = 0;
+const HW_ABSENT: u8 = 1;
+const HW_PRESENT: u8 = 2;
+
+/// Whether the CPU supports the SSE4.2 `crc32` instruction.
+static CRC32C_HW: AtomicU8 = AtomicU8::new(HW_UNKNOWN);
+
+fn crc32c_hw_available() -> bool {
+    match CRC32C_HW.load(Ordering::Relaxed) {
+        HW_PRESENT => true,
+        HW_ABSENT => false,
+        _ => {
+            let present = detect_sse42();
+            let state = if present { HW_PRESENT } else { HW_ABSENT };
+            CRC32C_HW.store(state, Ordering::Relaxed);
+            present
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn detect_sse42() -> bool {
+    crate::cpu::features::cpu_has_sse42()
+}
+
+// The CPUID page is not available in unit tests.
+#[cfg(test)]
+fn detect_sse42() -> bool {
+    false
+}
+
+fn crc32c_sw(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// # Safety
+///
+/// The CPU must support SSE4.2.
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_hw(crc: u32, data: &[u8]) -> u32 {
+    use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut crc = u64::from(crc);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    crc
+}
+
+/// Incremental CRC32C (Castagnoli) checksum.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32c {
+    state: u32,
+}
+
+impl Crc32c {
+    pub const fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    /// Feeds `data` into the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = if crc32c_hw_available() {
+            // SAFETY: SSE4.2 support was checked above.
+            unsafe { crc32c_hw(self.state, data) }
+        } else {
+            crc32c_sw(self.state, data)
+        };
+    }
+
+    /// Returns the checksum of all data fed so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the CRC32C checksum of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Largest prime below 2^16.
+const ADLER_MOD: u32 = 65521;
+/// Number of bytes which can be summed before the sums have to be reduced
+/// to avoid overflowing 32 bits.
+const ADLER_NMAX: usize = 5552;
+
+/// Incremental Adler-32 checksum.
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    pub const fn new() -> Self {
+        Self { a: 1, b: 0 }
+    }
+
+    /// Feeds `data` into the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER_NMAX) {
+            for &byte in chunk {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    /// Returns the checksum of all data fed so far.
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the Adler-32 checksum of `data`.
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(data);
+    adler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8a91_36aa);
+
+        let mut crc = Crc32c::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_crc32c_hw() {
+        // SAFETY: CPUID is available on all x86-64 CPUs.
+        let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+        if leaf.ecx & (1 << 20) == 0 {
+            return;
+        }
+        let data: [u8; 100] = core::array::from_fn(|i| i as u8);
+        for len in 0..data.len() {
+            // SAFETY: SSE4.2 support was checked above.
+            let hw = unsafe { crc32c_hw(!0, &data[..len]) };
+            assert_eq!(hw, crc32c_sw(!0, &data[..len]));
+        }
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[0xffu8; 10000]), adler32_naive(&[0xffu8; 10000]));
+    }
+
+    fn adler32_naive(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + u32::from(byte)) % ADLER_MOD;
+            b = (b + a) % ADLER_MOD;
+        }
+        (b << 16) | a
+    }
+}
@@ -9,10 +9,14 @@
 
 /// TPM 2.0 Reference Implementation by Microsoft
 pub mod mstpm;
+pub mod restore;
+
+extern crate alloc;
 
 use crate::vtpm::mstpm::MsTpm as Vtpm;
 use crate::{locking::LockGuard, protocols::vtpm::TpmPlatformCommand};
 use crate::{locking::SpinLock, protocols::errors::SvsmReqError};
+use alloc::vec::Vec;
 
 /// Basic services required to perform the VTPM Protocol
 pub trait VtpmProtocolInterface {
@@ -66,6 +70,14 @@ pub trait VtpmInterface: MsTpmSimulatorInterface {
     /// Prepare the TPM to be used for the first time. At this stage,
     /// the TPM is manufactured.
     fn init(&mut self) -> Result<(), SvsmReqError>;
+
+    /// Returns a copy of the NV memory of the TPM.
+    fn read_nv(&self) -> Result<Vec<u8>, SvsmReqError>;
+
+    /// Replaces the NV memory of the TPM with `nv`, as returned by
+    /// [`VtpmInterface::read_nv()`]. The TPM picks up the new contents on
+    /// its next reset.
+    fn write_nv(&mut self, nv: &[u8]) -> Result<(), SvsmReqError>;
 }
 
 static VTPM: SpinLock<Vtpm> = SpinLock::new(Vtpm::new());
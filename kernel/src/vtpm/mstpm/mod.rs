@@ -17,11 +17,14 @@ use alloc::vec::Vec;
 use core::{ffi::c_void, ptr::addr_of_mut};
 use libmstpm::bindings::{
     TPM_Manufacture, TPM_TearDown, _plat__LocalitySet, _plat__NVDisable, _plat__NVEnable,
-    _plat__RunCommand, _plat__SetNvAvail, _plat__Signal_PowerOn, _plat__Signal_Reset,
+    _plat__NvCommit, _plat__NvMemoryRead, _plat__NvMemoryWrite, _plat__RunCommand,
+    _plat__SetNvAvail, _plat__Signal_PowerOn, _plat__Signal_Reset,
 };
 
 use crate::{
     address::VirtAddr,
+    error::SvsmError,
+    mm::alloc::AllocError,
     protocols::{errors::SvsmReqError, vtpm::TpmPlatformCommand},
     types::PAGE_SIZE,
     vtpm::{MsTpmSimulatorInterface, VtpmInterface, VtpmProtocolInterface},
@@ -76,6 +79,10 @@ impl VtpmProtocolInterface for MsTpm {
 
 pub const TPM_BUFFER_MAX_SIZE: usize = PAGE_SIZE;
 
+/// Size of the TPM NV memory, `NV_MEMORY_SIZE` in the `TpmProfile.h` of the
+/// reference implementation.
+const TPM_NV_MEMORY_SIZE: usize = 0x4000;
+
 impl MsTpmSimulatorInterface for MsTpm {
     fn send_tpm_command(
         &self,
@@ -188,4 +195,48 @@ impl VtpmInterface for MsTpm {
 
         Ok(())
     }
+
+    fn read_nv(&self) -> Result<Vec<u8>, SvsmReqError> {
+        if !self.is_powered_on {
+            return Err(SvsmReqError::invalid_request());
+        }
+        let mut nv = Vec::new();
+        nv.try_reserve_exact(TPM_NV_MEMORY_SIZE)
+            .map_err(|_| SvsmReqError::from(SvsmError::Alloc(AllocError::OutOfMemory)))?;
+        nv.resize(TPM_NV_MEMORY_SIZE, 0);
+        let ok = unsafe {
+            _plat__NvMemoryRead(
+                0,
+                TPM_NV_MEMORY_SIZE as u32,
+                nv.as_mut_ptr().cast::<c_void>(),
+            )
+        };
+        if ok == 0 {
+            log::error!("Failed to read the TPM NV memory");
+            return Err(SvsmReqError::incomplete());
+        }
+        Ok(nv)
+    }
+
+    fn write_nv(&mut self, nv: &[u8]) -> Result<(), SvsmReqError> {
+        if !self.is_powered_on {
+            return Err(SvsmReqError::invalid_request());
+        }
+        if nv.len() != TPM_NV_MEMORY_SIZE {
+            return Err(SvsmReqError::invalid_parameter());
+        }
+        let mut nv = nv.to_vec();
+        let ok = unsafe {
+            _plat__NvMemoryWrite(
+                0,
+                TPM_NV_MEMORY_SIZE as u32,
+                nv.as_mut_ptr().cast::<c_void>(),
+            )
+        };
+        if ok == 0 || unsafe { _plat__NvCommit() } != 0 {
+            log::error!("Failed to write the TPM NV memory");
+            return Err(SvsmReqError::incomplete());
+        }
+        Ok(())
+    }
 }
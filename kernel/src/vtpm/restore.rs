@@ -0,0 +1,237 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Handling of the vTPM state when a restore rewinds guest memory.
+//!
+//! The PCRs of the vTPM reflect what the guest measured so far. After a
+//! restore the guest continues from an earlier point, so PCRs extended in
+//! between would no longer match its event log. Depending on the
+//! [`VtpmRestorePolicy`] from the IGVM parameters, the vTPM is either
+//!
+//! * rewound together with guest memory: taking a backup saves the PCRs
+//!   into TPM NV memory with `TPM2_Shutdown(TPM_SU_STATE)` and keeps a copy
+//!   of NV memory. A restore writes the copy back, resets the TPM and
+//!   resumes it with `TPM2_Startup(TPM_SU_STATE)`, or
+//! * reset: a restore resets the TPM, starts it with cleared PCRs and
+//!   extends [`RESTORE_EVENT_PCR`] with a restore event, so a verifier can
+//!   tell that the PCRs were reset by a restore and not by a reboot.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootlib::igvm_params::{IGVM_VTPM_RESTORE_RESET, IGVM_VTPM_RESTORE_SNAPSHOT};
+
+use crate::crypto::digest::{Sha256, Sha256Trait, SHA256_SIZE};
+use crate::locking::SpinLock;
+use crate::protocols::errors::SvsmReqError;
+use crate::vtpm::mstpm::TPM_BUFFER_MAX_SIZE;
+use crate::vtpm::{vtpm_get_locked, MsTpmSimulatorInterface, VtpmInterface};
+
+/// PCR extended with [`RESTORE_EVENT`] when the vTPM is reset on restore.
+pub const RESTORE_EVENT_PCR: u32 = 23;
+/// Event data whose SHA-256 digest is extended into [`RESTORE_EVENT_PCR`].
+const RESTORE_EVENT: &[u8] = b"COCONUT-SVSM snapshot restore";
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_PCR_EXTEND: u32 = 0x0182;
+const TPM_CC_STARTUP: u32 = 0x0144;
+const TPM_CC_SHUTDOWN: u32 = 0x0145;
+const TPM_SU_CLEAR: u16 = 0x0000;
+const TPM_SU_STATE: u16 = 0x0001;
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_ALG_SHA256: u16 = 0x000b;
+/// Size of a command or response header: tag, size and command or
+/// response code.
+const TPM_HEADER_SIZE: usize = 10;
+
+/// How the vTPM state is handled when a restore rewinds guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VtpmRestorePolicy {
+    /// Reset the vTPM and record the restore as an event.
+    Reset,
+    /// Rewind the vTPM state together with guest memory.
+    Snapshot,
+}
+
+static RESTORE_POLICY: SpinLock<VtpmRestorePolicy> = SpinLock::new(VtpmRestorePolicy::Reset);
+/// NV memory of the vTPM saved with the backup.
+static SAVED_NV: SpinLock<Option<Vec<u8>>> = SpinLock::new(None);
+
+/// Sets the restore policy from its `IGVM_VTPM_RESTORE_*` value.
+pub fn set_vtpm_restore_policy(value: u32) {
+    let policy = match value {
+        IGVM_VTPM_RESTORE_RESET => VtpmRestorePolicy::Reset,
+        IGVM_VTPM_RESTORE_SNAPSHOT => VtpmRestorePolicy::Snapshot,
+        _ => {
+            log::warn!(
+                "Unknown vTPM restore policy {}, resetting on restore",
+                value
+            );
+            VtpmRestorePolicy::Reset
+        }
+    };
+    log::info!("vTPM restore policy: {:?}", policy);
+    *RESTORE_POLICY.lock() = policy;
+}
+
+/// Returns the policy the stored backup will be restored with.
+pub fn vtpm_backup_policy() -> VtpmRestorePolicy {
+    if SAVED_NV.lock().is_some() {
+        VtpmRestorePolicy::Snapshot
+    } else {
+        VtpmRestorePolicy::Reset
+    }
+}
+
+fn command(tag: u16, code: u32, params: &[u8]) -> Vec<u8> {
+    let size = (TPM_HEADER_SIZE + params.len()) as u32;
+    let mut cmd = Vec::with_capacity(size as usize);
+    cmd.extend_from_slice(&tag.to_be_bytes());
+    cmd.extend_from_slice(&size.to_be_bytes());
+    cmd.extend_from_slice(&code.to_be_bytes());
+    cmd.extend_from_slice(params);
+    cmd
+}
+
+/// Builds a `TPM2_Startup` or `TPM2_Shutdown` command of type `su`.
+fn su_command(code: u32, su: u16) -> Vec<u8> {
+    command(TPM_ST_NO_SESSIONS, code, &su.to_be_bytes())
+}
+
+/// Runs a TPM command at locality 0 and fails if the TPM does not report
+/// success.
+fn run_command<T: MsTpmSimulatorInterface>(vtpm: &T, cmd: &[u8]) -> Result<(), SvsmReqError> {
+    let mut buffer = alloc::vec![0u8; TPM_BUFFER_MAX_SIZE];
+    buffer[..cmd.len()].copy_from_slice(cmd);
+    let mut length = cmd.len();
+    vtpm.send_tpm_command(&mut buffer, &mut length, 0)?;
+    if length < TPM_HEADER_SIZE {
+        return Err(SvsmReqError::incomplete());
+    }
+    let rc = u32::from_be_bytes(buffer[6..10].try_into().unwrap());
+    if rc != 0 {
+        log::error!(
+            "vTPM command {:#x} failed rc={:#x}",
+            u32::from_be_bytes(cmd[6..10].try_into().unwrap()),
+            rc
+        );
+        return Err(SvsmReqError::incomplete());
+    }
+    Ok(())
+}
+
+fn extend_pcr<T: MsTpmSimulatorInterface>(
+    vtpm: &T,
+    pcr: u32,
+    digest: &[u8; SHA256_SIZE],
+) -> Result<(), SvsmReqError> {
+    let mut params = Vec::new();
+    params.extend_from_slice(&pcr.to_be_bytes());
+    // Empty password authorization session.
+    params.extend_from_slice(&9u32.to_be_bytes());
+    params.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+    params.extend_from_slice(&0u16.to_be_bytes());
+    params.push(0);
+    params.extend_from_slice(&0u16.to_be_bytes());
+    // A single SHA-256 digest.
+    params.extend_from_slice(&1u32.to_be_bytes());
+    params.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+    params.extend_from_slice(digest);
+    run_command(vtpm, &command(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND, &params))
+}
+
+/// Has the TPM save its volatile state, including the PCRs, into NV memory
+/// and returns a copy of NV memory. The TPM no longer accepts commands
+/// after `TPM2_Shutdown`, so it is resumed from the saved state right away
+/// and keeps operating as before.
+fn save_state<T: VtpmInterface>(vtpm: &mut T) -> Result<Vec<u8>, SvsmReqError> {
+    run_command(vtpm, &su_command(TPM_CC_SHUTDOWN, TPM_SU_STATE))?;
+    let nv = vtpm.read_nv()?;
+    vtpm.signal_poweron(true)?;
+    run_command(vtpm, &su_command(TPM_CC_STARTUP, TPM_SU_STATE))?;
+    Ok(nv)
+}
+
+/// Saves the vTPM state with a new backup if it is to be rewound on
+/// restore.
+pub fn vtpm_snapshot() -> Result<(), SvsmReqError> {
+    if *RESTORE_POLICY.lock() != VtpmRestorePolicy::Snapshot {
+        return Ok(());
+    }
+    let mut vtpm = vtpm_get_locked();
+    if !vtpm.is_powered_on() {
+        return Ok(());
+    }
+    let nv = save_state(&mut *vtpm)?;
+    *SAVED_NV.lock() = Some(nv);
+    Ok(())
+}
+
+/// Drops the vTPM state saved with a discarded backup.
+pub fn vtpm_discard_snapshot() {
+    SAVED_NV.lock().take();
+}
+
+/// Takes the vTPM state saved with the backup, e.g. to keep it with a
+/// snapshot which is no longer the current one.
+pub fn vtpm_take_snapshot() -> Option<Vec<u8>> {
+    SAVED_NV.lock().take()
+}
+
+/// Makes `nv` the vTPM state saved with the backup.
+pub fn vtpm_put_snapshot(nv: Option<Vec<u8>>) {
+    *SAVED_NV.lock() = nv;
+}
+
+/// Returns the vTPM state to be moved to another host, or `None` if the
+/// vTPM is not powered on. The vTPM keeps running on this host.
+pub fn vtpm_migration_state() -> Result<Option<Vec<u8>>, SvsmReqError> {
+    let mut vtpm = vtpm_get_locked();
+    if !vtpm.is_powered_on() {
+        return Ok(None);
+    }
+    Ok(Some(save_state(&mut *vtpm)?))
+}
+
+/// Replaces the vTPM state with `nv`, as returned by
+/// [`vtpm_migration_state`] on another host, and resumes the TPM with it.
+pub fn vtpm_load_migration_state(nv: &[u8]) -> Result<(), SvsmReqError> {
+    let mut vtpm = vtpm_get_locked();
+    vtpm.write_nv(nv)?;
+    vtpm.signal_poweron(true)?;
+    run_command(&*vtpm, &su_command(TPM_CC_STARTUP, TPM_SU_STATE))?;
+    log::info!("vTPM state loaded from migration");
+    Ok(())
+}
+
+/// Brings the vTPM in line with guest memory after a restore and returns
+/// the policy which was applied. Without saved state, e.g. after importing
+/// a snapshot, the vTPM is reset.
+pub fn vtpm_restore() -> Result<VtpmRestorePolicy, SvsmReqError> {
+    let mut vtpm = vtpm_get_locked();
+    if !vtpm.is_powered_on() {
+        return Ok(VtpmRestorePolicy::Reset);
+    }
+
+    if let Some(nv) = SAVED_NV.lock().as_deref() {
+        vtpm.write_nv(nv)?;
+        vtpm.signal_poweron(true)?;
+        run_command(&*vtpm, &su_command(TPM_CC_STARTUP, TPM_SU_STATE))?;
+        log::info!("vTPM state rewound with guest memory");
+        return Ok(VtpmRestorePolicy::Snapshot);
+    }
+
+    if *RESTORE_POLICY.lock() == VtpmRestorePolicy::Snapshot {
+        log::warn!("No vTPM state saved with the backup, resetting the vTPM");
+    }
+    vtpm.signal_poweron(true)?;
+    run_command(&*vtpm, &su_command(TPM_CC_STARTUP, TPM_SU_CLEAR))?;
+    let mut hash = Sha256::new();
+    hash.update(RESTORE_EVENT);
+    extend_pcr(&*vtpm, RESTORE_EVENT_PCR, &hash.finalize())?;
+    log::info!(
+        "vTPM reset on restore, restore event extended into PCR {}",
+        RESTORE_EVENT_PCR
+    );
+    Ok(VtpmRestorePolicy::Reset)
+}
@@ -14,6 +14,9 @@ int  _plat__Signal_PowerOn(void);
 int  _plat__Signal_Reset(void);
 void _plat__NVDisable(int delete);
 int  _plat__NVEnable(void *platParameter);
+int  _plat__NvMemoryRead(unsigned int startOffset, unsigned int size, void *data);
+int  _plat__NvMemoryWrite(unsigned int startOffset, unsigned int size, void *data);
+int  _plat__NvCommit(void);
 
 int  TPM_Manufacture(int firstTime);
 int  TPM_TearDown(void);
\ No newline at end of file
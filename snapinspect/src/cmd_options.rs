@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+pub struct CmdOptions {
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Print the header and section table of a snapshot container.
+    Info {
+        /// The filename of the snapshot container
+        #[arg()]
+        input: String,
+    },
+    /// List the extents of a snapshot container.
+    Extents {
+        /// The filename of the snapshot container
+        #[arg()]
+        input: String,
+    },
+    /// Check that all extents and sections of a snapshot container are
+    /// well-formed and print the snapshot measurement.
+    Verify {
+        /// The filename of the snapshot container
+        #[arg()]
+        input: String,
+
+        /// Expected snapshot measurement as a hex string. Verification fails
+        /// if the measurement of the container differs.
+        #[arg(long)]
+        measurement: Option<String>,
+    },
+    /// Print the statistics recorded in a snapshot container as one
+    /// `name=value` pair per line.
+    Stats {
+        /// The filename of the snapshot container
+        #[arg()]
+        input: String,
+    },
+    /// Compare the guest memory restored by two snapshot containers page by
+    /// page.
+    Diff {
+        /// The filename of the first snapshot container
+        #[arg()]
+        first: String,
+
+        /// The filename of the second snapshot container
+        #[arg()]
+        second: String,
+    },
+}
@@ -0,0 +1,276 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use sha2::{Digest, Sha256};
+use snapshot::{
+    section_flags, section_kind, Container, DigestAlgorithm, Extent, ExtentKind, FormatError,
+    DIGEST_SIZE,
+};
+
+/// Contents of a single guest page in a snapshot. Pages of compressed
+/// payload sections are decoded into a copy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Page<'a> {
+    Zero,
+    Data(Cow<'a, [u8]>),
+}
+
+impl Page<'_> {
+    fn is_zero(&self) -> bool {
+        match self {
+            Page::Zero => true,
+            Page::Data(data) => data.iter().all(|&b| b == 0),
+        }
+    }
+
+    /// Returns whether both pages restore the same guest memory contents.
+    pub fn same_contents(&self, other: &Page<'_>) -> bool {
+        match (self, other) {
+            (Page::Data(a), Page::Data(b)) => a == b,
+            _ => self.is_zero() && other.is_zero(),
+        }
+    }
+}
+
+pub fn format_error(path: &str, err: FormatError) -> Box<dyn Error> {
+    format!("{}: {}", path, err).into()
+}
+
+/// Returns the name of a section kind.
+pub fn section_kind_name(kind: u16) -> &'static str {
+    match kind {
+        section_kind::PAYLOAD => "payload",
+        section_kind::CPUID => "cpuid",
+        section_kind::VTPM_POLICY => "vtpm-policy",
+        section_kind::STATISTICS => "statistics",
+        _ => "unknown",
+    }
+}
+
+/// Returns the guest physical address of page `page` of an extent.
+fn page_gpa(container: &Container<'_>, extent: &Extent, page: u64) -> Result<u64, FormatError> {
+    page.checked_mul(u64::from(container.header().page_size))
+        .and_then(|offset| extent.gpa.checked_add(offset))
+        .ok_or(FormatError::OutOfBounds)
+}
+
+/// Returns the contents of page `page` of an extent.
+fn page<'a>(
+    container: &Container<'a>,
+    extent: &Extent,
+    page: u64,
+) -> Result<Page<'a>, FormatError> {
+    match extent.kind {
+        ExtentKind::Zero => Ok(Page::Zero),
+        ExtentKind::Data => match container.page_data(extent, page) {
+            Err(FormatError::EncodedSection(section_flags::COMPRESSED)) => {
+                let mut data = vec![0u8; container.header().page_size as usize];
+                container.read_page(extent, page, &mut data)?;
+                Ok(Page::Data(Cow::Owned(data)))
+            }
+            data => data.map(|data| Page::Data(Cow::Borrowed(data))),
+        },
+    }
+}
+
+/// Computes the snapshot measurement as defined by the container format.
+pub fn measurement(container: &Container<'_>) -> Result<[u8; DIGEST_SIZE], FormatError> {
+    let mut hasher = Sha256::new();
+    let mut page = vec![0u8; container.header().page_size as usize];
+    for extent in container.extents() {
+        let extent = extent?;
+        hasher.update(extent.measurement_header());
+        if extent.kind == ExtentKind::Data {
+            for i in 0..extent.page_count {
+                container.read_page(&extent, i, &mut page)?;
+                hasher.update(&page);
+            }
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Checks the tables of a container and returns a description of every
+/// problem found.
+pub fn verify(container: &Container<'_>) -> Result<Vec<String>, FormatError> {
+    let mut problems = Vec::new();
+
+    for (index, section) in container.sections().enumerate() {
+        let section = section?;
+        match container.verify_section(&section) {
+            Ok(()) => {}
+            Err(FormatError::DigestMismatch) => {
+                problems.push(format!("section {}: contents do not match digest", index));
+            }
+            Err(_) => problems.push(format!(
+                "section {}: contents outside of the container",
+                index
+            )),
+        }
+        if container.header().digest_alg == DigestAlgorithm::None
+            && section.digest != [0; DIGEST_SIZE]
+        {
+            problems.push(format!(
+                "section {}: digest set without digest algorithm",
+                index
+            ));
+        }
+    }
+
+    let mut end = 0u64;
+    for (index, extent) in container.extents().enumerate() {
+        let extent = extent?;
+        if extent.gpa < end {
+            problems.push(format!(
+                "extent {}: {:#x} overlaps or is not sorted after the previous extent",
+                index, extent.gpa
+            ));
+        }
+        let last = extent
+            .page_count
+            .checked_sub(1)
+            .map(|last| page_gpa(container, &extent, last));
+        match last {
+            None => problems.push(format!("extent {}: no pages", index)),
+            Some(Err(_)) => problems.push(format!("extent {}: address overflow", index)),
+            Some(Ok(last)) => end = last + u64::from(container.header().page_size),
+        }
+
+        if extent.kind != ExtentKind::Data {
+            continue;
+        }
+        match container.section(u32::from(extent.section)) {
+            Ok(section) if !section.is_payload() => {
+                problems.push(format!(
+                    "extent {}: section {} is not a payload section",
+                    index, extent.section
+                ));
+            }
+            Ok(section) if section.flags & section_flags::ENCRYPTED != 0 => {
+                problems.push(format!(
+                    "extent {}: section {} is encrypted, contents not checked",
+                    index, extent.section
+                ));
+            }
+            Ok(_) => {
+                let mut page = vec![0u8; container.header().page_size as usize];
+                if extent.page_count != 0
+                    && container
+                        .read_page(&extent, extent.page_count - 1, &mut page)
+                        .is_err()
+                {
+                    problems.push(format!(
+                        "extent {}: payload outside of section {}",
+                        index, extent.section
+                    ));
+                }
+            }
+            Err(e) => problems.push(format!("extent {}: {}", index, e)),
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Returns the contents of all guest pages of a container by address.
+pub fn pages<'a>(container: &Container<'a>) -> Result<BTreeMap<u64, Page<'a>>, FormatError> {
+    let mut map = BTreeMap::new();
+    for extent in container.extents() {
+        let extent = extent?;
+        for i in 0..extent.page_count {
+            map.insert(
+                page_gpa(container, &extent, i)?,
+                page(container, &extent, i)?,
+            );
+        }
+    }
+    Ok(map)
+}
+
+/// Differences between the guest memory restored by two snapshots.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PageDiff {
+    /// Pages only present in the first snapshot.
+    pub removed: Vec<u64>,
+    /// Pages only present in the second snapshot.
+    pub added: Vec<u64>,
+    /// Pages present in both snapshots with different contents.
+    pub changed: Vec<u64>,
+}
+
+/// Compares the pages of two snapshots.
+pub fn diff(first: &BTreeMap<u64, Page<'_>>, second: &BTreeMap<u64, Page<'_>>) -> PageDiff {
+    let mut result = PageDiff::default();
+    for (gpa, page) in first.iter() {
+        match second.get(gpa) {
+            None => result.removed.push(*gpa),
+            Some(other) if !page.same_contents(other) => result.changed.push(*gpa),
+            Some(_) => {}
+        }
+    }
+    result.added = second
+        .keys()
+        .filter(|gpa| !first.contains_key(gpa))
+        .copied()
+        .collect();
+    result
+}
+
+/// Merges sorted page addresses into runs of contiguous pages, returned as
+/// pairs of start address and page count.
+pub fn runs(addrs: &[u64], page_size: u64) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for &gpa in addrs {
+        match runs.last_mut() {
+            Some((start, count)) if *start + *count * page_size == gpa => *count += 1,
+            _ => runs.push((gpa, 1)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = 4096;
+
+    #[test]
+    fn diff_compares_contents() {
+        let a = [1u8; PAGE as usize];
+        let b = [2u8; PAGE as usize];
+        let zero = [0u8; PAGE as usize];
+
+        let first = BTreeMap::from([
+            (0x1000, Page::Data(Cow::Borrowed(&a[..]))),
+            (0x2000, Page::Zero),
+            (0x3000, Page::Data(Cow::Borrowed(&a[..]))),
+        ]);
+        let second = BTreeMap::from([
+            (0x1000, Page::Data(Cow::Borrowed(&b[..]))),
+            (0x2000, Page::Data(Cow::Borrowed(&zero[..]))),
+            (0x4000, Page::Zero),
+        ]);
+
+        assert_eq!(
+            diff(&first, &second),
+            PageDiff {
+                removed: vec![0x3000],
+                added: vec![0x4000],
+                changed: vec![0x1000],
+            }
+        );
+    }
+
+    #[test]
+    fn runs_merge_contiguous_pages() {
+        let addrs = [0x1000, 0x2000, 0x3000, 0x5000, 0x7000, 0x8000];
+        assert_eq!(
+            runs(&addrs, PAGE),
+            vec![(0x1000, 3), (0x5000, 1), (0x7000, 2)]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Host-side inspection of snapshot containers exported by the SVSM.
+
+use std::error::Error;
+use std::fs;
+
+use clap::Parser;
+use cmd_options::{CmdOptions, Commands};
+use container::{diff, format_error, measurement, pages, runs, section_kind_name, verify};
+use snapshot::{section_kind, Container, ExtentKind, SnapshotStats};
+
+mod cmd_options;
+mod container;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = CmdOptions::parse();
+
+    match options.command {
+        Commands::Info { input } => info_command(&input),
+        Commands::Extents { input } => extents_command(&input),
+        Commands::Verify { input, measurement } => verify_command(&input, measurement.as_deref()),
+        Commands::Stats { input } => stats_command(&input),
+        Commands::Diff { first, second } => diff_command(&first, &second),
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    fs::read(path).map_err(|e| {
+        eprintln!("Failed to open snapshot file {}", path);
+        e.into()
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn info_command(path: &str) -> Result<(), Box<dyn Error>> {
+    let data = read_file(path)?;
+    let container = Container::parse(&data).map_err(|e| format_error(path, e))?;
+    let header = container.header();
+
+    println!(
+        "Format version:    {}.{}",
+        header.version_major, header.version_minor
+    );
+    println!("Total size:        {:#x}", header.total_size);
+    println!("Page size:         {:#x}", header.page_size);
+    println!("Digest algorithm:  {:?}", header.digest_alg);
+    println!("Extents:           {}", header.extent_count);
+    println!("Sections:          {}", header.section_count);
+
+    for (index, section) in container.sections().enumerate() {
+        let section = section.map_err(|e| format_error(path, e))?;
+        println!(
+            "  [{}] {:<8} flags {:#06x} offset {:#x} length {:#x}",
+            index,
+            section_kind_name(section.kind),
+            section.flags,
+            section.offset,
+            section.length
+        );
+    }
+    Ok(())
+}
+
+fn extents_command(path: &str) -> Result<(), Box<dyn Error>> {
+    let data = read_file(path)?;
+    let container = Container::parse(&data).map_err(|e| format_error(path, e))?;
+
+    for (index, extent) in container.extents().enumerate() {
+        let extent = extent.map_err(|e| format_error(path, e))?;
+        match extent.kind {
+            ExtentKind::Data => println!(
+                "  [{}] {:#018x} {:>8} pages data  section {} offset {:#x}",
+                index, extent.gpa, extent.page_count, extent.section, extent.payload_offset
+            ),
+            ExtentKind::Zero => println!(
+                "  [{}] {:#018x} {:>8} pages zero",
+                index, extent.gpa, extent.page_count
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn verify_command(path: &str, expected: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let data = read_file(path)?;
+    let container = Container::parse(&data).map_err(|e| format_error(path, e))?;
+
+    let problems = verify(&container).map_err(|e| format_error(path, e))?;
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(format!("{}: {} problem(s) found", path, problems.len()).into());
+    }
+
+    let digest = hex(&measurement(&container).map_err(|e| format_error(path, e))?);
+    println!("Measurement: {}", digest);
+    if let Some(expected) = expected {
+        if !expected.eq_ignore_ascii_case(&digest) {
+            return Err(format!("{}: measurement does not match {}", path, expected).into());
+        }
+        println!("Measurement matches");
+    }
+    Ok(())
+}
+
+/// Returns `num / den` for printing, or zero if `den` is zero.
+fn ratio(num: u64, den: u64) -> f64 {
+    if den == 0 {
+        0.0
+    } else {
+        num as f64 / den as f64
+    }
+}
+
+fn stats_command(path: &str) -> Result<(), Box<dyn Error>> {
+    let data = read_file(path)?;
+    let container = Container::parse(&data).map_err(|e| format_error(path, e))?;
+
+    let mut found = None;
+    for section in container.sections() {
+        let section = section.map_err(|e| format_error(path, e))?;
+        if section.kind == section_kind::STATISTICS {
+            found = Some(section);
+        }
+    }
+    let Some(section) = found else {
+        return Err(format!("{}: no statistics recorded", path).into());
+    };
+    let bytes = container
+        .section_data(&section)
+        .map_err(|e| format_error(path, e))?;
+    let stats = SnapshotStats::from_bytes(bytes).map_err(|e| format_error(path, e))?;
+
+    println!("creation_ns={}", stats.creation_ns);
+    println!("cow_faults={}", stats.cow_faults);
+    println!("logical_pages={}", stats.logical_pages);
+    println!("stored_pages={}", stats.stored_pages);
+    println!("payload_bytes={}", stats.payload_bytes);
+    println!("stored_bytes={}", stats.stored_bytes);
+    println!(
+        "dedup_ratio={:.3}",
+        ratio(stats.logical_pages, stats.stored_pages)
+    );
+    println!(
+        "compression_ratio={:.3}",
+        ratio(stats.payload_bytes, stats.stored_bytes)
+    );
+    Ok(())
+}
+
+fn diff_command(first: &str, second: &str) -> Result<(), Box<dyn Error>> {
+    let first_data = read_file(first)?;
+    let second_data = read_file(second)?;
+    let first_container = Container::parse(&first_data).map_err(|e| format_error(first, e))?;
+    let second_container = Container::parse(&second_data).map_err(|e| format_error(second, e))?;
+
+    let page_size = first_container.header().page_size;
+    if second_container.header().page_size != page_size {
+        return Err("Snapshots use different page sizes".into());
+    }
+
+    let first_pages = pages(&first_container).map_err(|e| format_error(first, e))?;
+    let second_pages = pages(&second_container).map_err(|e| format_error(second, e))?;
+    let result = diff(&first_pages, &second_pages);
+
+    let page_size = u64::from(page_size);
+    for (label, addrs) in [
+        ("only in first ", &result.removed),
+        ("only in second", &result.added),
+        ("changed       ", &result.changed),
+    ] {
+        for (start, count) in runs(addrs, page_size) {
+            println!(
+                "{} {:#018x}-{:#018x} ({} pages)",
+                label,
+                start,
+                start + count * page_size,
+                count
+            );
+        }
+    }
+    println!(
+        "{} page(s) only in first, {} only in second, {} changed",
+        result.removed.len(),
+        result.added.len(),
+        result.changed.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,1179 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! This crate defines the versioned container format in which COCONUT-SVSM
+//! exports guest memory snapshots, so that snapshots taken by one SVSM build
+//! can be imported by another one and inspected by host tools.
+//!
+//! All integers are stored little-endian. A container consists of:
+//!
+//! * A [`SnapshotHeader`] at offset 0. Its `header_size` field records the
+//!   size of the header as written, so later minor versions can append
+//!   fields.
+//! * An extent table of `extent_count` [`Extent`] entries starting at
+//!   `extent_table_offset`, each `extent_entry_size` bytes long. Extents
+//!   describe runs of guest physical pages and are sorted by address.
+//! * A section table of `section_count` [`Section`] entries starting at
+//!   `section_table_offset`, each `section_entry_size` bytes long.
+//! * Payload sections holding the contents of data extents. Extents refer to
+//!   their payload through a section index and an offset into the
+//!   uncompressed, decrypted contents of that section.
+//!
+//! # Compatibility rules
+//!
+//! * Readers reject containers with a different major version.
+//! * Readers accept containers with a newer minor version. Newer minor
+//!   versions may only grow the header and table entries, which readers
+//!   skip using the recorded sizes, and may only add section kinds, which
+//!   readers ignore.
+//! * Readers reject extent kinds, section flags and digest algorithms they
+//!   do not know, because they cannot restore such content correctly.
+//!
+//! # Measurement
+//!
+//! The measurement of a snapshot identifies the guest memory it restores,
+//! independent of how the container is laid out. It is the SHA-256 digest
+//! of, for every extent in table order, the bytes returned by
+//! [`Extent::measurement_header`], followed by the contents of all pages of
+//! the extent if it is a data extent.
+//!
+//! # Encrypted payloads
+//!
+//! A payload section with [`section_flags::ENCRYPTED`] set starts with a
+//! random [`NONCE_PREFIX_SIZE`]-byte nonce prefix, followed by one record
+//! per page of its decrypted contents. A record is the page encrypted with
+//! AES-256-GCM without associated data, followed by the
+//! [`AUTH_TAG_SIZE`]-byte authentication tag, see
+//! [`encrypted_record_offset`]. The nonce of record `i` is the prefix
+//! followed by `i` as a `u32`, see [`encrypted_nonce`]. The key is agreed
+//! between the guest and the SVSM and is not part of the container. The
+//! measurement covers the decrypted contents. Added in minor version 4.
+//!
+//! # Compressed payloads
+//!
+//! A payload section with [`section_flags::COMPRESSED`] set holds one
+//! record per page of its uncompressed contents. It starts with a table of
+//! `n + 1` little-endian `u64` offsets from the start of the section, where
+//! `n` is the number of records, so the first entry is the end of the
+//! table. Record `i` spans from entry `i` to entry `i + 1`. A record of
+//! exactly one page holds the page as is, any other record holds the
+//! PackBits encoding of the page, see [`pack_page`] and [`unpack_record`].
+//!
+//! If [`section_flags::ENCRYPTED`] is set as well, the section starts with
+//! the nonce prefix, followed by the table. Every record is encrypted as a
+//! whole as described above, so it is followed by its authentication tag
+//! and record `i` uses nonce `i`. The table is not encrypted, but a record
+//! whose bounds were changed fails authentication. Added in minor version
+//! 6, see [`compressed_record_range`].
+//!
+//! # Section digests
+//!
+//! With [`DigestAlgorithm::Sha256`], the digest field of every section holds
+//! the SHA-256 digest of the section contents as stored in the container,
+//! i.e. after compression and encryption, so readers can detect corrupted
+//! sections before decoding them, see [`Container::verify_section`]. With
+//! [`DigestAlgorithm::None`] the digest fields are zero. Added in minor
+//! version 5.
+
+#![no_std]
+
+use core::fmt;
+use core::ops::Range;
+use sha2::{Digest, Sha256};
+
+/// Magic value at the start of every snapshot container.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"SVSMSNAP";
+
+/// Major version of the container format. Incompatible changes bump it.
+pub const FORMAT_VERSION_MAJOR: u16 = 1;
+/// Minor version of the container format. Compatible extensions bump it.
+pub const FORMAT_VERSION_MINOR: u16 = 6;
+
+/// Size of a version 1.0 header in bytes.
+pub const HEADER_SIZE: usize = 64;
+/// Size of a version 1.0 extent table entry in bytes.
+pub const EXTENT_ENTRY_SIZE: usize = 32;
+/// Size of a version 1.0 section table entry in bytes.
+pub const SECTION_ENTRY_SIZE: usize = 64;
+
+/// Size of the digest field of a section.
+pub const DIGEST_SIZE: usize = 32;
+/// Size of a version 1.3 statistics section in bytes.
+pub const STATS_SIZE: usize = 48;
+/// Size of the nonce prefix of an encrypted payload section.
+pub const NONCE_PREFIX_SIZE: usize = 8;
+/// Size of the AES-256-GCM nonce of an encrypted payload record.
+pub const NONCE_SIZE: usize = 12;
+/// Size of the authentication tag of an encrypted payload record.
+pub const AUTH_TAG_SIZE: usize = 16;
+/// Size of an entry of the record table of a compressed payload section.
+pub const RECORD_ENTRY_SIZE: usize = 8;
+
+/// Longest literal or repeated run of a PackBits block.
+const PACK_MAX_RUN: usize = 128;
+/// Shortest run which is encoded as a repeat block.
+const PACK_MIN_REPEAT: usize = 3;
+
+/// Errors reported while parsing a snapshot container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// The container is shorter than its contents claim.
+    Truncated,
+    /// The container does not start with [`SNAPSHOT_MAGIC`].
+    BadMagic,
+    /// The major version is not supported by this reader.
+    UnsupportedVersion(u16, u16),
+    /// A size recorded in the header is smaller than the minimum.
+    BadEntrySize,
+    /// The page size is not a power of two.
+    BadPageSize(u32),
+    /// The digest algorithm is unknown.
+    UnknownDigest(u16),
+    /// An extent has an unknown kind.
+    UnknownExtentKind(u16),
+    /// A section has unknown flags.
+    UnknownSectionFlags(u16),
+    /// A table entry or payload reference lies outside the container.
+    OutOfBounds,
+    /// Section contents with the given flags have to be decoded before
+    /// they can be read.
+    EncodedSection(u16),
+    /// The stored contents of a section do not match its digest.
+    DigestMismatch,
+    /// The record table of a compressed payload section is malformed.
+    BadRecordTable,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "container is truncated"),
+            Self::BadMagic => write!(f, "bad container magic"),
+            Self::UnsupportedVersion(major, minor) => {
+                write!(f, "unsupported container version {major}.{minor}")
+            }
+            Self::BadEntrySize => write!(f, "bad header or table entry size"),
+            Self::BadPageSize(size) => write!(f, "bad page size {size:#x}"),
+            Self::UnknownDigest(alg) => write!(f, "unknown digest algorithm {alg}"),
+            Self::UnknownExtentKind(kind) => write!(f, "unknown extent kind {kind}"),
+            Self::UnknownSectionFlags(flags) => write!(f, "unknown section flags {flags:#x}"),
+            Self::OutOfBounds => write!(f, "reference outside of the container"),
+            Self::EncodedSection(flags) => {
+                write!(f, "section contents with flags {flags:#x} need decoding")
+            }
+            Self::DigestMismatch => write!(f, "section contents do not match their digest"),
+            Self::BadRecordTable => write!(f, "malformed record table"),
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, val: u64) {
+    buf[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Algorithm used for the per-section digests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum DigestAlgorithm {
+    /// Sections carry no digest, the digest field is zero.
+    None = 0,
+    /// The digest field holds the SHA-256 digest of the stored section
+    /// contents. Added in minor version 5.
+    Sha256 = 1,
+}
+
+impl DigestAlgorithm {
+    /// Returns the digest of `data` as stored in the digest field of a
+    /// section.
+    pub fn digest(&self, data: &[u8]) -> [u8; DIGEST_SIZE] {
+        match self {
+            Self::None => [0; DIGEST_SIZE],
+            Self::Sha256 => Sha256::digest(data).into(),
+        }
+    }
+}
+
+impl TryFrom<u16> for DigestAlgorithm {
+    type Error = FormatError;
+
+    fn try_from(val: u16) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Sha256),
+            _ => Err(FormatError::UnknownDigest(val)),
+        }
+    }
+}
+
+/// The container header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    /// Major format version.
+    pub version_major: u16,
+    /// Minor format version.
+    pub version_minor: u16,
+    /// Size of the header in bytes.
+    pub header_size: u32,
+    /// Size of a guest page in bytes.
+    pub page_size: u32,
+    /// Algorithm of the section digests.
+    pub digest_alg: DigestAlgorithm,
+    /// Size of an extent table entry in bytes.
+    pub extent_entry_size: u16,
+    /// Size of a section table entry in bytes.
+    pub section_entry_size: u16,
+    /// Number of extent table entries.
+    pub extent_count: u32,
+    /// Number of section table entries.
+    pub section_count: u32,
+    /// Offset of the extent table from the start of the container.
+    pub extent_table_offset: u64,
+    /// Offset of the section table from the start of the container.
+    pub section_table_offset: u64,
+    /// Total size of the container in bytes.
+    pub total_size: u64,
+}
+
+impl SnapshotHeader {
+    /// Serializes the header in the current format version.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..8].copy_from_slice(&SNAPSHOT_MAGIC);
+        write_u16(&mut buf, 8, self.version_major);
+        write_u16(&mut buf, 10, self.version_minor);
+        write_u32(&mut buf, 12, self.header_size);
+        write_u32(&mut buf, 16, self.page_size);
+        write_u16(&mut buf, 20, self.digest_alg as u16);
+        write_u16(&mut buf, 22, self.extent_entry_size);
+        write_u16(&mut buf, 24, self.section_entry_size);
+        write_u32(&mut buf, 28, self.extent_count);
+        write_u32(&mut buf, 32, self.section_count);
+        write_u64(&mut buf, 40, self.extent_table_offset);
+        write_u64(&mut buf, 48, self.section_table_offset);
+        write_u64(&mut buf, 56, self.total_size);
+        buf
+    }
+
+    /// Parses and validates a header. `buf` must hold at least
+    /// [`HEADER_SIZE`] bytes, any bytes beyond are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, FormatError> {
+        let buf = buf.get(..HEADER_SIZE).ok_or(FormatError::Truncated)?;
+        if buf[0..8] != SNAPSHOT_MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+
+        let version_major = read_u16(buf, 8);
+        let version_minor = read_u16(buf, 10);
+        if version_major != FORMAT_VERSION_MAJOR {
+            return Err(FormatError::UnsupportedVersion(
+                version_major,
+                version_minor,
+            ));
+        }
+
+        let header = Self {
+            version_major,
+            version_minor,
+            header_size: read_u32(buf, 12),
+            page_size: read_u32(buf, 16),
+            digest_alg: DigestAlgorithm::try_from(read_u16(buf, 20))?,
+            extent_entry_size: read_u16(buf, 22),
+            section_entry_size: read_u16(buf, 24),
+            extent_count: read_u32(buf, 28),
+            section_count: read_u32(buf, 32),
+            extent_table_offset: read_u64(buf, 40),
+            section_table_offset: read_u64(buf, 48),
+            total_size: read_u64(buf, 56),
+        };
+
+        if (header.header_size as usize) < HEADER_SIZE
+            || usize::from(header.extent_entry_size) < EXTENT_ENTRY_SIZE
+            || usize::from(header.section_entry_size) < SECTION_ENTRY_SIZE
+        {
+            return Err(FormatError::BadEntrySize);
+        }
+        if !header.page_size.is_power_of_two() {
+            return Err(FormatError::BadPageSize(header.page_size));
+        }
+
+        Ok(header)
+    }
+
+    /// Returns the offset of the extent table entry with the given index.
+    pub fn extent_offset(&self, index: u32) -> Option<u64> {
+        let rel = u64::from(index).checked_mul(u64::from(self.extent_entry_size))?;
+        self.extent_table_offset.checked_add(rel)
+    }
+
+    /// Returns the offset of the section table entry with the given index.
+    pub fn section_offset(&self, index: u32) -> Option<u64> {
+        let rel = u64::from(index).checked_mul(u64::from(self.section_entry_size))?;
+        self.section_table_offset.checked_add(rel)
+    }
+}
+
+/// Kind of content described by an extent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ExtentKind {
+    /// The pages have contents stored in a payload section.
+    Data = 0,
+    /// The pages are all zeroes and have no payload.
+    Zero = 1,
+}
+
+impl TryFrom<u16> for ExtentKind {
+    type Error = FormatError;
+
+    fn try_from(val: u16) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Zero),
+            _ => Err(FormatError::UnknownExtentKind(val)),
+        }
+    }
+}
+
+/// A run of contiguous guest physical pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// Guest physical address of the first page.
+    pub gpa: u64,
+    /// Number of pages in the run.
+    pub page_count: u64,
+    /// Kind of the content.
+    pub kind: ExtentKind,
+    /// Index of the section holding the payload of data extents.
+    pub section: u16,
+    /// Offset of the payload within the section contents.
+    pub payload_offset: u64,
+}
+
+impl Extent {
+    /// Serializes the extent in the current format version.
+    pub fn to_bytes(&self) -> [u8; EXTENT_ENTRY_SIZE] {
+        let mut buf = [0u8; EXTENT_ENTRY_SIZE];
+        write_u64(&mut buf, 0, self.gpa);
+        write_u64(&mut buf, 8, self.page_count);
+        write_u16(&mut buf, 16, self.kind as u16);
+        write_u16(&mut buf, 18, self.section);
+        write_u64(&mut buf, 24, self.payload_offset);
+        buf
+    }
+
+    /// Returns the bytes describing this extent in the snapshot measurement:
+    /// the guest physical address, the page count and the kind, each as a
+    /// little-endian `u64`.
+    pub fn measurement_header(&self) -> [u8; 24] {
+        let mut buf = [0u8; 24];
+        write_u64(&mut buf, 0, self.gpa);
+        write_u64(&mut buf, 8, self.page_count);
+        write_u64(&mut buf, 16, self.kind as u64);
+        buf
+    }
+
+    /// Parses an extent. `buf` must hold at least [`EXTENT_ENTRY_SIZE`]
+    /// bytes, any bytes beyond are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, FormatError> {
+        let buf = buf.get(..EXTENT_ENTRY_SIZE).ok_or(FormatError::Truncated)?;
+        Ok(Self {
+            gpa: read_u64(buf, 0),
+            page_count: read_u64(buf, 8),
+            kind: ExtentKind::try_from(read_u16(buf, 16))?,
+            section: read_u16(buf, 18),
+            payload_offset: read_u64(buf, 24),
+        })
+    }
+}
+
+/// Kinds of sections defined by this format version. Unknown kinds are
+/// skipped by readers.
+pub mod section_kind {
+    /// Section holding page contents.
+    pub const PAYLOAD: u16 = 1;
+    /// Section holding the SNP CPUID page which defined the CPUID policy
+    /// visible to the guest when the snapshot was taken. Added in minor
+    /// version 1.
+    pub const CPUID: u16 = 2;
+    /// Section holding the [`vtpm_policy`](super::vtpm_policy) applied to
+    /// the vTPM when the snapshot is restored, as a `u32`. Added in minor
+    /// version 2.
+    pub const VTPM_POLICY: u16 = 3;
+    /// Section holding the [`SnapshotStats`](super::SnapshotStats) of the
+    /// snapshot. Added in minor version 3.
+    pub const STATISTICS: u16 = 4;
+}
+
+/// Handling of the vTPM state on restore, recorded in a
+/// [`section_kind::VTPM_POLICY`] section.
+pub mod vtpm_policy {
+    /// The snapshot was taken without a vTPM.
+    pub const NONE: u32 = 0;
+    /// The vTPM is reset on restore and the restore is recorded as an
+    /// event.
+    pub const RESET: u32 = 1;
+    /// The vTPM state is rewound together with guest memory.
+    pub const SNAPSHOT: u32 = 2;
+}
+
+/// Statistics about a snapshot for offline analysis, recorded in a
+/// [`section_kind::STATISTICS`] section. The values are raw counts, so
+/// readers can derive ratios without loss of precision. Later minor
+/// versions may append fields, which older readers skip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotStats {
+    /// Time it took to take the backup in nanoseconds, zero if unknown.
+    pub creation_ns: u64,
+    /// Number of copy-on-write faults resolved between the previous backup
+    /// and this one.
+    pub cow_faults: u64,
+    /// Number of guest pages restored by the snapshot.
+    pub logical_pages: u64,
+    /// Number of pages stored in the payload. Zero pages are not stored,
+    /// so `logical_pages / stored_pages` is the deduplication ratio.
+    pub stored_pages: u64,
+    /// Size of the payload before compression.
+    pub payload_bytes: u64,
+    /// Size of the payload as stored in the container, so
+    /// `payload_bytes / stored_bytes` is the compression ratio.
+    pub stored_bytes: u64,
+}
+
+impl SnapshotStats {
+    /// Serializes the statistics in the current format version.
+    pub fn to_bytes(&self) -> [u8; STATS_SIZE] {
+        let mut buf = [0u8; STATS_SIZE];
+        write_u64(&mut buf, 0, self.creation_ns);
+        write_u64(&mut buf, 8, self.cow_faults);
+        write_u64(&mut buf, 16, self.logical_pages);
+        write_u64(&mut buf, 24, self.stored_pages);
+        write_u64(&mut buf, 32, self.payload_bytes);
+        write_u64(&mut buf, 40, self.stored_bytes);
+        buf
+    }
+
+    /// Parses the statistics. `buf` must hold at least [`STATS_SIZE`]
+    /// bytes, any bytes beyond are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, FormatError> {
+        let buf = buf.get(..STATS_SIZE).ok_or(FormatError::Truncated)?;
+        Ok(Self {
+            creation_ns: read_u64(buf, 0),
+            cow_faults: read_u64(buf, 8),
+            logical_pages: read_u64(buf, 16),
+            stored_pages: read_u64(buf, 24),
+            payload_bytes: read_u64(buf, 32),
+            stored_bytes: read_u64(buf, 40),
+        })
+    }
+}
+
+/// Flags of a section.
+pub mod section_flags {
+    /// The section contents are compressed.
+    pub const COMPRESSED: u16 = 1 << 0;
+    /// The section contents are encrypted.
+    pub const ENCRYPTED: u16 = 1 << 1;
+    /// All flags known to this format version.
+    pub const KNOWN: u16 = COMPRESSED | ENCRYPTED;
+}
+
+/// Returns the offset of the record holding decrypted page `page` from the
+/// start of an encrypted payload section, or `None` on overflow.
+pub fn encrypted_record_offset(page: u64, page_size: u32) -> Option<u64> {
+    let record_size = u64::from(page_size).checked_add(AUTH_TAG_SIZE as u64)?;
+    page.checked_mul(record_size)?
+        .checked_add(NONCE_PREFIX_SIZE as u64)
+}
+
+/// Returns the stored length of an encrypted payload section holding
+/// `pages` pages, or `None` on overflow.
+pub fn encrypted_section_length(pages: u64, page_size: u32) -> Option<u64> {
+    encrypted_record_offset(pages, page_size)
+}
+
+/// Returns the nonce of record `record` of an encrypted payload section
+/// with nonce prefix `prefix`, or `None` if the index does not fit.
+pub fn encrypted_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], record: u64) -> Option<[u8; NONCE_SIZE]> {
+    let record = u32::try_from(record).ok()?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&record.to_le_bytes());
+    Some(nonce)
+}
+
+/// Returns the offset of the record table from the start of a compressed
+/// payload section with flags `flags`.
+pub fn record_table_offset(flags: u16) -> u64 {
+    if flags & section_flags::ENCRYPTED != 0 {
+        NONCE_PREFIX_SIZE as u64
+    } else {
+        0
+    }
+}
+
+/// Returns the range of record `record` relative to the start of the
+/// compressed payload section `section`, after checking it against the
+/// record table. `read_entry` returns the table entry at the given offset
+/// from the start of the section.
+pub fn compressed_record_range<F>(
+    section: &Section,
+    page_size: u32,
+    record: u64,
+    mut read_entry: F,
+) -> Result<Range<u64>, FormatError>
+where
+    F: FnMut(u64) -> Result<u64, FormatError>,
+{
+    let table = record_table_offset(section.flags);
+    let entry_size = RECORD_ENTRY_SIZE as u64;
+    let first = read_entry(table)?;
+    let records = first
+        .checked_sub(table)
+        .filter(|size| size % entry_size == 0)
+        .and_then(|size| (size / entry_size).checked_sub(1))
+        .ok_or(FormatError::BadRecordTable)?;
+    if record >= records {
+        return Err(FormatError::OutOfBounds);
+    }
+    // Both entries lie before `first`, so the offsets cannot overflow.
+    let start = read_entry(table + record * entry_size)?;
+    let end = read_entry(table + (record + 1) * entry_size)?;
+
+    let (min, max) = if section.flags & section_flags::ENCRYPTED != 0 {
+        let tag = AUTH_TAG_SIZE as u64;
+        (tag, u64::from(page_size) + tag)
+    } else {
+        (0, u64::from(page_size))
+    };
+    if start < first || end < start || end > section.length {
+        return Err(FormatError::BadRecordTable);
+    }
+    if !(min..=max).contains(&(end - start)) {
+        return Err(FormatError::BadRecordTable);
+    }
+    Ok(start..end)
+}
+
+/// Returns the length of the run of equal bytes at the start of `data`, at
+/// most [`PACK_MAX_RUN`].
+fn run_length(data: &[u8]) -> usize {
+    data.iter()
+        .take(PACK_MAX_RUN)
+        .take_while(|&&b| b == data[0])
+        .count()
+}
+
+/// Encodes `page` with PackBits into `out` and returns the length of the
+/// encoding, or `None` if it does not fit into `out`.
+///
+/// A block starts with a header byte `h`. If `h` is at most 127, it is
+/// followed by `h + 1` literal bytes. If `h` is at least 129, it is
+/// followed by one byte which is repeated `257 - h` times. A header of 128
+/// is skipped.
+pub fn pack_page(page: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    let mut pos = 0;
+    while pos < page.len() {
+        let run = run_length(&page[pos..]);
+        let (header, block) = if run >= PACK_MIN_REPEAT {
+            ((257 - run) as u8, &page[pos..pos + 1])
+        } else {
+            // Literal block up to the next repeat block.
+            let mut lit = 0;
+            while pos + lit < page.len()
+                && lit < PACK_MAX_RUN
+                && run_length(&page[pos + lit..]) < PACK_MIN_REPEAT
+            {
+                lit += 1;
+            }
+            ((lit - 1) as u8, &page[pos..pos + lit])
+        };
+        let end = len + 1 + block.len();
+        let dest = out.get_mut(len..end)?;
+        dest[0] = header;
+        dest[1..].copy_from_slice(block);
+        len = end;
+        pos += if run >= PACK_MIN_REPEAT {
+            run
+        } else {
+            block.len()
+        };
+    }
+    Some(len)
+}
+
+/// Decodes the PackBits encoding `data` into `page`. Input beyond the end
+/// of the page is ignored and the rest of the page is zeroed if the input
+/// ends early, so decoding never fails.
+pub fn unpack_page(data: &[u8], page: &mut [u8]) {
+    let mut data = data.iter().copied();
+    let mut pos = 0;
+    while pos < page.len() {
+        let Some(header) = data.next() else {
+            break;
+        };
+        match header {
+            0..=127 => {
+                for _ in 0..=header {
+                    match data.next() {
+                        Some(b) if pos < page.len() => {
+                            page[pos] = b;
+                            pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            128 => {}
+            _ => {
+                let Some(b) = data.next() else {
+                    break;
+                };
+                let end = (pos + 257 - header as usize).min(page.len());
+                page[pos..end].fill(b);
+                pos = end;
+            }
+        }
+    }
+    page[pos..].fill(0);
+}
+
+/// Decodes the (decrypted) record `record` of a compressed payload section
+/// into `page`.
+pub fn unpack_record(record: &[u8], page: &mut [u8]) {
+    if record.len() == page.len() {
+        page.copy_from_slice(record);
+    } else {
+        unpack_page(record, page);
+    }
+}
+
+/// A section table entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Section {
+    /// Kind of the section, see [`section_kind`].
+    pub kind: u16,
+    /// Flags of the section, see [`section_flags`].
+    pub flags: u16,
+    /// Offset of the section from the start of the container.
+    pub offset: u64,
+    /// Size of the section as stored in the container.
+    pub length: u64,
+    /// Digest of the stored section contents.
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl Section {
+    /// Serializes the section entry in the current format version.
+    pub fn to_bytes(&self) -> [u8; SECTION_ENTRY_SIZE] {
+        let mut buf = [0u8; SECTION_ENTRY_SIZE];
+        write_u16(&mut buf, 0, self.kind);
+        write_u16(&mut buf, 2, self.flags);
+        write_u64(&mut buf, 8, self.offset);
+        write_u64(&mut buf, 16, self.length);
+        buf[24..24 + DIGEST_SIZE].copy_from_slice(&self.digest);
+        buf
+    }
+
+    /// Parses a section entry. `buf` must hold at least
+    /// [`SECTION_ENTRY_SIZE`] bytes, any bytes beyond are ignored.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, FormatError> {
+        let buf = buf
+            .get(..SECTION_ENTRY_SIZE)
+            .ok_or(FormatError::Truncated)?;
+        let flags = read_u16(buf, 2);
+        if flags & !section_flags::KNOWN != 0 {
+            return Err(FormatError::UnknownSectionFlags(flags));
+        }
+        Ok(Self {
+            kind: read_u16(buf, 0),
+            flags,
+            offset: read_u64(buf, 8),
+            length: read_u64(buf, 16),
+            digest: buf[24..24 + DIGEST_SIZE].try_into().unwrap(),
+        })
+    }
+
+    /// Returns whether the section holds page contents.
+    pub fn is_payload(&self) -> bool {
+        self.kind == section_kind::PAYLOAD
+    }
+}
+
+/// Offsets of the parts of a container written in the current format
+/// version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContainerLayout {
+    /// Offset of the extent table.
+    pub extent_table_offset: u64,
+    /// Offset of the section table.
+    pub section_table_offset: u64,
+    /// Offset of the first payload section, aligned to the page size.
+    pub payload_offset: u64,
+}
+
+impl ContainerLayout {
+    /// Computes the layout of a container with the given number of table
+    /// entries.
+    pub fn new(extent_count: u32, section_count: u32, page_size: u32) -> Self {
+        let extent_table_offset = HEADER_SIZE as u64;
+        let section_table_offset =
+            extent_table_offset + u64::from(extent_count) * EXTENT_ENTRY_SIZE as u64;
+        let tables_end =
+            section_table_offset + u64::from(section_count) * SECTION_ENTRY_SIZE as u64;
+        let page_size = u64::from(page_size);
+        let payload_offset = tables_end.div_ceil(page_size) * page_size;
+        Self {
+            extent_table_offset,
+            section_table_offset,
+            payload_offset,
+        }
+    }
+
+    /// Builds a header for this layout.
+    pub fn header(
+        &self,
+        extent_count: u32,
+        section_count: u32,
+        page_size: u32,
+        total_size: u64,
+    ) -> SnapshotHeader {
+        SnapshotHeader {
+            version_major: FORMAT_VERSION_MAJOR,
+            version_minor: FORMAT_VERSION_MINOR,
+            header_size: HEADER_SIZE as u32,
+            page_size,
+            digest_alg: DigestAlgorithm::Sha256,
+            extent_entry_size: EXTENT_ENTRY_SIZE as u16,
+            section_entry_size: SECTION_ENTRY_SIZE as u16,
+            extent_count,
+            section_count,
+            extent_table_offset: self.extent_table_offset,
+            section_table_offset: self.section_table_offset,
+            total_size,
+        }
+    }
+}
+
+/// A parsed container held entirely in memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Container<'a> {
+    header: SnapshotHeader,
+    data: &'a [u8],
+}
+
+impl<'a> Container<'a> {
+    /// Parses the header of the container in `data` and checks that the
+    /// container is complete.
+    pub fn parse(data: &'a [u8]) -> Result<Self, FormatError> {
+        let header = SnapshotHeader::from_bytes(data)?;
+        let total_size = usize::try_from(header.total_size).map_err(|_| FormatError::Truncated)?;
+        let data = data.get(..total_size).ok_or(FormatError::Truncated)?;
+        Ok(Self { header, data })
+    }
+
+    /// Returns the container header.
+    pub fn header(&self) -> &SnapshotHeader {
+        &self.header
+    }
+
+    fn entry(&self, offset: Option<u64>, size: u16) -> Result<&'a [u8], FormatError> {
+        let start = offset
+            .and_then(|o| usize::try_from(o).ok())
+            .ok_or(FormatError::OutOfBounds)?;
+        let end = start
+            .checked_add(usize::from(size))
+            .ok_or(FormatError::OutOfBounds)?;
+        self.data.get(start..end).ok_or(FormatError::OutOfBounds)
+    }
+
+    /// Returns the extent with the given index.
+    pub fn extent(&self, index: u32) -> Result<Extent, FormatError> {
+        let buf = self.entry(
+            self.header.extent_offset(index),
+            self.header.extent_entry_size,
+        )?;
+        Extent::from_bytes(buf)
+    }
+
+    /// Returns an iterator over all extents.
+    pub fn extents(&self) -> impl Iterator<Item = Result<Extent, FormatError>> + '_ {
+        (0..self.header.extent_count).map(|i| self.extent(i))
+    }
+
+    /// Returns the section with the given index.
+    pub fn section(&self, index: u32) -> Result<Section, FormatError> {
+        let buf = self.entry(
+            self.header.section_offset(index),
+            self.header.section_entry_size,
+        )?;
+        Section::from_bytes(buf)
+    }
+
+    /// Returns an iterator over all sections.
+    pub fn sections(&self) -> impl Iterator<Item = Result<Section, FormatError>> + '_ {
+        (0..self.header.section_count).map(|i| self.section(i))
+    }
+
+    /// Returns the stored contents of a section.
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8], FormatError> {
+        let start = usize::try_from(section.offset).map_err(|_| FormatError::OutOfBounds)?;
+        let len = usize::try_from(section.length).map_err(|_| FormatError::OutOfBounds)?;
+        let end = start.checked_add(len).ok_or(FormatError::OutOfBounds)?;
+        self.data.get(start..end).ok_or(FormatError::OutOfBounds)
+    }
+
+    /// Checks the stored contents of a section against its digest.
+    pub fn verify_section(&self, section: &Section) -> Result<(), FormatError> {
+        let data = self.section_data(section)?;
+        if self.header.digest_alg.digest(data) != section.digest {
+            return Err(FormatError::DigestMismatch);
+        }
+        Ok(())
+    }
+
+    /// Copies the contents of page `page` of a data extent into `out`,
+    /// which must be one page long, decoding them if the payload section is
+    /// compressed. Encrypted payload sections cannot be read without the
+    /// key.
+    pub fn read_page(&self, extent: &Extent, page: u64, out: &mut [u8]) -> Result<(), FormatError> {
+        let section = self.section(u32::from(extent.section))?;
+        if section.flags != section_flags::COMPRESSED {
+            out.copy_from_slice(self.page_data(extent, page)?);
+            return Ok(());
+        }
+        let page_size = u64::from(self.header.page_size);
+        let rel = page
+            .checked_mul(page_size)
+            .and_then(|o| o.checked_add(extent.payload_offset))
+            .ok_or(FormatError::OutOfBounds)?;
+        // Records hold whole pages.
+        if rel % page_size != 0 {
+            return Err(FormatError::OutOfBounds);
+        }
+        let data = self.section_data(&section)?;
+        let range =
+            compressed_record_range(&section, self.header.page_size, rel / page_size, |off| {
+                let off = usize::try_from(off).map_err(|_| FormatError::OutOfBounds)?;
+                off.checked_add(RECORD_ENTRY_SIZE)
+                    .and_then(|end| data.get(off..end))
+                    .map(|entry| read_u64(entry, 0))
+                    .ok_or(FormatError::OutOfBounds)
+            })?;
+        // The range lies within the section data.
+        unpack_record(&data[range.start as usize..range.end as usize], out);
+        Ok(())
+    }
+
+    /// Returns the contents of page `page` of a data extent stored in a
+    /// plain (neither compressed nor encrypted) payload section.
+    pub fn page_data(&self, extent: &Extent, page: u64) -> Result<&'a [u8], FormatError> {
+        let section = self.section(u32::from(extent.section))?;
+        if section.flags != 0 {
+            return Err(FormatError::EncodedSection(section.flags));
+        }
+        let data = self.section_data(&section)?;
+        let page_size = u64::from(self.header.page_size);
+        let start = page
+            .checked_mul(page_size)
+            .and_then(|o| o.checked_add(extent.payload_offset))
+            .and_then(|o| usize::try_from(o).ok())
+            .ok_or(FormatError::OutOfBounds)?;
+        let end = start
+            .checked_add(self.header.page_size as usize)
+            .ok_or(FormatError::OutOfBounds)?;
+        data.get(start..end).ok_or(FormatError::OutOfBounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    const PAGE: u32 = 4096;
+
+    fn build() -> Vec<u8> {
+        let layout = ContainerLayout::new(2, 1, PAGE);
+        let payload_len = u64::from(PAGE) * 2;
+        let total = layout.payload_offset + payload_len;
+        let mut buf = vec![0u8; total as usize];
+
+        let header = layout.header(2, 1, PAGE, total);
+        buf[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+        let extents = [
+            Extent {
+                gpa: 0x1000,
+                page_count: 2,
+                kind: ExtentKind::Data,
+                section: 0,
+                payload_offset: 0,
+            },
+            Extent {
+                gpa: 0x10000,
+                page_count: 16,
+                kind: ExtentKind::Zero,
+                section: 0,
+                payload_offset: 0,
+            },
+        ];
+        for (i, e) in extents.iter().enumerate() {
+            let off = header.extent_offset(i as u32).unwrap() as usize;
+            buf[off..off + EXTENT_ENTRY_SIZE].copy_from_slice(&e.to_bytes());
+        }
+
+        let payload = layout.payload_offset as usize;
+        buf[payload..payload + PAGE as usize].fill(0xaa);
+        buf[payload + PAGE as usize..].fill(0xbb);
+
+        let section = Section {
+            kind: section_kind::PAYLOAD,
+            flags: 0,
+            offset: layout.payload_offset,
+            length: payload_len,
+            digest: header.digest_alg.digest(&buf[payload..]),
+        };
+        let off = header.section_offset(0).unwrap() as usize;
+        buf[off..off + SECTION_ENTRY_SIZE].copy_from_slice(&section.to_bytes());
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = build();
+        let container = Container::parse(&buf).unwrap();
+        assert_eq!(container.header().extent_count, 2);
+
+        let extents: Vec<_> = container.extents().map(Result::unwrap).collect();
+        assert_eq!(extents[0].gpa, 0x1000);
+        assert_eq!(extents[1].kind, ExtentKind::Zero);
+
+        let page0 = container.page_data(&extents[0], 0).unwrap();
+        let page1 = container.page_data(&extents[0], 1).unwrap();
+        assert!(page0.iter().all(|&b| b == 0xaa));
+        assert!(page1.iter().all(|&b| b == 0xbb));
+        assert_eq!(
+            container.page_data(&extents[0], 2),
+            Err(FormatError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn version_rules() {
+        let mut buf = build();
+        // Newer minor versions are accepted.
+        write_u16(&mut buf, 10, FORMAT_VERSION_MINOR + 1);
+        assert!(Container::parse(&buf).is_ok());
+        // Other major versions are rejected.
+        write_u16(&mut buf, 8, FORMAT_VERSION_MAJOR + 1);
+        assert_eq!(
+            Container::parse(&buf).unwrap_err(),
+            FormatError::UnsupportedVersion(FORMAT_VERSION_MAJOR + 1, FORMAT_VERSION_MINOR + 1)
+        );
+    }
+
+    #[test]
+    fn section_digests() {
+        let mut buf = build();
+        let container = Container::parse(&buf).unwrap();
+        assert_eq!(container.header().digest_alg, DigestAlgorithm::Sha256);
+        let section = container.section(0).unwrap();
+        assert_eq!(container.verify_section(&section), Ok(()));
+
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let container = Container::parse(&buf).unwrap();
+        assert_eq!(
+            container.verify_section(&section),
+            Err(FormatError::DigestMismatch)
+        );
+
+        // Containers without digests carry zero digest fields.
+        write_u16(&mut buf, 20, DigestAlgorithm::None as u16);
+        let container = Container::parse(&buf).unwrap();
+        let section = Section {
+            digest: [0; DIGEST_SIZE],
+            ..section
+        };
+        assert_eq!(container.verify_section(&section), Ok(()));
+        write_u16(&mut buf, 20, 2);
+        assert_eq!(
+            Container::parse(&buf).unwrap_err(),
+            FormatError::UnknownDigest(2)
+        );
+    }
+
+    #[test]
+    fn stats_round_trip() {
+        let stats = SnapshotStats {
+            creation_ns: 1_500_000,
+            cow_faults: 42,
+            logical_pages: 1024,
+            stored_pages: 256,
+            payload_bytes: 256 * 4096,
+            stored_bytes: 256 * 4096,
+        };
+        let mut buf = vec![0u8; STATS_SIZE + 8];
+        buf[..STATS_SIZE].copy_from_slice(&stats.to_bytes());
+        // Fields appended by later minor versions are skipped.
+        assert_eq!(SnapshotStats::from_bytes(&buf), Ok(stats));
+        assert_eq!(
+            SnapshotStats::from_bytes(&buf[..STATS_SIZE - 1]),
+            Err(FormatError::Truncated)
+        );
+    }
+
+    #[test]
+    fn encrypted_layout() {
+        let record = u64::from(PAGE) + AUTH_TAG_SIZE as u64;
+        assert_eq!(encrypted_record_offset(0, PAGE), Some(8));
+        assert_eq!(encrypted_record_offset(3, PAGE), Some(8 + 3 * record));
+        assert_eq!(encrypted_section_length(2, PAGE), Some(8 + 2 * record));
+        assert_eq!(encrypted_record_offset(u64::MAX, PAGE), None);
+
+        let prefix = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            encrypted_nonce(&prefix, 0x0102_0304),
+            Some([1, 2, 3, 4, 5, 6, 7, 8, 4, 3, 2, 1])
+        );
+        assert_eq!(encrypted_nonce(&prefix, 1 << 32), None);
+
+        let mut buf = build();
+        let off = Container::parse(&buf)
+            .unwrap()
+            .header()
+            .section_offset(0)
+            .unwrap() as usize;
+        write_u16(&mut buf, off + 2, section_flags::ENCRYPTED);
+        let container = Container::parse(&buf).unwrap();
+        let extent = container.extent(0).unwrap();
+        assert_eq!(
+            container.page_data(&extent, 0),
+            Err(FormatError::EncodedSection(section_flags::ENCRYPTED))
+        );
+    }
+
+    #[test]
+    fn packbits() {
+        let mut page = [0x5au8; PAGE as usize];
+        page[100..110].copy_from_slice(b"0123456789");
+        page[2000] = 1;
+        page[4095] = 2;
+        let mut packed = [0u8; 128];
+        let len = pack_page(&page, &mut packed).unwrap();
+        let mut decoded = [0xffu8; PAGE as usize];
+        unpack_page(&packed[..len], &mut decoded);
+        assert_eq!(decoded, page);
+        // The encoding does not fit.
+        assert_eq!(pack_page(&page, &mut packed[..len - 1]), None);
+
+        // Truncated input zeroes the rest of the page.
+        unpack_page(&[0x81, 7, 5], &mut decoded);
+        assert!(decoded[..128].iter().all(|&b| b == 7));
+        assert!(decoded[128..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn compressed_records() {
+        let mut buf = build();
+        let container = Container::parse(&buf).unwrap();
+        let header = *container.header();
+        let mut section = container.section(0).unwrap();
+
+        // Two records: a packed page of 0xaa and a plain page of 0xbb.
+        let mut packed = [0u8; PAGE as usize];
+        let len = pack_page(&[0xaa; PAGE as usize], &mut packed).unwrap() as u64;
+        let first = 3 * RECORD_ENTRY_SIZE as u64;
+        let table = [first, first + len, first + len + u64::from(PAGE)];
+        let start = section.offset as usize;
+        for (i, entry) in table.iter().enumerate() {
+            write_u64(&mut buf, start + i * RECORD_ENTRY_SIZE, *entry);
+        }
+        let records = start + first as usize;
+        buf[records..records + len as usize].copy_from_slice(&packed[..len as usize]);
+        buf[records + len as usize..records + len as usize + PAGE as usize].fill(0xbb);
+
+        section.flags = section_flags::COMPRESSED;
+        section.length = table[2];
+        section.digest = header
+            .digest_alg
+            .digest(&buf[start..start + table[2] as usize]);
+        let off = header.section_offset(0).unwrap() as usize;
+        buf[off..off + SECTION_ENTRY_SIZE].copy_from_slice(&section.to_bytes());
+
+        let container = Container::parse(&buf).unwrap();
+        assert_eq!(container.verify_section(&section), Ok(()));
+        let extent = container.extent(0).unwrap();
+        let mut page = [0u8; PAGE as usize];
+        container.read_page(&extent, 0, &mut page).unwrap();
+        assert!(page.iter().all(|&b| b == 0xaa));
+        container.read_page(&extent, 1, &mut page).unwrap();
+        assert!(page.iter().all(|&b| b == 0xbb));
+        assert_eq!(
+            container.read_page(&extent, 2, &mut page),
+            Err(FormatError::OutOfBounds)
+        );
+        assert_eq!(
+            container.page_data(&extent, 0),
+            Err(FormatError::EncodedSection(section_flags::COMPRESSED))
+        );
+
+        let read =
+            |entries: [u64; 3]| move |off: u64| Ok(entries[off as usize / RECORD_ENTRY_SIZE]);
+        // Records must lie within the section and hold at most one page.
+        let page_size = u64::from(PAGE);
+        let bad = [first, first + 2 * page_size, first + 2 * page_size];
+        assert_eq!(
+            compressed_record_range(&section, PAGE, 0, read(bad)),
+            Err(FormatError::BadRecordTable)
+        );
+        let bad = [first, first - 1, first];
+        assert_eq!(
+            compressed_record_range(&section, PAGE, 0, read(bad)),
+            Err(FormatError::BadRecordTable)
+        );
+        let bad = [first + 1, first + 1, first + 1];
+        assert_eq!(
+            compressed_record_range(&section, PAGE, 0, read(bad)),
+            Err(FormatError::BadRecordTable)
+        );
+        assert_eq!(
+            compressed_record_range(&section, PAGE, 1, read(table)),
+            Ok(first + len..table[2])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut buf = build();
+        assert_eq!(
+            Container::parse(&buf[..HEADER_SIZE]).unwrap_err(),
+            FormatError::Truncated
+        );
+        buf[0] = b'X';
+        assert_eq!(Container::parse(&buf).unwrap_err(), FormatError::BadMagic);
+    }
+}